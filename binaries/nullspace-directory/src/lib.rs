@@ -0,0 +1,121 @@
+//! The nullspace directory. The binary runs it from the command line; it is also a library so
+//! that other crates can embed a directory, for example in tests.
+
+mod db;
+mod merkle;
+mod mirror;
+mod pow;
+mod server;
+mod state;
+
+use std::{path::Path, sync::Arc, time::Duration};
+
+use axum::{Router, routing::post};
+use futures_concurrency::future::Join;
+use tokio::net::TcpListener;
+use nullspace_crypt::signing::SigningSecret;
+use url::Url;
+
+use crate::{mirror::MirrorState, state::DirectoryState};
+
+const DIRECTORY_ID: &str = "nullspace-directory";
+
+pub use pow::POW_EFFORT;
+
+/// How often an anchor directory commits staged updates into a chunk.
+pub const CHUNK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Whether a directory signs its own chunks or follows another directory.
+pub enum DirectoryRole {
+    /// Signs anchors with the secret key, committing a chunk every `chunk_interval` and asking
+    /// for proofs of work of `pow_effort` from updates.
+    Anchor {
+        secret_key: Box<SigningSecret>,
+        chunk_interval: Duration,
+        pow_effort: u64,
+    },
+    /// Mirrors the directory at the endpoint, forwarding updates to it.
+    Mirror(Url),
+}
+
+/// Serves a directory on `listener`, keeping its state under `db_path`.
+pub async fn serve(
+    listener: TcpListener,
+    db_path: &Path,
+    role: DirectoryRole,
+) -> anyhow::Result<()> {
+    std::fs::create_dir_all(db_path)?;
+
+    let (pool_res, merkle_res) = (db::init_sqlite(db_path), async {
+        merkle::open_store(db_path)
+    })
+        .join()
+        .await;
+    let pool = pool_res?;
+    let merkle = merkle_res?;
+
+    let (secret_key, mirror, chunk_interval, pow_effort) = match role {
+        DirectoryRole::Anchor {
+            secret_key,
+            chunk_interval,
+            pow_effort,
+        } => {
+            tracing::info!(
+                anchor_public_key = %secret_key.public_key(),
+                "directory anchor public key"
+            );
+            (Some(*secret_key), None, chunk_interval, pow_effort)
+        }
+        DirectoryRole::Mirror(endpoint) => {
+            tracing::info!(endpoint = %endpoint, "directory mirror enabled");
+            (
+                None,
+                Some(Arc::new(MirrorState::new(endpoint))),
+                CHUNK_INTERVAL,
+                POW_EFFORT,
+            )
+        }
+    };
+    let state = Arc::new(DirectoryState {
+        pool,
+        merkle,
+        secret_key,
+        directory_id: DIRECTORY_ID.into(),
+        pow_effort,
+        staging: tokio::sync::Mutex::new(Default::default()),
+        mirror,
+    });
+
+    if state.mirror.is_some() {
+        tokio::spawn(mirror::run_mirror_sync(state.clone()));
+    } else {
+        tokio::spawn(run_chunker(state.clone(), chunk_interval));
+    }
+
+    let app = Router::new()
+        .route("/", post(server::rpc_handler))
+        .with_state(state);
+    axum::serve(listener, app).await?;
+    Ok(())
+}
+
+async fn run_chunker(state: Arc<DirectoryState>, interval: Duration) {
+    let interval_ms = interval.as_millis().max(1) as u64;
+    loop {
+        let now_ms = unix_time_ms();
+        let wait = interval_ms - (now_ms % interval_ms);
+        tokio::time::sleep(Duration::from_millis(wait)).await;
+        if let Err(err) = server::commit_chunk(state.clone()).await {
+            tracing::error!(error = ?err, "failed to commit chunk");
+            tokio::time::sleep(Duration::from_secs(1)).await;
+        }
+    }
+}
+
+fn unix_time_ms() -> u64 {
+    use std::time::{SystemTime, UNIX_EPOCH};
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_millis() as u64
+}
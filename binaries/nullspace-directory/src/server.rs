@@ -61,8 +61,9 @@ pub async fn rpc_handler(
 #[async_trait::async_trait]
 impl DirectoryProtocol for DirectoryServer {
     async fn v1_get_pow_seed(&self) -> PowSeed {
-        let seed = pow::new_seed();
-        if let Err(err) = db::insert_pow_seed(&self.state.pool, &seed, pow::POW_EFFORT).await {
+        let effort = self.state.pow_effort;
+        let seed = pow::new_seed(effort);
+        if let Err(err) = db::insert_pow_seed(&self.state.pool, &seed, effort).await {
             tracing::warn!(error = ?err, "failed to insert pow seed");
         }
         if let Err(err) = db::purge_pow_seeds(&self.state.pool, unix_time()).await {
@@ -14,6 +14,7 @@ pub struct DirectoryState {
     pub merkle: Arc<MeshaNodeStore>,
     pub secret_key: Option<SigningSecret>,
     pub directory_id: SmolStr,
+    pub pow_effort: u64,
     pub staging: Mutex<BTreeMap<String, Vec<DirectoryUpdate>>>,
     pub mirror: Option<Arc<MirrorState>>,
 }
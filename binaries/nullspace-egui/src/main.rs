@@ -2,16 +2,18 @@ use std::collections::BTreeMap;
 use std::path::PathBuf;
 use std::sync::Arc;
 use std::sync::atomic::{AtomicBool, Ordering};
-use std::time::Duration;
+use std::time::{Duration, Instant};
 
 use clap::Parser;
 
 use egui::{Modal, Spinner};
 use egui_file_dialog::FileDialog as EguiFileDialog;
-use nullspace_client::{Client, Config, internal::Event};
+use nullspace_client::internal::{ConvoId, Event};
+use nullspace_client::{Client, Config, RpcSocketConfig, default_medium_key_retention_secs};
 use nullspace_crypt::hash::Hash;
 use nullspace_crypt::signing::SigningPublic;
 use nullspace_structs::fragment::Attachment;
+use nullspace_structs::username::UserName;
 use tokio::{
     runtime::Runtime,
     sync::mpsc::{self, Receiver},
@@ -47,6 +49,18 @@ struct Cli {
     dir_endpoint: String,
     #[arg(long, default_value = DEFAULT_DIR_ANCHOR_PK)]
     dir_anchor_pk: String,
+    #[arg(long, default_value_t = default_medium_key_retention_secs())]
+    medium_key_retention_secs: u64,
+    /// Also serve the client RPC on this Unix socket.
+    #[arg(long)]
+    rpc_socket: Option<PathBuf>,
+    /// Permissions of the RPC socket, in octal.
+    #[arg(long, default_value = "600", value_parser = parse_octal_mode)]
+    rpc_socket_mode: u32,
+}
+
+fn parse_octal_mode(value: &str) -> Result<u32, std::num::ParseIntError> {
+    u32::from_str_radix(value.trim_start_matches("0o"), 8)
 }
 
 struct NullspaceApp {
@@ -79,6 +93,8 @@ struct AppState {
     upload_error: BTreeMap<i64, String>,
     download_progress: BTreeMap<Hash, (u64, u64)>,
     download_error: BTreeMap<Hash, String>,
+
+    typing: BTreeMap<ConvoId, BTreeMap<UserName, Instant>>,
 }
 
 impl NullspaceApp {
@@ -167,6 +183,7 @@ impl NullspaceApp {
                 upload_error: BTreeMap::new(),
                 download_progress: BTreeMap::new(),
                 download_error: BTreeMap::new(),
+                typing: BTreeMap::new(),
             },
         }
     }
@@ -213,13 +230,20 @@ impl eframe::App for NullspaceApp {
             match event {
                 Event::State { logged_in } => self.state.logged_in = Some(logged_in),
                 Event::ConvoUpdated { convo_id } => {
-                    let _ = convo_id;
+                    self.state.typing.remove(&convo_id);
                     self.state.msg_updates = self.state.msg_updates.saturating_add(1);
                 }
                 Event::GroupUpdated { group } => {
                     let _ = group;
                     self.state.msg_updates = self.state.msg_updates.saturating_add(1);
                 }
+                Event::Typing { convo_id, user } => {
+                    self.state
+                        .typing
+                        .entry(convo_id)
+                        .or_default()
+                        .insert(user, Instant::now());
+                }
                 Event::UploadProgress {
                     id,
                     uploaded_size,
@@ -287,6 +311,14 @@ impl eframe::App for NullspaceApp {
                         .insert(attachment_id, error.to_string());
                     self.state.attach_updates += 1;
                 }
+                Event::VerifiedKeyChanged { username } => {
+                    tracing::warn!(username = %username, "verified contact key changed");
+                    self.state.error_dialog = Some(format!(
+                        "The safety number with {username} changed. Their account may have been \
+                         reset, or someone may be impersonating them. Verify it again before \
+                         trusting new messages."
+                    ));
+                }
             }
         }
         egui::CentralPanel::default().show(ctx, |ui| {
@@ -368,6 +400,12 @@ fn main() -> eframe::Result<()> {
             .dir_anchor_pk
             .parse::<SigningPublic>()
             .expect("dir anchor pk"),
+        medium_key_retention_secs: cli.medium_key_retention_secs,
+        rpc_socket: cli.rpc_socket.map(|path| RpcSocketConfig {
+            path,
+            mode: cli.rpc_socket_mode,
+            lz4: false,
+        }),
     };
     let client = Client::new(config);
     let mut options = eframe::NativeOptions::default();
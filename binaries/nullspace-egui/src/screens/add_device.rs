@@ -4,6 +4,7 @@ use eframe::egui::{DragValue, Response, TextEdit, Widget, Window};
 use egui_hooks::UseHookExt;
 use egui_hooks::hook::state::Var;
 use poll_promise::Promise;
+use nullspace_client::internal::ProvisionCode;
 use nullspace_structs::timestamp::Timestamp;
 
 use crate::NullspaceApp;
@@ -21,6 +22,8 @@ impl Widget for AddDevice<'_> {
         let mut never_expires: Var<bool> = ui.use_state(|| true, ()).into_var();
         let mut expiry_days: Var<u32> = ui.use_state(|| 365, ()).into_var();
         let mut bundle_str: Var<String> = ui.use_state(String::new, ()).into_var();
+        let mut code_str: Var<String> = ui.use_state(String::new, ()).into_var();
+        let send_req = ui.use_state(PromiseSlot::<Result<(), String>>::new, ());
         let bundle_req = ui.use_state(
             PromiseSlot::<Result<nullspace_client::internal::NewDeviceBundle, String>>::new,
             (),
@@ -34,7 +37,7 @@ impl Widget for AddDevice<'_> {
                 .default_pos(center)
                 .open(&mut window_open)
                 .show(ui.ctx(), |ui| {
-                    ui.label("Generate a device bundle here, then paste it into the new device");
+                    ui.label("Choose what the new device may do");
                     let busy = bundle_req.is_running();
                     ui.checkbox(&mut can_issue, "Allow this device to issue new devices");
                     ui.checkbox(&mut never_expires, "Never expires");
@@ -44,18 +47,52 @@ impl Widget for AddDevice<'_> {
                             ui.add(DragValue::new(&mut *expiry_days).speed(1));
                         });
                     });
+                    let expiry = if *never_expires {
+                        Timestamp(u64::MAX)
+                    } else {
+                        let secs = u64::from(*expiry_days)
+                            .saturating_mul(86_400)
+                            .saturating_add(Timestamp::now().0);
+                        Timestamp(secs)
+                    };
+                    ui.separator();
+                    ui.label("Enter the code shown on the new device");
+                    ui.horizontal(|ui| {
+                        ui.add(TextEdit::singleline(&mut *code_str).desired_width(240.0));
+                        let sending = send_req.is_running();
+                        if ui
+                            .add_enabled(
+                                !sending && !code_str.trim().is_empty(),
+                                eframe::egui::Button::new("Send"),
+                            )
+                            .clicked()
+                        {
+                            let code = ProvisionCode(code_str.trim().into());
+                            let can_issue = *can_issue;
+                            let promise = Promise::spawn_async(async move {
+                                flatten_rpc(get_rpc().provision_send(code, can_issue, expiry).await)
+                            });
+                            send_req.start(promise);
+                        }
+                    });
+                    if let Some(result) = send_req.take() {
+                        match result {
+                            Ok(()) => {
+                                code_str.clear();
+                                self.app.state.error_dialog =
+                                    Some("device bundle sent".to_string());
+                            }
+                            Err(err) => {
+                                self.app.state.error_dialog = Some(err);
+                            }
+                        }
+                    }
+                    ui.separator();
+                    ui.label("Or generate a device bundle here, then paste it into the new device");
                     if ui
                         .add_enabled(!busy, eframe::egui::Button::new("Generate bundle"))
                         .clicked()
                     {
-                        let expiry = if *never_expires {
-                            Timestamp(u64::MAX)
-                        } else {
-                            let secs = u64::from(*expiry_days)
-                                .saturating_mul(86_400)
-                                .saturating_add(Timestamp::now().0);
-                            Timestamp(secs)
-                        };
                         let can_issue = *can_issue;
                         let promise = Promise::spawn_async(async move {
                             flatten_rpc(get_rpc().new_device_bundle(can_issue, expiry).await)
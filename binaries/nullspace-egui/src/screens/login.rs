@@ -28,10 +28,13 @@ impl Widget for Login<'_> {
         let mut server_str = ui.use_state(|| "".to_string(), ()).into_var();
         let mut server_choice = ui.use_state(|| "~public_test".to_string(), ()).into_var();
         let mut custom_server_str = ui.use_state(|| "".to_string(), ()).into_var();
+        let mut invite_code = ui.use_state(String::new, ()).into_var();
         let mut bundle_str = ui.use_state(String::new, ()).into_var();
+        let mut provision_code = ui.use_state(String::new, ()).into_var();
 
         let register_start = ui.use_state(PromiseSlot::new, ());
         let register_finish = ui.use_state(PromiseSlot::new, ());
+        let provision_start = ui.use_state(PromiseSlot::new, ());
         Modal::new(ui.next_auto_id()).show(ui.ctx(), |ui| {
             ui.heading("Login or register");
             ui.separator();
@@ -115,6 +118,11 @@ impl Widget for Login<'_> {
                         );
                     }
 
+                    ui.add(
+                        TextEdit::singleline(&mut *invite_code)
+                            .hint_text("Invite code (if the server needs one)"),
+                    );
+
                     let register_enabled =
                         !register_start.is_running() && !register_finish.is_running();
 
@@ -132,9 +140,11 @@ impl Widget for Login<'_> {
                                 return;
                             }
                         };
+                        let invite_code = invite_code.trim();
                         let request = RegisterFinish::BootstrapNewUser {
                             username,
                             server_name,
+                            invite_code: (!invite_code.is_empty()).then(|| invite_code.into()),
                         };
                         let promise = Promise::spawn_async(async move {
                             flatten_rpc(get_rpc().register_finish(request).await)
@@ -170,6 +180,44 @@ impl Widget for Login<'_> {
                     ui.label(
                         RichText::new("On your other device, go to [File] > [Add device]").small(),
                     );
+                    ui.separator();
+                    ui.label("Or show a code here and enter it on your other device:");
+                    let provision_enabled =
+                        !provision_start.is_running() && !register_finish.is_running();
+                    if provision_code.is_empty() {
+                        if ui
+                            .add_enabled(provision_enabled, eframe::egui::Button::new("Show code"))
+                            .clicked()
+                        {
+                            let username: UserName = username_str.parse().unwrap();
+                            let promise = Promise::spawn_async(async move {
+                                flatten_rpc(get_rpc().provision_start(username).await)
+                            });
+                            provision_start.start(promise);
+                        }
+                    } else {
+                        ui.label(RichText::new(provision_code.as_str()).monospace().size(18.0));
+                    }
+                    if let Some(result) = provision_start.take() {
+                        match result {
+                            Ok(code) => {
+                                *provision_code = code.0.to_string();
+                                let username: UserName = username_str.parse().unwrap();
+                                let promise = Promise::spawn_async(async move {
+                                    let rpc = get_rpc();
+                                    let bundle =
+                                        flatten_rpc(rpc.provision_recv(username, code).await)?;
+                                    let request = RegisterFinish::AddDevice { bundle };
+                                    flatten_rpc(rpc.register_finish(request).await)
+                                });
+                                register_finish.start(promise);
+                            }
+                            Err(err) => {
+                                self.0.state.error_dialog = Some(format!("provision: {err}"));
+                            }
+                        }
+                    }
+                    ui.separator();
                     let add_enabled = !register_start.is_running() && !register_finish.is_running();
                     if ui
                         .add_enabled(add_enabled, eframe::egui::Button::new("Log in"))
@@ -198,6 +246,7 @@ impl Widget for Login<'_> {
                                 self.0.state.error_dialog = Some("device added".to_string());
                             }
                             Err(err) => {
+                                provision_code.clear();
                                 self.0.state.error_dialog = Some(format!("add device: {err}"));
                             }
                         }
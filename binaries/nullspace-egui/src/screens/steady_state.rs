@@ -32,6 +32,13 @@ impl Widget for SteadyState<'_> {
             },
             self.0.state.msg_updates,
         );
+        let requests = ui.use_memo(
+            || {
+                let result = pollster::block_on(get_rpc().request_list());
+                flatten_rpc(result)
+            },
+            self.0.state.msg_updates,
+        );
         let own_username = ui.use_memo(
             || {
                 let result = pollster::block_on(get_rpc().own_username());
@@ -90,6 +97,7 @@ impl Widget for SteadyState<'_> {
                 self.render_left(
                     ui,
                     &convos,
+                    &requests,
                     &mut selected_chat,
                     &mut show_add_contact,
                     &mut show_add_group,
@@ -129,6 +137,7 @@ impl<'a> SteadyState<'a> {
         &mut self,
         ui: &mut eframe::egui::Ui,
         convos: &Result<Vec<ConvoSummary>, String>,
+        requests: &Result<Vec<ConvoSummary>, String>,
         selected_chat: &mut Option<ConvoId>,
         show_add_contact: &mut bool,
         show_add_group: &mut bool,
@@ -142,6 +151,7 @@ impl<'a> SteadyState<'a> {
             }
         });
         ui.separator();
+        self.render_requests(ui, requests, selected_chat);
         match convos {
             Ok(lst) => {
                 ui.with_layout(Layout::top_down_justified(Align::Min), |ui| {
@@ -151,9 +161,11 @@ impl<'a> SteadyState<'a> {
                             ConvoId::Direct { peer } => {
                                 self.0.state.profile_loader.label_for(peer)
                             }
-                            ConvoId::Group { group_id } => {
-                                format!("Group {}", group_id.short_id())
-                            }
+                            ConvoId::Group { group_id } => convo
+                                .group_metadata
+                                .as_ref()
+                                .and_then(|metadata| metadata.name.clone())
+                                .unwrap_or_else(|| format!("Group {}", group_id.short_id())),
                         };
                         if ui
                             .selectable_label(*selected_chat == Some(selection.clone()), label)
@@ -170,6 +182,58 @@ impl<'a> SteadyState<'a> {
         }
     }
 
+    fn render_requests(
+        &mut self,
+        ui: &mut eframe::egui::Ui,
+        requests: &Result<Vec<ConvoSummary>, String>,
+        selected_chat: &mut Option<ConvoId>,
+    ) {
+        let requests = match requests {
+            Ok(requests) if requests.is_empty() => return,
+            Ok(requests) => requests,
+            Err(err) => {
+                self.0.state.error_dialog.replace(err.to_string());
+                return;
+            }
+        };
+        ui.collapsing(format!("Message requests ({})", requests.len()), |ui| {
+            for request in requests {
+                let ConvoId::Direct { peer } = &request.convo_id else {
+                    continue;
+                };
+                let selection = request.convo_id.clone();
+                let label = self.0.state.profile_loader.label_for(peer);
+                if ui
+                    .selectable_label(*selected_chat == Some(selection.clone()), label)
+                    .clicked()
+                {
+                    selected_chat.replace(selection.clone());
+                }
+                ui.horizontal(|ui| {
+                    let accept = ui.small_button("Accept").clicked();
+                    let block = ui.small_button("Block").clicked();
+                    let result = if accept {
+                        Some(pollster::block_on(get_rpc().request_accept(peer.clone())))
+                    } else if block {
+                        if *selected_chat == Some(selection) {
+                            selected_chat.take();
+                        }
+                        Some(pollster::block_on(get_rpc().contact_block(peer.clone())))
+                    } else {
+                        None
+                    };
+                    if let Some(result) = result {
+                        if let Err(err) = flatten_rpc(result) {
+                            self.0.state.error_dialog.replace(err);
+                        }
+                        self.0.state.msg_updates = self.0.state.msg_updates.saturating_add(1);
+                    }
+                });
+            }
+        });
+        ui.separator();
+    }
+
     fn render_right(&mut self, ui: &mut eframe::egui::Ui, selected_chat: &Option<ConvoId>) {
         if let Some(selection) = selected_chat {
             ui.add(Convo(self.0, selection.clone()));
@@ -1,12 +1,13 @@
 use chrono::{DateTime, Local};
 use eframe::egui::{Response, TextWrapMode, Widget, Window};
-use egui::{Color32, RichText};
+use egui::{Button, Color32, Rect, RichText, Sense, vec2};
 use egui_hooks::UseHookExt;
 use egui_taffy::{Tui, TuiBuilderLogic, tui};
 use nullspace_client::internal::UserDetails;
 use nullspace_structs::timestamp::NanoTimestamp;
 use nullspace_structs::username::UserName;
 use poll_promise::Promise;
+use qrcode::{Color as QrColor, QrCode};
 use taffy::style_helpers::{auto, fr, length};
 use taffy::{AlignItems, Display, FlexDirection, LengthPercentage, Size as TaffySize, Style};
 
@@ -37,10 +38,21 @@ impl Widget for UserInfo {
             .collapsible(false)
             .open(&mut window_open)
             .show(ui.ctx(), |ui| {
+                let mut refresh = ui.use_state(|| 0u64, username.clone()).into_var();
                 let details_promise = ui.use_state(
                     PromiseSlot::<Result<UserDetails, String>>::new,
-                    username.clone(),
+                    (username.clone(), *refresh),
                 );
+                let verify_promise =
+                    ui.use_state(PromiseSlot::<Result<(), String>>::new, username.clone());
+                if let Some(result) = verify_promise.take() {
+                    match result {
+                        Ok(()) => *refresh += 1,
+                        Err(err) => {
+                            ui.label(RichText::new(err).color(Color32::RED));
+                        }
+                    }
+                }
 
                 if details_promise.is_idle() {
                     let username = username.clone();
@@ -159,6 +171,46 @@ impl Widget for UserInfo {
                                     tui.wrap_mode(TextWrapMode::Extend).label("None");
                                 }
                             });
+
+                            // Safety number row
+                            render_info_row(tui, "Safety number", |tui| {
+                                tui.ui(|ui| {
+                                    let groups =
+                                        details.safety_number.digits.split(' ').collect::<Vec<_>>();
+                                    for line in groups.chunks(4) {
+                                        ui.label(RichText::new(line.join(" ")).monospace());
+                                    }
+                                    render_qr(ui, &details.safety_number.qr_payload);
+                                    if details.verified {
+                                        ui.label(RichText::new("Verified").color(Color32::GREEN));
+                                    } else {
+                                        ui.label(RichText::new("Not verified").color(Color32::GRAY));
+                                    }
+                                    let label = if details.verified {
+                                        "Clear verification"
+                                    } else {
+                                        "Mark as verified"
+                                    };
+                                    if ui
+                                        .add_enabled(
+                                            !verify_promise.is_running(),
+                                            Button::new(label),
+                                        )
+                                        .clicked()
+                                    {
+                                        let username = details.username.clone();
+                                        let verified = !details.verified;
+                                        let promise = Promise::spawn_async(async move {
+                                            flatten_rpc(
+                                                get_rpc()
+                                                    .user_set_verified(username, verified)
+                                                    .await,
+                                            )
+                                        });
+                                        verify_promise.start(promise);
+                                    }
+                                });
+                            });
                         });
                     });
             });
@@ -189,6 +241,37 @@ fn render_info_row(tui: &mut Tui, label: &str, content: impl FnOnce(&mut Tui)) {
     });
 }
 
+/// Paints a QR code of the payload, black on white with a quiet zone around it.
+fn render_qr(ui: &mut egui::Ui, payload: &str) {
+    const MODULE: f32 = 3.0;
+    const QUIET_MODULES: usize = 2;
+    let Ok(code) = QrCode::new(payload.as_bytes()) else {
+        ui.label(RichText::new("QR code unavailable").color(Color32::GRAY));
+        return;
+    };
+    let width = code.width();
+    let side = (width + 2 * QUIET_MODULES) as f32 * MODULE;
+    let (rect, _) = ui.allocate_exact_size(vec2(side, side), Sense::hover());
+    let painter = ui.painter_at(rect);
+    painter.rect_filled(rect, 0.0, Color32::WHITE);
+    for y in 0..width {
+        for x in 0..width {
+            if code[(x, y)] == QrColor::Dark {
+                let min = rect.min
+                    + vec2(
+                        (x + QUIET_MODULES) as f32 * MODULE,
+                        (y + QUIET_MODULES) as f32 * MODULE,
+                    );
+                painter.rect_filled(
+                    Rect::from_min_size(min, vec2(MODULE, MODULE)),
+                    0.0,
+                    Color32::BLACK,
+                );
+            }
+        }
+    }
+}
+
 fn format_timestamp(ts: Option<NanoTimestamp>) -> String {
     let Some(ts) = ts else {
         return "--:--".to_string();
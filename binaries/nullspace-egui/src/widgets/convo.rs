@@ -21,11 +21,15 @@ use crate::{NullspaceApp, widgets::convo::cluster::cluster_convo};
 use convo_state::ConvoState;
 use image_clip::{PasteImage, persist_paste_image, read_clipboard_image};
 use std::path::{Path, PathBuf};
+use std::time::Duration;
 
 mod cluster;
 mod convo_state;
 mod image_clip;
 
+/// How long a typing indicator is shown without a fresh one.
+const TYPING_TIMEOUT: Duration = Duration::from_secs(5);
+
 pub struct Convo<'a>(pub &'a mut NullspaceApp, pub ConvoId);
 
 fn infer_mime(path: &Path) -> SmolStr {
@@ -317,6 +321,8 @@ fn render_composer(
         }
     }
 
+    render_typing(ui, app, convo_id);
+
     ui.take_available_space();
 
     // the texting part
@@ -335,6 +341,10 @@ fn render_composer(
         })
         .inner;
 
+    if text_response.changed() && !draft.trim().is_empty() {
+        send_typing(convo_id);
+    }
+
     let enter_pressed = text_response.has_focus()
         && text_response
             .ctx
@@ -385,6 +395,34 @@ fn render_composer(
     }
 }
 
+fn render_typing(ui: &mut egui::Ui, app: &mut NullspaceApp, convo_id: &ConvoId) {
+    let Some(typing) = app.state.typing.get_mut(convo_id) else {
+        return;
+    };
+    typing.retain(|_, at| at.elapsed() < TYPING_TIMEOUT);
+    if typing.is_empty() {
+        return;
+    }
+    let names: Vec<String> = typing
+        .keys()
+        .map(|user| app.state.profile_loader.label_for(user))
+        .collect();
+    let verb = if names.len() == 1 { "is" } else { "are" };
+    ui.label(
+        RichText::new(format!("{} {verb} typing...", names.join(", ")))
+            .color(Color32::GRAY)
+            .size(11.0),
+    );
+    ui.ctx().request_repaint_after(Duration::from_secs(1));
+}
+
+fn send_typing(convo_id: &ConvoId) {
+    let convo_id = convo_id.clone();
+    tokio::spawn(async move {
+        let _ = flatten_rpc(get_rpc().convo_typing(convo_id).await);
+    });
+}
+
 fn send_message(convo_id: &ConvoId, message: String) {
     let convo_id = convo_id.clone();
     tokio::spawn(async move {
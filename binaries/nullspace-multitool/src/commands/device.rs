@@ -7,10 +7,11 @@ use clap::{Parser, Subcommand};
 use serde::{Serialize, de::DeserializeOwned};
 use url::Url;
 use nullspace_crypt::hash::{BcsHashExt, Hash};
+use nullspace_crypt::signing::SigningPublic;
 use nullspace_rpc_pool::RpcPool;
 use nullspace_structs::{
     Blob,
-    certificate::{CertificateChain, DeviceCertificate, DeviceSecret},
+    certificate::{CertificateChain, DeviceCertificate, DeviceRevocation, DeviceSecret},
     server::{AuthToken, ServerClient, MailboxId, MailboxRecvArgs},
     username::UserName,
     timestamp::{NanoTimestamp, Timestamp},
@@ -66,6 +67,17 @@ enum Command {
         #[arg(long)]
         chain: PathBuf,
     },
+    Revoke {
+        username: UserName,
+        device_hash: Hash,
+        #[arg(long)]
+        chain: PathBuf,
+        #[arg(long)]
+        issuer_secret: PathBuf,
+    },
+    Revocations {
+        username: UserName,
+    },
     MailboxSend {
         username: UserName,
         #[arg(long)]
@@ -93,6 +105,11 @@ struct AuthOutput {
     auth_token: AuthToken,
 }
 
+#[derive(Serialize)]
+struct StatusOutput {
+    status: &'static str,
+}
+
 #[derive(Serialize)]
 struct ChainDumpEntry {
     cert: DeviceCertificate,
@@ -103,8 +120,8 @@ pub async fn run(args: Args, global: &GlobalArgs) -> anyhow::Result<()> {
     let rpc_pool = RpcPool::new();
     match args.command {
         Command::List { username } => {
-            let endpoint = resolve_server_endpoint(global, &username).await?;
-            let client = ServerClient::from(rpc_pool.rpc(endpoint));
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
             let chains = client
                 .v1_device_certs(username)
                 .await?
@@ -117,8 +134,8 @@ pub async fn run(args: Args, global: &GlobalArgs) -> anyhow::Result<()> {
         }
         Command::Auth { username, chain } => {
             let chain = read_bcs::<CertificateChain>(&chain)?;
-            let endpoint = resolve_server_endpoint(global, &username).await?;
-            let client = ServerClient::from(rpc_pool.rpc(endpoint));
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
             let auth_token = client
                 .v1_device_auth(username, chain)
                 .await?
@@ -182,13 +199,39 @@ pub async fn run(args: Args, global: &GlobalArgs) -> anyhow::Result<()> {
                 .collect();
             print_json(&dump)?;
         }
+        Command::Revoke {
+            username,
+            device_hash,
+            chain,
+            issuer_secret,
+        } => {
+            let chain = read_bcs::<CertificateChain>(&chain)?;
+            let issuer_secret = read_bcs::<DeviceSecret>(&issuer_secret)?;
+            let revocation = DeviceRevocation::new(device_hash, chain, &issuer_secret);
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
+            client
+                .v1_device_revoke(username, revocation)
+                .await?
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+            print_json(&StatusOutput { status: "ok" })?;
+        }
+        Command::Revocations { username } => {
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
+            let revocations = client
+                .v1_device_revocations(username)
+                .await?
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+            print_json(&revocations)?;
+        }
         Command::MailboxSend {
             username,
             chain,
             message,
         } => {
-            let endpoint = resolve_server_endpoint(global, &username).await?;
-            let client = ServerClient::from(rpc_pool.rpc(endpoint));
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
             let chain = read_bcs::<CertificateChain>(&chain)?;
             let auth = client
                 .v1_device_auth(username.clone(), chain)
@@ -209,8 +252,8 @@ pub async fn run(args: Args, global: &GlobalArgs) -> anyhow::Result<()> {
             chain,
             timeout_ms,
         } => {
-            let endpoint = resolve_server_endpoint(global, &username).await?;
-            let client = ServerClient::from(rpc_pool.rpc(endpoint));
+            let (endpoint, server_pk) = resolve_server_endpoint(global, &username).await?;
+            let client = ServerClient::from(rpc_pool.rpc_pinned(endpoint, server_pk));
             let chain = read_bcs::<CertificateChain>(&chain)?;
             let auth = client
                 .v1_device_auth(username.clone(), chain)
@@ -242,7 +285,10 @@ pub async fn run(args: Args, global: &GlobalArgs) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn resolve_server_endpoint(global: &GlobalArgs, username: &UserName) -> anyhow::Result<Url> {
+async fn resolve_server_endpoint(
+    global: &GlobalArgs,
+    username: &UserName,
+) -> anyhow::Result<(Url, SigningPublic)> {
     let client = build_dir_client(global).await?;
     let descriptor = client
         .get_user_descriptor(username)
@@ -258,7 +304,7 @@ async fn resolve_server_endpoint(global: &GlobalArgs, username: &UserName) -> an
         .first()
         .cloned()
         .context("server has no public URLs")?;
-    Ok(url)
+    Ok((url, server.server_pk))
 }
 
 fn expiry_from_ttl(ttl_secs: Option<u64>) -> Timestamp {
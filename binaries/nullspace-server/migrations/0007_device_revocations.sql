@@ -0,0 +1,8 @@
+CREATE TABLE device_revocations (
+    device_hash BLOB PRIMARY KEY,
+    username TEXT NOT NULL,
+    revocation BLOB NOT NULL
+);
+
+CREATE INDEX device_revocations_username_idx
+    ON device_revocations (username);
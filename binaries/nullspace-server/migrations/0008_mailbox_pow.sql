@@ -0,0 +1 @@
+ALTER TABLE mailboxes ADD COLUMN pow_effort INTEGER;
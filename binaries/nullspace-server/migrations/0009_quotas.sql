@@ -0,0 +1,17 @@
+CREATE TABLE quota_usage (
+    subject TEXT NOT NULL,
+    kind TEXT NOT NULL,
+    window_start INTEGER NOT NULL,
+    amount INTEGER NOT NULL,
+    PRIMARY KEY (subject, kind)
+);
+
+ALTER TABLE mailbox_entries ADD COLUMN quota_subject TEXT;
+
+CREATE INDEX mailbox_entries_by_quota_subject
+    ON mailbox_entries (quota_subject);
+
+ALTER TABLE fragments ADD COLUMN quota_subject TEXT;
+
+CREATE INDEX fragments_by_quota_subject
+    ON fragments (quota_subject);
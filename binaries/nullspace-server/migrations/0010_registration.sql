@@ -0,0 +1,20 @@
+CREATE TABLE admitted_users (
+    username TEXT PRIMARY KEY,
+    admitted_at INTEGER NOT NULL
+);
+
+-- everyone who already has a device here stays admitted under any policy but the allowlist
+INSERT OR IGNORE INTO admitted_users (username, admitted_at)
+    SELECT DISTINCT username, 0 FROM device_auth_tokens;
+
+CREATE TABLE registration_allowlist (
+    username TEXT PRIMARY KEY,
+    added_at INTEGER NOT NULL
+);
+
+CREATE TABLE invite_codes (
+    code TEXT PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    used_by TEXT,
+    used_at INTEGER
+);
@@ -0,0 +1,6 @@
+-- usernames that said they are moving their account here, whose devices are accepted before the
+-- directory names this server
+CREATE TABLE pending_migrations (
+    username TEXT PRIMARY KEY,
+    expires_at INTEGER NOT NULL
+);
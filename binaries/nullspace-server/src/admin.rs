@@ -0,0 +1,92 @@
+use clap::Subcommand;
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+
+use crate::database::DATABASE;
+
+/// Commands for managing who may register at this server.
+#[derive(Subcommand, Debug)]
+pub enum AdminCommand {
+    /// Add a username to the registration allowlist.
+    Allow { username: UserName },
+    /// Remove a username from the registration allowlist. Its devices keep working, but it cannot
+    /// authenticate new ones.
+    Disallow { username: UserName },
+    /// List the usernames on the registration allowlist.
+    Allowlist,
+    /// Create single-use invite codes, printing one per line.
+    InviteCreate {
+        #[arg(long, default_value_t = 1)]
+        count: u32,
+    },
+    /// List the invite codes, and who used them.
+    InviteList,
+    /// Delete an invite code that was not used yet.
+    InviteRevoke { code: String },
+}
+
+pub async fn run(command: &AdminCommand) -> anyhow::Result<()> {
+    let db = &*DATABASE;
+    match command {
+        AdminCommand::Allow { username } => {
+            sqlx::query(
+                "INSERT OR IGNORE INTO registration_allowlist (username, added_at) VALUES (?, ?)",
+            )
+            .bind(username.as_str())
+            .bind(NanoTimestamp::now().0 as i64)
+            .execute(db)
+            .await?;
+        }
+        AdminCommand::Disallow { username } => {
+            sqlx::query("DELETE FROM registration_allowlist WHERE username = ?")
+                .bind(username.as_str())
+                .execute(db)
+                .await?;
+        }
+        AdminCommand::Allowlist => {
+            let usernames = sqlx::query_scalar::<_, String>(
+                "SELECT username FROM registration_allowlist ORDER BY username",
+            )
+            .fetch_all(db)
+            .await?;
+            for username in usernames {
+                println!("{username}");
+            }
+        }
+        AdminCommand::InviteCreate { count } => {
+            for _ in 0..*count {
+                let code = format!("{:016x}", rand::random::<u64>());
+                sqlx::query("INSERT INTO invite_codes (code, created_at) VALUES (?, ?)")
+                    .bind(&code)
+                    .bind(NanoTimestamp::now().0 as i64)
+                    .execute(db)
+                    .await?;
+                println!("{code}");
+            }
+        }
+        AdminCommand::InviteList => {
+            let rows = sqlx::query_as::<_, (String, Option<String>)>(
+                "SELECT code, used_by FROM invite_codes ORDER BY created_at",
+            )
+            .fetch_all(db)
+            .await?;
+            for (code, used_by) in rows {
+                match used_by {
+                    Some(username) => println!("{code}\tused by {username}"),
+                    None => println!("{code}\tunused"),
+                }
+            }
+        }
+        AdminCommand::InviteRevoke { code } => {
+            let result =
+                sqlx::query("DELETE FROM invite_codes WHERE code = ? AND used_by IS NULL")
+                    .bind(code)
+                    .execute(db)
+                    .await?;
+            if result.rows_affected() == 0 {
+                anyhow::bail!("no unused invite code {code}");
+            }
+        }
+    }
+    Ok(())
+}
@@ -6,11 +6,16 @@ use url::Url;
 use nullspace_crypt::signing::SigningPublic;
 use nullspace_structs::server::ServerName;
 
+use crate::admin::AdminCommand;
+
 #[derive(Parser, Debug)]
 #[command(name = "nullspace-server")]
-struct Args {
+pub struct Args {
     #[arg(long)]
     config: PathBuf,
+    /// Run an admin command against the server database instead of serving.
+    #[command(subcommand)]
+    pub admin: Option<AdminCommand>,
 }
 
 #[derive(Debug, Deserialize)]
@@ -18,6 +23,12 @@ pub struct Config {
     pub listen: SocketAddr,
     pub tcp_listen: Option<SocketAddr>,
     pub lz4_listen: Option<SocketAddr>,
+    /// Serves `noise+tcp://` clients, proving possession of the signing key to them.
+    pub noise_listen: Option<SocketAddr>,
+    /// Serves `ws://` clients, and `wss://` ones behind a TLS-terminating proxy.
+    pub ws_listen: Option<SocketAddr>,
+    /// Serves `unix://` or `lz4+unix://` clients on the same machine.
+    pub unix_listen: Option<UnixListenConfig>,
     pub db_path: String,
     pub fragments_path: PathBuf,
     pub signing_sk: PathBuf,
@@ -27,10 +38,67 @@ pub struct Config {
     pub directory_pk: SigningPublic,
     #[serde(default)]
     pub proxy_enabled: bool,
+    #[serde(default)]
+    pub quotas: QuotaConfig,
+    #[serde(default)]
+    pub registration: RegistrationPolicy,
+}
+
+#[derive(Debug, Deserialize)]
+pub struct UnixListenConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file.
+    #[serde(default = "default_unix_mode")]
+    pub mode: u32,
+    /// Compresses with LZ4, like `lz4_listen`.
+    #[serde(default)]
+    pub lz4: bool,
+}
+
+fn default_unix_mode() -> u32 {
+    0o600
+}
+
+/// Which usernames may authenticate devices at this server.
+#[derive(Clone, Copy, Debug, Default, Deserialize, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum RegistrationPolicy {
+    /// Any username that exists in the directory.
+    #[default]
+    Open,
+    /// Only usernames on the allowlist.
+    Allowlist,
+    /// Usernames that redeemed a single-use invite code, on their first device authentication.
+    Invite,
+}
+
+/// Usage quotas. Every limit is off unless configured.
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaConfig {
+    /// Limits for each username, shared by all of its devices.
+    pub user: QuotaLimits,
+    /// Limits for each auth token that is not a device token, such as group tokens.
+    pub token: QuotaLimits,
+    /// Limits shared by every sender that goes through the anonymous ACL entry of one mailbox.
+    pub anonymous: QuotaLimits,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(default)]
+pub struct QuotaLimits {
+    /// Bytes of unexpired mailbox entries and fragments.
+    pub stored_bytes: Option<u64>,
+    /// Bytes of fragments uploaded per UTC day.
+    pub frag_bytes_per_day: Option<u64>,
+    /// Mailbox entries sent per minute.
+    pub messages_per_minute: Option<u64>,
 }
 
+pub static ARGS: LazyLock<Args> = LazyLock::new(Args::parse);
+
 pub static CONFIG: LazyLock<Config> = LazyLock::new(|| {
-    let args = Args::parse();
+    let args = &*ARGS;
     let raw = fs::read_to_string(&args.config).unwrap_or_else(|err| {
         panic!("failed to read config {}: {err}", args.config.display());
     });
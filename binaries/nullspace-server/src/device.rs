@@ -1,21 +1,30 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::Duration;
 
 use nullspace_crypt::dh::DhPublic;
 use nullspace_crypt::hash::{BcsHashExt, Hash};
 use nullspace_crypt::signing::Signable;
-use nullspace_structs::certificate::CertificateChain;
+use nullspace_structs::certificate::{CertificateChain, DeviceRevocation};
 use nullspace_structs::server::{AuthToken, ServerRpcError, SignedMediumPk};
+use nullspace_structs::timestamp::NanoTimestamp;
 use nullspace_structs::username::UserName;
+use smol_str::SmolStr;
+use sqlx::{Sqlite, Transaction};
 
 use crate::config::CONFIG;
 use crate::database::DATABASE;
 use crate::dir_client::DIR_CLIENT;
 use crate::fatal_retry_later;
-use crate::mailbox;
+use crate::{mailbox, registration};
+
+/// How long a migration announced with `migration_prepare` lets devices of a username that is
+/// proper to another server authenticate here.
+const MIGRATION_PREPARE_TTL: Duration = Duration::from_secs(24 * 60 * 60);
 
 pub async fn device_auth(
     username: UserName,
     cert: CertificateChain,
+    invite_code: Option<SmolStr>,
 ) -> Result<AuthToken, ServerRpcError> {
     let device = cert.last_device();
     let device_hash = device.pk.bcs_hash();
@@ -28,7 +37,7 @@ pub async fn device_auth(
         tracing::debug!(username = %username, "device auth denied: username not in directory");
         return Err(ServerRpcError::AccessDenied);
     };
-    if descriptor.server_name != CONFIG.server_name {
+    if descriptor.server_name != CONFIG.server_name && !migration_pending(&username).await? {
         tracing::debug!(
             username = %username,
             expected = %CONFIG.server_name,
@@ -44,6 +53,11 @@ pub async fn device_auth(
     }
 
     let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let revoked = revoked_hashes(&mut tx, &username).await?;
+    if chain_is_revoked(&cert, &revoked) {
+        tracing::debug!(username = %username, "device auth denied: device revoked");
+        return Err(ServerRpcError::AccessDenied);
+    }
     let existing_token = sqlx::query_scalar::<_, Vec<u8>>(
         "SELECT auth_token FROM device_auth_tokens WHERE username = ? AND device_hash = ?",
     )
@@ -52,6 +66,10 @@ pub async fn device_auth(
     .fetch_optional(tx.as_mut())
     .await
     .map_err(fatal_retry_later)?;
+    // the registration policy only gates new devices; ones that already have a token keep it
+    if existing_token.is_none() {
+        registration::admit(&mut tx, &username, invite_code.as_deref()).await?;
+    }
     let has_existing_token = existing_token.is_some();
     let mut auth_token: Option<AuthToken> = match existing_token {
         Some(data) => Some(bcs::from_bytes(&data).map_err(fatal_retry_later)?),
@@ -97,28 +115,211 @@ pub async fn device_auth(
     Ok(auth_token)
 }
 
+/// Records that a username is moving its account here, so that its devices can authenticate before
+/// the directory names this server. The registration policy applies as for a new username.
+pub async fn migration_prepare(
+    username: UserName,
+    cert: CertificateChain,
+    invite_code: Option<SmolStr>,
+) -> Result<(), ServerRpcError> {
+    let descriptor = DIR_CLIENT
+        .get_user_descriptor(&username)
+        .await
+        .map_err(fatal_retry_later)?;
+    let Some(descriptor) = descriptor else {
+        tracing::debug!(username = %username, "migration denied: username not in directory");
+        return Err(ServerRpcError::AccessDenied);
+    };
+    if cert.verify(descriptor.root_cert_hash).is_err() {
+        tracing::debug!(username = %username, "migration denied: certificate chain invalid");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    if descriptor.server_name == CONFIG.server_name {
+        // already here, so devices authenticate as usual
+        return Ok(());
+    }
+
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let revoked = revoked_hashes(&mut tx, &username).await?;
+    if chain_is_revoked(&cert, &revoked) {
+        tracing::debug!(username = %username, "migration denied: device revoked");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    registration::admit(&mut tx, &username, invite_code.as_deref()).await?;
+    let now = NanoTimestamp::now().0;
+    sqlx::query("DELETE FROM pending_migrations WHERE expires_at <= ?")
+        .bind(now as i64)
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    sqlx::query("INSERT OR REPLACE INTO pending_migrations (username, expires_at) VALUES (?, ?)")
+        .bind(username.as_str())
+        .bind(now.saturating_add(MIGRATION_PREPARE_TTL.as_nanos() as u64) as i64)
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    tx.commit().await.map_err(fatal_retry_later)?;
+    tracing::debug!(
+        username = %username,
+        from = %descriptor.server_name,
+        "migration prepared"
+    );
+    Ok(())
+}
+
+async fn migration_pending(username: &UserName) -> Result<bool, ServerRpcError> {
+    let row = sqlx::query_scalar::<_, i64>(
+        "SELECT 1 FROM pending_migrations WHERE username = ? AND expires_at > ?",
+    )
+    .bind(username.as_str())
+    .bind(NanoTimestamp::now().0 as i64)
+    .fetch_optional(&*DATABASE)
+    .await
+    .map_err(fatal_retry_later)?;
+    Ok(row.is_some())
+}
+
 pub async fn device_list(
     username: UserName,
 ) -> Result<Option<BTreeMap<Hash, CertificateChain>>, ServerRpcError> {
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
     let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
         "SELECT device_hash, cert_chain FROM device_certificates WHERE username = ?",
     )
     .bind(username.as_str())
-    .fetch_all(&*DATABASE)
+    .fetch_all(tx.as_mut())
     .await
     .map_err(fatal_retry_later)?;
     if rows.is_empty() {
         return Ok(None);
     }
+    let revoked = revoked_hashes(&mut tx, &username).await?;
+    tx.commit().await.map_err(fatal_retry_later)?;
     let mut out = BTreeMap::new();
     for (device_hash, chain_bytes) in rows {
         let hash = bytes_to_hash(&device_hash)?;
-        let chain = bcs::from_bytes(&chain_bytes).map_err(fatal_retry_later)?;
+        let chain: CertificateChain = bcs::from_bytes(&chain_bytes).map_err(fatal_retry_later)?;
+        if chain_is_revoked(&chain, &revoked) {
+            continue;
+        }
         out.insert(hash, chain);
     }
     Ok(Some(out))
 }
 
+pub async fn device_revoke(
+    username: UserName,
+    revocation: DeviceRevocation,
+) -> Result<(), ServerRpcError> {
+    let descriptor = DIR_CLIENT
+        .get_user_descriptor(&username)
+        .await
+        .map_err(fatal_retry_later)?;
+    let Some(descriptor) = descriptor else {
+        tracing::debug!(username = %username, "device revoke denied: username not in directory");
+        return Err(ServerRpcError::AccessDenied);
+    };
+    if descriptor.server_name != CONFIG.server_name {
+        tracing::debug!(username = %username, "device revoke denied: username server mismatch");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    if let Err(err) = revocation.verify_issuer(descriptor.root_cert_hash) {
+        tracing::debug!(username = %username, error = %err, "device revoke denied: invalid revocation");
+        return Err(ServerRpcError::AccessDenied);
+    }
+
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let mut revoked = revoked_hashes(&mut tx, &username).await?;
+    if chain_is_revoked(&revocation.issuer, &revoked) {
+        tracing::debug!(username = %username, "device revoke denied: issuer revoked");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    sqlx::query(
+        "INSERT OR IGNORE INTO device_revocations (device_hash, username, revocation) \
+         VALUES (?, ?, ?)",
+    )
+    .bind(revocation.device_hash.to_bytes().to_vec())
+    .bind(username.as_str())
+    .bind(bcs::to_bytes(&revocation).map_err(fatal_retry_later)?)
+    .execute(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    revoked.insert(revocation.device_hash);
+
+    // the revoked device and everything it issued lose their tokens, and with them all access
+    let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+        "SELECT device_hash, cert_chain FROM device_certificates WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_all(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    let mut affected = vec![revocation.device_hash];
+    for (device_hash, chain_bytes) in rows {
+        let chain: CertificateChain = bcs::from_bytes(&chain_bytes).map_err(fatal_retry_later)?;
+        if chain_is_revoked(&chain, &revoked) {
+            affected.push(bytes_to_hash(&device_hash)?);
+        }
+    }
+    for device_hash in affected {
+        let token = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT auth_token FROM device_auth_tokens WHERE username = ? AND device_hash = ?",
+        )
+        .bind(username.as_str())
+        .bind(device_hash.to_bytes().to_vec())
+        .fetch_optional(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+        if let Some(token) = token {
+            let token: AuthToken = bcs::from_bytes(&token).map_err(fatal_retry_later)?;
+            mailbox::revoke_token_acls(&mut tx, token).await?;
+        }
+        sqlx::query("DELETE FROM device_auth_tokens WHERE username = ? AND device_hash = ?")
+            .bind(username.as_str())
+            .bind(device_hash.to_bytes().to_vec())
+            .execute(tx.as_mut())
+            .await
+            .map_err(fatal_retry_later)?;
+        sqlx::query("DELETE FROM device_certificates WHERE username = ? AND device_hash = ?")
+            .bind(username.as_str())
+            .bind(device_hash.to_bytes().to_vec())
+            .execute(tx.as_mut())
+            .await
+            .map_err(fatal_retry_later)?;
+        sqlx::query("DELETE FROM device_medium_pks WHERE device_hash = ?")
+            .bind(device_hash.to_bytes().to_vec())
+            .execute(tx.as_mut())
+            .await
+            .map_err(fatal_retry_later)?;
+    }
+    tx.commit().await.map_err(fatal_retry_later)?;
+    tracing::debug!(
+        username = %username,
+        device_hash = %revocation.device_hash,
+        "device revoke accepted"
+    );
+    Ok(())
+}
+
+pub async fn device_revocations(
+    username: UserName,
+) -> Result<BTreeMap<Hash, DeviceRevocation>, ServerRpcError> {
+    let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>)>(
+        "SELECT device_hash, revocation FROM device_revocations WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_all(&*DATABASE)
+    .await
+    .map_err(fatal_retry_later)?;
+    let mut out = BTreeMap::new();
+    for (device_hash, revocation) in rows {
+        let hash = bytes_to_hash(&device_hash)?;
+        let revocation = bcs::from_bytes(&revocation).map_err(fatal_retry_later)?;
+        out.insert(hash, revocation);
+    }
+    Ok(out)
+}
+
 pub async fn device_add_medium_pk(
     auth: AuthToken,
     medium_pk: SignedMediumPk,
@@ -215,6 +416,28 @@ pub async fn auth_token_exists(auth: AuthToken) -> Result<bool, ServerRpcError>
     Ok(exists)
 }
 
+async fn revoked_hashes(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &UserName,
+) -> Result<BTreeSet<Hash>, ServerRpcError> {
+    let rows = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT device_hash FROM device_revocations WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_all(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    rows.iter().map(|bytes| bytes_to_hash(bytes)).collect()
+}
+
+/// A chain is revoked when any certificate in it is, since revoking a device also revokes what it
+/// issued.
+fn chain_is_revoked(chain: &CertificateChain, revoked: &BTreeSet<Hash>) -> bool {
+    chain
+        .iter()
+        .any(|cert| revoked.contains(&cert.pk.bcs_hash()))
+}
+
 fn bytes_to_hash(bytes: &[u8]) -> Result<Hash, ServerRpcError> {
     let buf: [u8; 32] = bytes
         .try_into()
@@ -57,7 +57,7 @@ pub async fn init_name() -> anyhow::Result<()> {
     Ok(())
 }
 
-fn load_signing_secret(path: &PathBuf) -> anyhow::Result<SigningSecret> {
+pub fn load_signing_secret(path: &PathBuf) -> anyhow::Result<SigningSecret> {
     if !path.exists() {
         let mut bytes = [0u8; 32];
         rand::rng().fill_bytes(&mut bytes);
@@ -10,7 +10,8 @@ use sqlx::{Sqlite, Transaction};
 
 use crate::config::CONFIG;
 use crate::database::DATABASE;
-use crate::{device, fatal_retry_later};
+use crate::fatal_retry_later;
+use crate::quota::{self, QuotaSubject};
 
 pub static FRAGMENTS: LazyLock<FragmentDb> =
     LazyLock::new(|| {
@@ -70,20 +71,33 @@ pub async fn upload_frag(
     frag: Fragment,
     ttl: u32,
 ) -> Result<(), ServerRpcError> {
-    if !device::auth_token_exists(auth).await? {
-        return Err(ServerRpcError::AccessDenied);
-    }
-
     let now = NanoTimestamp::now();
     let hash = frag.bcs_hash();
     let bytes = bcs::to_bytes(&frag).map_err(|_| fatal_retry_later("fragment bcs encode"))?;
     let size = i64::try_from(bytes.len()).map_err(|_| fatal_retry_later("fragment too large"))?;
     let expires_at = expires_at_from_ttl(now, ttl);
 
-    FRAGMENTS.store_bcs_bytes(&hash, &bytes).await?;
+    // charged in its own transaction, so that the database is not locked while writing the file
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let Some(subject) = QuotaSubject::for_device(&mut tx, auth).await? else {
+        return Err(ServerRpcError::AccessDenied);
+    };
+    // uploading a fragment the same subject already stores only extends its expiry
+    let charged = fragment_subject(&mut tx, hash).await? != Some(subject.key());
+    if charged {
+        quota::charge_fragment(&mut tx, &subject, bytes.len() as u64).await?;
+    }
+    tx.commit().await.map_err(fatal_retry_later)?;
+
+    if let Err(err) = FRAGMENTS.store_bcs_bytes(&hash, &bytes).await {
+        if charged && let Err(refund_err) = refund_upload(&subject, bytes.len() as u64).await {
+            tracing::warn!(error = %refund_err, "failed to refund a fragment upload");
+        }
+        return Err(err);
+    }
 
     let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
-    upsert_fragment_row(&mut tx, hash, now, expires_at, size).await?;
+    upsert_fragment_row(&mut tx, hash, now, expires_at, size, &subject).await?;
     tx.commit().await.map_err(fatal_retry_later)?;
 
     Ok(())
@@ -102,16 +116,36 @@ fn expires_at_from_ttl(now: NanoTimestamp, ttl: u32) -> Option<i64> {
     Some(now.0.saturating_add(ttl_ns) as i64)
 }
 
+/// The quota subject a stored fragment is charged to, if it is stored at all.
+async fn fragment_subject(
+    tx: &mut Transaction<'_, Sqlite>,
+    hash: Hash,
+) -> Result<Option<String>, ServerRpcError> {
+    sqlx::query_scalar::<_, Option<String>>("SELECT quota_subject FROM fragments WHERE hash = ?")
+        .bind(hash.to_bytes().to_vec())
+        .fetch_optional(tx.as_mut())
+        .await
+        .map(Option::flatten)
+        .map_err(fatal_retry_later)
+}
+
+async fn refund_upload(subject: &QuotaSubject, bytes: u64) -> Result<(), ServerRpcError> {
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    quota::refund_fragment(&mut tx, subject, bytes).await?;
+    tx.commit().await.map_err(fatal_retry_later)
+}
+
 async fn upsert_fragment_row(
     tx: &mut Transaction<'_, Sqlite>,
     hash: Hash,
     created_at: NanoTimestamp,
     expires_at: Option<i64>,
     size: i64,
+    subject: &QuotaSubject,
 ) -> Result<(), ServerRpcError> {
     sqlx::query(
-        "INSERT INTO fragments (hash, created_at, expires_at, size) \
-         VALUES (?, ?, ?, ?) \
+        "INSERT INTO fragments (hash, created_at, expires_at, size, quota_subject) \
+         VALUES (?, ?, ?, ?, ?) \
          ON CONFLICT(hash) DO UPDATE SET \
            expires_at = ( \
              CASE \
@@ -120,12 +154,14 @@ async fn upsert_fragment_row(
                ELSE excluded.expires_at \
              END \
            ), \
-           size = excluded.size",
+           size = excluded.size, \
+           quota_subject = excluded.quota_subject",
     )
     .bind(hash.to_bytes().to_vec())
     .bind(created_at.0 as i64)
     .bind(expires_at)
     .bind(size)
+    .bind(subject.key())
     .execute(tx.as_mut())
     .await
     .map_err(fatal_retry_later)?;
@@ -1,13 +1,16 @@
+mod pow;
 mod pubsub;
+mod subscription;
 
 use std::collections::BTreeMap;
 use std::sync::LazyLock;
 
 use bytes::Bytes;
 use futures_concurrency::future::Race;
-use sqlx::{Sqlite, Transaction};
-use tokio::time::{Duration, timeout};
+use sqlx::{Sqlite, SqlitePool, Transaction};
+use tokio::time::{Duration, Instant, timeout_at};
 use nullspace_crypt::hash::{BcsHashExt, Hash};
+use nullspace_structs::directory::{PowSeed, PowSolution};
 use nullspace_structs::server::{
     AuthToken, ServerRpcError, MailboxAcl, MailboxId, MailboxRecvArgs,
 };
@@ -22,14 +25,32 @@ use nullspace_structs::{
 use crate::database::DATABASE;
 use crate::fatal_retry_later;
 use crate::mailbox::pubsub::PubSub;
+use crate::quota::{self, QuotaSubject};
+
+pub use subscription::{mailbox_subscribe, mailbox_unsubscribe};
 
 static MAILBOX_NOTIFY: LazyLock<PubSub> = LazyLock::new(PubSub::new);
 
+/// Most entries returned for a single mailbox by one multirecv.
+const MAX_MAILBOX_ENTRIES: usize = 100;
+
+/// Most entries returned by one multirecv, across all mailboxes.
+const MAX_RECV_ENTRIES: usize = 500;
+
+/// Most message bytes returned by one multirecv, across all mailboxes. This leaves plenty of room
+/// under the transport's frame limit once the entries are encoded as JSON.
+const MAX_RECV_BYTES: usize = 256 * 1024;
+
+/// How long multirecv waits after being woken up, so that mailboxes that get entries at around the
+/// same time come back in one response.
+const COALESCE_WINDOW: Duration = Duration::from_millis(20);
+
 pub async fn mailbox_send(
     auth: AuthToken,
     mailbox: MailboxId,
     message: Blob,
     ttl: u32,
+    pow: Option<PowSolution>,
 ) -> Result<NanoTimestamp, ServerRpcError> {
     let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
     let now = NanoTimestamp::now();
@@ -39,13 +60,27 @@ pub async fn mailbox_send(
         tracing::debug!(auth = ?auth, mailbox = ?mailbox, "mailbox send denied");
         return Err(ServerRpcError::AccessDenied);
     }
+    let own_acl = acl.token_hash != AuthToken::anonymous().bcs_hash();
+    // senders with a device token at this server are not anonymous, even through the anonymous ACL
+    let subject = QuotaSubject::for_token(&mut tx, auth, own_acl, mailbox).await?;
+    if matches!(subject, QuotaSubject::Anonymous(_))
+        && let Some(effort) = load_pow_effort(&mut tx, &mailbox).await?
+    {
+        let Some(pow) = &pow else {
+            tracing::debug!(mailbox = ?mailbox, "mailbox send denied: pow stamp required");
+            return Err(ServerRpcError::AccessDenied);
+        };
+        pow::redeem_seed(mailbox, effort, pow)?;
+    }
+    quota::charge_message(&mut tx, &subject, message.inner.len() as u64).await?;
     let received_at = now;
     let expires_at = expires_at_from_ttl(received_at, ttl);
     let sender_hash = auth.bcs_hash();
     sqlx::query(
         "INSERT INTO mailbox_entries \
-         (mailbox_id, received_at, message_kind, message_body, sender_auth_token_hash, expires_at) \
-         VALUES (?, ?, ?, ?, ?, ?)",
+         (mailbox_id, received_at, message_kind, message_body, sender_auth_token_hash, expires_at, \
+          quota_subject) \
+         VALUES (?, ?, ?, ?, ?, ?, ?)",
     )
     .bind(mailbox.to_bytes().to_vec())
     .bind(received_at.0 as i64)
@@ -53,6 +88,7 @@ pub async fn mailbox_send(
     .bind(message.inner.to_vec())
     .bind(sender_hash.to_bytes().to_vec())
     .bind(expires_at)
+    .bind(subject.key())
     .execute(tx.as_mut())
     .await
     .map_err(fatal_retry_later)?;
@@ -62,77 +98,173 @@ pub async fn mailbox_send(
     Ok(received_at)
 }
 
+pub async fn mailbox_pow_seed(mailbox: MailboxId) -> Result<Option<PowSeed>, ServerRpcError> {
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let effort = load_pow_effort(&mut tx, &mailbox).await?;
+    tx.commit().await.map_err(fatal_retry_later)?;
+    Ok(effort.map(|effort| pow::issue_seed(mailbox, effort)))
+}
+
+pub async fn mailbox_pow_set(
+    auth: AuthToken,
+    mailbox: MailboxId,
+    effort: Option<u64>,
+) -> Result<(), ServerRpcError> {
+    if effort.is_some_and(|effort| effort == 0 || effort > pow::MAX_POW_EFFORT) {
+        tracing::debug!(auth = ?auth, effort, "mailbox pow set denied: effort out of range");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    // the anonymous ACL never grants receiving, so this only lets the owners through
+    let acl = acl_for_token(&mut tx, &mailbox, auth).await?;
+    if !acl.can_recv {
+        tracing::debug!(auth = ?auth, mailbox = ?mailbox, "mailbox pow set denied");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    sqlx::query("UPDATE mailboxes SET pow_effort = ? WHERE mailbox_id = ?")
+        .bind(effort.map(|effort| effort as i64))
+        .bind(mailbox.to_bytes().to_vec())
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    tx.commit().await.map_err(fatal_retry_later)?;
+    tracing::debug!(auth = ?auth, mailbox = ?mailbox, effort, "mailbox pow effort set");
+    Ok(())
+}
+
 pub async fn mailbox_multirecv(
     args: Vec<MailboxRecvArgs>,
     timeout_ms: u64,
 ) -> Result<BTreeMap<MailboxId, Vec<MailboxEntry>>, ServerRpcError> {
     tracing::debug!(args = args.len(), timeout_ms, "mailbox multirecv");
-    let mut futs = vec![];
-    for arg in args.iter() {
-        futs.push(async {
-            loop {
-                let notify_ctr = MAILBOX_NOTIFY.counter(arg.mailbox);
-                let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
-                let now = NanoTimestamp::now();
-                purge_expired_entries(&mut tx, now).await?;
-                let acl = acl_for_token(&mut tx, &arg.mailbox, arg.auth).await?;
-                if !acl.can_recv {
-                    tracing::debug!(auth = ?arg.auth, mailbox = ?arg.mailbox, "mailbox recv denied");
-                    return Err(ServerRpcError::AccessDenied);
-                }
-                let rows = sqlx::query_as::<_, (i64, String, Vec<u8>, Option<Vec<u8>>)>(
-                    "SELECT received_at, message_kind, message_body, sender_auth_token_hash \
-                FROM mailbox_entries \
-                WHERE mailbox_id = ? AND received_at > ? AND (expires_at IS NULL OR expires_at > ?) \
-                ORDER BY received_at, entry_id
-                LIMIT 100",
-                )
-                .bind(arg.mailbox.to_bytes().to_vec())
-                .bind(arg.after.0 as i64)
-                .bind(now.0 as i64)
-                .fetch_all(tx.as_mut())
-                .await
-                .map_err(fatal_retry_later)?;
-                let mut entries = Vec::with_capacity(rows.len());
-                for (received_at, kind, body, sender_hash) in rows {
-                    let message = Blob {
-                        kind: kind.into(),
-                        inner: Bytes::from(body),
-                    };
-                    let sender_auth_token_hash = match sender_hash {
-                        Some(bytes) => {
-                            let buf: [u8; 32] = bytes
-                                .try_into()
-                                .map_err(|_| fatal_retry_later("invalid sender auth token hash"))?;
-                            Some(nullspace_crypt::hash::Hash::from_bytes(buf))
-                        }
-                        None => None,
-                    };
-                    entries.push(MailboxEntry {
-                        message,
-                        received_at: NanoTimestamp(received_at as u64),
-                        sender_auth_token_hash,
-                    });
-                }
-                tx.commit().await.map_err(fatal_retry_later)?;
-                if !entries.is_empty() {
-                    return Ok((arg.mailbox, entries));
-                }
-                MAILBOX_NOTIFY.wait_gt(arg.mailbox, notify_ctr).await;
-            }
-        })
+    multirecv(
+        &DATABASE,
+        &MAILBOX_NOTIFY,
+        &args,
+        Duration::from_millis(timeout_ms),
+    )
+    .await
+}
+
+/// Waits until at least one of the mailboxes has entries, then returns every mailbox that has
+/// entries by then, up to the response caps.
+async fn multirecv(
+    db: &SqlitePool,
+    notify: &PubSub,
+    args: &[MailboxRecvArgs],
+    wait: Duration,
+) -> Result<BTreeMap<MailboxId, Vec<MailboxEntry>>, ServerRpcError> {
+    let deadline = Instant::now() + wait;
+    loop {
+        // counters are read before the database, so that a send in between still wakes us up
+        let counters = args
+            .iter()
+            .map(|arg| notify.counter(arg.mailbox))
+            .collect::<Vec<_>>();
+        let out = gather_ready(db, args).await?;
+        if !out.is_empty() || args.is_empty() {
+            return Ok(out);
+        }
+        let woken = args
+            .iter()
+            .zip(counters)
+            .map(|(arg, counter)| notify.wait_gt(arg.mailbox, counter))
+            .collect::<Vec<_>>();
+        if timeout_at(deadline, woken.race()).await.is_err() {
+            return Ok(BTreeMap::new());
+        }
+        // messages often arrive in bursts across several mailboxes, such as a group send
+        tokio::time::sleep(COALESCE_WINDOW).await;
     }
-    let race = futs.race();
-    let first = timeout(Duration::from_millis(timeout_ms), race).await;
-    let Ok(first) = first else {
-        return Ok(BTreeMap::new());
-    };
-    let (first_box, first_entries) = first?;
+}
+
+/// Reads the entries of every mailbox that has some, checking that each one can be received from.
+async fn gather_ready(
+    db: &SqlitePool,
+    args: &[MailboxRecvArgs],
+) -> Result<BTreeMap<MailboxId, Vec<MailboxEntry>>, ServerRpcError> {
+    let mut tx = db.begin().await.map_err(fatal_retry_later)?;
+    let now = NanoTimestamp::now();
+    purge_expired_entries(&mut tx, now).await?;
     let mut out = BTreeMap::new();
-    out.insert(first_box, first_entries);
+    let mut entries_left = MAX_RECV_ENTRIES;
+    let mut bytes_left = MAX_RECV_BYTES;
+    for arg in args {
+        let acl = acl_for_token(&mut tx, &arg.mailbox, arg.auth).await?;
+        if !acl.can_recv {
+            tracing::debug!(auth = ?arg.auth, mailbox = ?arg.mailbox, "mailbox recv denied");
+            return Err(ServerRpcError::AccessDenied);
+        }
+        if entries_left == 0 || bytes_left == 0 || out.contains_key(&arg.mailbox) {
+            continue;
+        }
+        let limit = entries_left.min(MAX_MAILBOX_ENTRIES);
+        let mut entries = load_entries(&mut tx, arg, now, limit).await?;
+        let mut keep = 0;
+        for entry in &entries {
+            let size = entry.message.inner.len();
+            // always let one entry through, so that a single large entry cannot wedge the mailbox
+            if size > bytes_left && (keep > 0 || !out.is_empty()) {
+                bytes_left = 0;
+                break;
+            }
+            bytes_left = bytes_left.saturating_sub(size);
+            keep += 1;
+        }
+        entries.truncate(keep);
+        entries_left -= entries.len();
+        if !entries.is_empty() {
+            out.insert(arg.mailbox, entries);
+        }
+    }
+    tx.commit().await.map_err(fatal_retry_later)?;
     Ok(out)
 }
 
+async fn load_entries(
+    tx: &mut Transaction<'_, Sqlite>,
+    arg: &MailboxRecvArgs,
+    now: NanoTimestamp,
+    limit: usize,
+) -> Result<Vec<MailboxEntry>, ServerRpcError> {
+    let rows = sqlx::query_as::<_, (i64, String, Vec<u8>, Option<Vec<u8>>)>(
+        "SELECT received_at, message_kind, message_body, sender_auth_token_hash \
+         FROM mailbox_entries \
+         WHERE mailbox_id = ? AND received_at > ? AND (expires_at IS NULL OR expires_at > ?) \
+         ORDER BY received_at, entry_id \
+         LIMIT ?",
+    )
+    .bind(arg.mailbox.to_bytes().to_vec())
+    .bind(arg.after.0 as i64)
+    .bind(now.0 as i64)
+    .bind(limit as i64)
+    .fetch_all(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    let mut entries = Vec::with_capacity(rows.len());
+    for (received_at, kind, body, sender_hash) in rows {
+        let message = Blob {
+            kind: kind.into(),
+            inner: Bytes::from(body),
+        };
+        let sender_auth_token_hash = match sender_hash {
+            Some(bytes) => {
+                let buf: [u8; 32] = bytes
+                    .try_into()
+                    .map_err(|_| fatal_retry_later("invalid sender auth token hash"))?;
+                Some(Hash::from_bytes(buf))
+            }
+            None => None,
+        };
+        entries.push(MailboxEntry {
+            message,
+            received_at: NanoTimestamp(received_at as u64),
+            sender_auth_token_hash,
+        });
+    }
+    Ok(entries)
+}
+
 pub async fn mailbox_acl_edit(
     auth: AuthToken,
     mailbox: MailboxId,
@@ -213,6 +345,19 @@ pub async fn update_dm_mailbox(
     Ok(())
 }
 
+/// Removes every mailbox ACL entry of a token, across all mailboxes.
+pub async fn revoke_token_acls(
+    tx: &mut Transaction<'_, Sqlite>,
+    token: AuthToken,
+) -> Result<(), ServerRpcError> {
+    sqlx::query("DELETE FROM mailbox_acl WHERE token_hash = ?")
+        .bind(token.bcs_hash().to_bytes().to_vec())
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    Ok(())
+}
+
 pub async fn register_group(
     auth: AuthToken,
     group: GroupId,
@@ -362,6 +507,20 @@ async fn auth_token_exists(
     Ok(row.is_some())
 }
 
+async fn load_pow_effort(
+    tx: &mut Transaction<'_, Sqlite>,
+    mailbox_id: &MailboxId,
+) -> Result<Option<u64>, ServerRpcError> {
+    let effort = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT pow_effort FROM mailboxes WHERE mailbox_id = ?",
+    )
+    .bind(mailbox_id.to_bytes().to_vec())
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    Ok(effort.flatten().map(|effort| effort as u64))
+}
+
 fn expires_at_from_ttl(received_at: NanoTimestamp, ttl: u32) -> Option<i64> {
     if ttl == 0 {
         return None;
@@ -384,3 +543,69 @@ async fn purge_expired_entries(
     .map_err(fatal_retry_later)?;
     Ok(())
 }
+
+#[cfg(test)]
+mod tests {
+    use sqlx::sqlite::SqlitePoolOptions;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn multirecv_returns_every_ready_mailbox() {
+        let db = SqlitePoolOptions::new()
+            .max_connections(1)
+            .connect("sqlite::memory:")
+            .await
+            .expect("open database");
+        sqlx::migrate!("./migrations")
+            .run(&db)
+            .await
+            .expect("run migrations");
+        let notify = PubSub::new();
+        let auth = AuthToken::random();
+
+        let mut args = vec![];
+        let mut tx = db.begin().await.expect("begin");
+        for i in 0..40u64 {
+            let username = UserName::parse(format!("@user{i:02}")).expect("username");
+            update_dm_mailbox(&mut tx, &username, Some(auth))
+                .await
+                .expect("create mailbox");
+            let mailbox = MailboxId::direct(&username);
+            for j in 0..20u64 {
+                sqlx::query(
+                    "INSERT INTO mailbox_entries \
+                     (mailbox_id, received_at, message_kind, message_body) VALUES (?, ?, ?, ?)",
+                )
+                .bind(mailbox.to_bytes().to_vec())
+                .bind((j + 1) as i64)
+                .bind("test")
+                .bind(vec![0u8; 16])
+                .execute(tx.as_mut())
+                .await
+                .expect("insert entry");
+            }
+            args.push(MailboxRecvArgs {
+                auth,
+                mailbox,
+                after: NanoTimestamp(0),
+            });
+        }
+        tx.commit().await.expect("commit");
+
+        // the first ten mailboxes fit under the overall cap, without being cut short
+        let out = multirecv(&db, &notify, &args[..10], Duration::from_secs(1))
+            .await
+            .expect("multirecv");
+        assert_eq!(out.len(), 10);
+        assert!(out.values().all(|entries| entries.len() == 20));
+
+        // all forty together are capped
+        let out = multirecv(&db, &notify, &args, Duration::from_secs(1))
+            .await
+            .expect("multirecv");
+        assert_eq!(out.len(), MAX_RECV_ENTRIES / 20);
+        let total = out.values().map(Vec::len).sum::<usize>();
+        assert_eq!(total, MAX_RECV_ENTRIES);
+    }
+}
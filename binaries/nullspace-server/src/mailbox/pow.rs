@@ -0,0 +1,136 @@
+use std::collections::HashMap;
+use std::sync::{Arc, LazyLock, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use moka::sync::Cache;
+use nullspace_crypt::hash::Hash;
+use nullspace_dirclient::pow::verify_pow;
+use nullspace_structs::directory::{PowAlgo, PowSeed, PowSolution};
+use nullspace_structs::server::{MailboxId, ServerRpcError};
+use nullspace_structs::timestamp::Timestamp;
+
+/// How long an issued seed can be solved and redeemed.
+const SEED_TTL: Duration = Duration::from_secs(120);
+
+/// The highest effort a mailbox owner may ask for. Solving takes a few milliseconds per unit of
+/// effort, so this keeps a stamp to seconds on a desktop and well within [`SEED_TTL`] on a phone.
+pub const MAX_POW_EFFORT: u64 = 5_000;
+
+/// Redeemed seeds remembered per mailbox. A mailbox that takes more stamps than this within
+/// [`SEED_TTL`] refuses further ones until the oldest expire, rather than forgetting seeds that
+/// could then be replayed.
+const MAX_REDEEMED_PER_MAILBOX: usize = 1_000;
+
+/// Seeds carry their own expiry and a MAC under this key, so issuing them keeps no state. The key
+/// is fresh on every start, which also voids the seeds redeemed before a restart.
+static SEED_KEY: LazyLock<[u8; 32]> = LazyLock::new(rand::random);
+
+/// Seeds redeemed for one mailbox, with the time each expires.
+type Redeemed = Arc<Mutex<HashMap<Hash, u64>>>;
+
+/// Seeds redeemed for each mailbox. A mailbox left alone for [`SEED_TTL`] has no seed that is
+/// still valid, so its set can be dropped.
+static REDEEMED: LazyLock<Cache<MailboxId, Redeemed>> =
+    LazyLock::new(|| Cache::builder().time_to_idle(SEED_TTL).build());
+
+/// Issues a seed bound to the mailbox. The seed is the expiry and a random nonce, followed by a
+/// MAC over both and the mailbox.
+pub fn issue_seed(mailbox: MailboxId, effort: u64) -> PowSeed {
+    let use_before = unix_time() + SEED_TTL.as_secs();
+    let nonce: [u8; 8] = rand::random();
+    let mut seed = [0u8; 32];
+    seed[..8].copy_from_slice(&use_before.to_be_bytes());
+    seed[8..16].copy_from_slice(&nonce);
+    seed[16..].copy_from_slice(&seed_mac(mailbox, use_before, nonce));
+    PowSeed {
+        algo: PowAlgo::EquiX { effort },
+        seed: Hash::from_bytes(seed),
+        use_before: Timestamp(use_before),
+    }
+}
+
+/// Checks a stamp for a send into the given mailbox at the mailbox's current effort. The seed is
+/// used up even if the stamp turns out to be invalid, so every attempt costs a fresh seed.
+pub fn redeem_seed(
+    mailbox: MailboxId,
+    effort: u64,
+    solution: &PowSolution,
+) -> Result<(), ServerRpcError> {
+    let seed = solution.seed.to_bytes();
+    let use_before = u64::from_be_bytes(seed[..8].try_into().unwrap());
+    let nonce: [u8; 8] = seed[8..16].try_into().unwrap();
+    if seed[16..] != seed_mac(mailbox, use_before, nonce) {
+        tracing::debug!(mailbox = ?mailbox, "pow stamp rejected: seed not issued for this mailbox");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    let now = unix_time();
+    if use_before <= now {
+        tracing::debug!(mailbox = ?mailbox, "pow stamp rejected: seed expired");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    {
+        let redeemed = REDEEMED.get_with(mailbox, Default::default);
+        let mut redeemed = redeemed.lock().unwrap();
+        redeemed.retain(|_, expiry| *expiry > now);
+        if redeemed.contains_key(&solution.seed) {
+            tracing::debug!(mailbox = ?mailbox, "pow stamp rejected: seed already used");
+            return Err(ServerRpcError::AccessDenied);
+        }
+        if redeemed.len() >= MAX_REDEEMED_PER_MAILBOX {
+            tracing::debug!(mailbox = ?mailbox, "pow stamp rejected: too many recent stamps");
+            return Err(ServerRpcError::RetryLater);
+        }
+        redeemed.insert(solution.seed, use_before);
+    }
+    let seed = PowSeed {
+        algo: PowAlgo::EquiX { effort },
+        seed: solution.seed,
+        use_before: Timestamp(use_before),
+    };
+    verify_pow(&seed, solution).map_err(|err| {
+        tracing::debug!(mailbox = ?mailbox, error = %err, "pow stamp rejected");
+        ServerRpcError::AccessDenied
+    })
+}
+
+fn seed_mac(mailbox: MailboxId, use_before: u64, nonce: [u8; 8]) -> [u8; 16] {
+    let mut msg = mailbox.to_bytes().to_vec();
+    msg.extend_from_slice(&use_before.to_be_bytes());
+    msg.extend_from_slice(&nonce);
+    let mac = Hash::keyed_digest(SEED_KEY.as_slice(), &msg).to_bytes();
+    mac[..16].try_into().unwrap()
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+#[cfg(test)]
+mod tests {
+    use nullspace_dirclient::pow::solve_pow;
+    use nullspace_structs::username::UserName;
+
+    use super::*;
+
+    #[test]
+    fn seed_redeems_once_and_only_for_its_mailbox() {
+        let mailbox = MailboxId::direct(&UserName::parse("@alice01").expect("username"));
+        let other = MailboxId::direct(&UserName::parse("@bob0001").expect("username"));
+
+        let seed = issue_seed(mailbox, 1);
+        let solution = solve_pow(&seed).expect("solve pow");
+        assert!(redeem_seed(other, 1, &solution).is_err());
+        redeem_seed(mailbox, 1, &solution).expect("redeem seed");
+        assert!(redeem_seed(mailbox, 1, &solution).is_err());
+
+        let seed = issue_seed(mailbox, 1);
+        let mut forged = solve_pow(&seed).expect("solve pow");
+        let mut bytes = forged.seed.to_bytes();
+        bytes[..8].copy_from_slice(&u64::MAX.to_be_bytes());
+        forged.seed = Hash::from_bytes(bytes);
+        assert!(redeem_seed(mailbox, 1, &forged).is_err());
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::BTreeMap;
+use std::sync::{LazyLock, Mutex};
+
+use futures_concurrency::future::Race;
+use nullspace_nanorpc::{NotificationSink, notification_sink};
+use nullspace_structs::server::{
+    MAILBOX_HEARTBEAT_SECS, MAILBOX_NOTIFY_METHOD, MailboxNotification, MailboxRecvArgs,
+    ServerRpcError,
+};
+use tokio::sync::oneshot;
+use tokio::time::Duration;
+
+use crate::database::DATABASE;
+use crate::fatal_retry_later;
+
+use super::{MAILBOX_NOTIFY, multirecv};
+
+/// Most subscriptions a single connection can hold at once.
+const MAX_SUBSCRIPTIONS_PER_CONNECTION: usize = 16;
+
+/// How long a subscription waits in one go before checking the mailboxes again from scratch. A
+/// round that ends with nothing new still pushes a notification, as a heartbeat.
+const SUBSCRIPTION_ROUND: Duration = Duration::from_secs(MAILBOX_HEARTBEAT_SECS);
+
+/// Cancel handles of subscriptions, by connection and subscription ID. Dropping the sender ends
+/// the subscription.
+type Subscriptions = BTreeMap<(u64, u64), oneshot::Sender<()>>;
+
+/// Live subscriptions.
+static SUBSCRIPTIONS: LazyLock<Mutex<Subscriptions>> = LazyLock::new(Default::default);
+
+pub async fn mailbox_subscribe(
+    subscription: u64,
+    args: Vec<MailboxRecvArgs>,
+) -> Result<bool, ServerRpcError> {
+    let Some(sink) = notification_sink() else {
+        tracing::debug!(subscription, "mailbox subscribe on a connection without notifications");
+        return Ok(false);
+    };
+    let connection = sink.connection_id();
+    let (cancel_tx, cancel_rx) = oneshot::channel();
+    {
+        let mut subscriptions = SUBSCRIPTIONS.lock().map_err(fatal_retry_later)?;
+        let existing = subscriptions
+            .range((connection, 0)..=(connection, u64::MAX))
+            .filter(|(key, _)| key.1 != subscription)
+            .count();
+        if existing >= MAX_SUBSCRIPTIONS_PER_CONNECTION {
+            tracing::debug!(
+                connection,
+                subscription,
+                "mailbox subscribe denied: too many subscriptions"
+            );
+            return Err(ServerRpcError::RetryLater);
+        }
+        subscriptions.insert((connection, subscription), cancel_tx);
+    }
+    tracing::debug!(connection, subscription, args = args.len(), "mailbox subscribe");
+    tokio::spawn(async move {
+        let run = run_subscription(subscription, &sink, args);
+        let cancelled = async {
+            let _ = cancel_rx.await;
+        };
+        (run, cancelled, sink.closed()).race().await;
+        // our receiver is gone by now, while a replacement under the same ID still has its own
+        if let Ok(mut subscriptions) = SUBSCRIPTIONS.lock()
+            && subscriptions
+                .get(&(connection, subscription))
+                .is_some_and(|cancel_tx| cancel_tx.is_closed())
+        {
+            subscriptions.remove(&(connection, subscription));
+        }
+        tracing::debug!(connection, subscription, "mailbox subscription ended");
+    });
+    Ok(true)
+}
+
+pub async fn mailbox_unsubscribe(subscription: u64) -> Result<(), ServerRpcError> {
+    let Some(sink) = notification_sink() else {
+        return Ok(());
+    };
+    SUBSCRIPTIONS
+        .lock()
+        .map_err(fatal_retry_later)?
+        .remove(&(sink.connection_id(), subscription));
+    Ok(())
+}
+
+/// Pushes new entries, or a heartbeat every round that has none, until the connection goes away or
+/// an error ends the subscription. Pushing is also how a connection whose peer vanished is found
+/// out, since writes to it eventually fail.
+async fn run_subscription(
+    subscription: u64,
+    sink: &NotificationSink,
+    mut args: Vec<MailboxRecvArgs>,
+) {
+    loop {
+        let entries = if args.is_empty() {
+            // nothing will ever come, but the subscription lives on until it is ended
+            tokio::time::sleep(SUBSCRIPTION_ROUND).await;
+            Ok(Default::default())
+        } else {
+            multirecv(&DATABASE, &MAILBOX_NOTIFY, &args, SUBSCRIPTION_ROUND).await
+        };
+        let done = match &entries {
+            Ok(entries) => {
+                for arg in args.iter_mut() {
+                    if let Some(last) = entries.get(&arg.mailbox).and_then(|entries| entries.last())
+                    {
+                        arg.after = last.received_at;
+                    }
+                }
+                false
+            }
+            Err(_) => true,
+        };
+        let notification = MailboxNotification {
+            subscription,
+            entries,
+        };
+        let params = match serde_json::to_value(&notification) {
+            Ok(params) => vec![params],
+            Err(err) => {
+                tracing::error!(error = %err, "failed to encode mailbox notification");
+                return;
+            }
+        };
+        if sink.notify(MAILBOX_NOTIFY_METHOD, params).await.is_err() || done {
+            return;
+        }
+    }
+}
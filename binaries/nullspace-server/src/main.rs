@@ -1,3 +1,4 @@
+mod admin;
 mod config;
 mod database;
 mod device;
@@ -5,6 +6,9 @@ mod dir_client;
 mod fragment;
 mod mailbox;
 mod profile;
+mod provision;
+mod quota;
+mod registration;
 mod rpc;
 mod rpc_pool;
 
@@ -18,7 +22,7 @@ use tokio::net::TcpListener;
 use tracing_subscriber::EnvFilter;
 use nullspace_structs::server::{ServerRpcError, ServerService};
 
-use crate::config::CONFIG;
+use crate::config::{ARGS, CONFIG};
 use crate::rpc::ServerRpc;
 
 #[tokio::main]
@@ -27,6 +31,10 @@ async fn main() -> anyhow::Result<()> {
         .unwrap_or_else(|_| EnvFilter::new("nullspace_server=debug"));
     tracing_subscriber::fmt().with_env_filter(filter).init();
 
+    if let Some(command) = &ARGS.admin {
+        return admin::run(command).await;
+    }
+
     dir_client::init_name().await?;
     let app = Router::new().route("/", post(rpc::rpc_handler));
     let listener = TcpListener::bind(CONFIG.listen).await?;
@@ -50,6 +58,34 @@ async fn main() -> anyhow::Result<()> {
         }));
     }
 
+    if let Some(noise_listen) = CONFIG.noise_listen {
+        let service = ServerService(ServerRpc);
+        let signing_sk = dir_client::load_signing_secret(&CONFIG.signing_sk)?;
+        servers.push(Box::pin(async move {
+            nullspace_nanorpc::serve_noise_tcp(noise_listen, service, signing_sk).await
+        }));
+    }
+
+    if let Some(ws_listen) = CONFIG.ws_listen {
+        let service = ServerService(ServerRpc);
+        servers.push(Box::pin(async move {
+            nullspace_nanorpc::serve_ws(ws_listen, service).await
+        }));
+    }
+
+    if let Some(unix_listen) = &CONFIG.unix_listen {
+        let service = ServerService(ServerRpc);
+        servers.push(Box::pin(async move {
+            nullspace_nanorpc::serve_unix(
+                &unix_listen.path,
+                service,
+                unix_listen.lz4,
+                unix_listen.mode,
+            )
+            .await
+        }));
+    }
+
     if servers.len() == 1 {
         servers.pop().unwrap().await?;
     } else {
@@ -0,0 +1,106 @@
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::sync::{Arc, LazyLock, Mutex};
+
+use async_event::Event;
+use moka::sync::Cache;
+use nullspace_structs::Blob;
+use nullspace_structs::server::{AuthToken, ServerRpcError};
+use tokio::time::{Duration, timeout};
+
+use crate::device;
+
+/// How long an allocated channel stays open without being received from.
+const CHANNEL_TTL: Duration = Duration::from_secs(10 * 60);
+
+/// Channel numbers are drawn below this bound, so that they stay short enough to type in.
+const CHANNEL_SPACE: u64 = 100_000_000;
+
+const MAX_OPEN_CHANNELS: u64 = 10_000;
+
+const MAX_VALUE_BYTES: usize = 64 * 1024;
+
+const SEND_WINDOW: Duration = Duration::from_secs(60);
+
+const MAX_SENDS_PER_WINDOW: u32 = 5;
+
+#[derive(Default)]
+struct Channel {
+    value: Mutex<Option<Blob>>,
+    event: Event,
+}
+
+static CHANNELS: LazyLock<Cache<u64, Arc<Channel>>> =
+    LazyLock::new(|| Cache::builder().time_to_live(CHANNEL_TTL).build());
+
+static SEND_COUNTS: LazyLock<Cache<AuthToken, Arc<AtomicU32>>> =
+    LazyLock::new(|| Cache::builder().time_to_live(SEND_WINDOW).build());
+
+pub async fn provision_allocate() -> Result<u64, ServerRpcError> {
+    if CHANNELS.entry_count() >= MAX_OPEN_CHANNELS {
+        tracing::debug!("provision allocate denied: too many open channels");
+        return Err(ServerRpcError::RetryLater);
+    }
+    loop {
+        let channel = rand::random_range(0..CHANNEL_SPACE);
+        let entry = CHANNELS.entry(channel).or_default();
+        if entry.is_fresh() {
+            tracing::debug!(channel, "provision channel allocated");
+            return Ok(channel);
+        }
+    }
+}
+
+pub async fn provision_send(
+    auth: AuthToken,
+    channel: u64,
+    value: Blob,
+) -> Result<(), ServerRpcError> {
+    if !device::auth_token_exists(auth).await? {
+        tracing::debug!(auth = ?auth, "provision send denied: unknown auth token");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    // counted before looking at the channel, so that guessing channel numbers is rate limited too
+    let sends = SEND_COUNTS.get_with(auth, Default::default);
+    if sends.fetch_add(1, Ordering::SeqCst) >= MAX_SENDS_PER_WINDOW {
+        tracing::debug!(auth = ?auth, "provision send rate limited");
+        return Err(ServerRpcError::RetryLater);
+    }
+    if value.inner.len() > MAX_VALUE_BYTES {
+        tracing::debug!(auth = ?auth, len = value.inner.len(), "provision send denied: too large");
+        return Err(ServerRpcError::AccessDenied);
+    }
+    let Some(chan) = CHANNELS.get(&channel) else {
+        tracing::debug!(auth = ?auth, channel, "provision send denied: no such channel");
+        return Err(ServerRpcError::AccessDenied);
+    };
+    {
+        let mut slot = chan.value.lock().unwrap();
+        if slot.is_some() {
+            tracing::debug!(auth = ?auth, channel, "provision send denied: already posted");
+            return Err(ServerRpcError::AccessDenied);
+        }
+        *slot = Some(value);
+    }
+    chan.event.notify_all();
+    tracing::debug!(auth = ?auth, channel, "provision send accepted");
+    Ok(())
+}
+
+pub async fn provision_recv(
+    channel: u64,
+    timeout_ms: u64,
+) -> Result<Option<Blob>, ServerRpcError> {
+    let Some(chan) = CHANNELS.get(&channel) else {
+        tracing::debug!(channel, "provision recv denied: no such channel");
+        return Err(ServerRpcError::AccessDenied);
+    };
+    let wait = chan
+        .event
+        .wait_until(|| chan.value.lock().unwrap().take());
+    let Ok(value) = timeout(Duration::from_millis(timeout_ms), wait).await else {
+        return Ok(None);
+    };
+    CHANNELS.invalidate(&channel);
+    tracing::debug!(channel, "provision channel received and closed");
+    Ok(Some(value))
+}
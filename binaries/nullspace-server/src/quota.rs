@@ -0,0 +1,218 @@
+use std::time::Duration;
+
+use nullspace_crypt::hash::{BcsHashExt, Hash};
+use nullspace_structs::server::{AuthToken, MailboxId, ServerRpcError};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use sqlx::{Sqlite, Transaction};
+
+use crate::config::{CONFIG, QuotaLimits};
+use crate::fatal_retry_later;
+
+const MESSAGE_WINDOW: Duration = Duration::from_secs(60);
+
+const FRAGMENT_WINDOW: Duration = Duration::from_secs(24 * 60 * 60);
+
+/// Who a request's usage is charged to.
+#[derive(Clone, Debug)]
+pub enum QuotaSubject {
+    /// A username, for any of its device tokens.
+    User(UserName),
+    /// A token that is not a device token, but has its own ACL entry, such as a group token.
+    Token(Hash),
+    /// Everyone that goes through an anonymous ACL entry into the given mailbox, so that a flood
+    /// against one mailbox does not use up the quota for everyone else.
+    Anonymous(MailboxId),
+}
+
+impl QuotaSubject {
+    /// Works out who to charge for a send into `mailbox` made with `auth`. Tokens that are
+    /// neither device tokens nor have their own ACL entry are all charged as anonymous, so that
+    /// making up fresh tokens does not get around the quota.
+    pub async fn for_token(
+        tx: &mut Transaction<'_, Sqlite>,
+        auth: AuthToken,
+        own_acl: bool,
+        mailbox: MailboxId,
+    ) -> Result<Self, ServerRpcError> {
+        if let Some(subject) = Self::for_device(tx, auth).await? {
+            return Ok(subject);
+        }
+        if own_acl && auth != AuthToken::anonymous() {
+            return Ok(Self::Token(auth.bcs_hash()));
+        }
+        Ok(Self::Anonymous(mailbox))
+    }
+
+    /// The username to charge for a request made with `auth`, if it is a device token.
+    pub async fn for_device(
+        tx: &mut Transaction<'_, Sqlite>,
+        auth: AuthToken,
+    ) -> Result<Option<Self>, ServerRpcError> {
+        Ok(device_username(tx, auth).await?.map(Self::User))
+    }
+
+    /// The key usage is tracked under in the database.
+    pub fn key(&self) -> String {
+        match self {
+            Self::User(username) => format!("user:{username}"),
+            Self::Token(hash) => format!("token:{hash}"),
+            Self::Anonymous(mailbox) => {
+                format!("anonymous:{}", Hash::from_bytes(mailbox.to_bytes()))
+            }
+        }
+    }
+
+    fn limits(&self) -> &'static QuotaLimits {
+        match self {
+            Self::User(_) => &CONFIG.quotas.user,
+            Self::Token(_) => &CONFIG.quotas.token,
+            Self::Anonymous(_) => &CONFIG.quotas.anonymous,
+        }
+    }
+}
+
+/// Charges a mailbox entry of the given size, failing with `QuotaExceeded` if it does not fit.
+pub async fn charge_message(
+    tx: &mut Transaction<'_, Sqlite>,
+    subject: &QuotaSubject,
+    bytes: u64,
+) -> Result<(), ServerRpcError> {
+    let limits = subject.limits();
+    if let Some(limit) = limits.stored_bytes {
+        check_stored_bytes(tx, subject, bytes, limit).await?;
+    }
+    if let Some(limit) = limits.messages_per_minute {
+        charge_window(tx, subject, "messages", MESSAGE_WINDOW, 1, limit).await?;
+    }
+    Ok(())
+}
+
+/// Charges a fragment upload of the given size, failing with `QuotaExceeded` if it does not fit.
+pub async fn charge_fragment(
+    tx: &mut Transaction<'_, Sqlite>,
+    subject: &QuotaSubject,
+    bytes: u64,
+) -> Result<(), ServerRpcError> {
+    let limits = subject.limits();
+    if let Some(limit) = limits.stored_bytes {
+        check_stored_bytes(tx, subject, bytes, limit).await?;
+    }
+    if let Some(limit) = limits.frag_bytes_per_day {
+        charge_window(tx, subject, "frag_bytes", FRAGMENT_WINDOW, bytes, limit).await?;
+    }
+    Ok(())
+}
+
+/// Gives back the daily upload charge of a fragment that was charged but could not be stored.
+pub async fn refund_fragment(
+    tx: &mut Transaction<'_, Sqlite>,
+    subject: &QuotaSubject,
+    bytes: u64,
+) -> Result<(), ServerRpcError> {
+    if subject.limits().frag_bytes_per_day.is_some() {
+        sqlx::query(
+            "UPDATE quota_usage SET amount = MAX(amount - ?, 0) \
+             WHERE subject = ? AND kind = ? AND window_start = ?",
+        )
+        .bind(bytes as i64)
+        .bind(subject.key())
+        .bind("frag_bytes")
+        .bind(window_start(FRAGMENT_WINDOW) as i64)
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    }
+    Ok(())
+}
+
+async fn check_stored_bytes(
+    tx: &mut Transaction<'_, Sqlite>,
+    subject: &QuotaSubject,
+    bytes: u64,
+    limit: u64,
+) -> Result<(), ServerRpcError> {
+    let key = subject.key();
+    let stored = sqlx::query_scalar::<_, i64>(
+        "SELECT \
+           (SELECT COALESCE(SUM(LENGTH(message_body)), 0) \
+            FROM mailbox_entries WHERE quota_subject = ?) + \
+           (SELECT COALESCE(SUM(size), 0) FROM fragments WHERE quota_subject = ?)",
+    )
+    .bind(&key)
+    .bind(&key)
+    .fetch_one(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    if (stored as u64).saturating_add(bytes) > limit {
+        tracing::debug!(subject = %key, stored, bytes, limit, "stored bytes quota exceeded");
+        return Err(ServerRpcError::QuotaExceeded);
+    }
+    Ok(())
+}
+
+/// Adds to a counter that resets at the start of every window, failing instead if that would go
+/// over the limit.
+async fn charge_window(
+    tx: &mut Transaction<'_, Sqlite>,
+    subject: &QuotaSubject,
+    kind: &str,
+    window: Duration,
+    amount: u64,
+    limit: u64,
+) -> Result<(), ServerRpcError> {
+    let key = subject.key();
+    let window_start = window_start(window);
+    let row = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT window_start, amount FROM quota_usage WHERE subject = ? AND kind = ?",
+    )
+    .bind(&key)
+    .bind(kind)
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    let used = match row {
+        Some((start, used)) if start as u64 == window_start => used as u64,
+        _ => 0,
+    };
+    let total = used.saturating_add(amount);
+    if total > limit {
+        tracing::debug!(subject = %key, kind, used, amount, limit, "quota exceeded");
+        return Err(ServerRpcError::QuotaExceeded);
+    }
+    sqlx::query(
+        "INSERT OR REPLACE INTO quota_usage (subject, kind, window_start, amount) \
+         VALUES (?, ?, ?, ?)",
+    )
+    .bind(&key)
+    .bind(kind)
+    .bind(window_start as i64)
+    .bind(total as i64)
+    .execute(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    Ok(())
+}
+
+fn window_start(window: Duration) -> u64 {
+    let now = NanoTimestamp::now().0;
+    let window_ns = window.as_nanos() as u64;
+    now - now % window_ns
+}
+
+async fn device_username(
+    tx: &mut Transaction<'_, Sqlite>,
+    auth: AuthToken,
+) -> Result<Option<UserName>, ServerRpcError> {
+    let auth_bytes = bcs::to_bytes(&auth).map_err(fatal_retry_later)?;
+    let username = sqlx::query_scalar::<_, String>(
+        "SELECT username FROM device_auth_tokens WHERE auth_token = ? LIMIT 1",
+    )
+    .bind(auth_bytes)
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    username
+        .map(|username| UserName::parse(username).map_err(fatal_retry_later))
+        .transpose()
+}
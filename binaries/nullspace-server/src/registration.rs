@@ -0,0 +1,123 @@
+use nullspace_structs::server::ServerRpcError;
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use smol_str::SmolStr;
+use sqlx::{Sqlite, Transaction};
+
+use crate::config::{CONFIG, RegistrationPolicy};
+use crate::database::DATABASE;
+use crate::fatal_retry_later;
+
+/// Admits a username under the registration policy, redeeming its invite code if it needs one.
+/// Fails with `AccessDenied` if the username may not use this server.
+pub async fn admit(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &UserName,
+    invite_code: Option<&str>,
+) -> Result<(), ServerRpcError> {
+    let admitted = match CONFIG.registration {
+        RegistrationPolicy::Open => true,
+        RegistrationPolicy::Allowlist => on_allowlist(tx, username).await?,
+        RegistrationPolicy::Invite => {
+            is_admitted(tx, username).await? || redeem_invite(tx, username, invite_code).await?
+        }
+    };
+    if !admitted {
+        tracing::debug!(
+            username = %username,
+            policy = ?CONFIG.registration,
+            "registration denied"
+        );
+        return Err(ServerRpcError::AccessDenied);
+    }
+    sqlx::query("INSERT OR IGNORE INTO admitted_users (username, admitted_at) VALUES (?, ?)")
+        .bind(username.as_str())
+        .bind(NanoTimestamp::now().0 as i64)
+        .execute(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    Ok(())
+}
+
+pub async fn registration_check(
+    username: UserName,
+    invite_code: Option<SmolStr>,
+) -> Result<bool, ServerRpcError> {
+    let mut tx = DATABASE.begin().await.map_err(fatal_retry_later)?;
+    let admitted = match CONFIG.registration {
+        RegistrationPolicy::Open => true,
+        RegistrationPolicy::Allowlist => on_allowlist(&mut tx, &username).await?,
+        RegistrationPolicy::Invite => {
+            is_admitted(&mut tx, &username).await?
+                || match &invite_code {
+                    Some(code) => invite_is_unused(&mut tx, code).await?,
+                    None => false,
+                }
+        }
+    };
+    tx.commit().await.map_err(fatal_retry_later)?;
+    Ok(admitted)
+}
+
+async fn on_allowlist(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &UserName,
+) -> Result<bool, ServerRpcError> {
+    let row = sqlx::query_scalar::<_, i64>(
+        "SELECT 1 FROM registration_allowlist WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    Ok(row.is_some())
+}
+
+async fn is_admitted(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &UserName,
+) -> Result<bool, ServerRpcError> {
+    let row = sqlx::query_scalar::<_, i64>("SELECT 1 FROM admitted_users WHERE username = ?")
+        .bind(username.as_str())
+        .fetch_optional(tx.as_mut())
+        .await
+        .map_err(fatal_retry_later)?;
+    Ok(row.is_some())
+}
+
+async fn invite_is_unused(
+    tx: &mut Transaction<'_, Sqlite>,
+    code: &str,
+) -> Result<bool, ServerRpcError> {
+    let row = sqlx::query_scalar::<_, i64>(
+        "SELECT 1 FROM invite_codes WHERE code = ? AND used_by IS NULL",
+    )
+    .bind(code)
+    .fetch_optional(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    Ok(row.is_some())
+}
+
+async fn redeem_invite(
+    tx: &mut Transaction<'_, Sqlite>,
+    username: &UserName,
+    invite_code: Option<&str>,
+) -> Result<bool, ServerRpcError> {
+    let Some(code) = invite_code else {
+        return Ok(false);
+    };
+    let result = sqlx::query(
+        "UPDATE invite_codes SET used_by = ?, used_at = ? WHERE code = ? AND used_by IS NULL",
+    )
+    .bind(username.as_str())
+    .bind(NanoTimestamp::now().0 as i64)
+    .bind(code)
+    .execute(tx.as_mut())
+    .await
+    .map_err(fatal_retry_later)?;
+    if result.rows_affected() > 0 {
+        tracing::debug!(username = %username, "invite code redeemed");
+    }
+    Ok(result.rows_affected() > 0)
+}
@@ -10,15 +10,17 @@ use bytes::Bytes;
 use moka::future::Cache;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcService, RpcTransport};
 use nullspace_rpc_pool::PooledTransport;
-use nullspace_structs::certificate::CertificateChain;
+use nullspace_structs::certificate::{CertificateChain, DeviceRevocation};
+use nullspace_structs::directory::{PowSeed, PowSolution};
 use nullspace_structs::server::{
     AuthToken, MailboxAcl, MailboxEntry, MailboxId, MailboxRecvArgs, ProxyError, ServerName,
     ServerProtocol, ServerRpcError, ServerService, SignedMediumPk,
 };
 use nullspace_structs::{Blob, profile::UserProfile, timestamp::NanoTimestamp, username::UserName};
+use smol_str::SmolStr;
 
 use crate::config::CONFIG;
-use crate::profile;
+use crate::{profile, provision, registration};
 use crate::rpc_pool::RPC_POOL;
 use crate::{device, dir_client::DIR_CLIENT, fragment, mailbox};
 
@@ -44,12 +46,59 @@ pub async fn rpc_handler(body: Bytes) -> impl IntoResponse {
 
 #[async_trait::async_trait]
 impl ServerProtocol for ServerRpc {
+    async fn v1_provision_allocate(&self) -> Result<u64, ServerRpcError> {
+        provision::provision_allocate().await
+    }
+
+    async fn v1_provision_send(
+        &self,
+        auth: AuthToken,
+        channel: u64,
+        value: Blob,
+    ) -> Result<(), ServerRpcError> {
+        provision::provision_send(auth, channel, value).await
+    }
+
+    async fn v1_provision_recv(
+        &self,
+        channel: u64,
+        timeout_ms: u64,
+    ) -> Result<Option<Blob>, ServerRpcError> {
+        provision::provision_recv(channel, timeout_ms).await
+    }
+
     async fn v1_device_auth(
         &self,
         username: UserName,
         cert: CertificateChain,
     ) -> Result<AuthToken, ServerRpcError> {
-        device::device_auth(username, cert).await
+        device::device_auth(username, cert, None).await
+    }
+
+    async fn v1_device_auth_invite(
+        &self,
+        username: UserName,
+        cert: CertificateChain,
+        invite_code: SmolStr,
+    ) -> Result<AuthToken, ServerRpcError> {
+        device::device_auth(username, cert, Some(invite_code)).await
+    }
+
+    async fn v1_migration_prepare(
+        &self,
+        username: UserName,
+        cert: CertificateChain,
+        invite_code: Option<SmolStr>,
+    ) -> Result<(), ServerRpcError> {
+        device::migration_prepare(username, cert, invite_code).await
+    }
+
+    async fn v1_registration_check(
+        &self,
+        username: UserName,
+        invite_code: Option<SmolStr>,
+    ) -> Result<bool, ServerRpcError> {
+        registration::registration_check(username, invite_code).await
     }
 
     async fn v1_device_certs(
@@ -60,6 +109,21 @@ impl ServerProtocol for ServerRpc {
         device::device_list(username).await
     }
 
+    async fn v1_device_revoke(
+        &self,
+        username: UserName,
+        revocation: DeviceRevocation,
+    ) -> Result<(), ServerRpcError> {
+        device::device_revoke(username, revocation).await
+    }
+
+    async fn v1_device_revocations(
+        &self,
+        username: UserName,
+    ) -> Result<BTreeMap<nullspace_crypt::hash::Hash, DeviceRevocation>, ServerRpcError> {
+        device::device_revocations(username).await
+    }
+
     async fn v1_mailbox_send(
         &self,
         auth: AuthToken,
@@ -67,7 +131,33 @@ impl ServerProtocol for ServerRpc {
         message: Blob,
         ttl: u32,
     ) -> Result<NanoTimestamp, ServerRpcError> {
-        mailbox::mailbox_send(auth, mailbox_id, message, ttl).await
+        mailbox::mailbox_send(auth, mailbox_id, message, ttl, None).await
+    }
+
+    async fn v1_mailbox_send_pow(
+        &self,
+        mailbox_id: MailboxId,
+        message: Blob,
+        ttl: u32,
+        pow: PowSolution,
+    ) -> Result<NanoTimestamp, ServerRpcError> {
+        mailbox::mailbox_send(AuthToken::anonymous(), mailbox_id, message, ttl, Some(pow)).await
+    }
+
+    async fn v1_mailbox_pow_seed(
+        &self,
+        mailbox_id: MailboxId,
+    ) -> Result<Option<PowSeed>, ServerRpcError> {
+        mailbox::mailbox_pow_seed(mailbox_id).await
+    }
+
+    async fn v1_mailbox_pow_set(
+        &self,
+        auth: AuthToken,
+        mailbox_id: MailboxId,
+        effort: Option<u64>,
+    ) -> Result<(), ServerRpcError> {
+        mailbox::mailbox_pow_set(auth, mailbox_id, effort).await
     }
 
     async fn v1_device_medium_pks(
@@ -105,6 +195,18 @@ impl ServerProtocol for ServerRpc {
         mailbox::mailbox_multirecv(args, timeout_ms).await
     }
 
+    async fn v1_mailbox_subscribe(
+        &self,
+        subscription: u64,
+        args: Vec<MailboxRecvArgs>,
+    ) -> Result<bool, ServerRpcError> {
+        mailbox::mailbox_subscribe(subscription, args).await
+    }
+
+    async fn v1_mailbox_unsubscribe(&self, subscription: u64) -> Result<(), ServerRpcError> {
+        mailbox::mailbox_unsubscribe(subscription).await
+    }
+
     async fn v1_mailbox_acl_edit(
         &self,
         auth: AuthToken,
@@ -147,6 +249,10 @@ impl ServerProtocol for ServerRpc {
         if !CONFIG.proxy_enabled {
             return Err(ProxyError::NotSupported);
         }
+        // notifications would go to our own pooled connection, not back to the caller
+        if req.method == "v1_mailbox_subscribe" {
+            return Err(ProxyError::Upstream("mailbox subscriptions cannot be proxied".into()));
+        }
         static PROXY_CACHE: LazyLock<Cache<ServerName, PooledTransport>> = LazyLock::new(|| {
             Cache::builder()
                 .time_to_idle(Duration::from_secs(12 * 60 * 60))
@@ -169,7 +275,7 @@ impl ServerProtocol for ServerRpc {
                     .first()
                     .cloned()
                     .ok_or_else(|| anyhow::anyhow!("server has no public URLs"))?;
-                Ok(RPC_POOL.rpc(endpoint))
+                Ok(RPC_POOL.rpc_pinned(endpoint, descriptor.server_pk))
             })
             .await
             .map_err(|err: Arc<anyhow::Error>| ProxyError::Upstream(err.to_string()))?;
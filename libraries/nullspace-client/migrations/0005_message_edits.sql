@@ -0,0 +1,3 @@
+ALTER TABLE convos ADD COLUMN version INTEGER NOT NULL DEFAULT 0;
+
+ALTER TABLE convo_messages ADD COLUMN edited_at INTEGER;
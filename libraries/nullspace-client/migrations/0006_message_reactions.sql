@@ -0,0 +1,9 @@
+CREATE TABLE message_reactions (
+    message_id INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    emoji TEXT NOT NULL,
+    active INTEGER NOT NULL CHECK (active IN (0, 1)),
+    updated_at INTEGER NOT NULL,
+    PRIMARY KEY (message_id, username, emoji),
+    FOREIGN KEY (message_id) REFERENCES convo_messages(id) ON DELETE CASCADE
+);
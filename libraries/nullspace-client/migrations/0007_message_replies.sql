@@ -0,0 +1,6 @@
+ALTER TABLE convo_messages ADD COLUMN parent_sender TEXT;
+
+ALTER TABLE convo_messages ADD COLUMN parent_sent_at INTEGER;
+
+CREATE INDEX convo_messages_parent_idx
+    ON convo_messages (convo_id, parent_sender, parent_sent_at);
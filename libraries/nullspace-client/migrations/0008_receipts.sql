@@ -0,0 +1,13 @@
+CREATE TABLE convo_receipts (
+    convo_id INTEGER NOT NULL,
+    username TEXT NOT NULL,
+    delivered_up_to INTEGER NOT NULL,
+    read_up_to INTEGER NOT NULL,
+    PRIMARY KEY (convo_id, username),
+    FOREIGN KEY (convo_id) REFERENCES convos(id) ON DELETE CASCADE
+);
+
+CREATE TABLE client_settings (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    read_receipts INTEGER NOT NULL CHECK (read_receipts IN (0, 1))
+);
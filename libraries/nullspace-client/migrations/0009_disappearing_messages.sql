@@ -0,0 +1,8 @@
+ALTER TABLE convos ADD COLUMN message_timer INTEGER;
+ALTER TABLE convos ADD COLUMN message_timer_set_at INTEGER;
+
+ALTER TABLE convo_messages ADD COLUMN expires_at INTEGER;
+
+CREATE INDEX convo_messages_expires_idx
+    ON convo_messages (expires_at)
+    WHERE expires_at IS NOT NULL;
@@ -0,0 +1,3 @@
+ALTER TABLE groups ADD COLUMN name TEXT;
+ALTER TABLE groups ADD COLUMN topic TEXT;
+ALTER TABLE groups ADD COLUMN avatar BLOB;
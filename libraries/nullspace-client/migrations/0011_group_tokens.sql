@@ -0,0 +1,10 @@
+CREATE TABLE group_issued_tokens (
+    group_id BLOB NOT NULL,
+    username TEXT NOT NULL,
+    token_hash BLOB NOT NULL,
+    PRIMARY KEY (group_id, token_hash),
+    FOREIGN KEY (group_id) REFERENCES groups(group_id) ON DELETE CASCADE
+);
+
+CREATE INDEX group_issued_tokens_member_idx
+    ON group_issued_tokens (group_id, username);
@@ -0,0 +1 @@
+ALTER TABLE groups ADD COLUMN rekey_pending_since INTEGER;
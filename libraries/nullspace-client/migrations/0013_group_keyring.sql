@@ -0,0 +1,13 @@
+CREATE TABLE group_keys (
+    group_id BLOB NOT NULL,
+    epoch BLOB NOT NULL,
+    group_key BLOB NOT NULL,
+    added_at INTEGER NOT NULL,
+    PRIMARY KEY (group_id, epoch),
+    FOREIGN KEY (group_id) REFERENCES groups(group_id) ON DELETE CASCADE
+);
+
+CREATE INDEX group_keys_by_age
+    ON group_keys (group_id, added_at);
+
+ALTER TABLE groups DROP COLUMN group_key_prev;
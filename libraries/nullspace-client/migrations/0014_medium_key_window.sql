@@ -0,0 +1,11 @@
+CREATE TABLE client_medium_keys (
+    id INTEGER PRIMARY KEY,
+    mpk_short BLOB NOT NULL,
+    medium_sk BLOB NOT NULL,
+    expires_at INTEGER NOT NULL
+);
+
+CREATE INDEX client_medium_keys_by_short
+    ON client_medium_keys (mpk_short);
+
+ALTER TABLE client_identity DROP COLUMN medium_sk_prev;
@@ -0,0 +1,4 @@
+CREATE TABLE user_device_revocations_cache (
+    username TEXT NOT NULL PRIMARY KEY,
+    revoked BLOB NOT NULL
+);
@@ -0,0 +1,9 @@
+CREATE TABLE account_migration (
+    id INTEGER PRIMARY KEY CHECK (id = 1),
+    old_server TEXT NOT NULL,
+    new_server TEXT NOT NULL,
+    step TEXT NOT NULL,
+    old_auth BLOB,
+    moved_at INTEGER,
+    started_at INTEGER NOT NULL
+);
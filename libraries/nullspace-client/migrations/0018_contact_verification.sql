@@ -0,0 +1,5 @@
+CREATE TABLE contact_verification (
+    username TEXT PRIMARY KEY,
+    root_cert_hash BLOB NOT NULL,
+    verified_at INTEGER NOT NULL
+);
@@ -0,0 +1,6 @@
+ALTER TABLE convos ADD COLUMN is_request INTEGER NOT NULL DEFAULT 0;
+
+CREATE TABLE blocked_users (
+    username TEXT PRIMARY KEY,
+    blocked_at INTEGER NOT NULL
+);
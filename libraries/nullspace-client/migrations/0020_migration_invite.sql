@@ -0,0 +1 @@
+ALTER TABLE account_migration ADD COLUMN invite_code TEXT;
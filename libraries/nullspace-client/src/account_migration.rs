@@ -0,0 +1,344 @@
+use std::time::Duration;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use nullspace_crypt::hash::BcsHashExt;
+use nullspace_structs::server::{AuthToken, ServerName};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserDescriptor;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::auth_tokens::get_auth_token;
+use crate::config::Config;
+use crate::convo::{announce_account_move, drain_direct_mailbox};
+use crate::database::{DATABASE, DbNotify};
+use crate::directory::DIR_CLIENT;
+use crate::identity::{Identity, store_server_name};
+use crate::internal::InternalRpcError;
+use crate::server::{SERVER_CACHE, get_server_client};
+use crate::user_info::forget_cached_user;
+
+/// How long the old mailbox keeps being drained after the directory update. Contacts cache user
+/// descriptors for a minute, so they may keep sending to the old server for a little while.
+const DRAIN_GRACE: Duration = Duration::from_secs(5 * 60);
+
+/// How long to wait between drains of the old mailbox, and between retries of a failed step.
+const MIGRATION_RETRY: Duration = Duration::from_secs(30);
+
+/// The steps of moving our account to another server, in order. Every step is safe to repeat, so
+/// an interrupted migration resumes at the step it was in.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationStep {
+    /// Registering every device certificate chain and medium-term key at the new server.
+    RegisterDevices,
+    /// Pointing the user descriptor in the directory at the new server.
+    UpdateDescriptor,
+    /// Reading what is still left in the direct mailbox at the old server.
+    DrainOldMailbox,
+    /// Telling direct contacts about the move.
+    Announce,
+    Done,
+}
+
+impl MigrationStep {
+    fn as_str(self) -> &'static str {
+        match self {
+            MigrationStep::RegisterDevices => "register_devices",
+            MigrationStep::UpdateDescriptor => "update_descriptor",
+            MigrationStep::DrainOldMailbox => "drain_old_mailbox",
+            MigrationStep::Announce => "announce",
+            MigrationStep::Done => "done",
+        }
+    }
+
+    fn parse(s: &str) -> anyhow::Result<Self> {
+        Ok(match s {
+            "register_devices" => MigrationStep::RegisterDevices,
+            "update_descriptor" => MigrationStep::UpdateDescriptor,
+            "drain_old_mailbox" => MigrationStep::DrainOldMailbox,
+            "announce" => MigrationStep::Announce,
+            "done" => MigrationStep::Done,
+            _ => anyhow::bail!("unknown migration step {s}"),
+        })
+    }
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MigrationStatus {
+    pub old_server: ServerName,
+    pub new_server: ServerName,
+    pub step: MigrationStep,
+}
+
+struct MigrationRow {
+    status: MigrationStatus,
+    invite_code: Option<SmolStr>,
+    old_auth: Option<AuthToken>,
+    moved_at: Option<NanoTimestamp>,
+}
+
+/// Records a migration of our account to `new_server`, which the migration loop then carries out.
+/// Only a device that owns the username in the directory can do this, since it has to sign the
+/// new user descriptor. The invite code is for new servers that only admit usernames by invite.
+pub async fn start_migration(
+    ctx: &AnyCtx<Config>,
+    new_server: ServerName,
+    invite_code: Option<SmolStr>,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    if let Some(existing) = load_migration(db).await?
+        && existing.status.step != MigrationStep::Done
+    {
+        if existing.status.new_server == new_server {
+            return Ok(());
+        }
+        anyhow::bail!("a migration to {} is in progress", existing.status.new_server);
+    }
+    let dir = ctx.get(DIR_CLIENT);
+    let listing = dir.query_raw(identity.username.as_str()).await?;
+    if !listing
+        .owners
+        .contains(&identity.device_secret.public().signing_public())
+    {
+        return Err(InternalRpcError::AccessDenied.into());
+    }
+    dir.get_server_descriptor(&new_server)
+        .await?
+        .context("server not found")?;
+    let descriptor = dir
+        .get_user_descriptor(&identity.username)
+        .await?
+        .context("identity username not in directory")?;
+    if descriptor.server_name == new_server {
+        anyhow::bail!("account is already at {new_server}");
+    }
+    sqlx::query(
+        "INSERT OR REPLACE INTO account_migration \
+         (id, old_server, new_server, step, old_auth, moved_at, started_at, invite_code) \
+         VALUES (1, ?, ?, ?, NULL, NULL, ?, ?)",
+    )
+    .bind(descriptor.server_name.as_str())
+    .bind(new_server.as_str())
+    .bind(MigrationStep::RegisterDevices.as_str())
+    .bind(NanoTimestamp::now().0 as i64)
+    .bind(invite_code.as_deref())
+    .execute(db)
+    .await?;
+    DbNotify::touch();
+    Ok(())
+}
+
+pub async fn migration_status(db: &sqlx::SqlitePool) -> anyhow::Result<Option<MigrationStatus>> {
+    Ok(load_migration(db).await?.map(|row| row.status))
+}
+
+pub async fn account_migration_loop(ctx: &AnyCtx<Config>) {
+    let mut notify = DbNotify::new();
+    loop {
+        match resume_migration(ctx).await {
+            Ok(()) => notify.wait_for_change().await,
+            Err(err) => {
+                tracing::warn!(error = %err, "account migration error");
+                tokio::time::sleep(MIGRATION_RETRY).await;
+            }
+        }
+    }
+}
+
+async fn resume_migration(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    loop {
+        let Some(row) = load_migration(db).await? else {
+            return Ok(());
+        };
+        let old_server = &row.status.old_server;
+        let new_server = &row.status.new_server;
+        match row.status.step {
+            MigrationStep::RegisterDevices => {
+                let old_auth =
+                    register_devices(ctx, old_server, new_server, row.invite_code.as_deref())
+                        .await?;
+                sqlx::query("UPDATE account_migration SET old_auth = ? WHERE id = 1")
+                    .bind(bcs::to_bytes(&old_auth)?)
+                    .execute(db)
+                    .await?;
+                set_step(db, MigrationStep::UpdateDescriptor).await?;
+            }
+            MigrationStep::UpdateDescriptor => {
+                update_descriptor(ctx, old_server, new_server).await?;
+                sqlx::query(
+                    "UPDATE account_migration SET moved_at = COALESCE(moved_at, ?) WHERE id = 1",
+                )
+                .bind(NanoTimestamp::now().0 as i64)
+                .execute(db)
+                .await?;
+                set_step(db, MigrationStep::DrainOldMailbox).await?;
+            }
+            MigrationStep::DrainOldMailbox => {
+                let old_auth = row.old_auth.context("old server auth token missing")?;
+                let drained = drain_direct_mailbox(ctx, old_server, old_auth).await?;
+                let moved_at = row.moved_at.unwrap_or_else(NanoTimestamp::now);
+                let grace_over = NanoTimestamp::now().0
+                    >= moved_at.0.saturating_add(DRAIN_GRACE.as_nanos() as u64);
+                if drained == 0 && grace_over {
+                    set_step(db, MigrationStep::Announce).await?;
+                } else {
+                    tracing::debug!(drained, "old mailbox not settled yet");
+                    tokio::time::sleep(MIGRATION_RETRY).await;
+                }
+            }
+            MigrationStep::Announce => {
+                let identity = Identity::load(db).await?;
+                announce_account_move(db, &identity, new_server).await?;
+                set_step(db, MigrationStep::Done).await?;
+            }
+            MigrationStep::Done => return Ok(()),
+        }
+    }
+}
+
+/// Copies every device of ours, with its medium-term key, from the old server to the new one.
+/// Returns our auth token at the old server, which keeps working there after the move.
+async fn register_devices(
+    ctx: &AnyCtx<Config>,
+    old_server: &ServerName,
+    new_server: &ServerName,
+    invite_code: Option<&str>,
+) -> anyhow::Result<AuthToken> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let old_auth = get_auth_token(ctx).await?;
+    let old = get_server_client(ctx, old_server).await?;
+    let new = get_server_client(ctx, new_server).await?;
+    // the new server only takes devices of a username proper to another server once told about
+    // the move, which is where its registration policy applies
+    new.v1_migration_prepare(
+        identity.username.clone(),
+        identity.cert_chain.clone(),
+        invite_code.map(SmolStr::new),
+    )
+    .await?
+    .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    let mut chains = old
+        .v1_device_certs(identity.username.clone())
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?
+        .unwrap_or_default();
+    chains
+        .entry(identity.cert_chain.last_device().pk.bcs_hash())
+        .or_insert_with(|| identity.cert_chain.clone());
+    let medium_pks = old
+        .v1_device_medium_pks(identity.username.clone())
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    for (device_hash, chain) in chains {
+        let auth = new
+            .v1_device_auth(identity.username.clone(), chain)
+            .await?
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        if let Some(medium_pk) = medium_pks.get(&device_hash) {
+            new.v1_device_add_medium_pk(auth, medium_pk.clone())
+                .await?
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        }
+    }
+    Ok(old_auth)
+}
+
+/// Points our user descriptor at the new server, and switches this device over to it.
+async fn update_descriptor(
+    ctx: &AnyCtx<Config>,
+    old_server: &ServerName,
+    new_server: &ServerName,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let dir = ctx.get(DIR_CLIENT);
+    let descriptor = dir
+        .get_user_descriptor(&identity.username)
+        .await?
+        .context("identity username not in directory")?;
+    if &descriptor.server_name != new_server {
+        let updated = UserDescriptor {
+            server_name: new_server.clone(),
+            root_cert_hash: descriptor.root_cert_hash,
+        };
+        dir.insert_user_descriptor(&identity.username, &updated, &identity.device_secret)
+            .await?;
+    }
+    store_server_name(db, new_server).await?;
+    let mut conn = db.acquire().await?;
+    forget_cached_user(&mut conn, &identity.username).await?;
+    drop(conn);
+    // cached clients may proxy through the old server
+    ctx.get(SERVER_CACHE).invalidate_all();
+
+    // revocations can only be posted once the new server is proper to the username
+    let old = get_server_client(ctx, old_server).await?;
+    let new = get_server_client(ctx, new_server).await?;
+    let revocations = old
+        .v1_device_revocations(identity.username.clone())
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    for revocation in revocations.into_values() {
+        match new
+            .v1_device_revoke(identity.username.clone(), revocation)
+            .await
+        {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => tracing::warn!(error = %err, "failed to copy device revocation"),
+            Err(err) => tracing::warn!(error = %err, "failed to copy device revocation"),
+        }
+    }
+    DbNotify::touch();
+    Ok(())
+}
+
+async fn set_step(db: &sqlx::SqlitePool, step: MigrationStep) -> anyhow::Result<()> {
+    sqlx::query("UPDATE account_migration SET step = ? WHERE id = 1")
+        .bind(step.as_str())
+        .execute(db)
+        .await?;
+    tracing::debug!(step = step.as_str(), "account migration step");
+    DbNotify::touch();
+    Ok(())
+}
+
+async fn load_migration(db: &sqlx::SqlitePool) -> anyhow::Result<Option<MigrationRow>> {
+    let row = sqlx::query_as::<
+        _,
+        (
+            String,
+            String,
+            String,
+            Option<Vec<u8>>,
+            Option<i64>,
+            Option<String>,
+        ),
+    >(
+        "SELECT old_server, new_server, step, old_auth, moved_at, invite_code \
+         FROM account_migration WHERE id = 1",
+    )
+    .fetch_optional(db)
+    .await?;
+    let Some((old_server, new_server, step, old_auth, moved_at, invite_code)) = row else {
+        return Ok(None);
+    };
+    let old_auth = match old_auth {
+        Some(bytes) => Some(bcs::from_bytes(&bytes)?),
+        None => None,
+    };
+    Ok(Some(MigrationRow {
+        status: MigrationStatus {
+            old_server: ServerName::parse(old_server)?,
+            new_server: ServerName::parse(new_server)?,
+            step: MigrationStep::parse(&step)?,
+        },
+        invite_code: invite_code.map(SmolStr::from),
+        old_auth,
+        moved_at: moved_at.map(|ns| NanoTimestamp(ns as u64)),
+    }))
+}
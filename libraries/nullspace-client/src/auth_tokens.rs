@@ -70,5 +70,5 @@ async fn server_client_direct(
         .cloned()
         .context("server has no public URLs")?;
     let rpc_pool = ctx.get(RPC_POOL);
-    Ok(ServerClient::from(rpc_pool.rpc(endpoint)))
+    Ok(ServerClient::from(rpc_pool.rpc_pinned(endpoint, descriptor.server_pk)))
 }
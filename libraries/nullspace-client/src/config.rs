@@ -10,6 +10,31 @@ pub struct Config {
     pub db_path: PathBuf,
     pub dir_endpoint: Url,
     pub dir_anchor_pk: SigningPublic,
+    /// How long a rotated-out medium-term secret is kept to decrypt late messages.
+    #[serde(default = "default_medium_key_retention_secs")]
+    pub medium_key_retention_secs: u64,
+    /// Also serves the internal RPC on a Unix socket, for local tools and sidecars.
+    #[serde(default)]
+    pub rpc_socket: Option<RpcSocketConfig>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct RpcSocketConfig {
+    pub path: PathBuf,
+    /// Permissions of the socket file.
+    #[serde(default = "default_rpc_socket_mode")]
+    pub mode: u32,
+    /// Compresses with LZ4, for `lz4+unix://` clients.
+    #[serde(default)]
+    pub lz4: bool,
+}
+
+pub fn default_rpc_socket_mode() -> u32 {
+    0o600
+}
+
+pub fn default_medium_key_retention_secs() -> u64 {
+    7 * 24 * 60 * 60
 }
 
 pub type Ctx<T> = fn(&AnyCtx<Config>) -> T;
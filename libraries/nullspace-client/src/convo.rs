@@ -1,6 +1,7 @@
 use anyctx::AnyCtx;
 use futures_concurrency::future::Race;
 use nullspace_crypt::hash::Hash;
+use nullspace_structs::event::MessageRef;
 use nullspace_structs::fragment::Attachment;
 use nullspace_structs::group::GroupId;
 use nullspace_structs::timestamp::NanoTimestamp;
@@ -13,15 +14,42 @@ use crate::config::Config;
 
 mod dm_common;
 mod dm_recv;
+mod control;
+mod edit;
 mod group;
+mod group_admin;
+mod group_keys;
+mod group_move;
 mod group_recv;
+mod incoming;
+mod moved;
+mod reaction;
+mod receipt;
 mod rekey;
+mod reply;
+mod requests;
 mod roster;
 mod send;
+mod timer;
+mod typing;
 
-pub use group::{accept_invite, create_group, invite, load_group};
+pub use control::{control_mimes, is_control_mime};
+pub use dm_recv::drain_direct_mailbox;
+pub use edit::{delete_message, edit_message};
+pub use group::{
+    accept_invite, create_group, invite, load_group, load_group_metadata, set_group_metadata,
+};
+pub use group_admin::{ban_member, leave_group, set_member_admin, unban_member};
+pub use group_move::move_group;
+pub use moved::announce_account_move;
+pub use reaction::{load_reactions, react_message};
+pub use receipt::{ReceiptState, load_receipts, mark_read};
+pub use reply::{load_reply_parents, reply_payload};
+pub use requests::{accept_request, block_user, blocked_users, unblock_user};
 pub use roster::GroupRoster;
 pub use send::queue_message;
+pub use timer::set_message_timer;
+pub use typing::send_typing;
 
 #[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[serde(tag = "kind", rename_all = "snake_case")]
@@ -66,6 +94,24 @@ pub struct ConvoMessage {
     pub body: MessageContent,
     pub send_error: Option<String>,
     pub received_at: Option<NanoTimestamp>,
+    pub edited_at: Option<NanoTimestamp>,
+    pub expires_at: Option<NanoTimestamp>,
+    pub reactions: Vec<ReactionSummary>,
+    pub parent: Option<ReplyParent>,
+    pub receipts: Vec<ReceiptState>,
+}
+
+/// The message a reply points at. `message_id` is unset if the parent is not stored locally.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReplyParent {
+    pub target: MessageRef,
+    pub message_id: Option<i64>,
+}
+
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReactionSummary {
+    pub emoji: SmolStr,
+    pub users: Vec<UserName>,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -88,12 +134,25 @@ pub enum MessageContent {
 pub enum OutgoingMessage {
     PlainText(String),
     Attachment(Attachment),
+    Reply { parent: i64, text: String },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct ConvoSummary {
     pub convo_id: ConvoId,
     pub last_message: Option<ConvoMessage>,
+    /// How long new messages live, in seconds, if disappearing messages are on.
+    pub message_timer: Option<u32>,
+    /// Set for group convos only.
+    pub group_metadata: Option<GroupMetadata>,
+}
+
+/// The admin-controlled name, topic and avatar of a group.
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub struct GroupMetadata {
+    pub name: Option<String>,
+    pub topic: Option<String>,
+    pub avatar: Option<Attachment>,
 }
 
 pub async fn convo_loop(ctx: &AnyCtx<Config>) {
@@ -102,6 +161,8 @@ pub async fn convo_loop(ctx: &AnyCtx<Config>) {
         dm_recv::dm_recv_loop(ctx),
         group_recv::group_recv_loop(ctx),
         rekey::group_rekey_loop(ctx),
+        receipt::receipt_loop(ctx),
+        timer::janitor_loop(ctx),
     )
         .race()
         .await;
@@ -0,0 +1,134 @@
+use anyhow::Context;
+use nullspace_structs::event::{
+    AccountMoved, Event, EventPayload, MessageDelete, MessageEdit, MessageReaction,
+    MessageReceipt, MessageRef, MessageTimer, Recipient,
+};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+
+use crate::database::{DbNotify, bump_convo_version};
+use crate::identity::Identity;
+
+use super::edit::{apply_message_delete, apply_message_edit};
+use super::moved::apply_account_moved;
+use super::reaction::apply_message_reaction;
+use super::receipt::apply_message_receipt;
+use super::send::queue_message;
+use super::timer::apply_message_timer;
+use super::{ConvoId, parse_convo_id};
+
+/// The mimes of control events, which change other messages rather than being shown as
+/// messages themselves.
+pub fn control_mimes() -> [&'static str; 6] {
+    [
+        MessageEdit::mime(),
+        MessageDelete::mime(),
+        MessageReaction::mime(),
+        MessageReceipt::mime(),
+        MessageTimer::mime(),
+        AccountMoved::mime(),
+    ]
+}
+
+/// Whether the mime is one of [`control_mimes`].
+pub fn is_control_mime(mime: &str) -> bool {
+    control_mimes().contains(&mime)
+}
+
+/// A stored message that a control event can refer to.
+pub(super) struct TargetMessage {
+    pub convo_id: ConvoId,
+    pub target: MessageRef,
+    pub mime: String,
+}
+
+pub(super) async fn load_target_message(
+    db: &sqlx::SqlitePool,
+    message_id: i64,
+) -> anyhow::Result<TargetMessage> {
+    let row = sqlx::query_as::<_, (String, String, String, String, i64, Option<String>)>(
+        "SELECT c.convo_type, c.convo_counterparty, m.sender_username, m.mime, m.sent_at, \
+                m.send_error \
+         FROM convo_messages m \
+         JOIN convos c ON m.convo_id = c.id \
+         WHERE m.id = ?",
+    )
+    .bind(message_id)
+    .fetch_optional(db)
+    .await?
+    .context("message not found")?;
+    let (convo_type, counterparty, sender_username, mime, sent_at, send_error) = row;
+    if send_error.is_some() || is_control_mime(&mime) {
+        anyhow::bail!("message cannot be referenced");
+    }
+    let convo_id = parse_convo_id(&convo_type, &counterparty).context("invalid convo entry")?;
+    Ok(TargetMessage {
+        convo_id,
+        target: MessageRef {
+            sender: UserName::parse(sender_username)?,
+            sent_at: NanoTimestamp(sent_at as u64),
+        },
+        mime,
+    })
+}
+
+/// Queues a control event for sending. The send loop applies it locally once it is delivered.
+pub(super) async fn queue_control<T: EventPayload>(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    convo_id: &ConvoId,
+    payload: &T,
+) -> anyhow::Result<()> {
+    let recipient = match convo_id {
+        ConvoId::Direct { peer } => Recipient::User(peer.clone()),
+        ConvoId::Group { group_id } => Recipient::Group(*group_id),
+    };
+    let content = Event::from_json_payload(recipient, NanoTimestamp::now(), payload)?;
+    let mut conn = db.acquire().await?;
+    queue_message(
+        &mut conn,
+        convo_id,
+        &identity.username,
+        &content.mime,
+        &content.body,
+    )
+    .await?;
+    DbNotify::touch();
+    Ok(())
+}
+
+/// Applies a verified control event from `sender` to the messages of a convo.
+pub(super) async fn apply_control_event(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    mime: &str,
+    body: &[u8],
+    sent_at: NanoTimestamp,
+) -> anyhow::Result<()> {
+    let changed = if mime == MessageEdit::mime() {
+        let edit: MessageEdit = serde_json::from_slice(body)?;
+        apply_message_edit(conn, convo_id, sender, edit, sent_at).await?
+    } else if mime == MessageDelete::mime() {
+        let delete: MessageDelete = serde_json::from_slice(body)?;
+        apply_message_delete(conn, convo_id, sender, delete).await?
+    } else if mime == MessageReaction::mime() {
+        let reaction: MessageReaction = serde_json::from_slice(body)?;
+        apply_message_reaction(conn, convo_id, sender, reaction, sent_at).await?
+    } else if mime == MessageReceipt::mime() {
+        let receipt: MessageReceipt = serde_json::from_slice(body)?;
+        apply_message_receipt(conn, convo_id, sender, receipt).await?
+    } else if mime == MessageTimer::mime() {
+        let timer: MessageTimer = serde_json::from_slice(body)?;
+        apply_message_timer(conn, convo_id, timer, sent_at).await?
+    } else if mime == AccountMoved::mime() {
+        serde_json::from_slice::<AccountMoved>(body)?;
+        apply_account_moved(conn, sender).await?
+    } else {
+        anyhow::bail!("unknown control mime {mime}");
+    };
+    if changed {
+        bump_convo_version(&mut *conn, convo_id).await?;
+    }
+    Ok(())
+}
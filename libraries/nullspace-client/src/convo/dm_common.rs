@@ -1,13 +1,19 @@
+use std::time::Duration;
+
 use anyctx::AnyCtx;
 use anyhow::Context;
+use futures_concurrency::future::Race;
 use nullspace_structs::server::{AuthToken, ServerName};
 
 use crate::config::Config;
-use crate::database::DATABASE;
+use crate::database::{DATABASE, DbNotify};
 use crate::directory::DIR_CLIENT;
 use crate::identity::{Identity, store_server_name};
 use crate::auth_tokens::get_auth_token;
 
+/// How often a device asks the directory whether its account moved to another server.
+const OWN_SERVER_CHECK_INTERVAL: Duration = Duration::from_secs(5 * 60);
+
 pub(super) async fn device_auth(ctx: &AnyCtx<Config>) -> anyhow::Result<AuthToken> {
     get_auth_token(ctx).await
 }
@@ -36,3 +42,31 @@ pub(super) async fn refresh_own_server_name(
     store_server_name(db, &descriptor.server_name).await?;
     Ok(descriptor.server_name)
 }
+
+/// Resolves once our own server is no longer `current`, whether this device moved the account or
+/// another device did.
+pub(super) async fn wait_own_server_change(ctx: &AnyCtx<Config>, current: &ServerName) {
+    let db = ctx.get(DATABASE);
+    let mut notify = DbNotify::new();
+    loop {
+        if let Ok(identity) = Identity::load(db).await
+            && identity.server_name.as_ref() != Some(current)
+        {
+            return;
+        }
+        let recheck = async {
+            tokio::time::sleep(OWN_SERVER_CHECK_INTERVAL).await;
+            let identity = match Identity::load(db).await {
+                Ok(identity) => identity,
+                Err(err) => {
+                    tracing::warn!(error = %err, "failed to load identity");
+                    return;
+                }
+            };
+            if let Err(err) = refresh_own_server_name(ctx, db, &identity).await {
+                tracing::warn!(error = %err, "failed to refresh server name");
+            }
+        };
+        (notify.wait_for_change(), recheck).race().await;
+    }
+}
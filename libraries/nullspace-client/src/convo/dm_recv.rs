@@ -1,25 +1,32 @@
 use std::time::Duration;
 
 use anyctx::AnyCtx;
-use nullspace_crypt::hash::BcsHashExt;
+use futures_concurrency::future::Race;
 use nullspace_structs::Blob;
 use nullspace_structs::e2ee::{DeviceSigned, HeaderEncrypted};
-use nullspace_structs::event::{Event, EventPayload, Recipient};
-use nullspace_structs::server::{MailboxId, ServerName};
+use nullspace_structs::event::{Event, EventPayload, Recipient, TypingIndicator};
+use nullspace_structs::server::{AuthToken, MailboxId, MailboxRecvArgs, ServerName};
 use nullspace_structs::timestamp::NanoTimestamp;
 use tracing::warn;
 
 use crate::database::{
-    DATABASE, DbNotify, ensure_convo_id, ensure_mailbox_state, load_mailbox_after,
-    update_mailbox_after,
+    DATABASE, DbNotify, ensure_mailbox_state, load_mailbox_after, update_mailbox_after,
 };
 use crate::identity::Identity;
 use crate::long_poll::LONG_POLLER;
-use crate::server::get_server_client;
+use crate::medium_keys::{decrypt_with_any, medium_secrets_for};
+use crate::server::{get_server_client, get_server_conn};
 use crate::user_info::get_user_root_hash;
-use crate::{attachments::store_attachment_root, config::Config};
+use crate::config::Config;
 
-use super::dm_common::{device_auth, refresh_own_server_name};
+use super::dm_common::{device_auth, refresh_own_server_name, wait_own_server_change};
+use super::ConvoId;
+use super::incoming::store_incoming_event;
+use super::requests::{ensure_incoming_direct_convo, is_accepted_direct_convo, is_blocked};
+use super::typing::receive_typing;
+
+/// How long each drain request waits for entries before the mailbox counts as empty.
+const DRAIN_POLL_MS: u64 = 1000;
 
 pub(super) async fn dm_recv_loop(ctx: &AnyCtx<Config>) {
     loop {
@@ -43,14 +50,23 @@ async fn dm_recv_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
             None => return Err(err),
         },
     };
-    let server = get_server_client(ctx, &server_name).await?;
+    let server = get_server_conn(ctx, &server_name).await?;
     let auth = device_auth(ctx).await?;
     let mailbox = MailboxId::direct(&identity.username);
     ensure_mailbox_state(db, &server_name, mailbox, NanoTimestamp(0)).await?;
     let mut after = load_mailbox_after(db, &server_name, mailbox).await?;
     let poller = ctx.get(LONG_POLLER);
     loop {
-        let entry = match poller.recv(server.clone(), auth, mailbox, after).await {
+        // start over at the new server once the account moves
+        let recv = async { Some(poller.recv(server.clone(), auth, mailbox, after).await) };
+        let moved = async {
+            wait_own_server_change(ctx, &server_name).await;
+            None
+        };
+        let Some(entry) = (recv, moved).race().await else {
+            return Ok(());
+        };
+        let entry = match entry {
             Ok(entry) => entry,
             Err(err) => {
                 tracing::warn!(error = %err, "mailbox recv error");
@@ -66,6 +82,46 @@ async fn dm_recv_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     }
 }
 
+/// Reads our direct mailbox at `server_name` until nothing newer is left, processing every entry
+/// like the recv loop does. Used to pick up what was still delivered to a server we moved away
+/// from. Returns how many entries were read.
+pub async fn drain_direct_mailbox(
+    ctx: &AnyCtx<Config>,
+    server_name: &ServerName,
+    auth: AuthToken,
+) -> anyhow::Result<usize> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let server = get_server_client(ctx, server_name).await?;
+    let mailbox = MailboxId::direct(&identity.username);
+    ensure_mailbox_state(db, server_name, mailbox, NanoTimestamp(0)).await?;
+    let mut after = load_mailbox_after(db, server_name, mailbox).await?;
+    let mut count = 0;
+    loop {
+        let args = vec![MailboxRecvArgs {
+            auth,
+            mailbox,
+            after,
+        }];
+        let mut batch = server
+            .v1_mailbox_multirecv(args, DRAIN_POLL_MS)
+            .await?
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        let entries = batch.remove(&mailbox).unwrap_or_default();
+        if entries.is_empty() {
+            return Ok(count);
+        }
+        for entry in entries {
+            after = entry.received_at;
+            count += 1;
+            if let Err(err) = process_mailbox_entry(ctx, server_name, mailbox, entry).await {
+                tracing::warn!(error = %err, "failed to process drained mailbox entry");
+            }
+        }
+        DbNotify::touch();
+    }
+}
+
 async fn process_mailbox_entry(
     ctx: &AnyCtx<Config>,
     server_name: &ServerName,
@@ -82,42 +138,23 @@ async fn process_mailbox_entry(
     }
     let encrypted: HeaderEncrypted = bcs::from_bytes(&message.inner)?;
     let header_count = encrypted.headers.len();
-    let current_mpk = identity.medium_sk_current.public_key();
-    let current_mpk_hash = current_mpk.bcs_hash().to_bytes();
-    let current_mpk_short = [current_mpk_hash[0], current_mpk_hash[1]];
-    let has_header = encrypted
-        .headers
-        .iter()
-        .any(|header| header.receiver_mpk_short == current_mpk_short);
+    let secrets = medium_secrets_for(db, &identity, &encrypted).await?;
     tracing::debug!(
         received_at = entry.received_at.0,
         header_count,
-        has_header,
+        candidate_keys = secrets.len(),
         "dm header-encrypted message received",
     );
-    let decrypted = match encrypted.decrypt_bytes(&identity.medium_sk_current) {
-        Ok(decrypted) => {
-            tracing::debug!("dm decrypt with current medium key ok");
-            decrypted
-        }
-        Err(err) => {
-            tracing::debug!(error = %err, "dm decrypt with current medium key failed");
-            match encrypted.decrypt_bytes(&identity.medium_sk_prev) {
-                Ok(decrypted) => {
-                    tracing::debug!("dm decrypt with previous medium key ok");
-                    decrypted
-                }
-                Err(err) => {
-                    tracing::warn!(error = %err, "dm decrypt with previous medium key failed");
-                    return Err(anyhow::anyhow!(
-                        "failed to decrypt header-encrypted message"
-                    ));
-                }
-            }
-        }
-    };
+    let decrypted = decrypt_with_any(&encrypted, &secrets)?;
     let signed: DeviceSigned = bcs::from_bytes(&decrypted)?;
     let sender_username = signed.sender().clone();
+    if sender_username != identity.username {
+        let mut conn = db.acquire().await?;
+        if is_blocked(&mut conn, &sender_username).await? {
+            tracing::debug!(sender = %sender_username, "dropping dm from blocked user");
+            return Ok(());
+        }
+    }
     let sender_root_hash = get_user_root_hash(ctx, &sender_username).await?;
     let message = signed
         .verify_blob(sender_root_hash)
@@ -127,8 +164,8 @@ async fn process_mailbox_entry(
         return Ok(());
     }
     let content: Event = bcs::from_bytes(&message.inner)?;
-    let recipient = match content.recipient {
-        Recipient::User(username) => username,
+    let recipient = match &content.recipient {
+        Recipient::User(username) => username.clone(),
         Recipient::Group(group_id) => {
             warn!(
                 sender = %sender_username,
@@ -151,26 +188,29 @@ async fn process_mailbox_entry(
     } else {
         sender_username.clone()
     };
-    let mut conn = db.acquire().await?;
-    if content.mime == nullspace_structs::fragment::Attachment::mime()
-        && let Ok(root) =
-            serde_json::from_slice::<nullspace_structs::fragment::Attachment>(&content.body)
-        {
-            let _ = store_attachment_root(&mut conn, &sender_username, &root).await;
+    if content.mime == TypingIndicator::mime() {
+        // typing in a message request would tell the sender we have the convo open
+        let mut conn = db.acquire().await?;
+        if !is_accepted_direct_convo(&mut conn, &peer_username).await? {
+            return Ok(());
         }
-    let convo_id = ensure_convo_id(&mut *conn, "direct", peer_username.as_str()).await?;
-    sqlx::query(
-        "INSERT OR IGNORE INTO convo_messages \
-         (convo_id, sender_username, mime, body, sent_at, received_at) \
-         VALUES (?, ?, ?, ?, ?, ?)",
+        drop(conn);
+        let convo_id = ConvoId::Direct {
+            peer: peer_username,
+        };
+        receive_typing(ctx, &identity, convo_id, sender_username, entry.received_at);
+        return Ok(());
+    }
+    let mut conn = db.acquire().await?;
+    let from_peer = sender_username != identity.username;
+    let convo_id = ensure_incoming_direct_convo(&mut conn, &peer_username, from_peer).await?;
+    store_incoming_event(
+        &mut conn,
+        convo_id,
+        &sender_username,
+        &content,
+        entry.received_at,
     )
-    .bind(convo_id)
-    .bind(sender_username.as_str())
-    .bind(content.mime.as_str())
-    .bind(content.body.to_vec())
-    .bind(content.sent_at.0 as i64)
-    .bind(entry.received_at.0 as i64)
-    .execute(&mut *conn)
     .await?;
     Ok(())
 }
@@ -0,0 +1,135 @@
+use anyctx::AnyCtx;
+use nullspace_structs::event::{EventPayload, MessageDelete, MessageEdit, MessageReply};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::database::DATABASE;
+use crate::identity::Identity;
+use crate::internal::InternalRpcError;
+
+use super::control::{TargetMessage, load_target_message, queue_control};
+
+pub async fn edit_message(
+    ctx: &AnyCtx<Config>,
+    message_id: i64,
+    text: String,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let own = load_own_message(db, &identity, message_id).await?;
+    if !is_editable_mime(&own.mime) {
+        anyhow::bail!("only text messages can be edited");
+    }
+    let edit = MessageEdit {
+        target: own.target,
+        text,
+    };
+    queue_control(db, &identity, &own.convo_id, &edit).await
+}
+
+pub async fn delete_message(ctx: &AnyCtx<Config>, message_id: i64) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let own = load_own_message(db, &identity, message_id).await?;
+    let delete = MessageDelete { target: own.target };
+    queue_control(db, &identity, &own.convo_id, &delete).await
+}
+
+async fn load_own_message(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    message_id: i64,
+) -> anyhow::Result<TargetMessage> {
+    let message = load_target_message(db, message_id).await?;
+    if message.target.sender != identity.username {
+        return Err(InternalRpcError::AccessDenied.into());
+    }
+    Ok(message)
+}
+
+fn is_editable_mime(mime: &str) -> bool {
+    mime == "text/plain" || mime == "text/markdown" || mime == MessageReply::mime()
+}
+
+/// Builds the body of an edited message, keeping its mime. Replies keep their parent.
+fn edited_body(mime: &str, body: &[u8], text: String) -> anyhow::Result<Option<Vec<u8>>> {
+    if mime == MessageReply::mime() {
+        let mut reply: MessageReply = serde_json::from_slice(body)?;
+        reply.text = text;
+        return Ok(Some(serde_json::to_vec(&reply)?));
+    }
+    if is_editable_mime(mime) {
+        return Ok(Some(text.into_bytes()));
+    }
+    Ok(None)
+}
+
+/// Replaces the text of the target message. Later edits win over earlier ones.
+pub(super) async fn apply_message_edit(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    edit: MessageEdit,
+    sent_at: NanoTimestamp,
+) -> anyhow::Result<bool> {
+    if &edit.target.sender != sender {
+        warn!(sender = %sender, target = %edit.target.sender, "ignoring edit of foreign message");
+        return Ok(false);
+    }
+    let row = sqlx::query_as::<_, (i64, String, Vec<u8>, Option<i64>)>(
+        "SELECT id, mime, body, edited_at FROM convo_messages \
+         WHERE convo_id = ? AND sender_username = ? AND sent_at = ?",
+    )
+    .bind(convo_id)
+    .bind(sender.as_str())
+    .bind(edit.target.sent_at.0 as i64)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some((message_id, mime, body, edited_at)) = row else {
+        warn!(sender = %sender, target = edit.target.sent_at.0, "dropping edit of unknown message");
+        return Ok(false);
+    };
+    if edited_at.is_some_and(|edited_at| edited_at >= sent_at.0 as i64) {
+        return Ok(false);
+    }
+    let Some(body) = edited_body(&mime, &body, edit.text)? else {
+        warn!(sender = %sender, mime = %mime, "dropping edit of non-text message");
+        return Ok(false);
+    };
+    sqlx::query("UPDATE convo_messages SET body = ?, edited_at = ? WHERE id = ?")
+        .bind(body)
+        .bind(sent_at.0 as i64)
+        .bind(message_id)
+        .execute(&mut *conn)
+        .await?;
+    Ok(true)
+}
+
+/// Removes the target message.
+pub(super) async fn apply_message_delete(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    delete: MessageDelete,
+) -> anyhow::Result<bool> {
+    if &delete.target.sender != sender {
+        warn!(sender = %sender, target = %delete.target.sender, "ignoring delete of foreign message");
+        return Ok(false);
+    }
+    let result = sqlx::query(
+        "DELETE FROM convo_messages \
+         WHERE convo_id = ? AND sender_username = ? AND sent_at = ?",
+    )
+    .bind(convo_id)
+    .bind(sender.as_str())
+    .bind(delete.target.sent_at.0 as i64)
+    .execute(&mut *conn)
+    .await?;
+    if result.rows_affected() == 0 {
+        warn!(sender = %sender, target = delete.target.sent_at.0, "dropping delete of unknown message");
+        return Ok(false);
+    }
+    Ok(true)
+}
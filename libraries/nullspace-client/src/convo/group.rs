@@ -6,7 +6,7 @@ use nullspace_crypt::hash::{BcsHashExt, Hash};
 use nullspace_structs::Blob;
 use nullspace_structs::event::{Event, EventPayload, Recipient};
 use nullspace_structs::group::{
-    GroupDescriptor, GroupId, GroupInviteMsg, GroupManageMsg, GroupMessage,
+    GroupDescriptor, GroupId, GroupInviteMsg, GroupManageMsg, GroupMessage, GroupMove,
 };
 use nullspace_structs::server::{AuthToken, MailboxAcl, MailboxId, ServerName};
 use nullspace_structs::timestamp::{NanoTimestamp, Timestamp};
@@ -15,11 +15,13 @@ use nullspace_structs::username::UserName;
 use crate::config::Config;
 use crate::database::{DATABASE, DbNotify, ensure_convo_id, ensure_mailbox_state};
 use crate::identity::Identity;
+use crate::internal::InternalRpcError;
 use crate::server::get_server_client;
 use crate::auth_tokens::get_auth_token;
 
-use super::ConvoId;
-use super::roster::GroupRoster;
+use super::group_keys::store_group_key;
+use super::roster::{GroupRoster, MAX_GROUP_NAME_BYTES, MAX_GROUP_TOPIC_BYTES};
+use super::{ConvoId, GroupMetadata};
 use super::send::queue_message;
 
 #[derive(Clone)]
@@ -29,7 +31,17 @@ pub struct GroupRecord {
     pub server_name: ServerName,
     pub token: AuthToken,
     pub group_key_current: AeadKey,
-    pub group_key_prev: AeadKey,
+    /// The latest move of the group that we have seen.
+    pub last_move: Option<GroupMove>,
+}
+
+impl GroupRecord {
+    /// Returns the move that we have seen but not followed yet, if any.
+    pub fn pending_move(&self) -> Option<&GroupMove> {
+        self.last_move
+            .as_ref()
+            .filter(|moved| moved.server != self.server_name)
+    }
 }
 
 pub async fn create_group(
@@ -82,17 +94,17 @@ pub async fn create_group(
     let mut tx = db.begin().await?;
     sqlx::query(
         "INSERT INTO groups \
-         (group_id, descriptor, server_name, token, group_key_current, group_key_prev, roster_version) \
-         VALUES (?, ?, ?, ?, ?, ?, 0)",
+         (group_id, descriptor, server_name, token, group_key_current, roster_version) \
+         VALUES (?, ?, ?, ?, ?, 0)",
     )
     .bind(group_id.as_bytes().to_vec())
     .bind(bcs::to_bytes(&descriptor)?)
     .bind(server_name.as_str())
     .bind(bcs::to_bytes(&token)?)
     .bind(bcs::to_bytes(&group_key)?)
-    .bind(bcs::to_bytes(&group_key)?)
     .execute(tx.as_mut())
     .await?;
+    store_group_key(&mut tx, group_id, &group_key, NanoTimestamp::now()).await?;
     let roster = GroupRoster::load(&mut tx, group_id, identity.username.clone()).await?;
     let _ = roster.list(&mut tx).await?;
     ensure_mailbox_state(
@@ -155,6 +167,16 @@ pub async fn invite(
         .await?
         .map_err(|err| anyhow::anyhow!(err.to_string()))?;
 
+    sqlx::query(
+        "INSERT OR IGNORE INTO group_issued_tokens (group_id, username, token_hash) \
+         VALUES (?, ?, ?)",
+    )
+    .bind(group.group_id.as_bytes().to_vec())
+    .bind(username.as_str())
+    .bind(invite_token.bcs_hash().to_bytes().to_vec())
+    .execute(db)
+    .await?;
+
     let manage = GroupManageMsg::InviteSent(username.clone());
     send_management_message(ctx, &identity, &group, manage).await?;
 
@@ -163,6 +185,7 @@ pub async fn invite(
         group_key: group.group_key_current.clone(),
         token: invite_token,
         created_at: NanoTimestamp::now(),
+        server: Some(group.server_name.clone()),
     };
     let content = Event::from_json_payload(
         Recipient::User(username.clone()),
@@ -205,6 +228,10 @@ pub async fn accept_invite(ctx: &AnyCtx<Config>, invite_id: i64) -> anyhow::Resu
     let group_id = descriptor.id();
     let group_key = invite.group_key.clone();
     let token = invite.token;
+    let server_name = invite
+        .server
+        .clone()
+        .unwrap_or_else(|| descriptor.server.clone());
 
     let mut tx = db.begin().await?;
     let existing = sqlx::query_as::<_, (i64,)>("SELECT 1 FROM groups WHERE group_id = ?")
@@ -214,28 +241,28 @@ pub async fn accept_invite(ctx: &AnyCtx<Config>, invite_id: i64) -> anyhow::Resu
     if existing.is_none() {
         sqlx::query(
             "INSERT INTO groups \
-             (group_id, descriptor, server_name, token, group_key_current, group_key_prev, roster_version) \
-             VALUES (?, ?, ?, ?, ?, ?, 0)",
+             (group_id, descriptor, server_name, token, group_key_current, roster_version) \
+             VALUES (?, ?, ?, ?, ?, 0)",
         )
         .bind(group_id.as_bytes().to_vec())
         .bind(bcs::to_bytes(&descriptor)?)
-        .bind(descriptor.server.as_str())
+        .bind(server_name.as_str())
         .bind(bcs::to_bytes(&token)?)
         .bind(bcs::to_bytes(&group_key)?)
-        .bind(bcs::to_bytes(&group_key)?)
         .execute(tx.as_mut())
         .await?;
+        store_group_key(&mut tx, group_id, &group_key, invite.created_at).await?;
     }
     ensure_mailbox_state(
         tx.as_mut(),
-        &descriptor.server,
+        &server_name,
         MailboxId::group_management(&group_id),
         NanoTimestamp(0),
     )
     .await?;
     ensure_mailbox_state(
         tx.as_mut(),
-        &descriptor.server,
+        &server_name,
         MailboxId::group_messages(&group_id),
         invite.created_at,
     )
@@ -252,20 +279,86 @@ pub async fn accept_invite(ctx: &AnyCtx<Config>, invite_id: i64) -> anyhow::Resu
     Ok(group_id)
 }
 
+/// Changes the name, topic or avatar of a group. Only admins may do this.
+pub async fn set_group_metadata(
+    ctx: &AnyCtx<Config>,
+    group_id: GroupId,
+    manage: GroupManageMsg,
+) -> anyhow::Result<()> {
+    let valid = match &manage {
+        GroupManageMsg::SetName(name) => name.len() <= MAX_GROUP_NAME_BYTES,
+        GroupManageMsg::SetTopic(topic) => topic.len() <= MAX_GROUP_TOPIC_BYTES,
+        GroupManageMsg::SetAvatar(_) => true,
+        _ => false,
+    };
+    if !valid {
+        anyhow::bail!("invalid group metadata change");
+    }
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let group = load_group(db, group_id).await?.context("group not found")?;
+    let mut conn = db.acquire().await?;
+    let roster =
+        GroupRoster::load(&mut conn, group_id, group.descriptor.init_admin.clone()).await?;
+    let is_admin = roster
+        .get(&mut conn, &identity.username)
+        .await?
+        .is_some_and(|member| member.is_admin && member.is_active());
+    drop(conn);
+    if !is_admin {
+        return Err(InternalRpcError::AccessDenied.into());
+    }
+    send_management_message(ctx, &identity, &group, manage).await?;
+    Ok(())
+}
+
+pub async fn load_group_metadata(
+    db: &sqlx::SqlitePool,
+    group_id: GroupId,
+) -> anyhow::Result<GroupMetadata> {
+    let row = sqlx::query_as::<_, (Option<String>, Option<String>, Option<Vec<u8>>)>(
+        "SELECT name, topic, avatar FROM groups WHERE group_id = ?",
+    )
+    .bind(group_id.as_bytes().to_vec())
+    .fetch_optional(db)
+    .await?
+    .context("group not found")?;
+    let (name, topic, avatar) = row;
+    Ok(GroupMetadata {
+        name,
+        topic,
+        avatar: avatar.map(|avatar| bcs::from_bytes(&avatar)).transpose()?,
+    })
+}
+
 pub async fn load_group(
     db: &sqlx::SqlitePool,
     group_id: GroupId,
 ) -> anyhow::Result<Option<GroupRecord>> {
-    let row = sqlx::query_as::<_, (Vec<u8>, Vec<u8>, String, Vec<u8>, Vec<u8>, Vec<u8>)>(
-        "SELECT group_id, descriptor, server_name, token, group_key_current, group_key_prev \
+    let row = sqlx::query_as::<_, GroupRow>(
+        "SELECT group_id, descriptor, server_name, token, group_key_current, last_move \
          FROM groups WHERE group_id = ?",
     )
     .bind(group_id.as_bytes().to_vec())
     .fetch_optional(db)
     .await?;
-    let Some((group_id_bytes, descriptor, server_name, token, key_current, key_prev)) = row else {
-        return Ok(None);
-    };
+    row.map(group_from_row).transpose()
+}
+
+pub async fn load_groups(db: &sqlx::SqlitePool) -> anyhow::Result<Vec<GroupRecord>> {
+    let rows = sqlx::query_as::<_, GroupRow>(
+        "SELECT group_id, descriptor, server_name, token, group_key_current, last_move \
+         FROM groups",
+    )
+    .fetch_all(db)
+    .await?;
+    rows.into_iter().map(group_from_row).collect()
+}
+
+type GroupRow = (Vec<u8>, Vec<u8>, String, Vec<u8>, Vec<u8>, Option<Vec<u8>>);
+
+fn group_from_row(row: GroupRow) -> anyhow::Result<GroupRecord> {
+    let (group_id_bytes, descriptor, server_name, token, key_current, last_move) = row;
     let group_id = GroupId::from_bytes(
         group_id_bytes
             .as_slice()
@@ -275,49 +368,20 @@ pub async fn load_group(
     let descriptor: GroupDescriptor = bcs::from_bytes(&descriptor)?;
     let token: AuthToken = bcs::from_bytes(&token)?;
     let group_key_current: AeadKey = bcs::from_bytes(&key_current)?;
-    let group_key_prev: AeadKey = bcs::from_bytes(&key_prev)?;
-    Ok(Some(GroupRecord {
+    let last_move = last_move
+        .map(|bytes| bcs::from_bytes(&bytes))
+        .transpose()?;
+    Ok(GroupRecord {
         group_id,
         descriptor,
         server_name: ServerName::parse(server_name)?,
         token,
         group_key_current,
-        group_key_prev,
-    }))
-}
-
-pub async fn load_groups(db: &sqlx::SqlitePool) -> anyhow::Result<Vec<GroupRecord>> {
-    let rows = sqlx::query_as::<_, (Vec<u8>, Vec<u8>, String, Vec<u8>, Vec<u8>, Vec<u8>)>(
-        "SELECT group_id, descriptor, server_name, token, group_key_current, group_key_prev \
-         FROM groups",
-    )
-    .fetch_all(db)
-    .await?;
-    let mut out = Vec::with_capacity(rows.len());
-    for (group_id_bytes, descriptor, server_name, token, key_current, key_prev) in rows {
-        let group_id = GroupId::from_bytes(
-            group_id_bytes
-                .as_slice()
-                .try_into()
-                .map_err(|_| anyhow::anyhow!("invalid group_id bytes"))?,
-        );
-        let descriptor: GroupDescriptor = bcs::from_bytes(&descriptor)?;
-        let token: AuthToken = bcs::from_bytes(&token)?;
-        let group_key_current: AeadKey = bcs::from_bytes(&key_current)?;
-        let group_key_prev: AeadKey = bcs::from_bytes(&key_prev)?;
-        out.push(GroupRecord {
-            group_id,
-            descriptor,
-            server_name: ServerName::parse(server_name)?,
-            token,
-            group_key_current,
-            group_key_prev,
-        });
-    }
-    Ok(out)
+        last_move,
+    })
 }
 
-async fn send_management_message(
+pub(super) async fn send_management_message(
     ctx: &AnyCtx<Config>,
     identity: &Identity,
     group: &GroupRecord,
@@ -345,6 +409,7 @@ async fn send_management_message(
         group,
         MailboxId::group_management(&group.group_id),
         blob,
+        0,
     )
     .await
 }
@@ -354,10 +419,11 @@ pub(super) async fn send_to_group_mailbox(
     group: &GroupRecord,
     mailbox: MailboxId,
     message: Blob,
+    ttl: u32,
 ) -> anyhow::Result<NanoTimestamp> {
     let server = get_server_client(ctx, &group.server_name).await?;
     server
-        .v1_mailbox_send(group.token, mailbox, message, 0)
+        .v1_mailbox_send(group.token, mailbox, message, ttl)
         .await?
         .map_err(|err| anyhow::anyhow!(err.to_string()))
 }
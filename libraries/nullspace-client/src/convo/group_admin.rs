@@ -0,0 +1,318 @@
+use anyctx::AnyCtx;
+use anyhow::Context;
+use nullspace_crypt::hash::{BcsHashExt, Hash};
+use nullspace_structs::group::{GroupId, GroupManageMsg};
+use nullspace_structs::server::{MailboxAcl, MailboxId};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use tracing::warn;
+
+use crate::config::Config;
+use crate::database::{DATABASE, DbNotify};
+use crate::identity::Identity;
+use crate::internal::InternalRpcError;
+use crate::server::get_server_client;
+
+use super::group::{GroupRecord, load_group, send_management_message};
+use super::group_move::{record_group_move, revoke_move_token};
+use super::rekey::send_group_rekey;
+use super::roster::{GroupRoster, RosterMember};
+
+/// Bans a member, revokes the mailbox access of the tokens we issued to them, and rotates the
+/// group key so that they cannot read anything sent afterwards. The banning admin is always the
+/// one that rekeys.
+pub async fn ban_member(
+    ctx: &AnyCtx<Config>,
+    group_id: GroupId,
+    username: UserName,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    if username == identity.username {
+        anyhow::bail!("cannot ban self");
+    }
+    let group = load_admin_group(ctx, &identity, group_id).await?;
+    let manage = GroupManageMsg::Ban(username);
+    send_management_message(ctx, &identity, &group, manage.clone()).await?;
+    // applied right away, so that the rekey already leaves the banned member out
+    apply_manage_message(ctx, &group, &identity.username, manage, NanoTimestamp::now()).await?;
+    Ok(())
+}
+
+/// Lifts a ban. The user goes back to pending and can be invited again.
+pub async fn unban_member(
+    ctx: &AnyCtx<Config>,
+    group_id: GroupId,
+    username: UserName,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let group = load_admin_group(ctx, &identity, group_id).await?;
+    send_management_message(ctx, &identity, &group, GroupManageMsg::Unban(username)).await?;
+    Ok(())
+}
+
+/// Grants or revokes admin rights of an active member.
+pub async fn set_member_admin(
+    ctx: &AnyCtx<Config>,
+    group_id: GroupId,
+    username: UserName,
+    is_admin: bool,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let group = load_admin_group(ctx, &identity, group_id).await?;
+    let mut conn = db.acquire().await?;
+    let roster =
+        GroupRoster::load(&mut conn, group_id, group.descriptor.init_admin.clone()).await?;
+    let target = roster
+        .get(&mut conn, &username)
+        .await?
+        .filter(|member| member.is_active())
+        .context("user is not an active member")?;
+    drop(conn);
+    if target.is_admin == is_admin {
+        return Ok(());
+    }
+    let manage = if is_admin {
+        GroupManageMsg::AddAdmin(username)
+    } else {
+        GroupManageMsg::RemoveAdmin(username)
+    };
+    send_management_message(ctx, &identity, &group, manage).await?;
+    Ok(())
+}
+
+/// Leaves a group: announces it, gives up our mailbox access and forgets the group keys. The
+/// convo history is kept.
+pub async fn leave_group(ctx: &AnyCtx<Config>, group_id: GroupId) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let group = load_group(db, group_id).await?.context("group not found")?;
+    send_management_message(ctx, &identity, &group, GroupManageMsg::Leave).await?;
+
+    let server = get_server_client(ctx, &group.server_name).await?;
+    let acl = MailboxAcl {
+        token_hash: group.token.bcs_hash(),
+        can_edit_acl: false,
+        can_send: false,
+        can_recv: false,
+    };
+    for mailbox in group_mailboxes(group_id) {
+        server
+            .v1_mailbox_acl_edit(group.token, mailbox, acl.clone())
+            .await?
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    }
+
+    let mut tx = db.begin().await?;
+    for mailbox in group_mailboxes(group_id) {
+        sqlx::query("DELETE FROM mailbox_state WHERE server_name = ? AND mailbox_id = ?")
+            .bind(group.server_name.as_str())
+            .bind(mailbox.to_bytes().to_vec())
+            .execute(tx.as_mut())
+            .await?;
+    }
+    sqlx::query("DELETE FROM groups WHERE group_id = ?")
+        .bind(group_id.as_bytes().to_vec())
+        .execute(tx.as_mut())
+        .await?;
+    tx.commit().await?;
+    DbNotify::touch();
+    Ok(())
+}
+
+/// Applies a management message to the local roster. When it changes the roster, the mailbox
+/// ACLs of the tokens we issued are brought in line with it. When it drops an active member, the
+/// group is marked as needing a rekey, and the admin responsible for it rekeys right away.
+pub(super) async fn apply_manage_message(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    sender: &UserName,
+    manage: GroupManageMsg,
+    received_at: NanoTimestamp,
+) -> anyhow::Result<bool> {
+    if let GroupManageMsg::Moved(moved) = &manage {
+        return record_group_move(ctx, group, sender, moved).await;
+    }
+    let db = ctx.get(DATABASE);
+    let mut tx = db.begin().await?;
+    let roster =
+        GroupRoster::load(&mut tx, group.group_id, group.descriptor.init_admin.clone()).await?;
+    let removed = removed_member(sender, &manage);
+    let was_active = match removed {
+        Some(username) => roster
+            .get(&mut tx, username)
+            .await?
+            .is_some_and(|member| member.is_active()),
+        None => false,
+    };
+    let changed = roster
+        .apply_manage_message(&mut tx, sender, manage.clone())
+        .await?;
+    let dropped = match removed {
+        Some(username) if changed && was_active => !roster
+            .get(&mut tx, username)
+            .await?
+            .is_some_and(|member| member.is_active()),
+        _ => false,
+    };
+    let responsible = if dropped {
+        sqlx::query(
+            "UPDATE groups SET rekey_pending_since = COALESCE(rekey_pending_since, ?) \
+             WHERE group_id = ?",
+        )
+        .bind(received_at.0 as i64)
+        .bind(group.group_id.as_bytes().to_vec())
+        .execute(tx.as_mut())
+        .await?;
+        let members = roster.list(&mut tx).await?;
+        rekey_responsible(&members, sender, &manage).cloned()
+    } else {
+        None
+    };
+    tx.commit().await?;
+    if !changed {
+        return Ok(false);
+    }
+    DbNotify::touch();
+    if let Err(err) = sync_member_acl(ctx, group, sender, &manage).await {
+        warn!(error = %err, group = ?group.group_id, "failed to update member mailbox acl");
+    }
+    if let Some(responsible) = responsible {
+        let identity = Identity::load(db).await?;
+        // on failure the rekey stays pending, and the rekey loop retries it
+        if responsible == identity.username
+            && let Err(err) = send_group_rekey(ctx, &identity, group).await
+        {
+            warn!(error = %err, group = ?group.group_id, "group rekey after removal failed");
+        }
+    }
+    Ok(true)
+}
+
+fn removed_member<'a>(sender: &'a UserName, manage: &'a GroupManageMsg) -> Option<&'a UserName> {
+    match manage {
+        GroupManageMsg::Leave => Some(sender),
+        GroupManageMsg::Ban(username) => Some(username),
+        _ => None,
+    }
+}
+
+/// Picks the single admin that rekeys after a removal, so that every member agrees on it without
+/// coordinating. A ban is rekeyed by the admin who issued it; a leave by the remaining active admin
+/// whose username sorts first.
+fn rekey_responsible<'a>(
+    members: &'a [RosterMember],
+    sender: &'a UserName,
+    manage: &GroupManageMsg,
+) -> Option<&'a UserName> {
+    match manage {
+        GroupManageMsg::Ban(_) => Some(sender),
+        _ => members
+            .iter()
+            .filter(|member| member.is_admin && member.is_active())
+            .map(|member| &member.username)
+            .min(),
+    }
+}
+
+/// Rewrites the ACL entries of the tokens we issued to the target of a management message. Only
+/// tokens we handed out are known to us, and the server only lets ACL editors overwrite them.
+async fn sync_member_acl(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    sender: &UserName,
+    manage: &GroupManageMsg,
+) -> anyhow::Result<()> {
+    let (username, can_edit_acl, allowed) = match manage {
+        GroupManageMsg::Leave => (sender, false, false),
+        GroupManageMsg::Ban(username) => (username, false, false),
+        GroupManageMsg::AddAdmin(username) => (username, true, true),
+        GroupManageMsg::RemoveAdmin(username) => (username, false, true),
+        _ => return Ok(()),
+    };
+    let db = ctx.get(DATABASE);
+    let mut token_hashes = Vec::new();
+    for token_hash in sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT token_hash FROM group_issued_tokens WHERE group_id = ? AND username = ?",
+    )
+    .bind(group.group_id.as_bytes().to_vec())
+    .bind(username.as_str())
+    .fetch_all(db)
+    .await?
+    {
+        token_hashes.push(Hash::from_bytes(
+            token_hash
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid token hash bytes"))?,
+        ));
+    }
+    // the move token lets anyone who has seen the move register a token, so every removal
+    // closes it, wherever the group is now
+    if removed_member(sender, manage).is_some()
+        && let Some(moved) = &group.last_move
+        && is_active_admin(db, &Identity::load(db).await?, group).await?
+        && let Err(err) = revoke_move_token(ctx, group, moved).await
+    {
+        warn!(error = %err, group = ?group.group_id, "failed to revoke move token");
+    }
+    if token_hashes.is_empty() {
+        return Ok(());
+    }
+    let server = get_server_client(ctx, &group.server_name).await?;
+    for token_hash in token_hashes {
+        let acl = MailboxAcl {
+            token_hash,
+            can_edit_acl,
+            can_send: allowed,
+            can_recv: allowed,
+        };
+        for mailbox in group_mailboxes(group.group_id) {
+            server
+                .v1_mailbox_acl_edit(group.token, mailbox, acl.clone())
+                .await?
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+pub(super) async fn load_admin_group(
+    ctx: &AnyCtx<Config>,
+    identity: &Identity,
+    group_id: GroupId,
+) -> anyhow::Result<GroupRecord> {
+    let db = ctx.get(DATABASE);
+    let group = load_group(db, group_id).await?.context("group not found")?;
+    if !is_active_admin(db, identity, &group).await? {
+        return Err(InternalRpcError::AccessDenied.into());
+    }
+    Ok(group)
+}
+
+pub(super) async fn is_active_admin(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    group: &GroupRecord,
+) -> anyhow::Result<bool> {
+    let mut conn = db.acquire().await?;
+    let roster = GroupRoster::load(
+        &mut conn,
+        group.group_id,
+        group.descriptor.init_admin.clone(),
+    )
+    .await?;
+    Ok(roster
+        .get(&mut conn, &identity.username)
+        .await?
+        .is_some_and(|member| member.is_admin && member.is_active()))
+}
+
+pub(super) fn group_mailboxes(group_id: GroupId) -> [MailboxId; 2] {
+    [
+        MailboxId::group_messages(&group_id),
+        MailboxId::group_management(&group_id),
+    ]
+}
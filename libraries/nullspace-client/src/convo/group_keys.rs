@@ -0,0 +1,60 @@
+use nullspace_crypt::aead::AeadKey;
+use nullspace_structs::group::{GroupId, GroupKeyEpoch};
+use nullspace_structs::timestamp::NanoTimestamp;
+
+use super::group::GroupRecord;
+
+/// How many group message keys are kept per group, counting the current one. Messages encrypted
+/// with anything older can no longer be read.
+const MAX_GROUP_KEYRING: i64 = 32;
+
+/// Adds a group message key to the keyring of a group, dropping the oldest keys beyond the bound.
+pub(super) async fn store_group_key(
+    tx: &mut sqlx::SqliteConnection,
+    group_id: GroupId,
+    key: &AeadKey,
+    added_at: NanoTimestamp,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT OR IGNORE INTO group_keys (group_id, epoch, group_key, added_at) \
+         VALUES (?, ?, ?, ?)",
+    )
+    .bind(group_id.as_bytes().to_vec())
+    .bind(GroupKeyEpoch::of_key(key).as_bytes().to_vec())
+    .bind(bcs::to_bytes(key)?)
+    .bind(added_at.0 as i64)
+    .execute(&mut *tx)
+    .await?;
+    sqlx::query(
+        "DELETE FROM group_keys WHERE group_id = ? AND epoch NOT IN \
+         (SELECT epoch FROM group_keys WHERE group_id = ? \
+          ORDER BY added_at DESC LIMIT ?)",
+    )
+    .bind(group_id.as_bytes().to_vec())
+    .bind(group_id.as_bytes().to_vec())
+    .bind(MAX_GROUP_KEYRING)
+    .execute(&mut *tx)
+    .await?;
+    Ok(())
+}
+
+/// Finds the key of an epoch, checking the current key first.
+pub(super) async fn lookup_group_key(
+    db: &sqlx::SqlitePool,
+    group: &GroupRecord,
+    epoch: GroupKeyEpoch,
+) -> anyhow::Result<Option<AeadKey>> {
+    if GroupKeyEpoch::of_key(&group.group_key_current) == epoch {
+        return Ok(Some(group.group_key_current.clone()));
+    }
+    let row = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT group_key FROM group_keys WHERE group_id = ? AND epoch = ?",
+    )
+    .bind(group.group_id.as_bytes().to_vec())
+    .bind(epoch.as_bytes().to_vec())
+    .fetch_optional(db)
+    .await?;
+    row.map(|key| bcs::from_bytes(&key))
+        .transpose()
+        .map_err(Into::into)
+}
@@ -0,0 +1,331 @@
+use anyctx::AnyCtx;
+use anyhow::Context;
+use nullspace_crypt::hash::{BcsHashExt, Hash};
+use nullspace_structs::group::{GroupId, GroupManageMsg, GroupMove};
+use nullspace_structs::server::{
+    AuthToken, MailboxAcl, MailboxRecvArgs, ServerClient, ServerName, ServerRpcError,
+};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+
+use crate::auth_tokens::get_auth_token;
+use crate::config::Config;
+use crate::database::{DATABASE, DbNotify, ensure_mailbox_state};
+use crate::identity::Identity;
+use crate::server::get_server_client;
+
+use super::group::{GroupRecord, send_management_message};
+use super::group_admin::{group_mailboxes, load_admin_group};
+use super::group_recv::drain_group_messages;
+use super::roster::GroupRoster;
+
+/// Moves the mailboxes of a group to another server, which must be the server of our own account.
+/// The group keeps its id, keys and roster; members follow the move when they see it in the
+/// management mailbox at the old server.
+pub async fn move_group(
+    ctx: &AnyCtx<Config>,
+    group_id: GroupId,
+    server_name: ServerName,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let group = load_admin_group(ctx, &identity, group_id).await?;
+    if group.server_name == server_name {
+        return Ok(());
+    }
+    let user_descriptor = ctx
+        .get(crate::directory::DIR_CLIENT)
+        .get_user_descriptor(&identity.username)
+        .await?
+        .context("identity username not in directory")?;
+    if user_descriptor.server_name != server_name {
+        anyhow::bail!("group server must match username server");
+    }
+
+    // the previous move token could otherwise still register tokens at the current server
+    if let Some(previous) = &group.last_move
+        && let Err(err) = revoke_move_token(ctx, &group, previous).await
+    {
+        tracing::warn!(error = %err, group = ?group_id, "failed to revoke previous move token");
+    }
+    let moved = GroupMove {
+        server: server_name.clone(),
+        token: AuthToken::random(),
+    };
+    let issued = issued_token_acls(db, &group).await?;
+    let server = get_server_client(ctx, &server_name).await?;
+    let auth = get_auth_token(ctx).await?;
+    register_moved_group(&server, auth, group_id, group.token, moved.token, &issued).await?;
+    send_management_message(ctx, &identity, &group, GroupManageMsg::Moved(moved.clone()))
+        .await?;
+    // our own token is registered already, so we switch over right away
+    store_last_move(db, group_id, &moved).await?;
+    switch_group_server(ctx, &group, &moved).await?;
+    Ok(())
+}
+
+/// Records a move announced in the management mailbox. Only active admins may move a group; the
+/// group recv task then follows the move.
+pub(super) async fn record_group_move(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    sender: &UserName,
+    moved: &GroupMove,
+) -> anyhow::Result<bool> {
+    if moved.server == group.server_name {
+        return Ok(false);
+    }
+    let db = ctx.get(DATABASE);
+    let mut conn = db.acquire().await?;
+    let roster = GroupRoster::load(
+        &mut conn,
+        group.group_id,
+        group.descriptor.init_admin.clone(),
+    )
+    .await?;
+    let sender_admin = roster
+        .get(&mut conn, sender)
+        .await?
+        .is_some_and(|member| member.is_admin && member.is_active());
+    drop(conn);
+    if !sender_admin {
+        tracing::warn!(group = ?group.group_id, sender = %sender, "ignoring group move by non-admin");
+        return Ok(false);
+    }
+    store_last_move(db, group.group_id, moved).await?;
+    DbNotify::touch();
+    Ok(true)
+}
+
+/// Registers our group token at the new server of a group, then switches over to it. The tokens
+/// we issued are registered too, so members who follow after the move token was revoked still get
+/// in.
+pub(super) async fn follow_group_move(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    moved: &GroupMove,
+) -> anyhow::Result<()> {
+    let server = get_server_client(ctx, &moved.server).await?;
+    register_member_token(&server, moved.token, group.group_id, group.token).await?;
+    let issued = issued_token_acls(ctx.get(DATABASE), group).await?;
+    register_issued_tokens(&server, group, &issued).await?;
+    switch_group_server(ctx, group, moved).await
+}
+
+/// Takes all rights away from a move token at the server it was issued for. Since entries are
+/// never overwritten by non-editors, the token can no longer register anyone there. Only ACL
+/// editors at that server may do this, so other admins are turned away.
+pub(super) async fn revoke_move_token(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    moved: &GroupMove,
+) -> anyhow::Result<()> {
+    let server = get_server_client(ctx, &moved.server).await?;
+    let acl = MailboxAcl {
+        token_hash: moved.token.bcs_hash(),
+        can_edit_acl: false,
+        can_send: false,
+        can_recv: false,
+    };
+    for mailbox in group_mailboxes(group.group_id) {
+        match server
+            .v1_mailbox_acl_edit(group.token, mailbox, acl.clone())
+            .await?
+        {
+            Ok(()) => {}
+            Err(ServerRpcError::AccessDenied) => {
+                tracing::debug!(group = ?group.group_id, "not an acl editor, leaving the move token");
+                return Ok(());
+            }
+            Err(err) => anyhow::bail!(err.to_string()),
+        }
+    }
+    Ok(())
+}
+
+/// Reads what is left at the old server, then points the group at the new one. Mailbox positions
+/// at the new server start from the beginning, since its mailboxes only hold what was sent after
+/// the move.
+async fn switch_group_server(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+    moved: &GroupMove,
+) -> anyhow::Result<()> {
+    drain_group_messages(ctx, group).await?;
+    let db = ctx.get(DATABASE);
+    let mut tx = db.begin().await?;
+    for mailbox in group_mailboxes(group.group_id) {
+        sqlx::query("DELETE FROM mailbox_state WHERE server_name = ? AND mailbox_id = ?")
+            .bind(group.server_name.as_str())
+            .bind(mailbox.to_bytes().to_vec())
+            .execute(tx.as_mut())
+            .await?;
+        ensure_mailbox_state(tx.as_mut(), &moved.server, mailbox, NanoTimestamp(0)).await?;
+    }
+    sqlx::query("UPDATE groups SET server_name = ? WHERE group_id = ?")
+        .bind(moved.server.as_str())
+        .bind(group.group_id.as_bytes().to_vec())
+        .execute(tx.as_mut())
+        .await?;
+    tx.commit().await?;
+    tracing::debug!(group = ?group.group_id, server = %moved.server, "group moved");
+    DbNotify::touch();
+    Ok(())
+}
+
+async fn store_last_move(
+    db: &sqlx::SqlitePool,
+    group_id: GroupId,
+    moved: &GroupMove,
+) -> anyhow::Result<()> {
+    sqlx::query("UPDATE groups SET last_move = ? WHERE group_id = ?")
+        .bind(bcs::to_bytes(moved)?)
+        .bind(group_id.as_bytes().to_vec())
+        .execute(db)
+        .await?;
+    Ok(())
+}
+
+/// The ACL entries of the tokens we issued to members who are still active, with admins keeping
+/// their ACL editing rights.
+async fn issued_token_acls(
+    db: &sqlx::SqlitePool,
+    group: &GroupRecord,
+) -> anyhow::Result<Vec<MailboxAcl>> {
+    let mut conn = db.acquire().await?;
+    let roster = GroupRoster::load(
+        &mut conn,
+        group.group_id,
+        group.descriptor.init_admin.clone(),
+    )
+    .await?;
+    let rows = sqlx::query_as::<_, (String, Vec<u8>)>(
+        "SELECT username, token_hash FROM group_issued_tokens WHERE group_id = ?",
+    )
+    .bind(group.group_id.as_bytes().to_vec())
+    .fetch_all(&mut *conn)
+    .await?;
+    let mut out = Vec::with_capacity(rows.len());
+    for (username, token_hash) in rows {
+        let Some(member) = roster
+            .get(&mut conn, &UserName::parse(username)?)
+            .await?
+            .filter(|member| member.is_active())
+        else {
+            continue;
+        };
+        out.push(MailboxAcl {
+            token_hash: Hash::from_bytes(
+                token_hash
+                    .as_slice()
+                    .try_into()
+                    .map_err(|_| anyhow::anyhow!("invalid token hash bytes"))?,
+            ),
+            can_edit_acl: member.is_admin,
+            can_send: true,
+            can_recv: true,
+        });
+    }
+    Ok(out)
+}
+
+/// Creates the group mailboxes at the new server. Our own group token gets full rights, the move
+/// token may only send and receive, and the tokens we issued keep the rights they had.
+async fn register_moved_group(
+    server: &ServerClient,
+    auth: AuthToken,
+    group_id: GroupId,
+    group_token: AuthToken,
+    move_token: AuthToken,
+    issued: &[MailboxAcl],
+) -> anyhow::Result<()> {
+    server
+        .v1_register_group(auth, group_id)
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    let own = MailboxAcl {
+        token_hash: group_token.bcs_hash(),
+        can_edit_acl: true,
+        can_send: true,
+        can_recv: true,
+    };
+    let mover = MailboxAcl {
+        token_hash: move_token.bcs_hash(),
+        can_edit_acl: false,
+        can_send: true,
+        can_recv: true,
+    };
+    for mailbox in group_mailboxes(group_id) {
+        for acl in [own.clone(), mover.clone()].into_iter().chain(issued.iter().cloned()) {
+            server
+                .v1_mailbox_acl_edit(auth, mailbox, acl)
+                .await?
+                .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        }
+    }
+    Ok(())
+}
+
+/// Gives the tokens we issued send and receive rights at the new server. Our own token may only
+/// add entries with a subset of its rights, and tokens that are registered already are left as
+/// they are.
+async fn register_issued_tokens(
+    server: &ServerClient,
+    group: &GroupRecord,
+    issued: &[MailboxAcl],
+) -> anyhow::Result<()> {
+    for mailbox in group_mailboxes(group.group_id) {
+        for acl in issued {
+            let acl = MailboxAcl {
+                can_edit_acl: false,
+                ..acl.clone()
+            };
+            match server
+                .v1_mailbox_acl_edit(group.token, mailbox, acl)
+                .await?
+            {
+                Ok(()) | Err(ServerRpcError::AccessDenied) => {}
+                Err(err) => anyhow::bail!(err.to_string()),
+            }
+        }
+    }
+    Ok(())
+}
+
+/// Uses the move token to give our group token send and receive rights at the new server. The
+/// server only lets a token add entries with rights it has itself, and never overwrite one, so a
+/// token that the moving admin already registered with more rights is left as it is.
+async fn register_member_token(
+    server: &ServerClient,
+    move_token: AuthToken,
+    group_id: GroupId,
+    group_token: AuthToken,
+) -> anyhow::Result<()> {
+    let acl = MailboxAcl {
+        token_hash: group_token.bcs_hash(),
+        can_edit_acl: false,
+        can_send: true,
+        can_recv: true,
+    };
+    for mailbox in group_mailboxes(group_id) {
+        match server
+            .v1_mailbox_acl_edit(move_token, mailbox, acl.clone())
+            .await?
+        {
+            Ok(()) => {}
+            Err(ServerRpcError::AccessDenied) => {
+                let probe = vec![MailboxRecvArgs {
+                    auth: group_token,
+                    mailbox,
+                    after: NanoTimestamp::now(),
+                }];
+                server
+                    .v1_mailbox_multirecv(probe, 0)
+                    .await?
+                    .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+            }
+            Err(err) => anyhow::bail!(err.to_string()),
+        }
+    }
+    Ok(())
+}
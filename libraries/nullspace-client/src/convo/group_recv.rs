@@ -5,9 +5,9 @@ use anyctx::AnyCtx;
 use anyhow::Context;
 use futures_concurrency::future::Race;
 use nullspace_structs::Blob;
-use nullspace_structs::event::{Event, EventPayload, Recipient};
+use nullspace_structs::event::{Event, EventPayload, Recipient, TypingIndicator};
 use nullspace_structs::group::{GroupId, GroupManageMsg, GroupMessage};
-use nullspace_structs::server::MailboxId;
+use nullspace_structs::server::{MailboxId, MailboxRecvArgs};
 use nullspace_structs::timestamp::NanoTimestamp;
 use tracing::warn;
 
@@ -15,14 +15,21 @@ use crate::database::{
     DATABASE, DbNotify, ensure_convo_id, ensure_mailbox_state, load_mailbox_after,
     update_mailbox_after,
 };
+use crate::identity::Identity;
 use crate::long_poll::LONG_POLLER;
-use crate::server::get_server_client;
-use crate::{attachments::store_attachment_root, config::Config};
+use crate::server::{get_server_client, get_server_conn};
+use crate::config::Config;
 
 use super::ConvoId;
 use super::group::{GroupRecord, load_group, load_groups};
+use super::group_admin::apply_manage_message;
+use super::group_move::follow_group_move;
+use super::group_keys::lookup_group_key;
+use super::incoming::store_incoming_event;
 use super::rekey::process_group_rekey_entry;
-use super::roster::GroupRoster;
+use super::typing::receive_typing;
+
+const DRAIN_POLL_MS: u64 = 1000;
 
 #[derive(Clone, Copy)]
 enum GroupMailboxKind {
@@ -99,6 +106,13 @@ async fn group_recv_task(ctx: AnyCtx<Config>, group_id: GroupId) {
                 continue;
             }
         };
+        if let Some(moved) = group.pending_move() {
+            if let Err(err) = follow_group_move(&ctx, &group, moved).await {
+                tracing::warn!(error = %err, group = ?group.group_id, "failed to follow group move");
+                tokio::time::sleep(Duration::from_secs(1)).await;
+            }
+            continue;
+        }
         if let Err(err) = group_recv_once(&ctx, &group).await {
             tracing::warn!(error = %err, group = ?group.group_id, "group recv error");
             tokio::time::sleep(Duration::from_secs(1)).await;
@@ -115,7 +129,7 @@ async fn group_recv_once(ctx: &AnyCtx<Config>, group: &GroupRecord) -> anyhow::R
     ensure_mailbox_state(db, &group.server_name, manage_box, NanoTimestamp(0)).await?;
     let message_after = load_mailbox_after(db, &group.server_name, message_box).await?;
     let manage_after = load_mailbox_after(db, &group.server_name, manage_box).await?;
-    let server = get_server_client(ctx, &group.server_name).await?;
+    let server = get_server_conn(ctx, &group.server_name).await?;
     let token = group.token;
     let server_messages = server.clone();
     let server_manage = server.clone();
@@ -148,6 +162,42 @@ async fn group_recv_once(ctx: &AnyCtx<Config>, group: &GroupRecord) -> anyhow::R
     Ok(())
 }
 
+/// Processes whatever is still left in the messages mailbox of the group at its current server,
+/// without waiting for anything new.
+pub(super) async fn drain_group_messages(
+    ctx: &AnyCtx<Config>,
+    group: &GroupRecord,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let server = get_server_client(ctx, &group.server_name).await?;
+    let mailbox = MailboxId::group_messages(&group.group_id);
+    ensure_mailbox_state(db, &group.server_name, mailbox, NanoTimestamp(0)).await?;
+    let mut after = load_mailbox_after(db, &group.server_name, mailbox).await?;
+    loop {
+        let args = vec![MailboxRecvArgs {
+            auth: group.token,
+            mailbox,
+            after,
+        }];
+        let mut batch = server
+            .v1_mailbox_multirecv(args, DRAIN_POLL_MS)
+            .await?
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        let entries = batch.remove(&mailbox).unwrap_or_default();
+        if entries.is_empty() {
+            return Ok(());
+        }
+        for entry in entries {
+            after = entry.received_at;
+            update_mailbox_after(db, &group.server_name, mailbox, after).await?;
+            if let Err(err) = process_group_message_entry(ctx, group, entry).await {
+                warn!(error = %err, group = ?group.group_id, "failed to process drained group entry");
+            }
+        }
+        DbNotify::touch();
+    }
+}
+
 async fn process_group_message_entry(
     ctx: &AnyCtx<Config>,
     group: &GroupRecord,
@@ -156,17 +206,18 @@ async fn process_group_message_entry(
     let db = ctx.get(DATABASE);
     let message = entry.message;
     if message.kind == Blob::V1_GROUP_REKEY {
-        return process_group_rekey_entry(ctx, group, &message).await;
+        return process_group_rekey_entry(ctx, group, &message, entry.received_at).await;
     }
     if message.kind != Blob::V1_GROUP_MESSAGE {
         warn!(kind = %message.kind, "ignoring non-group message");
         return Ok(());
     }
     let group_message: GroupMessage = bcs::from_bytes(&message.inner)?;
-    let signed = match group_message.decrypt_message(&group.group_key_current) {
-        Ok(signed) => signed,
-        Err(_) => group_message.decrypt_message(&group.group_key_prev)?,
+    let Some(key) = lookup_group_key(db, group, group_message.epoch).await? else {
+        warn!(group = ?group.group_id, epoch = %group_message.epoch, "unknown group key epoch");
+        return Ok(());
     };
+    let signed = group_message.decrypt_message(&key)?;
     let sender = signed.sender().clone();
     let sender_descriptor = ctx
         .get(crate::directory::DIR_CLIENT)
@@ -181,8 +232,8 @@ async fn process_group_message_entry(
         return Ok(());
     }
     let content: Event = bcs::from_bytes(&message.inner)?;
-    let recipient = match content.recipient {
-        Recipient::Group(group_id) => group_id,
+    let recipient = match &content.recipient {
+        Recipient::Group(group_id) => *group_id,
         Recipient::User(username) => {
             warn!(sender = %sender, recipient = %username, "ignoring group message to user");
             return Ok(());
@@ -192,27 +243,17 @@ async fn process_group_message_entry(
         warn!(group = ?group.group_id, recipient = ?recipient, "group recipient mismatch");
         return Ok(());
     }
+    if content.mime == TypingIndicator::mime() {
+        let identity = Identity::load(db).await?;
+        let convo_id = ConvoId::Group {
+            group_id: group.group_id,
+        };
+        receive_typing(ctx, &identity, convo_id, sender, entry.received_at);
+        return Ok(());
+    }
     let mut conn = db.acquire().await?;
-    if content.mime == nullspace_structs::fragment::Attachment::mime()
-        && let Ok(root) =
-            serde_json::from_slice::<nullspace_structs::fragment::Attachment>(&content.body)
-        {
-            let _ = store_attachment_root(&mut conn, &sender, &root).await;
-        }
     let convo_id = ensure_convo_id(&mut *conn, "group", &group.group_id.to_string()).await?;
-    sqlx::query(
-        "INSERT OR IGNORE INTO convo_messages \
-         (convo_id, sender_username, mime, body, sent_at, received_at) \
-         VALUES (?, ?, ?, ?, ?, ?)",
-    )
-    .bind(convo_id)
-    .bind(sender.as_str())
-    .bind(content.mime.as_str())
-    .bind(content.body.to_vec())
-    .bind(content.sent_at.0 as i64)
-    .bind(entry.received_at.0 as i64)
-    .execute(&mut *conn)
-    .await?;
+    store_incoming_event(&mut conn, convo_id, &sender, &content, entry.received_at).await?;
     Ok(())
 }
 
@@ -261,16 +302,6 @@ async fn process_group_management_entry(
         return Ok(());
     }
     let manage: GroupManageMsg = serde_json::from_slice(&content.body)?;
-    let db = ctx.get(DATABASE);
-    let mut tx = db.begin().await?;
-    let roster =
-        GroupRoster::load(&mut tx, group.group_id, group.descriptor.init_admin.clone()).await?;
-    let changed = roster
-        .apply_manage_message(&mut tx, &sender, manage)
-        .await?;
-    tx.commit().await?;
-    if changed {
-        DbNotify::touch();
-    }
+    apply_manage_message(ctx, group, &sender, manage, entry.received_at).await?;
     Ok(())
 }
@@ -0,0 +1,60 @@
+use nullspace_structs::event::{Event, EventPayload};
+use nullspace_structs::fragment::Attachment;
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+
+use crate::attachments::store_attachment_root;
+
+use super::control::{apply_control_event, is_control_mime};
+use super::reply::reply_parent;
+use super::timer::is_expired;
+
+/// Stores a verified incoming event into a convo. Control events are applied to the messages
+/// they reference instead of being stored, and events that already expired are dropped.
+pub(super) async fn store_incoming_event(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    content: &Event,
+    received_at: NanoTimestamp,
+) -> anyhow::Result<()> {
+    let (content, expires_at) = content.clone().split_expiry()?;
+    if is_expired(expires_at) {
+        return Ok(());
+    }
+    if is_control_mime(&content.mime) {
+        return apply_control_event(
+            conn,
+            convo_id,
+            sender,
+            &content.mime,
+            &content.body,
+            content.sent_at,
+        )
+        .await;
+    }
+    if content.mime == Attachment::mime()
+        && let Ok(root) = serde_json::from_slice::<Attachment>(&content.body)
+    {
+        let _ = store_attachment_root(&mut *conn, sender, &root).await;
+    }
+    let parent = reply_parent(&content.mime, &content.body);
+    sqlx::query(
+        "INSERT OR IGNORE INTO convo_messages \
+         (convo_id, sender_username, mime, body, sent_at, received_at, \
+          parent_sender, parent_sent_at, expires_at) \
+         VALUES (?, ?, ?, ?, ?, ?, ?, ?, ?)",
+    )
+    .bind(convo_id)
+    .bind(sender.as_str())
+    .bind(content.mime.as_str())
+    .bind(content.body.to_vec())
+    .bind(content.sent_at.0 as i64)
+    .bind(received_at.0 as i64)
+    .bind(parent.as_ref().map(|parent| parent.sender.as_str().to_string()))
+    .bind(parent.as_ref().map(|parent| parent.sent_at.0 as i64))
+    .bind(expires_at.map(|ts| ts.0 as i64))
+    .execute(&mut *conn)
+    .await?;
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use nullspace_structs::event::AccountMoved;
+use nullspace_structs::server::ServerName;
+use nullspace_structs::username::UserName;
+
+use crate::identity::Identity;
+use crate::user_info::forget_cached_user;
+
+use super::ConvoId;
+use super::control::queue_control;
+
+/// Tells every direct contact that our account moved to `server_name`.
+pub async fn announce_account_move(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    server_name: &ServerName,
+) -> anyhow::Result<()> {
+    let peers = sqlx::query_scalar::<_, String>(
+        "SELECT convo_counterparty FROM convos WHERE convo_type = 'direct'",
+    )
+    .fetch_all(db)
+    .await?;
+    let moved = AccountMoved {
+        server_name: server_name.clone(),
+    };
+    for peer in peers {
+        let peer = UserName::parse(peer)?;
+        if peer == identity.username {
+            continue;
+        }
+        queue_control(db, identity, &ConvoId::Direct { peer }, &moved).await?;
+    }
+    Ok(())
+}
+
+/// Forgets what we cached about the sender's account, so that the next lookup goes to the
+/// directory. The announced server itself is not trusted.
+pub(super) async fn apply_account_moved(
+    conn: &mut sqlx::SqliteConnection,
+    sender: &UserName,
+) -> anyhow::Result<bool> {
+    forget_cached_user(conn, sender).await?;
+    Ok(false)
+}
@@ -0,0 +1,111 @@
+use std::collections::BTreeMap;
+
+use anyctx::AnyCtx;
+use nullspace_structs::event::MessageReaction;
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use smol_str::SmolStr;
+
+use crate::config::Config;
+use crate::database::DATABASE;
+use crate::identity::Identity;
+
+use super::ReactionSummary;
+use super::control::{load_target_message, queue_control};
+
+const MAX_EMOJI_BYTES: usize = 64;
+
+pub async fn react_message(
+    ctx: &AnyCtx<Config>,
+    message_id: i64,
+    emoji: SmolStr,
+    add: bool,
+) -> anyhow::Result<()> {
+    if emoji.is_empty() || emoji.len() > MAX_EMOJI_BYTES {
+        anyhow::bail!("invalid reaction");
+    }
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let message = load_target_message(db, message_id).await?;
+    let reaction = MessageReaction {
+        target: message.target,
+        emoji,
+        add,
+    };
+    queue_control(db, &identity, &message.convo_id, &reaction).await
+}
+
+/// Records a reaction from `sender`. Later changes win over earlier ones for the same emoji.
+pub(super) async fn apply_message_reaction(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    reaction: MessageReaction,
+    sent_at: NanoTimestamp,
+) -> anyhow::Result<bool> {
+    if reaction.emoji.is_empty() || reaction.emoji.len() > MAX_EMOJI_BYTES {
+        anyhow::bail!("invalid reaction");
+    }
+    let message_id = sqlx::query_scalar::<_, i64>(
+        "SELECT id FROM convo_messages \
+         WHERE convo_id = ? AND sender_username = ? AND sent_at = ?",
+    )
+    .bind(convo_id)
+    .bind(reaction.target.sender.as_str())
+    .bind(reaction.target.sent_at.0 as i64)
+    .fetch_optional(&mut *conn)
+    .await?;
+    let Some(message_id) = message_id else {
+        tracing::debug!(sender = %sender, "ignoring reaction to unknown message");
+        return Ok(false);
+    };
+    let result = sqlx::query(
+        "INSERT INTO message_reactions (message_id, username, emoji, active, updated_at) \
+         VALUES (?, ?, ?, ?, ?) \
+         ON CONFLICT(message_id, username, emoji) DO UPDATE \
+         SET active = excluded.active, updated_at = excluded.updated_at \
+         WHERE excluded.updated_at > message_reactions.updated_at",
+    )
+    .bind(message_id)
+    .bind(sender.as_str())
+    .bind(reaction.emoji.as_str())
+    .bind(reaction.add)
+    .bind(sent_at.0 as i64)
+    .execute(&mut *conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Loads the reactions on a page of messages in one query. Messages without reactions are left
+/// out of the map.
+pub async fn load_reactions(
+    db: &sqlx::SqlitePool,
+    message_ids: &[i64],
+) -> anyhow::Result<BTreeMap<i64, Vec<ReactionSummary>>> {
+    if message_ids.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    // the ids go in as one JSON array, so that a long page does not run into the limit on
+    // bound parameters
+    let rows = sqlx::query_as::<_, (i64, String, String)>(
+        "SELECT message_id, emoji, username FROM message_reactions \
+         WHERE message_id IN (SELECT value FROM json_each(?)) AND active = 1 \
+         ORDER BY message_id, updated_at",
+    )
+    .bind(serde_json::to_string(message_ids)?)
+    .fetch_all(db)
+    .await?;
+    let mut out: BTreeMap<i64, Vec<ReactionSummary>> = BTreeMap::new();
+    for (message_id, emoji, username) in rows {
+        let username = UserName::parse(username)?;
+        let reactions = out.entry(message_id).or_default();
+        match reactions.iter_mut().find(|summary| summary.emoji == emoji) {
+            Some(summary) => summary.users.push(username),
+            None => reactions.push(ReactionSummary {
+                emoji: SmolStr::new(emoji),
+                users: vec![username],
+            }),
+        }
+    }
+    Ok(out)
+}
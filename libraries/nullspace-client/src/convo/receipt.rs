@@ -0,0 +1,204 @@
+use std::time::Duration;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use nullspace_structs::event::{MessageReceipt, ReceiptKind};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::database::{DATABASE, DbNotify, bump_convo_version};
+use crate::identity::Identity;
+use crate::settings::load_settings;
+
+use super::ConvoId;
+use super::control::queue_control;
+
+/// How long to wait after a change before sending delivery receipts, so that bursts of incoming
+/// messages are acknowledged with a single receipt.
+const RECEIPT_BATCH_DELAY: Duration = Duration::from_secs(2);
+
+/// The receipt state of a message for one participant of the convo.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ReceiptState {
+    pub username: UserName,
+    pub kind: ReceiptKind,
+}
+
+pub(super) async fn receipt_loop(ctx: &AnyCtx<Config>) {
+    let mut notify = DbNotify::new();
+    loop {
+        if let Err(err) = send_delivery_receipts(ctx).await {
+            tracing::warn!(error = %err, "failed to send delivery receipts");
+        }
+        notify.wait_for_change().await;
+        tokio::time::sleep(RECEIPT_BATCH_DELAY).await;
+    }
+}
+
+async fn send_delivery_receipts(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let rows = sqlx::query_as::<_, (i64, String, i64)>(
+        "SELECT c.id, c.convo_counterparty, MAX(m.sent_at) \
+         FROM convos c \
+         JOIN convo_messages m ON m.convo_id = c.id \
+         LEFT JOIN convo_receipts r ON r.convo_id = c.id AND r.username = ? \
+         WHERE c.convo_type = 'direct' AND c.is_request = 0 AND m.sender_username != ? \
+           AND m.received_at IS NOT NULL \
+         GROUP BY c.id \
+         HAVING MAX(m.sent_at) > COALESCE(MAX(r.delivered_up_to), 0)",
+    )
+    .bind(identity.username.as_str())
+    .bind(identity.username.as_str())
+    .fetch_all(db)
+    .await?;
+    for (convo_row_id, counterparty, up_to) in rows {
+        let convo_id = ConvoId::Direct {
+            peer: UserName::parse(counterparty)?,
+        };
+        let receipt = MessageReceipt {
+            kind: ReceiptKind::Delivered,
+            up_to: NanoTimestamp(up_to as u64),
+        };
+        send_receipt(db, &identity, &convo_id, convo_row_id, receipt).await?;
+    }
+    Ok(())
+}
+
+/// Marks everything up to and including `message_id` as read, telling the peer unless read
+/// receipts are turned off.
+pub async fn mark_read(
+    ctx: &AnyCtx<Config>,
+    convo_id: &ConvoId,
+    message_id: i64,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let (convo_row_id, sent_at) = sqlx::query_as::<_, (i64, i64)>(
+        "SELECT c.id, m.sent_at \
+         FROM convo_messages m \
+         JOIN convos c ON m.convo_id = c.id \
+         WHERE c.convo_type = ? AND c.convo_counterparty = ? AND m.id = ?",
+    )
+    .bind(convo_id.convo_type())
+    .bind(convo_id.counterparty())
+    .bind(message_id)
+    .fetch_optional(db)
+    .await?
+    .context("message not found")?;
+    let receipt = MessageReceipt {
+        kind: ReceiptKind::Read,
+        up_to: NanoTimestamp(sent_at as u64),
+    };
+    let settings = load_settings(db).await?;
+    if settings.read_receipts && matches!(convo_id, ConvoId::Direct { .. }) {
+        send_receipt(db, &identity, convo_id, convo_row_id, receipt).await?;
+    } else {
+        let mut conn = db.acquire().await?;
+        if apply_message_receipt(&mut conn, convo_row_id, &identity.username, receipt).await? {
+            bump_convo_version(&mut *conn, convo_row_id).await?;
+        }
+        DbNotify::touch();
+    }
+    Ok(())
+}
+
+/// Records our own receipt right away, so it is not queued twice, and queues it for the peer.
+async fn send_receipt(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    convo_id: &ConvoId,
+    convo_row_id: i64,
+    receipt: MessageReceipt,
+) -> anyhow::Result<()> {
+    let mut conn = db.acquire().await?;
+    if !apply_message_receipt(&mut conn, convo_row_id, &identity.username, receipt.clone()).await? {
+        return Ok(());
+    }
+    drop(conn);
+    queue_control(db, identity, convo_id, &receipt).await
+}
+
+/// Advances the receipt state of `sender` in a convo. Receipts never move backwards.
+pub(super) async fn apply_message_receipt(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sender: &UserName,
+    receipt: MessageReceipt,
+) -> anyhow::Result<bool> {
+    let up_to = receipt.up_to.0 as i64;
+    let read_up_to = match receipt.kind {
+        ReceiptKind::Delivered => 0,
+        ReceiptKind::Read => up_to,
+    };
+    let result = sqlx::query(
+        "INSERT INTO convo_receipts (convo_id, username, delivered_up_to, read_up_to) \
+         VALUES (?, ?, ?, ?) \
+         ON CONFLICT(convo_id, username) DO UPDATE \
+         SET delivered_up_to = MAX(delivered_up_to, excluded.delivered_up_to), \
+             read_up_to = MAX(read_up_to, excluded.read_up_to) \
+         WHERE excluded.delivered_up_to > convo_receipts.delivered_up_to \
+            OR excluded.read_up_to > convo_receipts.read_up_to",
+    )
+    .bind(convo_id)
+    .bind(sender.as_str())
+    .bind(up_to)
+    .bind(read_up_to)
+    .execute(&mut *conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// How far each participant of a convo has gotten, loaded once per page so that the receipts of
+/// each message are worked out without another query.
+pub struct ConvoReceipts {
+    /// `(username, delivered_up_to, read_up_to)`, ordered by username.
+    progress: Vec<(UserName, i64, i64)>,
+}
+
+impl ConvoReceipts {
+    /// The receipt state of a message that `sender` sent at `sent_at`, for everyone but the sender.
+    pub fn for_message(&self, sender: &UserName, sent_at: i64) -> Vec<ReceiptState> {
+        self.progress
+            .iter()
+            .filter(|(username, delivered_up_to, _)| {
+                username != sender && *delivered_up_to >= sent_at
+            })
+            .map(|(username, _, read_up_to)| ReceiptState {
+                username: username.clone(),
+                kind: if *read_up_to >= sent_at {
+                    ReceiptKind::Read
+                } else {
+                    ReceiptKind::Delivered
+                },
+            })
+            .collect()
+    }
+}
+
+/// Loads how far the participants of the convo have gotten through it.
+pub async fn load_receipts(
+    db: &sqlx::SqlitePool,
+    convo_id: &ConvoId,
+) -> anyhow::Result<ConvoReceipts> {
+    let rows = sqlx::query_as::<_, (String, i64, i64)>(
+        "SELECT r.username, r.delivered_up_to, r.read_up_to \
+         FROM convo_receipts r \
+         JOIN convos c ON r.convo_id = c.id \
+         WHERE c.convo_type = ? AND c.convo_counterparty = ? \
+         ORDER BY r.username",
+    )
+    .bind(convo_id.convo_type())
+    .bind(convo_id.counterparty())
+    .fetch_all(db)
+    .await?;
+    let progress = rows
+        .into_iter()
+        .map(|(username, delivered_up_to, read_up_to)| {
+            Ok((UserName::parse(username)?, delivered_up_to, read_up_to))
+        })
+        .collect::<anyhow::Result<_>>()?;
+    Ok(ConvoReceipts { progress })
+}
@@ -1,5 +1,5 @@
-use std::collections::BTreeMap;
-use std::time::Duration;
+use std::collections::{BTreeMap, BTreeSet};
+use std::time::{Duration, Instant};
 
 use anyctx::AnyCtx;
 use anyhow::Context;
@@ -7,35 +7,87 @@ use bytes::Bytes;
 use rand::Rng;
 use tracing::warn;
 use nullspace_crypt::aead::AeadKey;
-use nullspace_crypt::dh::DhPublic;
+use nullspace_crypt::dh::{DhPublic, DhSecret};
 use nullspace_crypt::hash::{BcsHashExt, Hash};
 use nullspace_crypt::signing::Signable;
 use nullspace_structs::Blob;
 use nullspace_structs::certificate::CertificateChain;
 use nullspace_structs::e2ee::{DeviceSigned, HeaderEncrypted};
+use nullspace_structs::group::{GroupId, GroupKeyEpoch};
 use nullspace_structs::server::{MailboxId, SignedMediumPk};
+use nullspace_structs::timestamp::NanoTimestamp;
 use nullspace_structs::username::UserName;
 
 use crate::config::Config;
 use crate::database::{DATABASE, DbNotify};
 use crate::identity::Identity;
+use crate::medium_keys::{decrypt_with_any, medium_secrets_for};
 use crate::user_info::get_user_info;
 
-use super::group::{GroupRecord, load_groups, send_to_group_mailbox};
+use super::group::{GroupRecord, load_group, load_groups, send_to_group_mailbox};
+use super::group_admin::is_active_admin;
+use super::group_keys::store_group_key;
 use super::roster::{GroupRoster, RosterMember};
 
 const GROUP_REKEY_MEAN_SECS: f64 = 3600.0;
+const PENDING_REKEY_CHECK: Duration = Duration::from_secs(30);
+const PENDING_REKEY_GRACE: Duration = Duration::from_secs(120);
 
 pub(super) async fn group_rekey_loop(ctx: &AnyCtx<Config>) {
+    let mut next_periodic = Instant::now();
     loop {
-        if let Err(err) = group_rekey_loop_once(ctx).await {
-            tracing::warn!(error = %err, "group rekey loop error");
+        if let Err(err) = pending_rekey_once(ctx).await {
+            tracing::warn!(error = %err, "pending group rekey error");
         }
-        let wait = sample_rekey_interval();
-        tokio::time::sleep(wait).await;
+        if Instant::now() >= next_periodic {
+            if let Err(err) = group_rekey_loop_once(ctx).await {
+                tracing::warn!(error = %err, "group rekey loop error");
+            }
+            next_periodic = Instant::now() + sample_rekey_interval();
+        }
+        let wait = next_periodic.saturating_duration_since(Instant::now());
+        tokio::time::sleep(wait.min(PENDING_REKEY_CHECK)).await;
     }
 }
 
+/// Fallback for removals whose designated admin has not rekeyed within the grace period. Every
+/// active admin steps in, so this may produce duplicate rekeys, which receivers tolerate.
+async fn pending_rekey_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let cutoff = NanoTimestamp::now()
+        .0
+        .saturating_sub(PENDING_REKEY_GRACE.as_nanos() as u64);
+    let pending = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT group_id FROM groups \
+         WHERE rekey_pending_since IS NOT NULL AND rekey_pending_since <= ?",
+    )
+    .bind(cutoff as i64)
+    .fetch_all(db)
+    .await?;
+    if pending.is_empty() {
+        return Ok(());
+    }
+    let identity = Identity::load(db).await?;
+    for group_id in pending {
+        let group_id = GroupId::from_bytes(
+            group_id
+                .as_slice()
+                .try_into()
+                .map_err(|_| anyhow::anyhow!("invalid group_id bytes"))?,
+        );
+        let Some(group) = load_group(db, group_id).await? else {
+            continue;
+        };
+        if !is_active_admin(db, &identity, &group).await? {
+            continue;
+        }
+        if let Err(err) = send_group_rekey(ctx, &identity, &group).await {
+            warn!(error = %err, group = ?group.group_id, "pending group rekey failed");
+        }
+    }
+    Ok(())
+}
+
 async fn group_rekey_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let db = ctx.get(DATABASE);
     let identity = Identity::load(db).await?;
@@ -73,15 +125,36 @@ async fn group_rekey_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     Ok(())
 }
 
-async fn send_group_rekey(
+/// Sends a fresh group key to every active member. On success, any removal recorded before the
+/// recipients were collected no longer needs a rekey.
+pub(super) async fn send_group_rekey(
     ctx: &AnyCtx<Config>,
     identity: &Identity,
     group: &GroupRecord,
 ) -> anyhow::Result<()> {
+    let started_at = NanoTimestamp::now();
     let recipients = collect_group_recipients(ctx, group).await?;
-    let new_key = AeadKey::random();
-    let key_bytes = new_key.to_bytes();
-    let payload = bcs::to_bytes(&(group.group_id, key_bytes))?;
+    let outer = seal_group_rekey(identity, group.group_id, &AeadKey::random(), recipients)?;
+    send_to_group_mailbox(
+        ctx,
+        group,
+        MailboxId::group_messages(&group.group_id),
+        outer,
+        0,
+    )
+    .await?;
+    clear_rekey_pending(ctx.get(DATABASE), group.group_id, started_at).await?;
+    Ok(())
+}
+
+fn seal_group_rekey(
+    identity: &Identity,
+    group_id: GroupId,
+    new_key: &AeadKey,
+    recipients: Vec<DhPublic>,
+) -> anyhow::Result<Blob> {
+    let epoch = GroupKeyEpoch::of_key(new_key);
+    let payload = bcs::to_bytes(&(group_id, epoch, new_key.to_bytes()))?;
     let signed = DeviceSigned::sign_bytes(
         Bytes::from(payload),
         identity.username.clone(),
@@ -91,16 +164,32 @@ async fn send_group_rekey(
     let signed_bytes = bcs::to_bytes(&signed)?;
     let encrypted = HeaderEncrypted::encrypt_bytes(&signed_bytes, recipients)
         .map_err(|_| anyhow::anyhow!("failed to encrypt group rekey"))?;
-    let outer = Blob {
+    Ok(Blob {
         kind: Blob::V1_GROUP_REKEY.into(),
         inner: Bytes::from(bcs::to_bytes(&encrypted)?),
-    };
-    send_to_group_mailbox(
-        ctx,
-        group,
-        MailboxId::group_messages(&group.group_id),
-        outer,
+    })
+}
+
+fn open_group_rekey(
+    encrypted: &HeaderEncrypted,
+    secrets: &[DhSecret],
+) -> anyhow::Result<DeviceSigned> {
+    let decrypted = decrypt_with_any(encrypted, secrets)?;
+    Ok(bcs::from_bytes(&decrypted)?)
+}
+
+async fn clear_rekey_pending(
+    db: &sqlx::SqlitePool,
+    group_id: GroupId,
+    up_to: NanoTimestamp,
+) -> anyhow::Result<()> {
+    sqlx::query(
+        "UPDATE groups SET rekey_pending_since = NULL \
+         WHERE group_id = ? AND rekey_pending_since <= ?",
     )
+    .bind(group_id.as_bytes().to_vec())
+    .bind(up_to.0 as i64)
+    .execute(db)
     .await?;
     Ok(())
 }
@@ -119,8 +208,12 @@ async fn collect_group_recipients(
     for member in members.into_iter().filter(RosterMember::is_active) {
         let username = member.username;
         let peer = get_user_info(ctx, &username).await?;
-        let user_recipients =
-            collect_recipients(&username, &peer.device_chains, &peer.medium_pks)?;
+        let user_recipients = collect_recipients(
+            &username,
+            &peer.device_chains,
+            &peer.medium_pks,
+            &peer.revoked,
+        )?;
         if !user_recipients.is_empty() {
             handles.push(username);
             recipients.extend(user_recipients);
@@ -137,6 +230,7 @@ fn collect_recipients(
     username: &UserName,
     chains: &BTreeMap<Hash, CertificateChain>,
     medium_pks: &BTreeMap<Hash, SignedMediumPk>,
+    revoked: &BTreeSet<Hash>,
 ) -> anyhow::Result<Vec<DhPublic>> {
     let mut recipients = Vec::new();
     for (device_hash, chain) in chains {
@@ -151,6 +245,13 @@ fn collect_recipients(
             );
             continue;
         }
+        if chain
+            .iter()
+            .any(|cert| revoked.contains(&cert.pk.bcs_hash()))
+        {
+            tracing::debug!(username = %username, device_hash = %device_hash, "skipping revoked device");
+            continue;
+        }
         let Some(medium_pk) = medium_pks.get(device_hash) else {
             warn!(username = %username, device_hash = %device_hash, "missing medium-term key");
             continue;
@@ -168,15 +269,13 @@ pub(super) async fn process_group_rekey_entry(
     ctx: &AnyCtx<Config>,
     group: &GroupRecord,
     message: &Blob,
+    received_at: NanoTimestamp,
 ) -> anyhow::Result<()> {
     let db = ctx.get(DATABASE);
     let identity = Identity::load(db).await?;
     let encrypted: HeaderEncrypted = bcs::from_bytes(&message.inner)?;
-    let decrypted = match encrypted.decrypt_bytes(&identity.medium_sk_current) {
-        Ok(decrypted) => decrypted,
-        Err(_) => encrypted.decrypt_bytes(&identity.medium_sk_prev)?,
-    };
-    let signed: DeviceSigned = bcs::from_bytes(&decrypted)?;
+    let secrets = medium_secrets_for(db, &identity, &encrypted).await?;
+    let signed = open_group_rekey(&encrypted, &secrets)?;
     let sender_username = signed.sender().clone();
     let mut conn = db.acquire().await?;
     let roster =
@@ -197,7 +296,7 @@ pub(super) async fn process_group_rekey_entry(
     let payload = signed
         .verify_bytes(sender_descriptor.root_cert_hash)
         .map_err(|_| anyhow::anyhow!("failed to verify device-signed rekey"))?;
-    let (rekey_group, key_bytes): (nullspace_structs::group::GroupId, [u8; 32]) =
+    let (rekey_group, epoch, key_bytes): (GroupId, GroupKeyEpoch, [u8; 32]) =
         bcs::from_bytes(&payload)?;
     if rekey_group != group.group_id {
         warn!(
@@ -208,12 +307,27 @@ pub(super) async fn process_group_rekey_entry(
         return Ok(());
     }
     let new_key = AeadKey::from_bytes(key_bytes);
-    sqlx::query("UPDATE groups SET group_key_prev = ?, group_key_current = ? WHERE group_id = ?")
-        .bind(bcs::to_bytes(&group.group_key_current)?)
+    if GroupKeyEpoch::of_key(&new_key) != epoch {
+        warn!(group = %group.group_id, epoch = %epoch, "ignoring rekey with mismatched epoch");
+        return Ok(());
+    }
+    let mut tx = db.begin().await?;
+    // the outgoing key may predate the keyring, and messages sealed under it can still arrive
+    let outgoing_key: Vec<u8> =
+        sqlx::query_scalar("SELECT group_key_current FROM groups WHERE group_id = ?")
+            .bind(group.group_id.as_bytes().to_vec())
+            .fetch_one(tx.as_mut())
+            .await?;
+    let outgoing_key: AeadKey = bcs::from_bytes(&outgoing_key)?;
+    store_group_key(&mut tx, group.group_id, &outgoing_key, received_at).await?;
+    sqlx::query("UPDATE groups SET group_key_current = ? WHERE group_id = ?")
         .bind(bcs::to_bytes(&new_key)?)
         .bind(group.group_id.as_bytes().to_vec())
-        .execute(db)
+        .execute(tx.as_mut())
         .await?;
+    store_group_key(&mut tx, group.group_id, &new_key, received_at).await?;
+    tx.commit().await?;
+    clear_rekey_pending(db, group.group_id, received_at).await?;
     DbNotify::touch();
     Ok(())
 }
@@ -224,3 +338,4 @@ fn sample_rekey_interval() -> Duration {
     let secs = -u.ln() * GROUP_REKEY_MEAN_SECS;
     Duration::from_secs_f64(secs)
 }
+
@@ -0,0 +1,79 @@
+use std::collections::BTreeMap;
+
+use bytes::Bytes;
+use nullspace_structs::event::{EventPayload, MessageRef, MessageReply};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use smol_str::SmolStr;
+
+use super::control::load_target_message;
+use super::{ConvoId, ReplyParent};
+
+/// Builds the mime and body of a reply to a message of the same convo.
+pub async fn reply_payload(
+    db: &sqlx::SqlitePool,
+    convo_id: &ConvoId,
+    parent_id: i64,
+    text: String,
+) -> anyhow::Result<(SmolStr, Bytes)> {
+    let parent = load_target_message(db, parent_id).await?;
+    if &parent.convo_id != convo_id {
+        anyhow::bail!("reply parent is in a different convo");
+    }
+    let reply = MessageReply {
+        parent: parent.target,
+        text,
+    };
+    Ok((
+        SmolStr::new(MessageReply::mime()),
+        Bytes::from(serde_json::to_vec(&reply)?),
+    ))
+}
+
+/// Extracts the parent reference of a stored message, if it is a reply.
+pub(super) fn reply_parent(mime: &str, body: &[u8]) -> Option<MessageRef> {
+    if mime != MessageReply::mime() {
+        return None;
+    }
+    serde_json::from_slice::<MessageReply>(body)
+        .ok()
+        .map(|reply| reply.parent)
+}
+
+/// Loads the parents of the replies among several messages, such as a page of history, in one
+/// query. Messages that are not replies are left out.
+pub async fn load_reply_parents(
+    db: &sqlx::SqlitePool,
+    message_ids: &[i64],
+) -> anyhow::Result<BTreeMap<i64, ReplyParent>> {
+    if message_ids.is_empty() {
+        return Ok(BTreeMap::new());
+    }
+    let rows = sqlx::query_as::<_, (i64, String, i64, Option<i64>)>(
+        "SELECT m.id, m.parent_sender, m.parent_sent_at, p.id \
+         FROM convo_messages m \
+         LEFT JOIN convo_messages p \
+           ON p.convo_id = m.convo_id \
+          AND p.sender_username = m.parent_sender \
+          AND p.sent_at = m.parent_sent_at \
+         WHERE m.id IN (SELECT value FROM json_each(?)) \
+           AND m.parent_sender IS NOT NULL AND m.parent_sent_at IS NOT NULL",
+    )
+    .bind(serde_json::to_string(message_ids)?)
+    .fetch_all(db)
+    .await?;
+    let mut out = BTreeMap::new();
+    for (message_id, sender, sent_at, parent_id) in rows {
+        out.insert(
+            message_id,
+            ReplyParent {
+                target: MessageRef {
+                    sender: UserName::parse(sender)?,
+                    sent_at: NanoTimestamp(sent_at as u64),
+                },
+                message_id: parent_id,
+            },
+        );
+    }
+    Ok(out)
+}
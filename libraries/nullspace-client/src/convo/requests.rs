@@ -0,0 +1,112 @@
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+
+use crate::database::DbNotify;
+
+/// Blocks a user: nothing they send us directly is stored anymore. A message request from them
+/// that we have not accepted is dropped along with its messages; an accepted convo is kept.
+pub async fn block_user(db: &sqlx::SqlitePool, username: &UserName) -> anyhow::Result<()> {
+    let mut tx = db.begin().await?;
+    sqlx::query("INSERT OR IGNORE INTO blocked_users (username, blocked_at) VALUES (?, ?)")
+        .bind(username.as_str())
+        .bind(NanoTimestamp::now().0 as i64)
+        .execute(tx.as_mut())
+        .await?;
+    sqlx::query(
+        "DELETE FROM convos \
+         WHERE convo_type = 'direct' AND convo_counterparty = ? AND is_request = 1",
+    )
+    .bind(username.as_str())
+    .execute(tx.as_mut())
+    .await?;
+    tx.commit().await?;
+    DbNotify::touch();
+    Ok(())
+}
+
+pub async fn unblock_user(db: &sqlx::SqlitePool, username: &UserName) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM blocked_users WHERE username = ?")
+        .bind(username.as_str())
+        .execute(db)
+        .await?;
+    DbNotify::touch();
+    Ok(())
+}
+
+pub async fn blocked_users(db: &sqlx::SqlitePool) -> anyhow::Result<Vec<UserName>> {
+    let rows = sqlx::query_scalar::<_, String>(
+        "SELECT username FROM blocked_users ORDER BY blocked_at DESC",
+    )
+    .fetch_all(db)
+    .await?;
+    rows.into_iter()
+        .map(|username| Ok(UserName::parse(username)?))
+        .collect()
+}
+
+/// Moves the direct convo with a user out of message requests and into the convo list.
+pub async fn accept_request(db: &sqlx::SqlitePool, peer: &UserName) -> anyhow::Result<()> {
+    let result = sqlx::query(
+        "UPDATE convos SET is_request = 0 \
+         WHERE convo_type = 'direct' AND convo_counterparty = ? AND is_request = 1",
+    )
+    .bind(peer.as_str())
+    .execute(db)
+    .await?;
+    if result.rows_affected() > 0 {
+        DbNotify::touch();
+    }
+    Ok(())
+}
+
+pub(super) async fn is_blocked(
+    conn: &mut sqlx::SqliteConnection,
+    username: &UserName,
+) -> anyhow::Result<bool> {
+    let row = sqlx::query_scalar::<_, i64>("SELECT 1 FROM blocked_users WHERE username = ?")
+        .bind(username.as_str())
+        .fetch_optional(&mut *conn)
+        .await?;
+    Ok(row.is_some())
+}
+
+/// Whether we have a direct convo with a user that is not a message request.
+pub(super) async fn is_accepted_direct_convo(
+    conn: &mut sqlx::SqliteConnection,
+    peer: &UserName,
+) -> anyhow::Result<bool> {
+    let row = sqlx::query_scalar::<_, i64>(
+        "SELECT 1 FROM convos \
+         WHERE convo_type = 'direct' AND convo_counterparty = ? AND is_request = 0",
+    )
+    .bind(peer.as_str())
+    .fetch_optional(&mut *conn)
+    .await?;
+    Ok(row.is_some())
+}
+
+/// Looks up the direct convo that an incoming message belongs to, creating it if needed. A convo
+/// that a peer starts, rather than one of our own devices, starts out as a message request.
+pub(super) async fn ensure_incoming_direct_convo(
+    conn: &mut sqlx::SqliteConnection,
+    peer: &UserName,
+    from_peer: bool,
+) -> anyhow::Result<i64> {
+    sqlx::query(
+        "INSERT INTO convos (convo_type, convo_counterparty, created_at, is_request) \
+         VALUES ('direct', ?, ?, ?) \
+         ON CONFLICT(convo_type, convo_counterparty) DO NOTHING",
+    )
+    .bind(peer.as_str())
+    .bind(NanoTimestamp::now().0 as i64)
+    .bind(from_peer)
+    .execute(&mut *conn)
+    .await?;
+    let id = sqlx::query_scalar::<_, i64>(
+        "SELECT id FROM convos WHERE convo_type = 'direct' AND convo_counterparty = ?",
+    )
+    .bind(peer.as_str())
+    .fetch_one(&mut *conn)
+    .await?;
+    Ok(id)
+}
@@ -1,9 +1,13 @@
 use anyhow::Context;
+use nullspace_structs::fragment::Attachment;
 use nullspace_structs::group::{GroupId, GroupManageMsg};
 use nullspace_structs::username::UserName;
 
 use crate::internal::GroupMemberStatus;
 
+pub(super) const MAX_GROUP_NAME_BYTES: usize = 128;
+pub(super) const MAX_GROUP_TOPIC_BYTES: usize = 1024;
+
 #[derive(Clone, Debug)]
 pub struct RosterMember {
     pub username: UserName,
@@ -201,6 +205,29 @@ impl GroupRoster {
                     false
                 }
             }
+            GroupManageMsg::SetName(name) => {
+                if !sender_admin || name.len() > MAX_GROUP_NAME_BYTES {
+                    false
+                } else {
+                    self.set_name(tx, &name).await?
+                }
+            }
+            GroupManageMsg::SetTopic(topic) => {
+                if !sender_admin || topic.len() > MAX_GROUP_TOPIC_BYTES {
+                    false
+                } else {
+                    self.set_topic(tx, &topic).await?
+                }
+            }
+            GroupManageMsg::SetAvatar(avatar) => {
+                if !sender_admin {
+                    false
+                } else {
+                    self.set_avatar(tx, avatar.as_ref()).await?
+                }
+            }
+            // a move changes where the group lives rather than who is in it
+            GroupManageMsg::Moved(_) => false,
         };
 
         if changed {
@@ -326,6 +353,49 @@ impl GroupRoster {
         Ok(result.rows_affected() > 0)
     }
 
+    async fn set_name(&self, tx: &mut sqlx::SqliteConnection, name: &str) -> anyhow::Result<bool> {
+        let name = Some(name).filter(|name| !name.is_empty());
+        let result = sqlx::query("UPDATE groups SET name = ? WHERE group_id = ? AND name IS NOT ?")
+            .bind(name)
+            .bind(self.group_id.as_bytes().to_vec())
+            .bind(name)
+            .execute(&mut *tx)
+            .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_topic(
+        &self,
+        tx: &mut sqlx::SqliteConnection,
+        topic: &str,
+    ) -> anyhow::Result<bool> {
+        let topic = Some(topic).filter(|topic| !topic.is_empty());
+        let result =
+            sqlx::query("UPDATE groups SET topic = ? WHERE group_id = ? AND topic IS NOT ?")
+                .bind(topic)
+                .bind(self.group_id.as_bytes().to_vec())
+                .bind(topic)
+                .execute(&mut *tx)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
+    async fn set_avatar(
+        &self,
+        tx: &mut sqlx::SqliteConnection,
+        avatar: Option<&Attachment>,
+    ) -> anyhow::Result<bool> {
+        let avatar = avatar.map(bcs::to_bytes).transpose()?;
+        let result =
+            sqlx::query("UPDATE groups SET avatar = ? WHERE group_id = ? AND avatar IS NOT ?")
+                .bind(avatar.clone())
+                .bind(self.group_id.as_bytes().to_vec())
+                .bind(avatar)
+                .execute(&mut *tx)
+                .await?;
+        Ok(result.rows_affected() > 0)
+    }
+
     async fn bump_version(&self, tx: &mut sqlx::SqliteConnection) -> anyhow::Result<()> {
         sqlx::query("UPDATE groups SET roster_version = roster_version + 1 WHERE group_id = ?")
             .bind(self.group_id.as_bytes().to_vec())
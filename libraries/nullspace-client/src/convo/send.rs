@@ -1,4 +1,4 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::time::Duration;
 
 use anyctx::AnyCtx;
@@ -6,13 +6,16 @@ use anyhow::Context;
 use bytes::Bytes;
 use nullspace_crypt::hash::{BcsHashExt, Hash};
 use nullspace_crypt::signing::Signable;
+use nullspace_dirclient::pow::solve_pow;
 use nullspace_structs::Blob;
 use nullspace_structs::certificate::CertificateChain;
 use nullspace_structs::e2ee::{DeviceSigned, HeaderEncrypted};
-use nullspace_structs::event::EventPayload;
+use nullspace_structs::event::{EventPayload, MessageTimer};
 use nullspace_structs::event::{Event, Recipient};
 use nullspace_structs::group::GroupMessage;
-use nullspace_structs::server::{AuthToken, MailboxId, SignedMediumPk};
+use nullspace_structs::server::{
+    AuthToken, MailboxId, ServerClient, ServerRpcError, SignedMediumPk,
+};
 use nullspace_structs::timestamp::NanoTimestamp;
 use nullspace_structs::username::UserName;
 use smol_str::SmolStr;
@@ -25,6 +28,9 @@ use crate::user_info::{UserInfo, get_user_info};
 use crate::{attachments::store_attachment_root, config::Config};
 
 use super::dm_common::own_server_name;
+use super::control::{apply_control_event, is_control_mime};
+use super::reply::reply_parent;
+use super::timer::{mailbox_ttl, message_expiry};
 use super::group::{load_group, send_to_group_mailbox};
 use super::{ConvoId, parse_convo_id};
 
@@ -38,10 +44,18 @@ pub async fn queue_message(
     let counterparty = convo_id.counterparty();
     let convo_id = ensure_convo_id(&mut *tx, convo_id.convo_type(), &counterparty).await?;
     let sent_at = NanoTimestamp::now();
+    let parent = reply_parent(mime, body);
+    // timer changes themselves must outlive the timer they replace
+    let expires_at = if mime == MessageTimer::mime() {
+        None
+    } else {
+        message_expiry(&mut *tx, convo_id, sent_at).await?
+    };
     let row = sqlx::query_as::<_, (i64,)>(
         "INSERT INTO convo_messages \
-         (convo_id, sender_username, mime, body, sent_at, received_at) \
-         VALUES (?, ?, ?, ?, ?, NULL) \
+         (convo_id, sender_username, mime, body, sent_at, received_at, \
+          parent_sender, parent_sent_at, expires_at) \
+         VALUES (?, ?, ?, ?, ?, NULL, ?, ?, ?) \
          RETURNING id",
     )
     .bind(convo_id)
@@ -49,6 +63,9 @@ pub async fn queue_message(
     .bind(mime.as_str())
     .bind(body.to_vec())
     .bind(sent_at.0 as i64)
+    .bind(parent.as_ref().map(|parent| parent.sender.as_str().to_string()))
+    .bind(parent.as_ref().map(|parent| parent.sent_at.0 as i64))
+    .bind(expires_at.map(|ts| ts.0 as i64))
     .fetch_one(&mut *tx)
     .await?;
     if mime == nullspace_structs::fragment::Attachment::mime()
@@ -86,19 +103,21 @@ async fn send_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
                 continue;
             }
         };
-        let ctx = ctx.clone();
-        match retry_backoff(async move || {
-            send_message(
-                ctx.clone(),
-                &convo_id,
-                &pending.mime,
-                &pending.body,
-                pending.sent_at,
-            )
-            .await
+        let send_ctx = ctx.clone();
+        let mime = pending.mime.clone();
+        let body = pending.body.clone();
+        let sent_at = pending.sent_at;
+        let expires_at = pending.expires_at;
+        let result = retry_backoff(async move || {
+            send_message(send_ctx.clone(), &convo_id, &mime, &body, sent_at, expires_at).await
         })
-        .await
-        {
+        .await;
+        if is_control_mime(&pending.mime) {
+            finish_control_message(ctx, &pending, result).await?;
+            DbNotify::touch();
+            continue;
+        }
+        match result {
             Ok(received_at) => {
                 let mut conn = db.acquire().await?;
                 mark_message_sent(&mut conn, pending.id, received_at).await?;
@@ -113,18 +132,56 @@ async fn send_loop_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     }
 }
 
+/// Control messages only live in the outbox: once sent, they are applied locally and removed.
+async fn finish_control_message(
+    ctx: &AnyCtx<Config>,
+    pending: &PendingMessage,
+    result: anyhow::Result<NanoTimestamp>,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let mut tx = db.begin().await?;
+    match result {
+        Ok(_) => {
+            apply_control_event(
+                &mut tx,
+                pending.convo_row_id,
+                &identity.username,
+                &pending.mime,
+                &pending.body,
+                pending.sent_at,
+            )
+            .await?;
+        }
+        Err(err) => {
+            tracing::warn!(error = %err, mime = %pending.mime, "failed to send control message");
+        }
+    }
+    sqlx::query("DELETE FROM convo_messages WHERE id = ?")
+        .bind(pending.id)
+        .execute(tx.as_mut())
+        .await?;
+    tx.commit().await?;
+    Ok(())
+}
+
 struct PendingMessage {
     id: i64,
+    convo_row_id: i64,
     convo_type: String,
     counterparty: String,
     mime: SmolStr,
     body: Bytes,
     sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
 }
 
+type PendingRow = (i64, i64, String, String, String, Vec<u8>, i64, Option<i64>);
+
 async fn next_pending_message(db: &sqlx::SqlitePool) -> anyhow::Result<Option<PendingMessage>> {
-    let row = sqlx::query_as::<_, (i64, String, String, String, Vec<u8>, i64)>(
-        "SELECT m.id, c.convo_type, c.convo_counterparty, m.mime, m.body, m.sent_at \
+    let row = sqlx::query_as::<_, PendingRow>(
+        "SELECT m.id, m.convo_id, c.convo_type, c.convo_counterparty, m.mime, m.body, m.sent_at, \
+                m.expires_at \
          FROM convo_messages m \
          JOIN convos c ON m.convo_id = c.id \
          WHERE m.received_at IS NULL AND m.send_error IS NULL \
@@ -133,16 +190,19 @@ async fn next_pending_message(db: &sqlx::SqlitePool) -> anyhow::Result<Option<Pe
     )
     .fetch_optional(db)
     .await?;
-    let Some((id, convo_type, counterparty, mime, body, sent_at)) = row else {
+    let Some((id, convo_row_id, convo_type, counterparty, mime, body, sent_at, expires_at)) = row
+    else {
         return Ok(None);
     };
     Ok(Some(PendingMessage {
         id,
+        convo_row_id,
         convo_type,
         counterparty,
         mime: SmolStr::new(mime),
         body: Bytes::from(body),
         sent_at: NanoTimestamp(sent_at as u64),
+        expires_at: expires_at.map(|ts| NanoTimestamp(ts as u64)),
     }))
 }
 
@@ -152,11 +212,13 @@ async fn send_message(
     mime: &SmolStr,
     body: &Bytes,
     sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
 ) -> anyhow::Result<NanoTimestamp> {
     match convo_id {
-        ConvoId::Direct { peer } => send_dm(&ctx, peer, mime, body, sent_at).await,
+        ConvoId::Direct { peer } => send_dm(&ctx, peer, mime, body, sent_at, expires_at).await,
         ConvoId::Group { group_id } => {
-            send_group_message(&ctx, *group_id, mime, body, sent_at).await
+            let ttl = mailbox_ttl(expires_at);
+            send_group_message(&ctx, *group_id, mime, body, sent_at, expires_at, ttl).await
         }
     }
 }
@@ -167,27 +229,83 @@ async fn send_dm(
     mime: &SmolStr,
     body: &Bytes,
     sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
 ) -> anyhow::Result<NanoTimestamp> {
     let db = ctx.get(DATABASE);
     let identity = Identity::load(db).await?;
+    let message = dm_content(peer, mime, body, sent_at, expires_at)?;
+
+    let ttl = mailbox_ttl(expires_at);
+    let peer_received_at = send_dm_once(ctx, &identity, peer, &message, ttl).await?;
+    let self_received_at = if identity.username != *peer {
+        send_dm_once(ctx, &identity, &identity.username, &message, ttl).await?
+    } else {
+        peer_received_at
+    };
+    Ok(self_received_at)
+}
+
+/// Sends an event that is never stored, straight to the convo mailbox and expiring after `ttl`
+/// seconds. Unlike queued messages, no copy is sent to our own mailbox.
+pub(super) async fn send_ephemeral(
+    ctx: &AnyCtx<Config>,
+    convo_id: &ConvoId,
+    mime: &SmolStr,
+    body: &Bytes,
+    ttl: u32,
+) -> anyhow::Result<()> {
+    let sent_at = NanoTimestamp::now();
+    match convo_id {
+        ConvoId::Direct { peer } => {
+            let identity = Identity::load(ctx.get(DATABASE)).await?;
+            let message = dm_content(peer, mime, body, sent_at, None)?;
+            send_dm_once(ctx, &identity, peer, &message, ttl).await?;
+        }
+        ConvoId::Group { group_id } => {
+            send_group_message(ctx, *group_id, mime, body, sent_at, None, ttl).await?;
+        }
+    }
+    Ok(())
+}
+
+/// Builds the event for a message, wrapping it with its expiry in disappearing convos.
+fn event_content(
+    recipient: Recipient,
+    mime: &SmolStr,
+    body: &Bytes,
+    sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
+) -> Event {
     let content = Event {
-        recipient: Recipient::User(peer.clone()),
+        recipient,
         sent_at,
         mime: mime.clone(),
         body: body.clone(),
     };
-    let message = Blob {
+    match expires_at {
+        Some(expires_at) => content.with_expiry(expires_at),
+        None => content,
+    }
+}
+
+fn dm_content(
+    peer: &UserName,
+    mime: &SmolStr,
+    body: &Bytes,
+    sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
+) -> anyhow::Result<Blob> {
+    let content = event_content(
+        Recipient::User(peer.clone()),
+        mime,
+        body,
+        sent_at,
+        expires_at,
+    );
+    Ok(Blob {
         kind: Blob::V1_MESSAGE_CONTENT.into(),
         inner: Bytes::from(bcs::to_bytes(&content)?),
-    };
-
-    let peer_received_at = send_dm_once(ctx, &identity, peer, &message).await?;
-    let self_received_at = if identity.username != *peer {
-        send_dm_once(ctx, &identity, &identity.username, &message).await?
-    } else {
-        peer_received_at
-    };
-    Ok(self_received_at)
+    })
 }
 
 async fn send_dm_once(
@@ -195,6 +313,7 @@ async fn send_dm_once(
     identity: &Identity,
     target: &UserName,
     message: &Blob,
+    ttl: u32,
 ) -> anyhow::Result<NanoTimestamp> {
     let peer = get_user_info(ctx, target).await?;
     let own_server = own_server_name(ctx, identity).await?;
@@ -218,9 +337,38 @@ async fn send_dm_once(
         kind: Blob::V1_DIRECT_MESSAGE.into(),
         inner: Bytes::from(bcs::to_bytes(&encrypted)?),
     };
-    let received_at = peer
+    let mailbox = MailboxId::direct(target);
+    let received_at = match peer
         .server
-        .v1_mailbox_send(auth, MailboxId::direct(target), message, 0)
+        .v1_mailbox_send(auth, mailbox, message.clone(), ttl)
+        .await?
+    {
+        Ok(received_at) => received_at,
+        Err(ServerRpcError::AccessDenied) if auth == AuthToken::anonymous() => {
+            send_dm_with_pow(&peer.server, mailbox, message, ttl).await?
+        }
+        Err(err) => anyhow::bail!(err.to_string()),
+    };
+    Ok(received_at)
+}
+
+/// Sends into a direct mailbox whose owner asks anonymous senders for a proof-of-work stamp.
+async fn send_dm_with_pow(
+    server: &ServerClient,
+    mailbox: MailboxId,
+    message: Blob,
+    ttl: u32,
+) -> anyhow::Result<NanoTimestamp> {
+    let Some(seed) = server
+        .v1_mailbox_pow_seed(mailbox)
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?
+    else {
+        anyhow::bail!(ServerRpcError::AccessDenied.to_string());
+    };
+    let pow = tokio::task::spawn_blocking(move || solve_pow(&seed)).await??;
+    let received_at = server
+        .v1_mailbox_send_pow(mailbox, message, ttl, pow)
         .await?
         .map_err(|err| anyhow::anyhow!(err.to_string()))?;
     Ok(received_at)
@@ -232,16 +380,19 @@ async fn send_group_message(
     mime: &SmolStr,
     body: &Bytes,
     sent_at: NanoTimestamp,
+    expires_at: Option<NanoTimestamp>,
+    ttl: u32,
 ) -> anyhow::Result<NanoTimestamp> {
     let db = ctx.get(DATABASE);
     let identity = Identity::load(db).await?;
     let group = load_group(db, group_id).await?.context("group not found")?;
-    let content = Event {
-        recipient: Recipient::Group(group.group_id),
+    let content = event_content(
+        Recipient::Group(group.group_id),
+        mime,
+        body,
         sent_at,
-        mime: mime.clone(),
-        body: body.clone(),
-    };
+        expires_at,
+    );
     let group_message = GroupMessage::encrypt_message(
         &content,
         identity.username.clone(),
@@ -259,6 +410,7 @@ async fn send_group_message(
         &group,
         MailboxId::group_messages(&group.group_id),
         blob,
+        ttl,
     )
     .await
 }
@@ -267,6 +419,7 @@ fn collect_recipients(
     username: &UserName,
     chains: &BTreeMap<Hash, CertificateChain>,
     medium_pks: &BTreeMap<Hash, SignedMediumPk>,
+    revoked: &BTreeSet<Hash>,
 ) -> anyhow::Result<Vec<nullspace_crypt::dh::DhPublic>> {
     let mut recipients = Vec::new();
     for (device_hash, chain) in chains {
@@ -281,6 +434,13 @@ fn collect_recipients(
             );
             continue;
         }
+        if chain
+            .iter()
+            .any(|cert| revoked.contains(&cert.pk.bcs_hash()))
+        {
+            tracing::debug!(username = %username, device_hash = %device_hash, "skipping revoked device");
+            continue;
+        }
         let Some(medium_pk) = medium_pks.get(device_hash) else {
             warn!(username = %username, device_hash = %device_hash, "missing medium-term key");
             continue;
@@ -298,7 +458,12 @@ fn collect_recipients(
 }
 
 fn recipients_from_peer(peer: &UserInfo) -> anyhow::Result<Vec<nullspace_crypt::dh::DhPublic>> {
-    collect_recipients(&peer.username, &peer.device_chains, &peer.medium_pks)
+    collect_recipients(
+        &peer.username,
+        &peer.device_chains,
+        &peer.medium_pks,
+        &peer.revoked,
+    )
 }
 
 async fn mark_message_sent(
@@ -0,0 +1,211 @@
+use std::collections::BTreeSet;
+use std::time::Duration;
+
+use anyctx::AnyCtx;
+use futures_concurrency::future::Race;
+use nullspace_crypt::hash::BcsHashExt;
+use nullspace_structs::event::{EventPayload, MessageTimer};
+use nullspace_structs::fragment::Attachment;
+use nullspace_structs::timestamp::NanoTimestamp;
+
+use crate::config::Config;
+use crate::database::{DATABASE, DbNotify, bump_convo_version, ensure_convo_id};
+use crate::identity::Identity;
+
+use super::ConvoId;
+use super::control::queue_control;
+
+/// Longest allowed timer, which keeps every expiry within a `u32` mailbox TTL.
+const MAX_TIMER_SECS: u32 = 365 * 24 * 60 * 60;
+
+/// How long the janitor sleeps when no message is about to expire.
+const JANITOR_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Turns disappearing messages on or off for a convo. The new timer applies to every message
+/// sent after this call, including by the other participants once they receive it.
+pub async fn set_message_timer(
+    ctx: &AnyCtx<Config>,
+    convo_id: &ConvoId,
+    seconds: Option<u32>,
+) -> anyhow::Result<()> {
+    let timer = MessageTimer { seconds };
+    validate_timer(&timer)?;
+    let db = ctx.get(DATABASE);
+    let identity = Identity::load(db).await?;
+    let mut conn = db.acquire().await?;
+    let convo_row_id =
+        ensure_convo_id(&mut *conn, convo_id.convo_type(), &convo_id.counterparty()).await?;
+    // applied right away, so that messages queued behind the timer already expire
+    if apply_message_timer(&mut conn, convo_row_id, timer.clone(), NanoTimestamp::now()).await? {
+        bump_convo_version(&mut *conn, convo_row_id).await?;
+    }
+    drop(conn);
+    queue_control(db, &identity, convo_id, &timer).await
+}
+
+fn validate_timer(timer: &MessageTimer) -> anyhow::Result<()> {
+    if timer
+        .seconds
+        .is_some_and(|seconds| seconds == 0 || seconds > MAX_TIMER_SECS)
+    {
+        anyhow::bail!("invalid message timer");
+    }
+    Ok(())
+}
+
+/// Records a timer change from any participant. The change with the latest `sent_at` wins.
+pub(super) async fn apply_message_timer(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    timer: MessageTimer,
+    sent_at: NanoTimestamp,
+) -> anyhow::Result<bool> {
+    validate_timer(&timer)?;
+    let result = sqlx::query(
+        "UPDATE convos SET message_timer = ?, message_timer_set_at = ? \
+         WHERE id = ? AND (message_timer_set_at IS NULL OR message_timer_set_at < ?)",
+    )
+    .bind(timer.seconds.map(i64::from))
+    .bind(sent_at.0 as i64)
+    .bind(convo_id)
+    .bind(sent_at.0 as i64)
+    .execute(&mut *conn)
+    .await?;
+    Ok(result.rows_affected() > 0)
+}
+
+/// Computes when a message sent now into the convo should expire.
+pub(super) async fn message_expiry(
+    conn: &mut sqlx::SqliteConnection,
+    convo_id: i64,
+    sent_at: NanoTimestamp,
+) -> anyhow::Result<Option<NanoTimestamp>> {
+    let seconds =
+        sqlx::query_scalar::<_, Option<i64>>("SELECT message_timer FROM convos WHERE id = ?")
+            .bind(convo_id)
+            .fetch_optional(&mut *conn)
+            .await?
+            .flatten();
+    Ok(seconds
+        .map(|seconds| NanoTimestamp(sent_at.0.saturating_add(seconds as u64 * 1_000_000_000))))
+}
+
+/// The mailbox TTL that makes the server drop a message at about the same time as clients do.
+pub(super) fn mailbox_ttl(expires_at: Option<NanoTimestamp>) -> u32 {
+    let Some(expires_at) = expires_at else {
+        return 0;
+    };
+    let remaining = expires_at.0.saturating_sub(NanoTimestamp::now().0);
+    remaining
+        .div_ceil(1_000_000_000)
+        .clamp(1, u64::from(u32::MAX)) as u32
+}
+
+pub(super) async fn janitor_loop(ctx: &AnyCtx<Config>) {
+    let mut notify = DbNotify::new();
+    loop {
+        let wait = match delete_expired(ctx).await {
+            Ok(Some(next)) => Duration::from_nanos(next.0.saturating_sub(NanoTimestamp::now().0))
+                .min(JANITOR_INTERVAL),
+            Ok(None) => JANITOR_INTERVAL,
+            Err(err) => {
+                tracing::warn!(error = %err, "failed to delete expired messages");
+                JANITOR_INTERVAL
+            }
+        };
+        (tokio::time::sleep(wait), notify.wait_for_change())
+            .race()
+            .await;
+    }
+}
+
+/// Deletes expired messages and their downloaded attachments, returning the next expiry.
+async fn delete_expired(ctx: &AnyCtx<Config>) -> anyhow::Result<Option<NanoTimestamp>> {
+    let db = ctx.get(DATABASE);
+    let now = NanoTimestamp::now().0 as i64;
+    let expired = sqlx::query_as::<_, (i64, String, Vec<u8>)>(
+        "SELECT convo_id, mime, body FROM convo_messages WHERE expires_at <= ?",
+    )
+    .bind(now)
+    .fetch_all(db)
+    .await?;
+    if !expired.is_empty() {
+        let mut convo_ids = BTreeSet::new();
+        let mut roots = Vec::new();
+        let mut tx = db.begin().await?;
+        for (convo_id, mime, body) in expired {
+            convo_ids.insert(convo_id);
+            if mime == Attachment::mime()
+                && let Ok(root) = serde_json::from_slice::<Attachment>(&body)
+            {
+                roots.push(root);
+            }
+        }
+        sqlx::query("DELETE FROM convo_messages WHERE expires_at <= ?")
+            .bind(now)
+            .execute(tx.as_mut())
+            .await?;
+        for root in roots {
+            delete_attachment(&mut tx, &root).await?;
+        }
+        for convo_id in convo_ids {
+            bump_convo_version(tx.as_mut(), convo_id).await?;
+        }
+        tx.commit().await?;
+        DbNotify::touch();
+    }
+    let next = sqlx::query_scalar::<_, Option<i64>>(
+        "SELECT MIN(expires_at) FROM convo_messages WHERE expires_at IS NOT NULL",
+    )
+    .fetch_one(db)
+    .await?;
+    Ok(next.map(|next| NanoTimestamp(next as u64)))
+}
+
+/// Deletes an attachment of an expired message, unless a remaining message still refers to it.
+async fn delete_attachment(
+    conn: &mut sqlx::SqliteConnection,
+    root: &Attachment,
+) -> anyhow::Result<()> {
+    let root_hash = root.bcs_hash();
+    let bodies = sqlx::query_scalar::<_, Vec<u8>>("SELECT body FROM convo_messages WHERE mime = ?")
+        .bind(Attachment::mime())
+        .fetch_all(&mut *conn)
+        .await?;
+    let still_referenced = bodies.iter().any(|body| {
+        serde_json::from_slice::<Attachment>(body).is_ok_and(|other| other.bcs_hash() == root_hash)
+    });
+    if still_referenced {
+        return Ok(());
+    }
+    let hash = root_hash.to_bytes().to_vec();
+    let path = sqlx::query_scalar::<_, String>(
+        "SELECT download_path FROM attachment_paths WHERE hash = ?",
+    )
+    .bind(&hash)
+    .fetch_optional(&mut *conn)
+    .await?;
+    if let Some(path) = path {
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {}
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                tracing::warn!(error = %err, path, "failed to delete expired attachment");
+            }
+        }
+    }
+    sqlx::query("DELETE FROM attachment_paths WHERE hash = ?")
+        .bind(&hash)
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM attachment_roots WHERE hash = ?")
+        .bind(&hash)
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
+}
+
+/// Whether an incoming event has already expired and should be dropped.
+pub(super) fn is_expired(expires_at: Option<NanoTimestamp>) -> bool {
+    expires_at.is_some_and(|expires_at| expires_at <= NanoTimestamp::now())
+}
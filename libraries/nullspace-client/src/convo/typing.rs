@@ -0,0 +1,69 @@
+use std::collections::HashMap;
+use std::sync::Mutex;
+use std::time::{Duration, Instant};
+
+use anyctx::AnyCtx;
+use bytes::Bytes;
+use nullspace_structs::event::{EventPayload, TypingIndicator};
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use smol_str::SmolStr;
+
+use crate::config::{Config, Ctx};
+use crate::events::emit_event;
+use crate::identity::Identity;
+use crate::internal::Event;
+
+use super::ConvoId;
+use super::send::send_ephemeral;
+
+/// How long a typing indicator stays in the mailbox, in seconds.
+const TYPING_TTL: u32 = 5;
+
+/// Minimum time between two typing indicators sent to the same convo.
+const TYPING_INTERVAL: Duration = Duration::from_secs(3);
+
+static LAST_TYPING: Ctx<Mutex<HashMap<ConvoId, Instant>>> = |_ctx| Mutex::new(HashMap::new());
+
+/// Tells the other participants that we are composing a message. Calls made more often than
+/// [`TYPING_INTERVAL`] are dropped, so frontends can call this on every keystroke.
+pub async fn send_typing(ctx: &AnyCtx<Config>, convo_id: &ConvoId) -> anyhow::Result<()> {
+    {
+        let mut last = ctx.get(LAST_TYPING).lock().unwrap();
+        let now = Instant::now();
+        if last
+            .get(convo_id)
+            .is_some_and(|at| now.duration_since(*at) < TYPING_INTERVAL)
+        {
+            return Ok(());
+        }
+        last.insert(convo_id.clone(), now);
+    }
+    let mime = SmolStr::new(TypingIndicator::mime());
+    let body = Bytes::from(serde_json::to_vec(&TypingIndicator {})?);
+    send_ephemeral(ctx, convo_id, &mime, &body, TYPING_TTL).await
+}
+
+/// Surfaces a received typing indicator to the frontend, unless it is our own or already stale.
+pub(super) fn receive_typing(
+    ctx: &AnyCtx<Config>,
+    identity: &Identity,
+    convo_id: ConvoId,
+    sender: UserName,
+    received_at: NanoTimestamp,
+) {
+    if sender == identity.username {
+        return;
+    }
+    let ttl_ns = u64::from(TYPING_TTL) * 1_000_000_000;
+    if NanoTimestamp::now().0.saturating_sub(received_at.0) > ttl_ns {
+        return;
+    }
+    emit_event(
+        ctx,
+        Event::Typing {
+            convo_id,
+            user: sender,
+        },
+    );
+}
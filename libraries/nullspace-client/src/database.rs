@@ -55,6 +55,8 @@ pub static DATABASE: Ctx<SqlitePool> = |ctx| {
         .busy_timeout(Duration::from_secs(1))
         .journal_mode(sqlx::sqlite::SqliteJournalMode::Wal)
         .foreign_keys(true)
+        // retired medium-term secrets must not linger in free pages once deleted
+        .pragma("secure_delete", "on")
         .synchronous(sqlx::sqlite::SqliteSynchronous::Normal);
     pollster::block_on(async {
         let pool = SqlitePoolOptions::new()
@@ -107,6 +109,7 @@ async fn message_event_loop(ctx: &AnyCtx<Config>) {
     let mut last_seen_id = current_max_msg(db).await.unwrap_or(0);
     let mut last_seen_received_at = current_max_received_at(db).await.unwrap_or(0);
     let mut group_versions = load_group_versions(db).await.unwrap_or_default();
+    let mut convo_versions = load_convo_versions(db).await.unwrap_or_default();
     loop {
         notify.wait_for_change().await;
         let (new_last, mut convos) = match new_message_convos(db, last_seen_id).await {
@@ -127,6 +130,16 @@ async fn message_event_loop(ctx: &AnyCtx<Config>) {
             };
         last_seen_received_at = new_received_at;
         convos.extend(received_convos);
+        let (next_convo_versions, changed_convos) =
+            match updated_convo_versions(db, &convo_versions).await {
+                Ok(result) => result,
+                Err(err) => {
+                    tracing::warn!(error = %err, "failed to query convo version updates");
+                    continue;
+                }
+            };
+        convo_versions = next_convo_versions;
+        convos.extend(changed_convos);
         for convo_id in convos {
             emit_event(ctx, Event::ConvoUpdated { convo_id });
         }
@@ -257,6 +270,53 @@ async fn updated_group_versions(
     Ok((current, updated))
 }
 
+async fn load_convo_versions(
+    db: &sqlx::SqlitePool,
+) -> anyhow::Result<HashMap<crate::convo::ConvoId, i64>> {
+    let rows =
+        sqlx::query_as::<_, (String, String, i64)>(
+            "SELECT convo_type, convo_counterparty, version FROM convos",
+        )
+        .fetch_all(db)
+        .await?;
+    let mut out = HashMap::new();
+    for (convo_type, counterparty, version) in rows {
+        if let Some(convo_id) = parse_convo_id(&convo_type, &counterparty) {
+            out.insert(convo_id, version);
+        }
+    }
+    Ok(out)
+}
+
+async fn updated_convo_versions(
+    db: &sqlx::SqlitePool,
+    known: &HashMap<crate::convo::ConvoId, i64>,
+) -> anyhow::Result<(HashMap<crate::convo::ConvoId, i64>, Vec<crate::convo::ConvoId>)> {
+    let current = load_convo_versions(db).await?;
+    let mut updated = Vec::new();
+    for (convo_id, version) in &current {
+        match known.get(convo_id) {
+            Some(prev) if *prev >= *version => {}
+            // new convos are already reported through their messages
+            None if *version == 0 => {}
+            _ => updated.push(convo_id.clone()),
+        }
+    }
+    Ok((current, updated))
+}
+
+/// Marks a convo as changed in place, e.g. when a message is edited or deleted.
+pub async fn bump_convo_version<'e, E>(exec: E, convo_id: i64) -> anyhow::Result<()>
+where
+    E: Executor<'e, Database = Sqlite>,
+{
+    sqlx::query("UPDATE convos SET version = version + 1 WHERE id = ?")
+        .bind(convo_id)
+        .execute(exec)
+        .await?;
+    Ok(())
+}
+
 pub async fn ensure_mailbox_state<'e, E>(
     exec: E,
     server_name: &nullspace_structs::server::ServerName,
@@ -13,19 +13,18 @@ pub struct Identity {
     pub device_secret: DeviceSecret,
     pub cert_chain: CertificateChain,
     pub medium_sk_current: DhSecret,
-    pub medium_sk_prev: DhSecret,
 }
 
 impl Identity {
     pub async fn load(db: &SqlitePool) -> anyhow::Result<Self> {
         let row =
-            sqlx::query_as::<_, (String, Option<String>, Vec<u8>, Vec<u8>, Vec<u8>, Vec<u8>)>(
-                "SELECT username, server_name, device_secret, cert_chain, medium_sk_current, medium_sk_prev \
+            sqlx::query_as::<_, (String, Option<String>, Vec<u8>, Vec<u8>, Vec<u8>)>(
+                "SELECT username, server_name, device_secret, cert_chain, medium_sk_current \
                  FROM client_identity WHERE id = 1",
             )
             .fetch_optional(db)
             .await?;
-        let Some((username, server_name, device_secret, cert_chain, medium_sk_current, medium_sk_prev)) = row else {
+        let Some((username, server_name, device_secret, cert_chain, medium_sk_current)) = row else {
             anyhow::bail!("client identity not initialized");
         };
         let username = UserName::parse(username).context("invalid stored username")?;
@@ -36,14 +35,12 @@ impl Identity {
         let device_secret: DeviceSecret = bcs::from_bytes(&device_secret)?;
         let cert_chain: CertificateChain = bcs::from_bytes(&cert_chain)?;
         let medium_sk_current: DhSecret = bcs::from_bytes(&medium_sk_current)?;
-        let medium_sk_prev: DhSecret = bcs::from_bytes(&medium_sk_prev)?;
         Ok(Self {
             username,
             server_name,
             device_secret,
             cert_chain,
             medium_sk_current,
-            medium_sk_prev,
         })
     }
 
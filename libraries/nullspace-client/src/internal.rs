@@ -6,12 +6,14 @@ use nanorpc::nanorpc_derive;
 use nullspace_crypt::dh::DhSecret;
 use nullspace_crypt::hash::{BcsHashExt, Hash};
 use nullspace_crypt::signing::{Signable, Signature};
-use nullspace_structs::certificate::{CertificateChain, DeviceSecret};
-use nullspace_structs::event::EventPayload;
+use nullspace_structs::certificate::{CertificateChain, DeviceRevocation, DeviceSecret};
+use nullspace_structs::event::{EventPayload, MessageReply};
 use nullspace_structs::fragment::Attachment;
-use nullspace_structs::group::{GroupId, GroupInviteMsg};
+use nullspace_structs::group::{GroupId, GroupInviteMsg, GroupManageMsg};
 use nullspace_structs::profile::UserProfile;
-use nullspace_structs::server::{AuthToken, ServerClient, ServerName, SignedMediumPk};
+use nullspace_structs::server::{
+    AuthToken, MailboxId, ServerClient, ServerName, ServerRpcError, SignedMediumPk,
+};
 use nullspace_structs::timestamp::{NanoTimestamp, Timestamp};
 use nullspace_structs::username::{UserDescriptor, UserName};
 use serde::{Deserialize, Serialize};
@@ -24,18 +26,35 @@ use std::sync::Arc;
 use thiserror::Error;
 use tokio::sync::Mutex;
 
+use crate::account_migration::{self, MigrationStatus};
 use crate::attachments::{self, AttachmentStatus, store_attachment_root};
+use crate::auth_tokens::get_auth_token;
 use crate::config::Config;
-pub use crate::convo::{ConvoId, ConvoMessage, ConvoSummary, MessageContent, OutgoingMessage};
+pub use crate::account_migration::MigrationStep;
+pub use crate::convo::{
+    ConvoId, ConvoMessage, ConvoSummary, GroupMetadata, MessageContent, OutgoingMessage,
+    ReactionSummary, ReceiptState,
+};
+pub use crate::provision::ProvisionCode;
+pub use crate::safety::SafetyNumber;
+pub use crate::settings::Settings;
 use crate::convo::{
-    GroupRoster, accept_invite, create_group, invite, load_group, parse_convo_id, queue_message,
+    GroupRoster, accept_invite, accept_request, ban_member, block_user, blocked_users,
+    control_mimes, create_group, delete_message, edit_message, invite, is_control_mime,
+    leave_group, load_group, load_group_metadata, load_reactions, load_receipts,
+    load_reply_parents, mark_read, move_group, parse_convo_id, queue_message, react_message,
+    reply_payload, send_typing, set_group_metadata, set_member_admin, set_message_timer,
+    unban_member, unblock_user,
 };
 use crate::database::{DATABASE, DbNotify, identity_exists};
 use crate::directory::DIR_CLIENT;
 use crate::identity::Identity;
 use crate::profile::get_profile;
+use crate::provision;
+use crate::safety::{is_contact_verified, set_contact_verified};
 use crate::server::get_server_client;
-use crate::user_info::get_user_info;
+use crate::settings::{load_settings, store_settings};
+use crate::user_info::{get_user_info, get_user_root_hash};
 
 /// The internal JSON-RPC interface exposed by nullspace-client.
 #[nanorpc_derive]
@@ -52,7 +71,30 @@ pub trait InternalProtocol {
         can_issue: bool,
         expiry: Timestamp,
     ) -> Result<NewDeviceBundle, InternalRpcError>;
+    async fn device_revoke(&self, device_hash: Hash) -> Result<(), InternalRpcError>;
+    async fn provision_start(&self, username: UserName) -> Result<ProvisionCode, InternalRpcError>;
+    async fn provision_recv(
+        &self,
+        username: UserName,
+        code: ProvisionCode,
+    ) -> Result<NewDeviceBundle, InternalRpcError>;
+    async fn provision_send(
+        &self,
+        code: ProvisionCode,
+        can_issue: bool,
+        expiry: Timestamp,
+    ) -> Result<(), InternalRpcError>;
+    async fn account_migrate(
+        &self,
+        server: ServerName,
+        invite_code: Option<SmolStr>,
+    ) -> Result<(), InternalRpcError>;
+    async fn account_migration_status(
+        &self,
+    ) -> Result<Option<MigrationStatus>, InternalRpcError>;
     async fn convo_list(&self) -> Result<Vec<ConvoSummary>, InternalRpcError>;
+    async fn request_list(&self) -> Result<Vec<ConvoSummary>, InternalRpcError>;
+    async fn request_accept(&self, username: UserName) -> Result<(), InternalRpcError>;
     async fn convo_history(
         &self,
         convo_id: ConvoId,
@@ -60,11 +102,35 @@ pub trait InternalProtocol {
         after: Option<i64>,
         limit: u16,
     ) -> Result<Vec<ConvoMessage>, InternalRpcError>;
+    async fn convo_thread(
+        &self,
+        convo_id: ConvoId,
+        root: i64,
+    ) -> Result<Vec<ConvoMessage>, InternalRpcError>;
     async fn convo_send(
         &self,
         convo_id: ConvoId,
         message: OutgoingMessage,
     ) -> Result<i64, InternalRpcError>;
+    async fn convo_edit(&self, message_id: i64, text: String) -> Result<(), InternalRpcError>;
+    async fn convo_delete(&self, message_id: i64) -> Result<(), InternalRpcError>;
+    async fn convo_react(
+        &self,
+        message_id: i64,
+        emoji: SmolStr,
+        add: bool,
+    ) -> Result<(), InternalRpcError>;
+    async fn convo_mark_read(
+        &self,
+        convo_id: ConvoId,
+        message_id: i64,
+    ) -> Result<(), InternalRpcError>;
+    async fn convo_typing(&self, convo_id: ConvoId) -> Result<(), InternalRpcError>;
+    async fn convo_set_timer(
+        &self,
+        convo_id: ConvoId,
+        seconds: Option<u32>,
+    ) -> Result<(), InternalRpcError>;
     async fn convo_create_group(&self, server: ServerName) -> Result<ConvoId, InternalRpcError>;
     async fn own_server(&self) -> Result<ServerName, InternalRpcError>;
     async fn group_invite(
@@ -74,6 +140,35 @@ pub trait InternalProtocol {
     ) -> Result<(), InternalRpcError>;
     async fn group_members(&self, group: GroupId) -> Result<Vec<GroupMember>, InternalRpcError>;
     async fn group_accept_invite(&self, dm_id: i64) -> Result<GroupId, InternalRpcError>;
+    async fn group_set_name(&self, group: GroupId, name: String) -> Result<(), InternalRpcError>;
+    async fn group_set_topic(
+        &self,
+        group: GroupId,
+        topic: String,
+    ) -> Result<(), InternalRpcError>;
+    async fn group_set_avatar(
+        &self,
+        group: GroupId,
+        avatar: Option<Attachment>,
+    ) -> Result<(), InternalRpcError>;
+    async fn group_ban(&self, group: GroupId, username: UserName) -> Result<(), InternalRpcError>;
+    async fn group_unban(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError>;
+    async fn group_promote(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError>;
+    async fn group_demote(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError>;
+    async fn group_leave(&self, group: GroupId) -> Result<(), InternalRpcError>;
+    async fn group_move(&self, group: GroupId, server: ServerName) -> Result<(), InternalRpcError>;
 
     async fn attachment_upload(
         &self,
@@ -105,10 +200,25 @@ pub trait InternalProtocol {
         avatar: Option<Attachment>,
     ) -> Result<(), InternalRpcError>;
 
+    async fn own_settings(&self) -> Result<Settings, InternalRpcError>;
+
+    async fn own_settings_set(&self, settings: Settings) -> Result<(), InternalRpcError>;
+
+    async fn own_dm_pow_set(&self, effort: Option<u64>) -> Result<(), InternalRpcError>;
+
     async fn user_details(
         &self,
         username: UserName,
     ) -> Result<UserDetails, InternalRpcError>;
+    async fn user_set_verified(
+        &self,
+        username: UserName,
+        verified: bool,
+    ) -> Result<(), InternalRpcError>;
+
+    async fn contact_block(&self, username: UserName) -> Result<(), InternalRpcError>;
+    async fn contact_unblock(&self, username: UserName) -> Result<(), InternalRpcError>;
+    async fn contact_blocked_list(&self) -> Result<Vec<UserName>, InternalRpcError>;
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -122,6 +232,10 @@ pub enum Event {
     GroupUpdated {
         group: GroupId,
     },
+    Typing {
+        convo_id: ConvoId,
+        user: UserName,
+    },
     UploadProgress {
         id: i64,
         uploaded_size: u64,
@@ -148,6 +262,10 @@ pub enum Event {
         attachment_id: Hash,
         error: String,
     },
+    /// The root key of a contact we had verified changed, so the verification was cleared.
+    VerifiedKeyChanged {
+        username: UserName,
+    },
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -161,6 +279,9 @@ pub enum RegisterFinish {
     BootstrapNewUser {
         username: UserName,
         server_name: ServerName,
+        /// Needed by servers that only admit new usernames by invite.
+        #[serde(default)]
+        invite_code: Option<SmolStr>,
     },
     AddDevice {
         bundle: NewDeviceBundle,
@@ -196,6 +317,10 @@ pub struct UserDetails {
     pub server_name: Option<ServerName>,
     pub common_groups: Vec<GroupId>,
     pub last_dm_message: Option<UserLastMessageSummary>,
+    pub safety_number: SafetyNumber,
+    /// Whether we verified the safety number with this user, and their root key has not changed
+    /// since.
+    pub verified: bool,
 }
 
 #[derive(Clone, Debug, Serialize, Deserialize)]
@@ -277,7 +402,8 @@ impl InternalProtocol for InternalImpl {
             RegisterFinish::BootstrapNewUser {
                 username,
                 server_name,
-            } => register_bootstrap(self.ctx.clone(), username, server_name).await,
+                invite_code,
+            } => register_bootstrap(self.ctx.clone(), username, server_name, invite_code).await,
             RegisterFinish::AddDevice { bundle } => {
                 register_add_device(self.ctx.clone(), bundle).await
             }
@@ -317,9 +443,116 @@ impl InternalProtocol for InternalImpl {
         Ok(NewDeviceBundle(Bytes::from(encoded)))
     }
 
+    async fn device_revoke(&self, device_hash: Hash) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if !identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        let identity = Identity::load(db).await.map_err(internal_err)?;
+        let issuer_cert = identity.cert_chain.last_device();
+        let issuer_can_issue =
+            issuer_cert.pk == identity.device_secret.public() && issuer_cert.can_issue;
+        if !issuer_can_issue {
+            return Err(InternalRpcError::AccessDenied);
+        }
+        let Some(server_name) = identity.server_name.clone() else {
+            return Err(InternalRpcError::Other("server name not available".into()));
+        };
+        let server = server_from_name(&self.ctx, &server_name).await?;
+        let revocation =
+            DeviceRevocation::new(device_hash, identity.cert_chain, &identity.device_secret);
+        server
+            .v1_device_revoke(identity.username, revocation)
+            .await
+            .map_err(internal_err)?
+            .map_err(|err| InternalRpcError::Other(err.to_string()))?;
+        Ok(())
+    }
+
+    async fn provision_start(&self, username: UserName) -> Result<ProvisionCode, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        provision::provision_start(&self.ctx, &username)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn provision_recv(
+        &self,
+        username: UserName,
+        code: ProvisionCode,
+    ) -> Result<NewDeviceBundle, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        let raw = provision::provision_recv(&self.ctx, &username, &code)
+            .await
+            .map_err(map_anyhow_err)?;
+        let bundle: BundleInner = bcs::from_bytes(&raw).map_err(internal_err)?;
+        if bundle.username != username {
+            return Err(InternalRpcError::Other(
+                "device bundle is for a different username".into(),
+            ));
+        }
+        Ok(NewDeviceBundle(raw))
+    }
+
+    async fn provision_send(
+        &self,
+        code: ProvisionCode,
+        can_issue: bool,
+        expiry: Timestamp,
+    ) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if !identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        let identity = Identity::load(db).await.map_err(internal_err)?;
+        let bundle = issue_device_bundle(&identity, can_issue, expiry)?;
+        provision::provision_send(&self.ctx, &code, &bundle.0)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn account_migrate(
+        &self,
+        server: ServerName,
+        invite_code: Option<SmolStr>,
+    ) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if !identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        account_migration::start_migration(&self.ctx, server, invite_code)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn account_migration_status(
+        &self,
+    ) -> Result<Option<MigrationStatus>, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        account_migration::migration_status(db)
+            .await
+            .map_err(internal_err)
+    }
+
     async fn convo_list(&self) -> Result<Vec<ConvoSummary>, InternalRpcError> {
         let db = self.ctx.get(DATABASE);
-        convo_list(db).await.map_err(internal_err)
+        convo_list(db, false).await.map_err(internal_err)
+    }
+
+    async fn request_list(&self) -> Result<Vec<ConvoSummary>, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        convo_list(db, true).await.map_err(internal_err)
+    }
+
+    async fn request_accept(&self, username: UserName) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        accept_request(db, &username).await.map_err(internal_err)
     }
 
     async fn convo_history(
@@ -335,6 +568,17 @@ impl InternalProtocol for InternalImpl {
             .map_err(internal_err)
     }
 
+    async fn convo_thread(
+        &self,
+        convo_id: ConvoId,
+        root: i64,
+    ) -> Result<Vec<ConvoMessage>, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        convo_thread(db, convo_id, root)
+            .await
+            .map_err(internal_err)
+    }
+
     async fn convo_send(
         &self,
         convo_id: ConvoId,
@@ -350,7 +594,14 @@ impl InternalProtocol for InternalImpl {
                 SmolStr::new(Attachment::mime()),
                 Bytes::from(serde_json::to_vec(&root).map_err(internal_err)?),
             ),
+            OutgoingMessage::Reply { parent, text } => reply_payload(db, &convo_id, parent, text)
+                .await
+                .map_err(map_anyhow_err)?,
         };
+        // replying to a message request accepts it
+        if let ConvoId::Direct { peer } = &convo_id {
+            accept_request(db, peer).await.map_err(internal_err)?;
+        }
         let mut conn = db.acquire().await.map_err(internal_err)?;
         let id = queue_message(&mut conn, &convo_id, &identity.username, &mime, &body)
             .await
@@ -359,6 +610,55 @@ impl InternalProtocol for InternalImpl {
         Ok(id)
     }
 
+    async fn convo_edit(&self, message_id: i64, text: String) -> Result<(), InternalRpcError> {
+        edit_message(&self.ctx, message_id, text)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn convo_delete(&self, message_id: i64) -> Result<(), InternalRpcError> {
+        delete_message(&self.ctx, message_id)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn convo_react(
+        &self,
+        message_id: i64,
+        emoji: SmolStr,
+        add: bool,
+    ) -> Result<(), InternalRpcError> {
+        react_message(&self.ctx, message_id, emoji, add)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn convo_mark_read(
+        &self,
+        convo_id: ConvoId,
+        message_id: i64,
+    ) -> Result<(), InternalRpcError> {
+        mark_read(&self.ctx, &convo_id, message_id)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn convo_typing(&self, convo_id: ConvoId) -> Result<(), InternalRpcError> {
+        send_typing(&self.ctx, &convo_id)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn convo_set_timer(
+        &self,
+        convo_id: ConvoId,
+        seconds: Option<u32>,
+    ) -> Result<(), InternalRpcError> {
+        set_message_timer(&self.ctx, &convo_id, seconds)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
     async fn convo_create_group(&self, server: ServerName) -> Result<ConvoId, InternalRpcError> {
         let db = self.ctx.get(DATABASE);
         if !identity_exists(db).await.map_err(internal_err)? {
@@ -439,6 +739,78 @@ impl InternalProtocol for InternalImpl {
         result.map_err(internal_err)
     }
 
+    async fn group_set_name(&self, group: GroupId, name: String) -> Result<(), InternalRpcError> {
+        set_group_metadata(&self.ctx, group, GroupManageMsg::SetName(name))
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_set_topic(
+        &self,
+        group: GroupId,
+        topic: String,
+    ) -> Result<(), InternalRpcError> {
+        set_group_metadata(&self.ctx, group, GroupManageMsg::SetTopic(topic))
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_set_avatar(
+        &self,
+        group: GroupId,
+        avatar: Option<Attachment>,
+    ) -> Result<(), InternalRpcError> {
+        set_group_metadata(&self.ctx, group, GroupManageMsg::SetAvatar(avatar))
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_ban(&self, group: GroupId, username: UserName) -> Result<(), InternalRpcError> {
+        ban_member(&self.ctx, group, username)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_unban(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError> {
+        unban_member(&self.ctx, group, username)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_promote(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError> {
+        set_member_admin(&self.ctx, group, username, true)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_demote(
+        &self,
+        group: GroupId,
+        username: UserName,
+    ) -> Result<(), InternalRpcError> {
+        set_member_admin(&self.ctx, group, username, false)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
+    async fn group_leave(&self, group: GroupId) -> Result<(), InternalRpcError> {
+        leave_group(&self.ctx, group).await.map_err(map_anyhow_err)
+    }
+
+    async fn group_move(&self, group: GroupId, server: ServerName) -> Result<(), InternalRpcError> {
+        move_group(&self.ctx, group, server)
+            .await
+            .map_err(map_anyhow_err)
+    }
+
     async fn attachment_upload(
         &self,
         absolute_path: PathBuf,
@@ -502,6 +874,17 @@ impl InternalProtocol for InternalImpl {
         let user_info = get_user_info(&self.ctx, &username)
             .await
             .map_err(map_anyhow_err)?;
+        let own_root = get_user_root_hash(&self.ctx, &identity.username)
+            .await
+            .map_err(map_anyhow_err)?;
+        let peer_root = get_user_root_hash(&self.ctx, &username)
+            .await
+            .map_err(map_anyhow_err)?;
+        let safety_number =
+            SafetyNumber::new((&identity.username, own_root), (&username, peer_root));
+        let verified = is_contact_verified(db, &username, peer_root)
+            .await
+            .map_err(internal_err)?;
 
         let (display_name, avatar) = match profile {
             Some(profile) => (profile.display_name, profile.avatar),
@@ -522,15 +905,56 @@ impl InternalProtocol for InternalImpl {
             server_name: Some(user_info.server_name.clone()),
             common_groups,
             last_dm_message,
+            safety_number,
+            verified,
         })
     }
 
+    async fn user_set_verified(
+        &self,
+        username: UserName,
+        verified: bool,
+    ) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        let root_hash = get_user_root_hash(&self.ctx, &username)
+            .await
+            .map_err(map_anyhow_err)?;
+        set_contact_verified(db, &username, root_hash, verified)
+            .await
+            .map_err(internal_err)
+    }
+
+    async fn contact_block(&self, username: UserName) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        block_user(db, &username).await.map_err(internal_err)
+    }
+
+    async fn contact_unblock(&self, username: UserName) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        unblock_user(db, &username).await.map_err(internal_err)
+    }
+
+    async fn contact_blocked_list(&self) -> Result<Vec<UserName>, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        blocked_users(db).await.map_err(internal_err)
+    }
+
     async fn own_username(&self) -> Result<UserName, InternalRpcError> {
         let db = self.ctx.get(DATABASE);
         let identity = Identity::load(db).await.map_err(internal_err)?;
         Ok(identity.username)
     }
 
+    async fn own_settings(&self) -> Result<Settings, InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        load_settings(db).await.map_err(internal_err)
+    }
+
+    async fn own_settings_set(&self, settings: Settings) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        store_settings(db, &settings).await.map_err(internal_err)
+    }
+
     async fn own_profile_set(
         &self,
         display_name: Option<String>,
@@ -562,12 +986,35 @@ impl InternalProtocol for InternalImpl {
             .map_err(|err| InternalRpcError::Other(err.to_string()))?;
         Ok(())
     }
+
+    async fn own_dm_pow_set(&self, effort: Option<u64>) -> Result<(), InternalRpcError> {
+        let db = self.ctx.get(DATABASE);
+        if !identity_exists(db).await.map_err(internal_err)? {
+            return Err(InternalRpcError::NotReady);
+        }
+        let identity = Identity::load(db).await.map_err(internal_err)?;
+        let Some(server_name) = identity.server_name.clone() else {
+            return Err(InternalRpcError::Other("server name not available".into()));
+        };
+        let server = server_from_name(&self.ctx, &server_name).await?;
+        let auth = get_auth_token(&self.ctx).await.map_err(internal_err)?;
+        server
+            .v1_mailbox_pow_set(auth, MailboxId::direct(&identity.username), effort)
+            .await
+            .map_err(internal_err)?
+            .map_err(|err| match err {
+                ServerRpcError::AccessDenied => InternalRpcError::AccessDenied,
+                err => InternalRpcError::Other(err.to_string()),
+            })?;
+        Ok(())
+    }
 }
 
 async fn register_bootstrap(
     ctx: AnyCtx<Config>,
     username: UserName,
     server_name: ServerName,
+    invite_code: Option<SmolStr>,
 ) -> Result<(), InternalRpcError> {
     let dir = ctx.get(DIR_CLIENT);
     if dir
@@ -579,6 +1026,17 @@ async fn register_bootstrap(
         return Err(InternalRpcError::Other("username already exists".into()));
     }
     let server = server_from_name(&ctx, &server_name).await?;
+    // checked before claiming the username, which cannot be undone
+    let admitted = server
+        .v1_registration_check(username.clone(), invite_code.clone())
+        .await
+        .map_err(internal_err)?
+        .map_err(|err| InternalRpcError::Other(err.to_string()))?;
+    if !admitted {
+        return Err(InternalRpcError::Other(format!(
+            "{server_name} does not admit {username}; it may need an invite code"
+        )));
+    }
     let device_secret = DeviceSecret::random();
     let root_cert = device_secret.self_signed(Timestamp(u64::MAX), true);
     let cert_chain = CertificateChain {
@@ -599,11 +1057,20 @@ async fn register_bootstrap(
     dir.insert_user_descriptor(&username, &user_descriptor, &device_secret)
         .await
         .map_err(internal_err)?;
-    let auth = server
-        .v1_device_auth(username.clone(), cert_chain.clone())
-        .await
-        .map_err(internal_err)?
-        .map_err(|err| InternalRpcError::Other(err.to_string()))?;
+    let auth = match invite_code {
+        Some(invite_code) => {
+            server
+                .v1_device_auth_invite(username.clone(), cert_chain.clone(), invite_code)
+                .await
+        }
+        None => {
+            server
+                .v1_device_auth(username.clone(), cert_chain.clone())
+                .await
+        }
+    }
+    .map_err(internal_err)?
+    .map_err(|err| InternalRpcError::Other(err.to_string()))?;
     let medium_sk = register_medium_key(&server, auth, &device_secret).await?;
 
     persist_identity(
@@ -654,6 +1121,37 @@ async fn register_add_device(
     Ok(())
 }
 
+fn issue_device_bundle(
+    identity: &Identity,
+    can_issue: bool,
+    expiry: Timestamp,
+) -> Result<NewDeviceBundle, InternalRpcError> {
+    let issuer_cert = identity.cert_chain.last_device();
+    let issuer_can_issue =
+        issuer_cert.pk == identity.device_secret.public() && issuer_cert.can_issue;
+    if !issuer_can_issue {
+        return Err(InternalRpcError::AccessDenied);
+    }
+    let new_secret = DeviceSecret::random();
+    let cert =
+        identity
+            .device_secret
+            .issue_certificate(&new_secret.public(), expiry, can_issue);
+    let mut ancestors = identity.cert_chain.ancestors.clone();
+    ancestors.push(identity.cert_chain.this.clone());
+    let chain = CertificateChain {
+        ancestors,
+        this: cert,
+    };
+    let bundle = BundleInner {
+        username: identity.username.clone(),
+        device_secret: new_secret,
+        cert_chain: chain,
+    };
+    let encoded = bcs::to_bytes(&bundle).map_err(internal_err)?;
+    Ok(NewDeviceBundle(Bytes::from(encoded)))
+}
+
 async fn server_from_name(
     ctx: &AnyCtx<Config>,
     server_name: &ServerName,
@@ -700,15 +1198,14 @@ async fn persist_identity(
 ) -> Result<(), InternalRpcError> {
     sqlx::query(
         "INSERT INTO client_identity \
-         (id, username, server_name, device_secret, cert_chain, medium_sk_current, medium_sk_prev) \
-         VALUES (1, ?, ?, ?, ?, ?, ?)",
+         (id, username, server_name, device_secret, cert_chain, medium_sk_current) \
+         VALUES (1, ?, ?, ?, ?, ?)",
     )
     .bind(username.as_str())
     .bind(server_name.as_str())
     .bind(bcs::to_bytes(&device_secret).map_err(internal_err)?)
     .bind(bcs::to_bytes(&cert_chain).map_err(internal_err)?)
     .bind(bcs::to_bytes(&medium_sk).map_err(internal_err)?)
-    .bind(bcs::to_bytes(&medium_sk).map_err(internal_err)?)
     .execute(db)
     .await
     .map_err(internal_err)?;
@@ -734,68 +1231,117 @@ fn map_anyhow_err(err: anyhow::Error) -> InternalRpcError {
     }
 }
 
-async fn convo_list(db: &sqlx::SqlitePool) -> anyhow::Result<Vec<ConvoSummary>> {
-    let rows = sqlx::query_as::<
+async fn convo_list(db: &sqlx::SqlitePool, requests: bool) -> anyhow::Result<Vec<ConvoSummary>> {
+    // queued control rows are not messages, so they must not count as the last message
+    let control_mimes = control_mimes();
+    let placeholders = vec!["?"; control_mimes.len()].join(", ");
+    let query = format!(
+        "SELECT c.convo_type, c.convo_counterparty, c.created_at, c.message_timer, \
+                m.id, m.sender_username, m.mime, m.body, m.received_at, m.send_error, \
+                m.edited_at, m.expires_at, m.sent_at \
+         FROM convos c \
+         LEFT JOIN convo_messages m \
+           ON m.id = (SELECT MAX(id) FROM convo_messages \
+                      WHERE convo_id = c.id AND mime NOT IN ({placeholders})) \
+         WHERE c.is_request = ? \
+         ORDER BY (m.received_at IS NULL) DESC, m.received_at DESC, c.created_at DESC, c.id DESC"
+    );
+    let mut select = sqlx::query_as::<
         _,
         (
             String,
             String,
             i64,
             Option<i64>,
+            Option<i64>,
             Option<String>,
             Option<String>,
             Option<Vec<u8>>,
             Option<i64>,
             Option<String>,
+            Option<i64>,
+            Option<i64>,
+            Option<i64>,
         ),
-    >(
-        "SELECT c.convo_type, c.convo_counterparty, c.created_at, \
-                m.id, m.sender_username, m.mime, m.body, m.received_at, m.send_error \
-         FROM convos c \
-         LEFT JOIN convo_messages m \
-           ON m.id = (SELECT MAX(id) FROM convo_messages WHERE convo_id = c.id) \
-         ORDER BY (m.received_at IS NULL) DESC, m.received_at DESC, c.created_at DESC, c.id DESC",
-    )
-    .fetch_all(db)
-    .await?;
+    >(&query);
+    for mime in control_mimes {
+        select = select.bind(mime);
+    }
+    let rows = select.bind(requests).fetch_all(db).await?;
     let mut out = Vec::with_capacity(rows.len());
     for (
         convo_type,
         counterparty,
         _created_at,
+        message_timer,
         msg_id,
         sender_username,
         mime,
         body,
         received_at,
         send_error,
+        edited_at,
+        expires_at,
+        sent_at,
     ) in rows
     {
         let convo_id = parse_convo_id(&convo_type, &counterparty)
             .ok_or_else(|| anyhow::anyhow!("invalid convo row"))?;
-        let last_message = match (msg_id, sender_username, mime, body) {
-            (Some(id), Some(sender_username), Some(mime), Some(body)) => {
+        let last_message = match (msg_id, sender_username, mime, body, sent_at) {
+            (Some(id), Some(sender_username), Some(mime), Some(body), Some(sent_at)) => {
                 let sender = UserName::parse(sender_username)?;
                 let body = (decode_message_content(db, id, &sender, &mime, &body).await).ok();
-                body.map(|body| ConvoMessage {
-                    id,
-                    convo_id: convo_id.clone(),
-                    sender,
-                    body,
-                    send_error,
-                    received_at: received_at.map(|ts| NanoTimestamp(ts as u64)),
-                })
+                let receipts = load_receipts(db, &convo_id)
+                    .await?
+                    .for_message(&sender, sent_at);
+                match body {
+                    Some(body) => Some(ConvoMessage {
+                        id,
+                        convo_id: convo_id.clone(),
+                        sender,
+                        body,
+                        send_error,
+                        received_at: received_at.map(|ts| NanoTimestamp(ts as u64)),
+                        edited_at: edited_at.map(|ts| NanoTimestamp(ts as u64)),
+                        expires_at: expires_at.map(|ts| NanoTimestamp(ts as u64)),
+                        reactions: load_reactions(db, &[id])
+                            .await?
+                            .remove(&id)
+                            .unwrap_or_default(),
+                        parent: load_reply_parents(db, &[id]).await?.remove(&id),
+                        receipts,
+                    }),
+                    None => None,
+                }
             }
             _ => None,
         };
+        let group_metadata = match &convo_id {
+            ConvoId::Group { group_id } => load_group_metadata(db, *group_id).await.ok(),
+            ConvoId::Direct { .. } => None,
+        };
         out.push(ConvoSummary {
             convo_id,
             last_message,
+            message_timer: message_timer.map(|seconds| seconds as u32),
+            group_metadata,
         });
     }
     Ok(out)
 }
 
+type MessageRow = (
+    i64,
+    String,
+    String,
+    Vec<u8>,
+    Option<i64>,
+    Option<String>,
+    Option<i64>,
+    Option<i64>,
+    i64,
+);
+
 async fn convo_history(
     db: &sqlx::SqlitePool,
     convo_id: ConvoId,
@@ -807,25 +1353,91 @@ async fn convo_history(
     let after = after.unwrap_or(i64::MIN);
     let convo_type = convo_id.convo_type();
     let counterparty = convo_id.counterparty();
-    let mut rows =
-        sqlx::query_as::<_, (i64, String, String, Vec<u8>, Option<i64>, Option<String>)>(
-            "SELECT m.id, m.sender_username, m.mime, m.body, m.received_at, m.send_error \
+    // control rows are never shown, so they must not take up room in the page
+    let control_mimes = control_mimes();
+    let placeholders = vec!["?"; control_mimes.len()].join(", ");
+    let query = format!(
+        "SELECT m.id, m.sender_username, m.mime, m.body, m.received_at, m.send_error, \
+                m.edited_at, m.expires_at, m.sent_at \
          FROM convo_messages m \
          JOIN convos c ON m.convo_id = c.id \
          WHERE c.convo_type = ? AND c.convo_counterparty = ? AND m.id <= ? AND m.id >= ? \
+           AND m.mime NOT IN ({placeholders}) \
          ORDER BY m.id DESC \
-         LIMIT ?",
-        )
+         LIMIT ?"
+    );
+    let mut select = sqlx::query_as::<_, MessageRow>(&query)
         .bind(convo_type)
         .bind(counterparty)
         .bind(before)
-        .bind(after)
-        .bind(limit as i64)
-        .fetch_all(db)
-        .await?;
+        .bind(after);
+    for mime in control_mimes {
+        select = select.bind(mime);
+    }
+    let mut rows = select.bind(limit as i64).fetch_all(db).await?;
     rows.reverse();
+    messages_from_rows(db, &convo_id, rows).await
+}
+
+/// Returns every reply that descends from `root`, oldest first.
+async fn convo_thread(
+    db: &sqlx::SqlitePool,
+    convo_id: ConvoId,
+    root: i64,
+) -> anyhow::Result<Vec<ConvoMessage>> {
+    let convo_type = convo_id.convo_type();
+    let counterparty = convo_id.counterparty();
+    let rows = sqlx::query_as::<_, MessageRow>(
+        "WITH RECURSIVE thread(id, convo_id, sender_username, sent_at) AS ( \
+             SELECT m.id, m.convo_id, m.sender_username, m.sent_at \
+             FROM convo_messages m \
+             JOIN convos c ON m.convo_id = c.id \
+             WHERE c.convo_type = ? AND c.convo_counterparty = ? AND m.id = ? \
+             UNION \
+             SELECT m.id, m.convo_id, m.sender_username, m.sent_at \
+             FROM convo_messages m \
+             JOIN thread t \
+               ON m.convo_id = t.convo_id \
+              AND m.parent_sender = t.sender_username \
+              AND m.parent_sent_at = t.sent_at \
+         ) \
+         SELECT m.id, m.sender_username, m.mime, m.body, m.received_at, m.send_error, \
+                m.edited_at, m.expires_at, m.sent_at \
+         FROM convo_messages m \
+         WHERE m.id IN (SELECT id FROM thread) AND m.id != ? \
+         ORDER BY m.id",
+    )
+    .bind(convo_type)
+    .bind(counterparty)
+    .bind(root)
+    .bind(root)
+    .fetch_all(db)
+    .await?;
+    messages_from_rows(db, &convo_id, rows).await
+}
+
+async fn messages_from_rows(
+    db: &sqlx::SqlitePool,
+    convo_id: &ConvoId,
+    rows: Vec<MessageRow>,
+) -> anyhow::Result<Vec<ConvoMessage>> {
+    let ids: Vec<i64> = rows.iter().map(|row| row.0).collect();
+    let mut reactions = load_reactions(db, &ids).await?;
+    let mut parents = load_reply_parents(db, &ids).await?;
+    let receipts = load_receipts(db, convo_id).await?;
     let mut out = Vec::with_capacity(rows.len());
-    for (id, sender_username, mime, body, received_at, send_error) in rows {
+    for (
+        id,
+        sender_username,
+        mime,
+        body,
+        received_at,
+        send_error,
+        edited_at,
+        expires_at,
+        sent_at,
+    ) in rows
+    {
         let sender = UserName::parse(sender_username)?;
         let body = match decode_message_content(db, id, &sender, &mime, &body).await {
             Ok(body) => body,
@@ -833,6 +1445,7 @@ async fn convo_history(
                 continue;
             }
         };
+        let message_receipts = receipts.for_message(&sender, sent_at);
         out.push(ConvoMessage {
             id,
             convo_id: convo_id.clone(),
@@ -840,6 +1453,11 @@ async fn convo_history(
             body,
             send_error,
             received_at: received_at.map(|ts| NanoTimestamp(ts as u64)),
+            edited_at: edited_at.map(|ts| NanoTimestamp(ts as u64)),
+            expires_at: expires_at.map(|ts| NanoTimestamp(ts as u64)),
+            reactions: reactions.remove(&id).unwrap_or_default(),
+            parent: parents.remove(&id),
+            receipts: message_receipts,
         });
     }
     Ok(out)
@@ -950,6 +1568,11 @@ async fn decode_message_content(
         "text/markdown" => Ok(MessageContent::PlainText(
             String::from_utf8_lossy(body).to_string(),
         )),
+        mime if is_control_mime(mime) => Err(anyhow::anyhow!("control message")),
+        mime if mime == MessageReply::mime() => {
+            let reply: MessageReply = serde_json::from_slice(body)?;
+            Ok(MessageContent::PlainText(reply.text))
+        }
         mime if mime == GroupInviteMsg::mime() => Ok(MessageContent::GroupInvite {
             invite_id: message_id,
         }),
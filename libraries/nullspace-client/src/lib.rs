@@ -1,3 +1,4 @@
+mod account_migration;
 mod c_api;
 mod config;
 mod auth_tokens;
@@ -12,9 +13,12 @@ mod long_poll;
 mod main_loop;
 mod medium_keys;
 mod profile;
+mod provision;
 mod rpc_pool;
 mod retry;
+mod safety;
 mod server;
+mod settings;
 mod user_info;
 
 use std::sync::mpsc::Sender;
@@ -22,7 +26,9 @@ use std::sync::mpsc::Sender;
 use nanorpc::{DynRpcTransport, JrpcRequest, JrpcResponse, RpcTransport};
 use tokio::sync::oneshot;
 
-pub use crate::config::Config;
+pub use crate::config::{
+    Config, RpcSocketConfig, default_medium_key_retention_secs, default_rpc_socket_mode,
+};
 pub use crate::internal::InternalClient;
 
 pub struct Client {
@@ -6,19 +6,33 @@ use anyhow::Context;
 use async_channel::{Receiver, Sender};
 use dashmap::DashMap;
 use futures_concurrency::future::Race;
-use tokio::sync::oneshot;
+use nullspace_nanorpc::{Transport, TransportEvent};
+use tokio::sync::{broadcast, oneshot};
+use tokio::time::Instant;
 use nullspace_structs::server::{
-    AuthToken, ServerClient, ServerRpcError, MailboxEntry, MailboxId, MailboxRecvArgs,
+    AuthToken, MAILBOX_HEARTBEAT_SECS, MAILBOX_NOTIFY_METHOD, MailboxEntry, MailboxId,
+    MailboxNotification, MailboxRecvArgs, ServerClient, ServerRpcError,
 };
 use nullspace_structs::timestamp::NanoTimestamp;
 
 use crate::config::Ctx;
+use crate::server::ServerConn;
 
 const LONG_POLL_MIN_MS: u64 = 15_000;
 const LONG_POLL_MAX_MS: u64 = 30 * 60 * 1000;
 const LONG_POLL_INC_MS: u64 = 5_000;
 const LONG_POLL_DEC_FACTOR: f64 = 0.5;
 
+/// How long to long-poll instead after a server could not take a mailbox subscription.
+const STREAM_RETRY: Duration = Duration::from_secs(5 * 60);
+
+/// How long a subscription may go without a notification, heartbeats included, before we take
+/// the connection for dead.
+const HEARTBEAT_TIMEOUT: Duration = Duration::from_secs(MAILBOX_HEARTBEAT_SECS + 30);
+
+/// How long to wait for a server to take or end a subscription before long polling instead.
+const SUBSCRIBE_TIMEOUT: Duration = Duration::from_secs(30);
+
 pub static LONG_POLLER: Ctx<Arc<LongPoller>> = |_ctx| Arc::new(LongPoller::new());
 
 pub struct LongPoller {
@@ -34,7 +48,7 @@ impl LongPoller {
 
     pub async fn recv(
         &self,
-        server: Arc<ServerClient>,
+        server: ServerConn,
         auth: AuthToken,
         mailbox: MailboxId,
         after: NanoTimestamp,
@@ -55,8 +69,8 @@ impl LongPoller {
         rx.await.context("long poller worker closed")?
     }
 
-    fn worker_for_server(&self, server: Arc<ServerClient>) -> ServerWorker {
-        let key = Arc::as_ptr(&server) as usize;
+    fn worker_for_server(&self, server: ServerConn) -> ServerWorker {
+        let key = Arc::as_ptr(&server.client) as usize;
         if let Some(existing) = self.workers.get(&key) {
             return existing.clone();
         }
@@ -81,9 +95,10 @@ struct PollRequest {
     respond_to: oneshot::Sender<anyhow::Result<MailboxEntry>>,
 }
 
-async fn run_server_worker(server: Arc<ServerClient>, receiver: Receiver<PollRequest>) {
+async fn run_server_worker(server: ServerConn, receiver: Receiver<PollRequest>) {
     let mut pending: Vec<PollRequest> = Vec::new();
     let mut timeout_ms = LONG_POLL_MIN_MS;
+    let mut stream_after = Instant::now();
     loop {
         if pending.is_empty() {
             match receiver.recv().await {
@@ -94,6 +109,21 @@ async fn run_server_worker(server: Arc<ServerClient>, receiver: Receiver<PollReq
             }
             continue;
         }
+        if let Some(stream) = &server.stream
+            && Instant::now() >= stream_after
+        {
+            match stream_requests(stream, &receiver, &mut pending).await {
+                StreamExit::Shutdown => {
+                    fail_pending(&mut pending, "long poller shutdown");
+                    break;
+                }
+                StreamExit::Unavailable(err) => {
+                    tracing::debug!(error = %err, "mailbox subscription unavailable, long polling");
+                    stream_after = Instant::now() + STREAM_RETRY;
+                }
+            }
+            continue;
+        }
         let (args, mailbox_keys) = build_args(&pending);
         let recv_fut = async {
             match receiver.recv().await {
@@ -103,6 +133,7 @@ async fn run_server_worker(server: Arc<ServerClient>, receiver: Receiver<PollReq
         };
         let poll_fut = async {
             let response = server
+                .client
                 .v1_mailbox_multirecv(args, timeout_ms)
                 .await
                 .map_err(|err| anyhow::anyhow!(err.to_string()));
@@ -113,11 +144,7 @@ async fn run_server_worker(server: Arc<ServerClient>, receiver: Receiver<PollReq
                 pending.push(request);
             }
             WorkerEvent::Shutdown => {
-                for request in pending.drain(..) {
-                    let _ = request
-                        .respond_to
-                        .send(Err(anyhow::anyhow!("long poller shutdown")));
-                }
+                fail_pending(&mut pending, "long poller shutdown");
                 break;
             }
             WorkerEvent::PollResponse(response) => {
@@ -142,6 +169,284 @@ async fn run_server_worker(server: Arc<ServerClient>, receiver: Receiver<PollReq
     }
 }
 
+enum StreamExit {
+    Shutdown,
+    /// The subscription could not be made or kept up, so long polling should take over.
+    Unavailable(anyhow::Error),
+}
+
+enum StreamEvent {
+    NewRequest(PollRequest),
+    Transport(Result<TransportEvent, broadcast::error::RecvError>),
+    HeartbeatMissed,
+    Shutdown,
+}
+
+/// Serves pending requests through a mailbox subscription on a dedicated connection, until
+/// shutdown or until the subscription stops working.
+///
+/// A notification can carry many entries per mailbox, while each request takes a single one. The
+/// rest are kept, and the next requests for those mailboxes are answered from them. Requests
+/// that the subscription already covers just wait for it, so only a request for another mailbox,
+/// or one from before what the subscription has pushed, makes us subscribe again.
+async fn stream_requests(
+    stream: &Transport,
+    receiver: &Receiver<PollRequest>,
+    pending: &mut Vec<PollRequest>,
+) -> StreamExit {
+    let Some(mut events) = stream.events() else {
+        return StreamExit::Unavailable(anyhow::anyhow!("transport cannot carry notifications"));
+    };
+    let client = ServerClient::from(stream.clone());
+    let mut subscription: Option<u64> = None;
+    // where the subscription is at for each mailbox: it pushes what comes after these
+    let mut cursors: HashMap<(MailboxId, AuthToken), NanoTimestamp> = HashMap::new();
+    let mut buffered = Buffered::new();
+    let mut heard_at = Instant::now();
+    let mut stale = true;
+    loop {
+        if stale {
+            while let Ok(request) = receiver.try_recv() {
+                pending.push(request);
+            }
+            answer_pending(&mut buffered, pending);
+            let previous = subscription.take();
+            let args = resubscribe_args(pending, &cursors, &buffered);
+            buffered.retain(|&(mailbox, auth), _| {
+                args.iter()
+                    .any(|arg| arg.mailbox == mailbox && arg.auth == auth)
+            });
+            cursors.clear();
+            if !args.is_empty() {
+                let id = rand::random();
+                let subscribe = client.v1_mailbox_subscribe(id, args.clone());
+                match tokio::time::timeout(SUBSCRIBE_TIMEOUT, subscribe).await {
+                    Ok(Ok(Ok(true))) => {
+                        subscription = Some(id);
+                        cursors = args
+                            .into_iter()
+                            .map(|arg| ((arg.mailbox, arg.auth), arg.after))
+                            .collect();
+                        heard_at = Instant::now();
+                    }
+                    Ok(Ok(Ok(false))) => {
+                        return StreamExit::Unavailable(anyhow::anyhow!(
+                            "server cannot push notifications on this connection"
+                        ));
+                    }
+                    Ok(Ok(Err(err))) => {
+                        fail_pending(pending, &err.to_string());
+                        buffered.clear();
+                    }
+                    Ok(Err(err)) => {
+                        return StreamExit::Unavailable(anyhow::anyhow!(err.to_string()));
+                    }
+                    Err(_) => {
+                        return StreamExit::Unavailable(anyhow::anyhow!(
+                            "mailbox subscribe timed out"
+                        ));
+                    }
+                }
+            }
+            if let Some(previous) = previous {
+                let unsubscribe = client.v1_mailbox_unsubscribe(previous);
+                match tokio::time::timeout(SUBSCRIBE_TIMEOUT, unsubscribe).await {
+                    Ok(Ok(Ok(()))) => {}
+                    Ok(Ok(Err(err))) => {
+                        tracing::debug!(error = %err, "failed to end mailbox subscription");
+                    }
+                    Ok(Err(err)) => {
+                        tracing::debug!(error = %err, "failed to end mailbox subscription");
+                    }
+                    Err(_) => {
+                        return StreamExit::Unavailable(anyhow::anyhow!(
+                            "mailbox unsubscribe timed out"
+                        ));
+                    }
+                }
+            }
+            stale = false;
+        }
+        let recv_fut = async {
+            match receiver.recv().await {
+                Ok(request) => StreamEvent::NewRequest(request),
+                Err(_) => StreamEvent::Shutdown,
+            }
+        };
+        let event_fut = async { StreamEvent::Transport(events.recv().await) };
+        let heartbeat_fut = async {
+            if subscription.is_none() {
+                std::future::pending::<()>().await;
+            }
+            tokio::time::sleep_until(heard_at + HEARTBEAT_TIMEOUT).await;
+            StreamEvent::HeartbeatMissed
+        };
+        match (recv_fut, event_fut, heartbeat_fut).race().await {
+            StreamEvent::NewRequest(request) => {
+                if let Some(request) = answer_from_buffer(&mut buffered, request) {
+                    let covered = subscription.is_some()
+                        && cursors
+                            .get(&(request.mailbox, request.auth))
+                            .is_some_and(|cursor| request.after >= *cursor);
+                    stale |= !covered;
+                    pending.push(request);
+                }
+            }
+            StreamEvent::Shutdown => return StreamExit::Shutdown,
+            StreamEvent::Transport(Ok(TransportEvent::Notification { method, params })) => {
+                if method != MAILBOX_NOTIFY_METHOD {
+                    continue;
+                }
+                let notification = match serde_json::from_value::<(MailboxNotification,)>(params) {
+                    Ok((notification,)) => notification,
+                    Err(err) => {
+                        tracing::warn!(error = %err, "invalid mailbox notification");
+                        continue;
+                    }
+                };
+                let current = subscription == Some(notification.subscription);
+                if current {
+                    heard_at = Instant::now();
+                }
+                match notification.entries {
+                    // a replaced subscription may have been for other tokens, and the current one
+                    // pushes the same entries again from our cursors
+                    Ok(entries) if current => {
+                        deliver_entries(&entries, &cursors, &mut buffered, pending);
+                        advance_cursors(&mut cursors, &entries);
+                    }
+                    Ok(_) => {}
+                    // errors from subscriptions we already replaced are stale
+                    Err(err) if current => {
+                        fail_pending(pending, &err.to_string());
+                        subscription = None;
+                        cursors.clear();
+                        buffered.clear();
+                    }
+                    Err(_) => {}
+                }
+            }
+            // the subscription is gone along with the connection, but the cursors still say what
+            // we have seen
+            StreamEvent::Transport(Ok(TransportEvent::Reset)) => {
+                subscription = None;
+                stale = true;
+            }
+            // we may have missed entries, so subscribe again from the cursors we have
+            StreamEvent::Transport(Err(broadcast::error::RecvError::Lagged(_))) => {
+                stale = true;
+            }
+            StreamEvent::Transport(Err(broadcast::error::RecvError::Closed)) => {
+                return StreamExit::Unavailable(anyhow::anyhow!("transport stopped"));
+            }
+            // the connection may be dead without us being told, so subscribing again finds out,
+            // and long polling takes over if that fails
+            StreamEvent::HeartbeatMissed => {
+                tracing::debug!("mailbox subscription heartbeat missed, subscribing again");
+                stale = true;
+            }
+        }
+    }
+}
+
+/// Entries pushed on a subscription and not handed out yet, by the mailbox and the token they were
+/// received with. An entry is only ever handed to a request made with the same token.
+type Buffered = HashMap<(MailboxId, AuthToken), Vec<MailboxEntry>>;
+
+/// What to subscribe to next: the mailboxes of pending requests, from the earliest cursor among
+/// them, and the mailboxes that still have entries kept for them, from where the subscription was.
+fn resubscribe_args(
+    pending: &[PollRequest],
+    cursors: &HashMap<(MailboxId, AuthToken), NanoTimestamp>,
+    buffered: &Buffered,
+) -> Vec<MailboxRecvArgs> {
+    let (mut args, keys) = build_args(pending);
+    for (&key, &after) in cursors {
+        let (mailbox, auth) = key;
+        if buffered.contains_key(&key) && !keys.contains_key(&key) {
+            args.push(MailboxRecvArgs {
+                auth,
+                mailbox,
+                after,
+            });
+        }
+    }
+    args
+}
+
+/// Moves the cursors of the subscription past the entries it pushed, as the server does.
+fn advance_cursors(
+    cursors: &mut HashMap<(MailboxId, AuthToken), NanoTimestamp>,
+    entries: &BTreeMap<MailboxId, Vec<MailboxEntry>>,
+) {
+    for ((mailbox, _), cursor) in cursors.iter_mut() {
+        if let Some(last) = entries.get(mailbox).and_then(|entries| entries.last()) {
+            *cursor = last.received_at;
+        }
+    }
+}
+
+/// Keeps a batch of entries pushed on the current subscription, under each token it receives the
+/// mailbox with, then hands each pending request the first entry after its cursor, if there is one.
+fn deliver_entries(
+    entries: &BTreeMap<MailboxId, Vec<MailboxEntry>>,
+    cursors: &HashMap<(MailboxId, AuthToken), NanoTimestamp>,
+    buffered: &mut Buffered,
+    pending: &mut Vec<PollRequest>,
+) {
+    for (&key, &cursor) in cursors {
+        let Some(entries) = entries.get(&key.0) else {
+            continue;
+        };
+        let kept = buffered.entry(key).or_default();
+        kept.extend(
+            entries
+                .iter()
+                .filter(|entry| entry.received_at > cursor)
+                .cloned(),
+        );
+    }
+    answer_pending(buffered, pending);
+}
+
+fn answer_pending(buffered: &mut Buffered, pending: &mut Vec<PollRequest>) {
+    let mut still_pending = Vec::new();
+    for request in pending.drain(..) {
+        if let Some(request) = answer_from_buffer(buffered, request) {
+            still_pending.push(request);
+        }
+    }
+    *pending = still_pending;
+}
+
+/// Answers a request from the entries kept for its mailbox, handing it back if none is after its
+/// cursor. A request tells us that its sender is done with everything up to its cursor, so those
+/// entries are dropped.
+fn answer_from_buffer(buffered: &mut Buffered, request: PollRequest) -> Option<PollRequest> {
+    let key = (request.mailbox, request.auth);
+    let Some(kept) = buffered.get_mut(&key) else {
+        return Some(request);
+    };
+    kept.retain(|entry| entry.received_at > request.after);
+    let found = kept.first().cloned();
+    if kept.is_empty() {
+        buffered.remove(&key);
+    }
+    match found {
+        Some(entry) => {
+            let _ = request.respond_to.send(Ok(entry));
+            None
+        }
+        None => Some(request),
+    }
+}
+
+fn fail_pending(pending: &mut Vec<PollRequest>, message: &str) {
+    for request in pending.drain(..) {
+        let _ = request.respond_to.send(Err(anyhow::anyhow!(message.to_string())));
+    }
+}
+
 enum WorkerEvent {
     NewRequest(PollRequest),
     PollResponse(
@@ -259,3 +564,67 @@ fn aimd_decrease(current: u64) -> u64 {
     }
     next
 }
+
+#[cfg(test)]
+mod tests {
+    use nullspace_structs::Blob;
+    use nullspace_structs::username::UserName;
+
+    use super::*;
+
+    fn request(
+        auth: AuthToken,
+        mailbox: MailboxId,
+        after: u64,
+    ) -> (PollRequest, oneshot::Receiver<anyhow::Result<MailboxEntry>>) {
+        let (respond_to, rx) = oneshot::channel();
+        let request = PollRequest {
+            auth,
+            mailbox,
+            after: NanoTimestamp(after),
+            respond_to,
+        };
+        (request, rx)
+    }
+
+    #[test]
+    fn batch_answers_the_requests_that_follow() {
+        let mailbox = MailboxId::direct(&UserName::parse("@alice01").expect("username"));
+        let auth = AuthToken::random();
+        let entries = (1..=3)
+            .map(|at| MailboxEntry {
+                message: Blob {
+                    kind: Blob::V1_DIRECT_MESSAGE.into(),
+                    inner: Default::default(),
+                },
+                received_at: NanoTimestamp(at),
+                sender_auth_token_hash: None,
+            })
+            .collect();
+
+        let (first, mut first_rx) = request(auth, mailbox, 0);
+        let mut pending = vec![first];
+        let mut buffered = Buffered::new();
+        let cursors = HashMap::from([((mailbox, auth), NanoTimestamp(0))]);
+        let batch = BTreeMap::from([(mailbox, entries)]);
+        deliver_entries(&batch, &cursors, &mut buffered, &mut pending);
+        assert!(pending.is_empty());
+        let entry = first_rx.try_recv().expect("answered").expect("entry");
+        assert_eq!(entry.received_at, NanoTimestamp(1));
+
+        // what was pushed for one token is not handed to a request made with another
+        let (other, _) = request(AuthToken::random(), mailbox, 1);
+        assert!(answer_from_buffer(&mut buffered, other).is_some());
+
+        // the next requests are answered from what was pushed, without going to the server
+        for at in 2..=3 {
+            let (next, mut next_rx) = request(auth, mailbox, at - 1);
+            assert!(answer_from_buffer(&mut buffered, next).is_none());
+            let entry = next_rx.try_recv().expect("answered").expect("entry");
+            assert_eq!(entry.received_at, NanoTimestamp(at));
+        }
+        let (last, _) = request(auth, mailbox, 3);
+        assert!(answer_from_buffer(&mut buffered, last).is_some());
+        assert!(buffered.is_empty());
+    }
+}
@@ -6,6 +6,7 @@ use nanorpc::{JrpcRequest, JrpcResponse, RpcService};
 use tokio::sync::oneshot;
 
 use crate::Config;
+use crate::account_migration::account_migration_loop;
 use crate::database::{DATABASE, DbNotify, event_loop, identity_exists};
 use crate::events::init_event_tx;
 
@@ -32,6 +33,7 @@ pub async fn main_loop(
     init_event_tx(&ctx, event_tx.clone());
     let internal = InternalImpl::new(ctx.clone(), event_rx);
     let futs = (
+        rpc_socket_loop(&ctx, internal.clone()),
         rpc_loop(internal, req_rx),
         event_loop(&ctx),
         worker_loop(&ctx),
@@ -52,6 +54,20 @@ async fn rpc_loop(
     }
 }
 
+/// Serves the internal RPC on the configured Unix socket, if any. Failing to do so is logged
+/// rather than taking the client down.
+async fn rpc_socket_loop(ctx: &AnyCtx<Config>, internal: InternalImpl) {
+    if let Some(socket) = &ctx.init().rpc_socket {
+        let service = crate::internal::InternalService(internal);
+        if let Err(err) =
+            nullspace_nanorpc::serve_unix(&socket.path, service, socket.lz4, socket.mode).await
+        {
+            tracing::error!(error = %err, path = %socket.path.display(), "rpc socket failed");
+        }
+    }
+    std::future::pending().await
+}
+
 async fn worker_loop(ctx: &AnyCtx<Config>) {
     let db = ctx.get(DATABASE);
     let mut notify = DbNotify::new();
@@ -65,5 +81,11 @@ async fn worker_loop(ctx: &AnyCtx<Config>) {
         }
         notify.wait_for_change().await;
     }
-    (convo_loop(ctx), medium_key_loop(ctx)).race().await;
+    (
+        convo_loop(ctx),
+        medium_key_loop(ctx),
+        account_migration_loop(ctx),
+    )
+        .race()
+        .await;
 }
@@ -1,11 +1,13 @@
+use std::collections::BTreeSet;
 use std::time::Duration;
 
 use anyctx::AnyCtx;
 use anyhow::Context;
 use nullspace_crypt::dh::DhSecret;
 use nullspace_crypt::signing::Signable;
+use nullspace_structs::e2ee::{HeaderEncrypted, mpk_short};
 use nullspace_structs::server::SignedMediumPk;
-use nullspace_structs::timestamp::Timestamp;
+use nullspace_structs::timestamp::{NanoTimestamp, Timestamp};
 
 use crate::Config;
 use crate::auth_tokens::get_auth_token;
@@ -18,6 +20,9 @@ const MEDIUM_ROTATE_INTERVAL: Duration = Duration::from_secs(60 * 60);
 
 pub async fn medium_key_loop(ctx: &AnyCtx<Config>) {
     loop {
+        if let Err(err) = prune_expired(ctx.get(DATABASE)).await {
+            tracing::warn!(error = %err, "medium-key pruning error");
+        }
         tokio::time::sleep(MEDIUM_ROTATE_INTERVAL).await;
         if let Err(err) = rotate_once(ctx).await {
             tracing::warn!(error = %err, "medium-key rotation error");
@@ -25,6 +30,65 @@ pub async fn medium_key_loop(ctx: &AnyCtx<Config>) {
     }
 }
 
+/// Returns the medium-term secrets that may open `encrypted`: the current one first, then every
+/// retained one whose short hash matches one of its headers.
+pub async fn medium_secrets_for(
+    db: &sqlx::SqlitePool,
+    identity: &Identity,
+    encrypted: &HeaderEncrypted,
+) -> anyhow::Result<Vec<DhSecret>> {
+    let mut secrets = vec![identity.medium_sk_current.clone()];
+    let current_short = mpk_short(&identity.medium_sk_current.public_key());
+    let shorts: BTreeSet<[u8; 2]> = encrypted
+        .headers
+        .iter()
+        .map(|header| header.receiver_mpk_short)
+        .filter(|short| *short != current_short)
+        .collect();
+    let now = NanoTimestamp::now().0 as i64;
+    for short in shorts {
+        let rows = sqlx::query_scalar::<_, Vec<u8>>(
+            "SELECT medium_sk FROM client_medium_keys \
+             WHERE mpk_short = ? AND expires_at > ? ORDER BY expires_at DESC",
+        )
+        .bind(short.to_vec())
+        .bind(now)
+        .fetch_all(db)
+        .await?;
+        for row in rows {
+            secrets.push(bcs::from_bytes(&row)?);
+        }
+    }
+    Ok(secrets)
+}
+
+/// Decrypts with the first of `secrets` that works.
+pub fn decrypt_with_any(
+    encrypted: &HeaderEncrypted,
+    secrets: &[DhSecret],
+) -> anyhow::Result<Vec<u8>> {
+    secrets
+        .iter()
+        .find_map(|secret| encrypted.decrypt_bytes(secret).ok())
+        .context("failed to decrypt header-encrypted message")
+}
+
+/// Deletes the retained secrets that aged out, and checkpoints the WAL so that no copy of them
+/// survives there either.
+async fn prune_expired(db: &sqlx::SqlitePool) -> anyhow::Result<()> {
+    let result = sqlx::query("DELETE FROM client_medium_keys WHERE expires_at <= ?")
+        .bind(NanoTimestamp::now().0 as i64)
+        .execute(db)
+        .await?;
+    if result.rows_affected() > 0 {
+        sqlx::query("PRAGMA wal_checkpoint(TRUNCATE)")
+            .execute(db)
+            .await?;
+        tracing::debug!(count = result.rows_affected(), "expired medium-term keys deleted");
+    }
+    Ok(())
+}
+
 async fn rotate_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
     let db = ctx.get(DATABASE);
     let identity = Identity::load(db).await?;
@@ -42,15 +106,24 @@ async fn rotate_once(ctx: &AnyCtx<Config>) -> anyhow::Result<()> {
         signature: nullspace_crypt::signing::Signature::from_bytes([0u8; 64]),
     };
     signed.sign(&identity.device_secret);
+    let retention = ctx.init().medium_key_retention_secs;
+    let expires_at = NanoTimestamp::now()
+        .0
+        .saturating_add(retention.saturating_mul(1_000_000_000));
+    let mut tx = db.begin().await?;
     sqlx::query(
-        "UPDATE client_identity \
-         SET medium_sk_prev = ?, medium_sk_current = ? \
-         WHERE id = 1",
+        "INSERT INTO client_medium_keys (mpk_short, medium_sk, expires_at) VALUES (?, ?, ?)",
     )
+    .bind(mpk_short(&identity.medium_sk_current.public_key()).to_vec())
     .bind(bcs::to_bytes(&identity.medium_sk_current)?)
-    .bind(bcs::to_bytes(&new_sk)?)
-    .execute(db)
+    .bind(expires_at as i64)
+    .execute(tx.as_mut())
     .await?;
+    sqlx::query("UPDATE client_identity SET medium_sk_current = ? WHERE id = 1")
+        .bind(bcs::to_bytes(&new_sk)?)
+        .execute(tx.as_mut())
+        .await?;
+    tx.commit().await?;
     server
         .v1_device_add_medium_pk(auth, signed)
         .await?
@@ -0,0 +1,192 @@
+use std::fmt::Write;
+
+use anyctx::AnyCtx;
+use anyhow::Context;
+use bytes::Bytes;
+use nullspace_crypt::aead::AeadKey;
+use nullspace_crypt::hash::Hash;
+use nullspace_structs::Blob;
+use nullspace_structs::server::{ServerName, ServerRpcError};
+use nullspace_structs::username::UserName;
+use rand::RngCore;
+use serde::{Deserialize, Serialize};
+use smol_str::SmolStr;
+
+use crate::auth_tokens::get_auth_token;
+use crate::config::Config;
+use crate::database::DATABASE;
+use crate::directory::DIR_CLIENT;
+use crate::identity::Identity;
+use crate::server::get_server_client;
+
+const PROVISION_SECRET_LEN: usize = 10;
+
+const PROVISION_RECV_TIMEOUT_MS: u64 = 30_000;
+
+/// A short code shown by a new device, which an existing device uses to push a device bundle to it. It names a provisioning channel on the user's server, together with the secret the bundle is encrypted with.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(transparent)]
+pub struct ProvisionCode(pub SmolStr);
+
+struct ParsedCode {
+    channel: u64,
+    secret: [u8; PROVISION_SECRET_LEN],
+}
+
+impl ParsedCode {
+    fn random(channel: u64) -> Self {
+        let mut secret = [0u8; PROVISION_SECRET_LEN];
+        rand::thread_rng().fill_bytes(&mut secret);
+        Self { channel, secret }
+    }
+
+    fn parse(code: &ProvisionCode) -> anyhow::Result<Self> {
+        let (channel, secret_hex) = code
+            .0
+            .trim()
+            .split_once('-')
+            .context("malformed provisioning code")?;
+        let channel = channel.parse().context("malformed provisioning channel")?;
+        if secret_hex.len() != PROVISION_SECRET_LEN * 2 || !secret_hex.is_ascii() {
+            anyhow::bail!("malformed provisioning secret");
+        }
+        let mut secret = [0u8; PROVISION_SECRET_LEN];
+        for (i, byte) in secret.iter_mut().enumerate() {
+            *byte = u8::from_str_radix(&secret_hex[i * 2..i * 2 + 2], 16)
+                .context("malformed provisioning secret")?;
+        }
+        Ok(Self { channel, secret })
+    }
+
+    fn to_code(&self) -> ProvisionCode {
+        let mut code = format!("{}-", self.channel);
+        for byte in self.secret {
+            write!(code, "{byte:02x}").unwrap();
+        }
+        ProvisionCode(code.into())
+    }
+
+    fn key(&self) -> AeadKey {
+        AeadKey::from_bytes(Hash::keyed_digest(b"nullspace-provision", &self.secret).to_bytes())
+    }
+}
+
+/// Allocates a provisioning channel on the server of the given user, returning the code to show.
+pub async fn provision_start(
+    ctx: &AnyCtx<Config>,
+    username: &UserName,
+) -> anyhow::Result<ProvisionCode> {
+    let server_name = user_server_name(ctx, username).await?;
+    let server = get_server_client(ctx, &server_name).await?;
+    let channel = server
+        .v1_provision_allocate()
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    Ok(ParsedCode::random(channel).to_code())
+}
+
+/// Waits for a device bundle to be pushed to the provisioning channel of the given code, returning the decrypted bundle.
+pub async fn provision_recv(
+    ctx: &AnyCtx<Config>,
+    username: &UserName,
+    code: &ProvisionCode,
+) -> anyhow::Result<Bytes> {
+    let parsed = ParsedCode::parse(code)?;
+    let server_name = user_server_name(ctx, username).await?;
+    let server = get_server_client(ctx, &server_name).await?;
+    loop {
+        let value = match server
+            .v1_provision_recv(parsed.channel, PROVISION_RECV_TIMEOUT_MS)
+            .await?
+        {
+            Ok(value) => value,
+            Err(ServerRpcError::AccessDenied) => {
+                anyhow::bail!("provisioning channel expired")
+            }
+            Err(err) => anyhow::bail!(err.to_string()),
+        };
+        if let Some(value) = value {
+            return open_bundle(&parsed, &value);
+        }
+    }
+}
+
+/// Encrypts a device bundle and pushes it to the provisioning channel of the given code, through our own server.
+pub async fn provision_send(
+    ctx: &AnyCtx<Config>,
+    code: &ProvisionCode,
+    bundle: &[u8],
+) -> anyhow::Result<()> {
+    let parsed = ParsedCode::parse(code)?;
+    let identity = Identity::load(ctx.get(DATABASE)).await?;
+    let server_name = identity
+        .server_name
+        .context("server name not available")?;
+    let server = get_server_client(ctx, &server_name).await?;
+    let auth = get_auth_token(ctx).await?;
+    server
+        .v1_provision_send(auth, parsed.channel, seal_bundle(&parsed, bundle)?)
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+    Ok(())
+}
+
+async fn user_server_name(ctx: &AnyCtx<Config>, username: &UserName) -> anyhow::Result<ServerName> {
+    let descriptor = ctx
+        .get(DIR_CLIENT)
+        .get_user_descriptor(username)
+        .await?
+        .context("username not found")?;
+    Ok(descriptor.server_name)
+}
+
+fn seal_bundle(code: &ParsedCode, bundle: &[u8]) -> anyhow::Result<Blob> {
+    let mut nonce = [0u8; 24];
+    rand::thread_rng().fill_bytes(&mut nonce);
+    let ciphertext = code
+        .key()
+        .encrypt(nonce, bundle, &code.channel.to_be_bytes())
+        .map_err(|_| anyhow::anyhow!("failed to encrypt device bundle"))?;
+    let mut inner = nonce.to_vec();
+    inner.extend_from_slice(&ciphertext);
+    Ok(Blob {
+        kind: Blob::V1_DEVICE_BUNDLE.into(),
+        inner: Bytes::from(inner),
+    })
+}
+
+fn open_bundle(code: &ParsedCode, blob: &Blob) -> anyhow::Result<Bytes> {
+    if blob.kind != Blob::V1_DEVICE_BUNDLE || blob.inner.len() < 24 {
+        anyhow::bail!("unexpected provisioning payload");
+    }
+    let (nonce, ciphertext) = blob.inner.split_at(24);
+    let plaintext = code
+        .key()
+        .decrypt(
+            nonce.try_into().expect("nonce length"),
+            ciphertext,
+            &code.channel.to_be_bytes(),
+        )
+        .map_err(|_| anyhow::anyhow!("failed to decrypt device bundle"))?;
+    Ok(Bytes::from(plaintext))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn code_roundtrip_and_wrong_secret() {
+        let parsed = ParsedCode::random(12_345_678);
+        let code = parsed.to_code();
+        let reparsed = ParsedCode::parse(&code).expect("parse code");
+        assert_eq!(reparsed.channel, 12_345_678);
+        assert_eq!(reparsed.secret, parsed.secret);
+
+        let sealed = seal_bundle(&parsed, b"bundle").expect("seal");
+        assert_eq!(&open_bundle(&reparsed, &sealed).expect("open")[..], b"bundle");
+
+        let other = ParsedCode::random(12_345_678);
+        assert!(open_bundle(&other, &sealed).is_err());
+    }
+}
@@ -0,0 +1,165 @@
+use std::fmt::Write;
+
+use anyctx::AnyCtx;
+use nullspace_crypt::hash::Hash;
+use nullspace_structs::timestamp::NanoTimestamp;
+use nullspace_structs::username::UserName;
+use serde::{Deserialize, Serialize};
+
+use crate::config::Config;
+use crate::database::{DATABASE, DbNotify};
+use crate::events::emit_event;
+use crate::internal::Event;
+
+/// Number of 5-digit groups each user contributes to a safety number.
+const SAFETY_GROUPS_PER_USER: usize = 6;
+
+/// A number two users compare, in person or over another channel, to check that they see the same
+/// root keys for each other. It only depends on the two usernames and root certificate hashes, so
+/// both sides compute the same number.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SafetyNumber {
+    /// Sixty digits, in groups of five separated by spaces.
+    pub digits: String,
+    /// The payload to show as a QR code, for the other side to scan and compare.
+    pub qr_payload: String,
+}
+
+impl SafetyNumber {
+    pub fn new(a: (&UserName, Hash), b: (&UserName, Hash)) -> Self {
+        let (first, second) = if a.0 <= b.0 { (a, b) } else { (b, a) };
+        let groups = fingerprint_groups(first.0, first.1)
+            .into_iter()
+            .chain(fingerprint_groups(second.0, second.1))
+            .collect::<Vec<_>>();
+        Self {
+            digits: groups.join(" "),
+            qr_payload: format!("nullspace-safety:1:{}", groups.concat()),
+        }
+    }
+}
+
+/// One user's half of a safety number: a keyed hash of their username and root certificate hash,
+/// read out five bytes at a time as five decimal digits.
+fn fingerprint_groups(username: &UserName, root_hash: Hash) -> Vec<String> {
+    let input = bcs::to_bytes(&(username, root_hash)).expect("bcs serialization failed");
+    let digest = Hash::keyed_digest(b"nullspace-safety-number", &input).to_bytes();
+    digest
+        .chunks_exact(5)
+        .take(SAFETY_GROUPS_PER_USER)
+        .map(|chunk| {
+            let value = chunk
+                .iter()
+                .fold(0u64, |acc, byte| (acc << 8) | u64::from(*byte));
+            let mut group = String::with_capacity(5);
+            write!(group, "{:05}", value % 100_000).unwrap();
+            group
+        })
+        .collect()
+}
+
+/// Marks a contact as verified at the given root certificate hash, or clears the mark.
+pub async fn set_contact_verified(
+    db: &sqlx::SqlitePool,
+    username: &UserName,
+    root_hash: Hash,
+    verified: bool,
+) -> anyhow::Result<()> {
+    if verified {
+        sqlx::query(
+            "INSERT OR REPLACE INTO contact_verification (username, root_cert_hash, verified_at) \
+             VALUES (?, ?, ?)",
+        )
+        .bind(username.as_str())
+        .bind(root_hash.to_bytes().to_vec())
+        .bind(NanoTimestamp::now().0 as i64)
+        .execute(db)
+        .await?;
+    } else {
+        sqlx::query("DELETE FROM contact_verification WHERE username = ?")
+            .bind(username.as_str())
+            .execute(db)
+            .await?;
+    }
+    DbNotify::touch();
+    Ok(())
+}
+
+/// Whether the contact was verified at exactly this root certificate hash.
+pub async fn is_contact_verified(
+    db: &sqlx::SqlitePool,
+    username: &UserName,
+    root_hash: Hash,
+) -> anyhow::Result<bool> {
+    Ok(load_verified_root(db, username).await? == Some(root_hash))
+}
+
+/// Checks a freshly looked up root certificate hash against the one a contact was verified at. On
+/// a mismatch the verification no longer holds: it is cleared, and the UI is warned.
+pub async fn check_verified_root(
+    ctx: &AnyCtx<Config>,
+    username: &UserName,
+    root_hash: Hash,
+) -> anyhow::Result<()> {
+    let db = ctx.get(DATABASE);
+    let Some(verified_root) = load_verified_root(db, username).await? else {
+        return Ok(());
+    };
+    if verified_root == root_hash {
+        return Ok(());
+    }
+    tracing::warn!(
+        username = %username,
+        verified_root = %verified_root,
+        root_hash = %root_hash,
+        "root key of verified contact changed"
+    );
+    set_contact_verified(db, username, root_hash, false).await?;
+    emit_event(
+        ctx,
+        Event::VerifiedKeyChanged {
+            username: username.clone(),
+        },
+    );
+    Ok(())
+}
+
+async fn load_verified_root(
+    db: &sqlx::SqlitePool,
+    username: &UserName,
+) -> anyhow::Result<Option<Hash>> {
+    let row = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT root_cert_hash FROM contact_verification WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_optional(db)
+    .await?;
+    row.map(|bytes| {
+        Ok(Hash::from_bytes(bytes.as_slice().try_into().map_err(|_| {
+            anyhow::anyhow!("invalid root cert hash bytes")
+        })?))
+    })
+    .transpose()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn safety_number_is_symmetric_and_key_bound() {
+        let alice = UserName::parse("@alice01").expect("username");
+        let bob = UserName::parse("@bob01").expect("username");
+        let alice_root = Hash::digest(b"alice root");
+        let bob_root = Hash::digest(b"bob root");
+
+        let from_alice = SafetyNumber::new((&alice, alice_root), (&bob, bob_root));
+        let from_bob = SafetyNumber::new((&bob, bob_root), (&alice, alice_root));
+        assert_eq!(from_alice, from_bob);
+        assert_eq!(from_alice.digits.len(), 12 * 5 + 11);
+        assert!(from_alice.digits.split(' ').all(|group| group.len() == 5));
+
+        let swapped = SafetyNumber::new((&alice, Hash::digest(b"mallory root")), (&bob, bob_root));
+        assert_ne!(swapped.digits, from_alice.digits);
+    }
+}
@@ -6,7 +6,9 @@ use anyhow::Context;
 use async_trait::async_trait;
 use moka::future::Cache;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use nullspace_crypt::signing::SigningPublic;
 use nullspace_dirclient::DirClient;
+use nullspace_nanorpc::Transport;
 use nullspace_rpc_pool::{PooledTransport, RpcPool};
 use nullspace_structs::server::{AuthToken, ProxyError, ServerClient, ServerName};
 
@@ -16,17 +18,32 @@ use crate::database::DATABASE;
 use crate::identity::Identity;
 use crate::rpc_pool::RPC_POOL;
 
-pub static SERVER_CACHE: Ctx<Cache<ServerName, Arc<ServerClient>>> =
+pub static SERVER_CACHE: Ctx<Cache<ServerName, ServerConn>> =
     |_ctx: &anyctx::AnyCtx<Config>| {
         Cache::builder()
             .time_to_idle(Duration::from_secs(3600))
             .build()
     };
 
+/// A client for a server, plus a dedicated connection for mailbox subscriptions if the server is
+/// reached directly over a TCP transport. Subscriptions cannot go through a proxy.
+#[derive(Clone)]
+pub struct ServerConn {
+    pub client: Arc<ServerClient>,
+    pub stream: Option<Arc<Transport>>,
+}
+
 pub async fn get_server_client(
     ctx: &anyctx::AnyCtx<Config>,
     name: &ServerName,
 ) -> anyhow::Result<Arc<ServerClient>> {
+    Ok(get_server_conn(ctx, name).await?.client)
+}
+
+pub async fn get_server_conn(
+    ctx: &anyctx::AnyCtx<Config>,
+    name: &ServerName,
+) -> anyhow::Result<ServerConn> {
     let cache = ctx.get(SERVER_CACHE);
     cache
         .try_get_with(name.clone(), async {
@@ -56,7 +73,9 @@ pub async fn get_server_client(
                             .first()
                             .cloned()
                             .context("server has no public URLs")?;
-                        let proxy_client = Arc::new(ServerClient::from(rpc_pool.rpc(endpoint)));
+                        let proxy_client = Arc::new(ServerClient::from(
+                            rpc_pool.rpc_pinned(endpoint, descriptor.server_pk),
+                        ));
                         let auth_token = get_auth_token(ctx).await?;
                         Some(ProxyInfo {
                             own_server_name,
@@ -76,12 +95,20 @@ pub async fn get_server_client(
                 .first()
                 .cloned()
                 .context("server has no public URLs")?;
-            Ok(Arc::new(ServerClient::from(ProxyingTransport::new(
+            let stream = if proxy_info.is_none() {
+                let transport = Transport::new_pinned(endpoint.clone(), descriptor.server_pk);
+                transport.events().is_some().then(|| Arc::new(transport))
+            } else {
+                None
+            };
+            let client = Arc::new(ServerClient::from(ProxyingTransport::new(
                 name.clone(),
                 rpc_pool,
                 endpoint,
+                descriptor.server_pk,
                 proxy_info,
-            ))))
+            )));
+            Ok(ServerConn { client, stream })
         })
         .await
         .map_err(|err: Arc<anyhow::Error>| anyhow::anyhow!(err.to_string()))
@@ -110,12 +137,13 @@ impl ProxyingTransport {
         target_name: ServerName,
         pool: RpcPool,
         endpoint: url::Url,
+        server_pk: SigningPublic,
         proxy_info: Option<ProxyInfo>,
     ) -> Self {
         Self {
             inner: Arc::new(ProxyingTransportInner {
                 target_name,
-                target_transport: pool.rpc(endpoint),
+                target_transport: pool.rpc_pinned(endpoint, server_pk),
                 proxy_unsupported: AtomicBool::new(proxy_info.is_none()),
                 proxy_info,
             }),
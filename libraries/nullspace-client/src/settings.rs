@@ -0,0 +1,37 @@
+use serde::{Deserialize, Serialize};
+
+/// Local, per-account preferences.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct Settings {
+    pub read_receipts: bool,
+}
+
+impl Default for Settings {
+    fn default() -> Self {
+        Self {
+            read_receipts: true,
+        }
+    }
+}
+
+pub async fn load_settings(db: &sqlx::SqlitePool) -> anyhow::Result<Settings> {
+    let row =
+        sqlx::query_as::<_, (bool,)>("SELECT read_receipts FROM client_settings WHERE id = 1")
+            .fetch_optional(db)
+            .await?;
+    Ok(match row {
+        Some((read_receipts,)) => Settings { read_receipts },
+        None => Settings::default(),
+    })
+}
+
+pub async fn store_settings(db: &sqlx::SqlitePool, settings: &Settings) -> anyhow::Result<()> {
+    sqlx::query(
+        "INSERT INTO client_settings (id, read_receipts) VALUES (1, ?) \
+         ON CONFLICT(id) DO UPDATE SET read_receipts = excluded.read_receipts",
+    )
+    .bind(settings.read_receipts)
+    .execute(db)
+    .await?;
+    Ok(())
+}
@@ -1,11 +1,11 @@
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, BTreeSet};
 use std::sync::Arc;
 use std::time::Instant;
 
 use anyhow::Context;
 use futures_concurrency::future::TryJoin;
 use nullspace_crypt::hash::{BcsHashExt, Hash};
-use nullspace_structs::certificate::CertificateChain;
+use nullspace_structs::certificate::{CertificateChain, DeviceRevocation};
 use nullspace_structs::server::{ServerClient, ServerName, SignedMediumPk};
 use nullspace_structs::username::{UserDescriptor, UserName};
 use tracing::warn;
@@ -13,6 +13,7 @@ use tracing::warn;
 use crate::config::Config;
 use crate::database::DATABASE;
 use crate::directory::DIR_CLIENT;
+use crate::safety::check_verified_root;
 use crate::server::get_server_client;
 
 pub struct UserInfo {
@@ -21,6 +22,8 @@ pub struct UserInfo {
     pub server_name: ServerName,
     pub device_chains: BTreeMap<Hash, CertificateChain>,
     pub medium_pks: BTreeMap<Hash, SignedMediumPk>,
+    /// Hashes of devices that were revoked. Nothing should be encrypted to them.
+    pub revoked: BTreeSet<Hash>,
 }
 
 const USER_CACHE_TTL_SECONDS: i64 = 60;
@@ -56,22 +59,42 @@ pub async fn get_user_info(
 
     let mut cached_device_chains = load_cached_device_chains(db, username).await?;
     let mut cached_medium_pks = load_cached_medium_pks(db, username).await?;
+    let mut cached_revoked = load_cached_revoked(db, username).await?;
     let cached_fetched_at = load_cached_user_info_fetched_at(db, username).await?;
     let cache_fresh = cached_fetched_at.map(is_fresh).unwrap_or(false);
 
     let should_refresh = !cache_fresh || cached_device_chains.is_empty();
 
     if should_refresh {
-        let (chains, medium_pks) = (
+        let (chains, medium_pks, revocations) = (
             fetch_chains(&server, username),
             fetch_medium_pks(&server, username),
+            fetch_revocations(&server, username),
         )
             .try_join()
             .await?;
 
+        // revocations only ever accumulate, so a server cannot bring a revoked device back
+        for (device_hash, revocation) in revocations {
+            if revocation.device_hash != device_hash
+                || revocation.verify_issuer(root_hash).is_err()
+            {
+                warn!(username=%username, device_hash=%device_hash, "invalid device revocation");
+                continue;
+            }
+            cached_revoked.insert(device_hash);
+        }
         cached_device_chains = chains;
         cached_medium_pks = merge_monotonic_medium_pks(username, cached_medium_pks, medium_pks);
-        store_cached_user_info(db, username, &cached_device_chains, &cached_medium_pks).await?;
+        cached_medium_pks.retain(|device_hash, _| !cached_revoked.contains(device_hash));
+        store_cached_user_info(
+            db,
+            username,
+            &cached_device_chains,
+            &cached_medium_pks,
+            &cached_revoked,
+        )
+        .await?;
         tracing::debug!(username=%username, elapsed=debug(start.elapsed()), "refreshed peer info");
     }
 
@@ -105,6 +128,7 @@ pub async fn get_user_info(
         server_name: descriptor.server_name.clone(),
         device_chains,
         medium_pks: cached_medium_pks,
+        revoked: cached_revoked,
     }))
 }
 
@@ -112,7 +136,26 @@ pub async fn get_user_root_hash(
     ctx: &anyctx::AnyCtx<Config>,
     username: &UserName,
 ) -> anyhow::Result<Hash> {
-    Ok(get_user_descriptor(ctx, username).await?.root_cert_hash)
+    let root_hash = get_user_descriptor(ctx, username).await?.root_cert_hash;
+    check_verified_root(ctx, username, root_hash).await?;
+    Ok(root_hash)
+}
+
+/// Drops the cached descriptor and device info of a user, so that the next lookup goes to the
+/// directory and to whatever server it names now. Revocations are kept.
+pub async fn forget_cached_user(
+    conn: &mut sqlx::SqliteConnection,
+    username: &UserName,
+) -> anyhow::Result<()> {
+    sqlx::query("DELETE FROM user_descriptor_cache WHERE username = ?")
+        .bind(username.as_str())
+        .execute(&mut *conn)
+        .await?;
+    sqlx::query("DELETE FROM user_info_cache WHERE username = ?")
+        .bind(username.as_str())
+        .execute(&mut *conn)
+        .await?;
+    Ok(())
 }
 
 async fn fetch_chains(
@@ -136,6 +179,16 @@ async fn fetch_medium_pks(
         .map_err(|err| anyhow::anyhow!(err.to_string()))
 }
 
+async fn fetch_revocations(
+    server: &ServerClient,
+    username: &UserName,
+) -> anyhow::Result<BTreeMap<Hash, DeviceRevocation>> {
+    server
+        .v1_device_revocations(username.clone())
+        .await?
+        .map_err(|err| anyhow::anyhow!(err.to_string()))
+}
+
 fn is_fresh(fetched_at: i64) -> bool {
     now_seconds().saturating_sub(fetched_at) <= USER_CACHE_TTL_SECONDS
 }
@@ -254,11 +307,29 @@ async fn load_cached_medium_pks(
     Ok(medium_pks)
 }
 
+async fn load_cached_revoked(
+    db: &sqlx::SqlitePool,
+    username: &UserName,
+) -> anyhow::Result<BTreeSet<Hash>> {
+    let row = sqlx::query_scalar::<_, Vec<u8>>(
+        "SELECT revoked FROM user_device_revocations_cache WHERE username = ?",
+    )
+    .bind(username.as_str())
+    .fetch_optional(db)
+    .await?;
+    let Some(revoked_bytes) = row else {
+        return Ok(BTreeSet::new());
+    };
+    let revoked = bcs::from_bytes(&revoked_bytes)?;
+    Ok(revoked)
+}
+
 async fn store_cached_user_info(
     db: &sqlx::SqlitePool,
     username: &UserName,
     device_chains: &BTreeMap<Hash, CertificateChain>,
     medium_pks: &BTreeMap<Hash, SignedMediumPk>,
+    revoked: &BTreeSet<Hash>,
 ) -> anyhow::Result<()> {
     let mut tx = db.begin().await?;
 
@@ -279,6 +350,15 @@ async fn store_cached_user_info(
     .execute(tx.as_mut())
     .await?;
 
+    let revoked_bytes = bcs::to_bytes(revoked)?;
+    sqlx::query(
+        "INSERT OR REPLACE INTO user_device_revocations_cache (username, revoked) VALUES (?, ?)",
+    )
+    .bind(username.as_str())
+    .bind(revoked_bytes)
+    .execute(tx.as_mut())
+    .await?;
+
     sqlx::query("INSERT OR REPLACE INTO user_info_cache (username, fetched_at) VALUES (?, ?)")
         .bind(username.as_str())
         .bind(now_seconds())
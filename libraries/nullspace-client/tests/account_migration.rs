@@ -0,0 +1,88 @@
+mod common;
+
+use nullspace_client::internal::MigrationStep;
+use nullspace_crypt::hash::BcsHashExt;
+use nullspace_structs::server::ServerRpcError;
+
+use common::{Network, ServerOptions, wait_until};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn migration_to_an_invite_server_needs_a_code() {
+    let mut net = Network::start().await;
+    let old = net.add_server("~old01").await;
+    let new = net
+        .add_server_with(
+            "~new01",
+            ServerOptions {
+                extra_config: "registration = \"invite\"".into(),
+                ..Default::default()
+            },
+        )
+        .await;
+    let alice = net.add_client("@alice01", &old).await;
+    let bob = net.add_client("@bob01", &old).await;
+
+    let chains = net
+        .server(&old)
+        .rpc()
+        .v1_device_certs(alice.username.clone())
+        .await
+        .expect("transport")
+        .expect("device certs")
+        .expect("alice has devices");
+    let (device_hash, chain) = chains.into_iter().next().expect("a device");
+
+    // the directory still names the old server, so the new one turns the device away...
+    let new_rpc = net.server(&new).rpc();
+    let denied = new_rpc
+        .v1_device_auth(alice.username.clone(), chain.clone())
+        .await
+        .expect("transport");
+    assert!(matches!(denied, Err(ServerRpcError::AccessDenied)));
+    // ...and only takes a migration under its registration policy
+    let denied = new_rpc
+        .v1_migration_prepare(alice.username.clone(), chain.clone(), None)
+        .await
+        .expect("transport");
+    assert!(matches!(denied, Err(ServerRpcError::AccessDenied)));
+
+    let code = net.server(&new).admin(&["invite-create"]);
+    alice
+        .rpc
+        .account_migrate(new.clone(), Some(code.trim().into()))
+        .await
+        .expect("transport")
+        .expect("start migration");
+    wait_until("alice to switch servers", || async {
+        let status = alice
+            .rpc
+            .account_migration_status()
+            .await
+            .expect("transport")
+            .expect("migration status");
+        status.is_some_and(|status| {
+            matches!(
+                status.step,
+                MigrationStep::DrainOldMailbox | MigrationStep::Announce | MigrationStep::Done
+            )
+        })
+    })
+    .await;
+
+    let at_new = new_rpc
+        .v1_device_certs(alice.username.clone())
+        .await
+        .expect("transport")
+        .expect("device certs")
+        .unwrap_or_default();
+    assert_eq!(
+        at_new.get(&device_hash).map(|chain| chain.last_device().pk.bcs_hash()),
+        Some(device_hash)
+    );
+
+    // alice now talks through the new server
+    let convo = alice.dm(&bob);
+    alice.send_text(&convo, "from the new server").await;
+    bob.wait_for_text(&bob.dm(&alice), &alice, "from the new server")
+        .await;
+}
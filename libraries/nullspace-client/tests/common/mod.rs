@@ -0,0 +1,494 @@
+//! A throwaway nullspace network for end-to-end tests: an in-process directory, real server
+//! processes, and in-process clients, all on loopback ports under one temporary directory.
+//!
+//! Cargo only builds the binaries of the package under test, so the server binary is built on
+//! first use, with the same profile as the test.
+
+#![allow(dead_code)]
+
+use std::future::Future;
+use std::net::{SocketAddr, TcpListener as StdTcpListener};
+use std::path::{Path, PathBuf};
+use std::process::{Child, Command, Stdio};
+use std::sync::OnceLock;
+use std::time::{Duration, Instant};
+
+use nullspace_client::internal::{
+    ConvoId, ConvoMessage, GroupMemberStatus, InternalClient, InternalRpcError, MessageContent,
+    RegisterFinish,
+};
+use nullspace_client::{Client, Config};
+use nullspace_crypt::signing::{SigningPublic, SigningSecret};
+use nullspace_directory::DirectoryRole;
+use nullspace_rpc_pool::RpcPool;
+use nullspace_structs::directory::DirectoryClient;
+use nullspace_structs::group::GroupId;
+use nullspace_structs::server::{ServerClient, ServerName, ServerRpcError};
+use nullspace_structs::username::UserName;
+use tempfile::TempDir;
+use url::Url;
+
+/// How long a test waits for something to show up before giving up.
+pub const PATIENCE: Duration = Duration::from_secs(60);
+
+pub struct Network {
+    root: TempDir,
+    dir_url: Url,
+    dir_pk: SigningPublic,
+    servers: Vec<TestServer>,
+}
+
+pub struct TestServer {
+    pub name: ServerName,
+    pub db_path: PathBuf,
+    /// The HTTP endpoint, which is always served.
+    pub http_url: Url,
+    config_path: PathBuf,
+    child: Child,
+}
+
+pub struct TestClient {
+    pub username: UserName,
+    pub db_path: PathBuf,
+    pub rpc: InternalClient,
+    _client: Client,
+}
+
+/// Extra settings for a test server.
+#[derive(Default)]
+pub struct ServerOptions {
+    /// Advertises only the HTTP endpoint, so clients cannot open a push stream.
+    pub http_only: bool,
+    /// Appended verbatim to the generated config.
+    pub extra_config: String,
+}
+
+impl Network {
+    /// Starts a directory that commits a chunk every few hundred milliseconds and asks for only
+    /// token proofs of work.
+    pub async fn start() -> Self {
+        let root = tempfile::tempdir().expect("temp dir");
+        let secret_key = SigningSecret::random();
+        let dir_pk = secret_key.public_key();
+        let listener = tokio::net::TcpListener::bind("127.0.0.1:0")
+            .await
+            .expect("bind directory");
+        let dir_url = Url::parse(&format!(
+            "http://{}/",
+            listener.local_addr().expect("directory addr")
+        ))
+        .expect("directory url");
+        let db_path = root.path().join("directory");
+        tokio::spawn(async move {
+            let role = DirectoryRole::Anchor {
+                secret_key: Box::new(secret_key),
+                chunk_interval: Duration::from_millis(200),
+                pow_effort: 1,
+            };
+            if let Err(err) = nullspace_directory::serve(listener, &db_path, role).await {
+                panic!("directory failed: {err:?}");
+            }
+        });
+        // servers refuse to start against a directory that has no anchor yet
+        let dir = DirectoryClient(RpcPool::new().rpc(dir_url.clone()));
+        wait_until("the directory to commit its first chunk", || async {
+            matches!(dir.v1_get_anchor().await, Ok(Ok(_)))
+        })
+        .await;
+        Self {
+            root,
+            dir_url,
+            dir_pk,
+            servers: Vec::new(),
+        }
+    }
+
+    pub fn root(&self) -> &Path {
+        self.root.path()
+    }
+
+    pub async fn add_server(&mut self, name: &str) -> ServerName {
+        self.add_server_with(name, ServerOptions::default()).await
+    }
+
+    /// Spawns a server process and waits until it registered with the directory and listens.
+    pub async fn add_server_with(&mut self, name: &str, options: ServerOptions) -> ServerName {
+        let server_name = ServerName::parse(name).expect("server name");
+        let dir = self.root.path().join(name.trim_start_matches('~'));
+        std::fs::create_dir_all(dir.join("fragments")).expect("server dir");
+        let listen = free_addr();
+        let lz4_listen = free_addr();
+        let public_url = if options.http_only {
+            format!("http://{listen}/")
+        } else {
+            format!("lz4tcp://{lz4_listen}")
+        };
+        let db_path = dir.join("server.db");
+        let config = format!(
+            "listen = \"{listen}\"\n\
+             lz4_listen = \"{lz4_listen}\"\n\
+             db_path = \"{db}\"\n\
+             fragments_path = \"{fragments}\"\n\
+             signing_sk = \"{signing_sk}\"\n\
+             server_name = \"{server_name}\"\n\
+             public_urls = [\"{public_url}\"]\n\
+             directory_url = \"{dir_url}\"\n\
+             directory_pk = \"{dir_pk}\"\n\
+             proxy_enabled = true\n\
+             {extra}\n",
+            db = db_path.display(),
+            fragments = dir.join("fragments").display(),
+            signing_sk = dir.join("signing.key").display(),
+            dir_url = self.dir_url,
+            dir_pk = self.dir_pk,
+            extra = options.extra_config,
+        );
+        let config_path = dir.join("server.toml");
+        std::fs::write(&config_path, config).expect("write server config");
+
+        // the server logs to stdout
+        let log = if std::env::var_os("RUST_LOG").is_some() {
+            Stdio::inherit()
+        } else {
+            Stdio::null()
+        };
+        let child = Command::new(server_bin())
+            .arg("--config")
+            .arg(&config_path)
+            .stdout(log)
+            .spawn()
+            .expect("spawn server");
+        let mut server = TestServer {
+            name: server_name.clone(),
+            db_path,
+            http_url: Url::parse(&format!("http://{listen}/")).expect("server url"),
+            config_path,
+            child,
+        };
+        let deadline = Instant::now() + PATIENCE;
+        loop {
+            if let Some(status) = server.child.try_wait().expect("poll server") {
+                panic!("server {name} exited early: {status}");
+            }
+            if tokio::net::TcpStream::connect(listen).await.is_ok()
+                && tokio::net::TcpStream::connect(lz4_listen).await.is_ok()
+            {
+                break;
+            }
+            assert!(Instant::now() < deadline, "server {name} never started");
+            tokio::time::sleep(Duration::from_millis(100)).await;
+        }
+        self.servers.push(server);
+        server_name
+    }
+
+    pub fn server(&self, name: &ServerName) -> &TestServer {
+        self.servers
+            .iter()
+            .find(|server| &server.name == name)
+            .expect("unknown test server")
+    }
+
+    /// Starts a client and registers a new username at `server`.
+    pub async fn add_client(&self, username: &str, server: &ServerName) -> TestClient {
+        self.add_client_with_invite(username, server, None).await
+    }
+
+    pub async fn add_client_with_invite(
+        &self,
+        username: &str,
+        server: &ServerName,
+        invite_code: Option<&str>,
+    ) -> TestClient {
+        let client = self.start_client(username);
+        retry_later("register", || async {
+            client
+                .rpc
+                .register_finish(RegisterFinish::BootstrapNewUser {
+                    username: client.username.clone(),
+                    server_name: server.clone(),
+                    invite_code: invite_code.map(Into::into),
+                })
+                .await
+                .expect("transport")
+        })
+        .await;
+        client
+    }
+
+    /// Starts a client with an empty database, without registering anything.
+    pub fn start_client(&self, username: &str) -> TestClient {
+        let username = UserName::parse(username).expect("username");
+        let name = username.as_str().trim_start_matches('@');
+        let mut db_path = self.root.path().join(format!("{name}.db"));
+        let mut n = 1;
+        while db_path.exists() {
+            n += 1;
+            db_path = self.root.path().join(format!("{name}-{n}.db"));
+        }
+        let client = Client::new(Config {
+            db_path: db_path.clone(),
+            dir_endpoint: self.dir_url.clone(),
+            dir_anchor_pk: self.dir_pk,
+            medium_key_retention_secs: nullspace_client::default_medium_key_retention_secs(),
+            rpc_socket: None,
+        });
+        TestClient {
+            username,
+            db_path,
+            rpc: client.rpc(),
+            _client: client,
+        }
+    }
+}
+
+impl Drop for TestServer {
+    fn drop(&mut self) {
+        let _ = self.child.kill();
+        let _ = self.child.wait();
+    }
+}
+
+impl TestServer {
+    /// Opens the database of the server, to check what it stores.
+    pub async fn db(&self) -> sqlx::SqlitePool {
+        open_db(&self.db_path).await
+    }
+
+    /// Talks to the server directly, without a client in between.
+    pub fn rpc(&self) -> ServerClient {
+        ServerClient::from(RpcPool::new().rpc(self.http_url.clone()))
+    }
+
+    /// Runs an admin command against the server database, returning what it prints.
+    pub fn admin(&self, args: &[&str]) -> String {
+        let output = Command::new(server_bin())
+            .arg("--config")
+            .arg(&self.config_path)
+            .args(args)
+            .output()
+            .expect("run admin command");
+        assert!(output.status.success(), "admin command {args:?} failed");
+        String::from_utf8(output.stdout).expect("admin output")
+    }
+}
+
+impl TestClient {
+    /// Opens the database of the client, to check what it stores.
+    pub async fn db(&self) -> sqlx::SqlitePool {
+        open_db(&self.db_path).await
+    }
+
+    pub fn dm(&self, peer: &TestClient) -> ConvoId {
+        ConvoId::Direct {
+            peer: peer.username.clone(),
+        }
+    }
+
+    pub async fn send_text(&self, convo: &ConvoId, text: &str) -> i64 {
+        self.rpc
+            .convo_send(
+                convo.clone(),
+                nullspace_client::internal::OutgoingMessage::PlainText(text.into()),
+            )
+            .await
+            .expect("transport")
+            .expect("send")
+    }
+
+    pub async fn history(&self, convo: &ConvoId) -> Vec<ConvoMessage> {
+        self.rpc
+            .convo_history(convo.clone(), None, None, 1000)
+            .await
+            .expect("transport")
+            .unwrap_or_default()
+    }
+
+    /// Waits until a message with this text from `sender` is in the convo.
+    pub async fn wait_for_text(&self, convo: &ConvoId, sender: &TestClient, text: &str) -> i64 {
+        wait_for(&format!("{} to see {text:?}", self.username), || async {
+            self.history(convo)
+                .await
+                .into_iter()
+                .find(|msg| {
+                    msg.sender == sender.username
+                        && matches!(&msg.body, MessageContent::PlainText(body) if body == text)
+                })
+                .map(|msg| msg.id)
+        })
+        .await
+    }
+
+    pub async fn has_text(&self, convo: &ConvoId, text: &str) -> bool {
+        self.history(convo)
+            .await
+            .iter()
+            .any(|msg| matches!(&msg.body, MessageContent::PlainText(body) if body == text))
+    }
+}
+
+/// Polls `check` until it holds, panicking after [`PATIENCE`].
+pub async fn wait_until<F, Fut>(what: &str, mut check: F)
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = bool>,
+{
+    wait_for(what, || {
+        let fut = check();
+        async move { fut.await.then_some(()) }
+    })
+    .await
+}
+
+/// Polls `check` until it returns something, panicking after [`PATIENCE`].
+pub async fn wait_for<T, F, Fut>(what: &str, mut check: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Option<T>>,
+{
+    let deadline = Instant::now() + PATIENCE;
+    loop {
+        if let Some(found) = check().await {
+            return found;
+        }
+        if Instant::now() > deadline {
+            panic!("timed out waiting for {what}");
+        }
+        tokio::time::sleep(Duration::from_millis(100)).await;
+    }
+}
+
+/// Runs a client call again while the server asks to retry later, which it does when concurrent
+/// writes collide on its database.
+pub async fn retry_later<T, F, Fut>(what: &str, mut call: F) -> T
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<T, InternalRpcError>>,
+{
+    let retry_later = ServerRpcError::RetryLater.to_string();
+    wait_for(what, || {
+        let fut = call();
+        let retry_later = &retry_later;
+        async move {
+            match fut.await {
+                Ok(found) => Some(found),
+                Err(InternalRpcError::Other(err)) if &err == retry_later => None,
+                Err(err) => panic!("{what}: {err}"),
+            }
+        }
+    })
+    .await
+}
+
+async fn open_db(path: &Path) -> sqlx::SqlitePool {
+    let options = sqlx::sqlite::SqliteConnectOptions::new()
+        .filename(path)
+        .read_only(true);
+    sqlx::SqlitePool::connect_with(options)
+        .await
+        .expect("open database")
+}
+
+fn server_bin() -> &'static Path {
+    static SERVER_BIN: OnceLock<PathBuf> = OnceLock::new();
+    SERVER_BIN.get_or_init(|| {
+        // tests run from <target>/<profile>/deps
+        let test_exe = std::env::current_exe().expect("test executable");
+        let profile_dir = test_exe
+            .parent()
+            .and_then(Path::parent)
+            .expect("profile directory");
+        let profile = match profile_dir.file_name().and_then(|name| name.to_str()) {
+            Some("debug") => "dev",
+            Some(other) => other,
+            None => panic!("unexpected target layout"),
+        };
+        // the server alone resolves dependency features differently from these tests, so sharing
+        // a target directory would rebuild both every time
+        let target_dir = profile_dir
+            .parent()
+            .expect("target directory")
+            .join("e2e-server");
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        let status = Command::new(cargo)
+            .args(["build", "--quiet", "--package", "nullspace-server"])
+            .args(["--bin", "nullspace-server", "--profile", profile])
+            .arg("--target-dir")
+            .arg(&target_dir)
+            .status()
+            .expect("run cargo build");
+        assert!(status.success(), "building the server failed");
+        target_dir
+            .join(profile_dir.file_name().expect("profile name"))
+            .join(format!("nullspace-server{}", std::env::consts::EXE_SUFFIX))
+    })
+}
+
+fn free_addr() -> SocketAddr {
+    StdTcpListener::bind("127.0.0.1:0")
+        .and_then(|listener| listener.local_addr())
+        .expect("free port")
+}
+
+/// Creates a group at the own server of `admin`.
+pub async fn create_group(admin: &TestClient) -> GroupId {
+    let server = admin
+        .rpc
+        .own_server()
+        .await
+        .expect("transport")
+        .expect("own server");
+    let convo = retry_later("create group", || async {
+        admin
+            .rpc
+            .convo_create_group(server.clone())
+            .await
+            .expect("transport")
+    })
+    .await;
+    match convo {
+        ConvoId::Group { group_id } => group_id,
+        other => panic!("created a group but got {other:?}"),
+    }
+}
+
+/// Invites `member` into the group and waits until the admin sees them accepted.
+pub async fn join_group(admin: &TestClient, member: &TestClient, group: GroupId) {
+    retry_later("invite", || async {
+        admin
+            .rpc
+            .group_invite(group, member.username.clone())
+            .await
+            .expect("transport")
+    })
+    .await;
+    let dm = member.dm(admin);
+    let invite_id = wait_for(&format!("{} to get an invite", member.username), || async {
+        member
+            .history(&dm)
+            .await
+            .into_iter()
+            .find(|msg| matches!(msg.body, MessageContent::GroupInvite { .. }))
+            .map(|msg| msg.id)
+    })
+    .await;
+    retry_later("accept invite", || async {
+        member
+            .rpc
+            .group_accept_invite(invite_id)
+            .await
+            .expect("transport")
+    })
+    .await;
+    wait_until(&format!("{} to join", member.username), || async {
+        admin
+            .rpc
+            .group_members(group)
+            .await
+            .expect("transport")
+            .unwrap_or_default()
+            .iter()
+            .any(|m| m.username == member.username && m.status == GroupMemberStatus::Accepted)
+    })
+    .await;
+}
@@ -0,0 +1,131 @@
+mod common;
+
+use nullspace_client::internal::ConvoId;
+use nullspace_crypt::hash::BcsHashExt;
+use nullspace_structs::group::{GroupId, GroupMove};
+use nullspace_structs::server::{AuthToken, MailboxAcl, MailboxId, ServerRpcError};
+
+use common::{Network, TestClient, TestServer, create_group, join_group, retry_later, wait_until};
+
+/// The server a client has the group at.
+async fn group_server(client: &TestClient, group: GroupId) -> Option<String> {
+    sqlx::query_scalar("SELECT server_name FROM groups WHERE group_id = ?")
+        .bind(group.as_bytes().to_vec())
+        .fetch_optional(&client.db().await)
+        .await
+        .expect("group server")
+}
+
+async fn last_move(client: &TestClient, group: GroupId) -> GroupMove {
+    let bytes: Vec<u8> = sqlx::query_scalar("SELECT last_move FROM groups WHERE group_id = ?")
+        .bind(group.as_bytes().to_vec())
+        .fetch_one(&client.db().await)
+        .await
+        .expect("last move");
+    bcs::from_bytes(&bytes).expect("decode last move")
+}
+
+async fn stored_messages(server: &TestServer, group: GroupId) -> i64 {
+    sqlx::query_scalar("SELECT COUNT(*) FROM mailbox_entries WHERE mailbox_id = ?")
+        .bind(MailboxId::group_messages(&group).to_bytes().to_vec())
+        .fetch_one(&server.db().await)
+        .await
+        .expect("stored messages")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn group_moves_between_servers() {
+    let mut net = Network::start().await;
+    let old = net.add_server("~gmold01").await;
+    let new = net.add_server("~gmnew01").await;
+    let admin = net.add_client("@gmadmin01", &old).await;
+    let mover = net.add_client("@gmmover01", &new).await;
+    let member = net.add_client("@gmmember01", &old).await;
+
+    let group = create_group(&admin).await;
+    join_group(&admin, &mover, group).await;
+    join_group(&admin, &member, group).await;
+    retry_later("promote", || async {
+        admin
+            .rpc
+            .group_promote(group, mover.username.clone())
+            .await
+            .expect("transport")
+    })
+    .await;
+    wait_until("the mover to become an admin", || async {
+        mover
+            .rpc
+            .group_members(group)
+            .await
+            .expect("transport")
+            .unwrap_or_default()
+            .iter()
+            .any(|m| m.username == mover.username && m.is_admin)
+    })
+    .await;
+
+    let convo = ConvoId::Group { group_id: group };
+    admin.send_text(&convo, "before the move").await;
+    for client in [&mover, &member] {
+        client.wait_for_text(&convo, &admin, "before the move").await;
+    }
+
+    let at_old = stored_messages(net.server(&old), group).await;
+
+    // the member's token was issued by the other admin, so it gets to the new server through the
+    // move token
+    retry_later("move", || async {
+        mover
+            .rpc
+            .group_move(group, new.clone())
+            .await
+            .expect("transport")
+    })
+    .await;
+    for client in [&admin, &mover, &member] {
+        wait_until(&format!("{} to follow the move", client.username), || async {
+            group_server(client, group).await.as_deref() == Some(new.as_str())
+        })
+        .await;
+    }
+
+    member.send_text(&convo, "after the move").await;
+    for client in [&admin, &mover] {
+        client.wait_for_text(&convo, &member, "after the move").await;
+    }
+    admin.send_text(&convo, "reply after the move").await;
+    member
+        .wait_for_text(&convo, &admin, "reply after the move")
+        .await;
+    for client in [&admin, &mover, &member] {
+        assert!(client.has_text(&convo, "before the move").await);
+    }
+
+    // nothing new went to the old server
+    assert_eq!(stored_messages(net.server(&old), group).await, at_old);
+
+    // once someone leaves, the move token can no longer register anyone at the new server
+    let moved = last_move(&mover, group).await;
+    retry_later("leave", || async {
+        member.rpc.group_leave(group).await.expect("transport")
+    })
+    .await;
+    let new_rpc = net.server(&new).rpc();
+    wait_until("the move token to be revoked", || async {
+        let acl = MailboxAcl {
+            token_hash: AuthToken::random().bcs_hash(),
+            can_edit_acl: false,
+            can_send: true,
+            can_recv: true,
+        };
+        matches!(
+            new_rpc
+                .v1_mailbox_acl_edit(moved.token, MailboxId::group_messages(&group), acl)
+                .await
+                .expect("transport"),
+            Err(ServerRpcError::AccessDenied)
+        )
+    })
+    .await;
+}
@@ -0,0 +1,101 @@
+mod common;
+
+use nullspace_client::internal::ConvoId;
+use nullspace_crypt::aead::AeadKey;
+use nullspace_structs::Blob;
+use nullspace_structs::group::{GroupId, GroupMessage};
+use nullspace_structs::server::MailboxId;
+
+use common::{Network, TestClient, create_group, join_group, retry_later, wait_until};
+
+/// Every group message key a client holds for the group.
+async fn group_keys(client: &TestClient, group: GroupId) -> Vec<AeadKey> {
+    let db = client.db().await;
+    let mut keys: Vec<Vec<u8>> =
+        sqlx::query_scalar("SELECT group_key FROM group_keys WHERE group_id = ?")
+            .bind(group.as_bytes().to_vec())
+            .fetch_all(&db)
+            .await
+            .expect("group keys");
+    keys.extend(
+        sqlx::query_scalar::<_, Vec<u8>>("SELECT group_key_current FROM groups WHERE group_id = ?")
+            .bind(group.as_bytes().to_vec())
+            .fetch_optional(&db)
+            .await
+            .expect("current group key"),
+    );
+    keys.iter()
+        .map(|key| bcs::from_bytes(key).expect("decode group key"))
+        .collect()
+}
+
+async fn current_group_key(client: &TestClient, group: GroupId) -> Vec<u8> {
+    sqlx::query_scalar("SELECT group_key_current FROM groups WHERE group_id = ?")
+        .bind(group.as_bytes().to_vec())
+        .fetch_one(&client.db().await)
+        .await
+        .expect("current group key")
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn banned_member_cannot_decrypt_later_messages() {
+    let mut net = Network::start().await;
+    let server = net.add_server("~rekey01").await;
+    let admin = net.add_client("@admin01", &server).await;
+    let member = net.add_client("@member01", &server).await;
+    let removed = net.add_client("@removed01", &server).await;
+
+    let group = create_group(&admin).await;
+    join_group(&admin, &member, group).await;
+    join_group(&admin, &removed, group).await;
+    let convo = ConvoId::Group { group_id: group };
+
+    admin.send_text(&convo, "before the ban").await;
+    member.wait_for_text(&convo, &admin, "before the ban").await;
+    removed.wait_for_text(&convo, &admin, "before the ban").await;
+
+    let removed_key = current_group_key(&removed, group).await;
+    retry_later("ban", || async {
+        admin
+            .rpc
+            .group_ban(group, removed.username.clone())
+            .await
+            .expect("transport")
+    })
+    .await;
+    wait_until("the admin to rekey", || async {
+        current_group_key(&admin, group).await != removed_key
+    })
+    .await;
+
+    admin.send_text(&convo, "after the ban").await;
+    member.wait_for_text(&convo, &admin, "after the ban").await;
+    assert!(!removed.has_text(&convo, "after the ban").await);
+
+    // the removed member never got the new key, so even with the ciphertext in hand, it cannot
+    // read the message
+    let db = net.server(&server).db().await;
+    let (kind, body): (String, Vec<u8>) = sqlx::query_as(
+        "SELECT message_kind, message_body FROM mailbox_entries \
+         WHERE mailbox_id = ? ORDER BY received_at DESC LIMIT 1",
+    )
+    .bind(MailboxId::group_messages(&group).to_bytes().to_vec())
+    .fetch_one(&db)
+    .await
+    .expect("last group message");
+    assert_eq!(kind, Blob::V1_GROUP_MESSAGE);
+    let message: GroupMessage = bcs::from_bytes(&body).expect("decode group message");
+    assert!(
+        group_keys(&member, group)
+            .await
+            .iter()
+            .any(|key| message.decrypt_message(key).is_ok())
+    );
+    let removed_keys = group_keys(&removed, group).await;
+    assert!(!removed_keys.is_empty());
+    assert!(
+        removed_keys
+            .iter()
+            .all(|key| message.decrypt_message(key).is_err())
+    );
+}
@@ -0,0 +1,71 @@
+mod common;
+
+use nullspace_client::internal::{ConvoMessage, MessageContent};
+
+use common::{Network, retry_later, wait_until};
+
+fn reactions_of(history: &[ConvoMessage], pick: impl Fn(&ConvoMessage) -> bool) -> Vec<String> {
+    history
+        .iter()
+        .find(|msg| pick(msg))
+        .map(|msg| {
+            msg.reactions
+                .iter()
+                .map(|summary| format!("{} x{}", summary.emoji, summary.users.len()))
+                .collect()
+        })
+        .unwrap_or_default()
+}
+
+#[tokio::test(flavor = "multi_thread")]
+async fn history_shows_reactions_of_every_message() {
+    let mut net = Network::start().await;
+    let server = net.add_server("~react01").await;
+    let alice = net.add_client("@alice01", &server).await;
+    let bob = net.add_client("@bob01", &server).await;
+
+    let convo = alice.dm(&bob);
+    let first = alice.send_text(&convo, "first").await;
+    let second = alice.send_text(&convo, "second").await;
+    alice.send_text(&convo, "third").await;
+    for (message, emoji) in [(first, "👍"), (first, "🎉"), (second, "❤")] {
+        retry_later("react", || async {
+            alice
+                .rpc
+                .convo_react(message, emoji.into(), true)
+                .await
+                .expect("transport")
+        })
+        .await;
+    }
+
+    // reactions apply locally once they are sent
+    wait_until("alice to see the reactions", || async {
+        let history = alice.history(&convo).await;
+        reactions_of(&history, |msg| msg.id == first) == ["👍 x1", "🎉 x1"]
+            && reactions_of(&history, |msg| msg.id == second) == ["❤ x1"]
+    })
+    .await;
+    assert!(
+        alice
+            .history(&convo)
+            .await
+            .iter()
+            .filter(|msg| msg.id != first && msg.id != second)
+            .all(|msg| msg.reactions.is_empty())
+    );
+
+    // the peer attaches them to its own copies of the messages
+    let peer_convo = bob.dm(&alice);
+    bob.wait_for_text(&peer_convo, &alice, "third").await;
+    wait_until("bob to see the reactions", || async {
+        let history = bob.history(&peer_convo).await;
+        reactions_of(&history, |msg| is_text(msg, "first")) == ["👍 x1", "🎉 x1"]
+            && reactions_of(&history, |msg| is_text(msg, "second")) == ["❤ x1"]
+    })
+    .await;
+}
+
+fn is_text(msg: &ConvoMessage, text: &str) -> bool {
+    matches!(&msg.body, MessageContent::PlainText(body) if body == text)
+}
@@ -0,0 +1,84 @@
+mod common;
+
+use nullspace_client::internal::{InternalRpcError, RegisterFinish};
+use nullspace_structs::server::ServerRpcError;
+use nullspace_structs::timestamp::Timestamp;
+
+use common::{Network, ServerOptions, retry_later, wait_for};
+
+#[tokio::test(flavor = "multi_thread")]
+async fn disallowed_user_keeps_devices_but_cannot_add_more() {
+    let mut net = Network::start().await;
+    let server = net
+        .add_server_with(
+            "~gate01",
+            ServerOptions {
+                extra_config: "registration = \"allowlist\"".into(),
+                ..Default::default()
+            },
+        )
+        .await;
+    net.server(&server).admin(&["allow", "@alice01"]);
+    let alice = net.add_client("@alice01", &server).await;
+    net.server(&server).admin(&["disallow", "@alice01"]);
+
+    // the device alice already has still authenticates...
+    let rpc = net.server(&server).rpc();
+    let chains = rpc
+        .v1_device_certs(alice.username.clone())
+        .await
+        .expect("transport")
+        .expect("device certs")
+        .expect("alice has devices");
+    let (_, chain) = chains.into_iter().next().expect("a device");
+    wait_for("the existing device to authenticate", || async {
+        match rpc
+            .v1_device_auth(alice.username.clone(), chain.clone())
+            .await
+            .expect("transport")
+        {
+            Ok(token) => Some(token),
+            Err(ServerRpcError::RetryLater) => None,
+            Err(err) => panic!("existing device refused: {err}"),
+        }
+    })
+    .await;
+
+    // ...but a new one is turned away until she is allowed again
+    let bundle = alice
+        .rpc
+        .new_device_bundle(false, Timestamp(u64::MAX))
+        .await
+        .expect("transport")
+        .expect("device bundle");
+    let laptop = net.start_client("@alice01");
+    let retry = ServerRpcError::RetryLater.to_string();
+    let denied = wait_for("the new device to be refused", || async {
+        let result = laptop
+            .rpc
+            .register_finish(RegisterFinish::AddDevice {
+                bundle: bundle.clone(),
+            })
+            .await
+            .expect("transport");
+        match result {
+            Ok(()) => panic!("new device authenticated while disallowed"),
+            Err(InternalRpcError::Other(err)) if err == retry => None,
+            Err(err) => Some(err),
+        }
+    })
+    .await;
+    assert_eq!(denied.to_string(), ServerRpcError::AccessDenied.to_string());
+
+    net.server(&server).admin(&["allow", "@alice01"]);
+    retry_later("the new device to authenticate", || async {
+        laptop
+            .rpc
+            .register_finish(RegisterFinish::AddDevice {
+                bundle: bundle.clone(),
+            })
+            .await
+            .expect("transport")
+    })
+    .await;
+}
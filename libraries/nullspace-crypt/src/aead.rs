@@ -21,7 +21,7 @@ pub enum AeadError {
 
 /// XChaCha20-Poly1305 key used for symmetric encryption and decryption.
 #[serde_as]
-#[derive(Clone, PartialEq, Eq, Serialize, Deserialize, Derivative, Hash)]
+#[derive(Clone, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, Derivative, Hash)]
 #[derivative(Debug)]
 pub struct AeadKey(
     #[derivative(Debug(format_with = "redacted_debug"))]
@@ -18,7 +18,7 @@ use nullspace_structs::{
 use sqlx::SqlitePool;
 use std::time::{Duration, Instant};
 mod header_sync;
-mod pow;
+pub mod pow;
 
 /// High-level directory client with local header storage and proof checks.
 pub struct DirClient {
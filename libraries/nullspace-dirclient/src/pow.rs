@@ -1,6 +1,6 @@
 use std::time::Instant;
 
-use equix::EquiXBuilder;
+use equix::{EquiXBuilder, SolutionByteArray};
 use rand::RngCore;
 
 use nullspace_crypt::hash::Hash;
@@ -12,6 +12,40 @@ pub fn solve_pow(seed: &PowSeed) -> anyhow::Result<PowSolution> {
     }
 }
 
+/// Checks a solution against the seed it claims to solve. This does not check that the seed was
+/// issued by us, or that it is still fresh; that is up to the caller.
+pub fn verify_pow(seed: &PowSeed, solution: &PowSolution) -> anyhow::Result<()> {
+    if seed.seed != solution.seed {
+        anyhow::bail!("seed mismatch");
+    }
+    match seed.algo {
+        PowAlgo::EquiX { effort } => verify_equix_pow(effort, solution),
+    }
+}
+
+fn verify_equix_pow(effort: u64, solution: &PowSolution) -> anyhow::Result<()> {
+    let eq = EquiXBuilder::new();
+    let challenge = Hash::keyed_digest(&solution.seed.to_bytes(), &solution.nonce.to_be_bytes());
+    let bytes: SolutionByteArray = solution
+        .solution
+        .as_ref()
+        .try_into()
+        .map_err(|_| anyhow::anyhow!("invalid solution length"))?;
+    eq.verify_bytes(&challenge.to_bytes(), &bytes)
+        .map_err(|_| anyhow::anyhow!("invalid equix solution"))?;
+    if !meets_effort(&bytes, effort) {
+        anyhow::bail!("insufficient effort");
+    }
+    Ok(())
+}
+
+fn meets_effort(solution: &[u8], effort: u64) -> bool {
+    let sol_hash = Hash::digest(solution);
+    let mut first = [0u8; 8];
+    first.copy_from_slice(&sol_hash.to_bytes()[..8]);
+    u64::from_be_bytes(first).checked_mul(effort).is_some()
+}
+
 fn solve_equix_pow(seed: &PowSeed, effort: u64) -> anyhow::Result<PowSolution> {
     tracing::debug!(effort, "solving an equix PoW...");
     let start = Instant::now();
@@ -28,11 +62,7 @@ fn solve_equix_pow(seed: &PowSeed, effort: u64) -> anyhow::Result<PowSolution> {
         };
         for solution in solutions {
             let bytes = solution.to_bytes();
-            let sol_hash = Hash::digest(&bytes);
-            let mut first = [0u8; 8];
-            first.copy_from_slice(&sol_hash.to_bytes()[..8]);
-            let value = u64::from_be_bytes(first);
-            if value.checked_mul(effort).is_some() {
+            if meets_effort(&bytes, effort) {
                 tracing::debug!(
                     effort,
                     elapsed = debug(start.elapsed()),
@@ -67,6 +97,11 @@ mod tests {
         let solution = solve_pow(&seed).expect("solve pow");
         assert_eq!(solution.seed, seed.seed);
         assert_eq!(solution.solution.len(), equix::Solution::NUM_BYTES);
-        eprintln!("{:?}", start.elapsed())
+        verify_pow(&seed, &solution).expect("verify pow");
+        eprintln!("{:?}", start.elapsed());
+
+        let mut tampered = solution.clone();
+        tampered.nonce = tampered.nonce.wrapping_add(1);
+        assert!(verify_pow(&seed, &tampered).is_err());
     }
 }
@@ -2,15 +2,21 @@ use std::sync::{Arc, atomic::AtomicU64};
 
 use async_trait::async_trait;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use nullspace_crypt::signing::SigningPublic;
+use tokio::sync::broadcast;
 use url::Url;
 
 mod http;
+mod noise;
+mod notify;
 mod tcp;
+mod ws;
 
 pub(crate) const REQUEST_TIMEOUT_SECS: u64 = 600;
 pub(crate) const MAX_MESSAGE_BYTES: usize = 1024 * 1024;
 
-pub use tcp::{serve_lz4tcp, serve_tcp};
+pub use notify::{NotificationSink, TransportEvent, notification_sink};
+pub use tcp::{serve_lz4tcp, serve_noise_tcp, serve_tcp, serve_unix, serve_ws};
 
 #[derive(Clone)]
 pub struct Transport {
@@ -21,6 +27,16 @@ pub struct Transport {
 
 impl Transport {
     pub fn new(endpoint: Url) -> Self {
+        Self::with_server_pk(endpoint, None)
+    }
+
+    /// Like `new`, but over `noise+tcp` the server has to prove that it holds the secret key of
+    /// `server_pk`. The other schemes have nothing to check the key against.
+    pub fn new_pinned(endpoint: Url, server_pk: SigningPublic) -> Self {
+        Self::with_server_pk(endpoint, Some(server_pk))
+    }
+
+    fn with_server_pk(endpoint: Url, server_pk: Option<SigningPublic>) -> Self {
         match endpoint.scheme() {
             "http" | "https" => Self {
                 endpoint: endpoint.clone(),
@@ -37,9 +53,38 @@ impl Transport {
                 inner: TransportInner::Tcp(tcp::RawTcpClient::new_lz4(endpoint)),
                 inflight: Default::default(),
             },
+            "unix" => Self {
+                endpoint: endpoint.clone(),
+                inner: TransportInner::Tcp(tcp::RawTcpClient::new_unix(endpoint)),
+                inflight: Default::default(),
+            },
+            "lz4+unix" => Self {
+                endpoint: endpoint.clone(),
+                inner: TransportInner::Tcp(tcp::RawTcpClient::new_lz4unix(endpoint)),
+                inflight: Default::default(),
+            },
+            "ws" | "wss" => Self {
+                endpoint: endpoint.clone(),
+                inner: TransportInner::Tcp(tcp::RawTcpClient::new_ws(endpoint)),
+                inflight: Default::default(),
+            },
+            "noise+tcp" => Self {
+                endpoint: endpoint.clone(),
+                inner: TransportInner::Tcp(tcp::RawTcpClient::new_noise(endpoint, server_pk)),
+                inflight: Default::default(),
+            },
             scheme => panic!("unsupported RPC endpoint scheme: {scheme}"),
         }
     }
+
+    /// Subscribes to the events of the underlying connection. Returns `None` for HTTP, which has
+    /// no connection for the server to push notifications on.
+    pub fn events(&self) -> Option<broadcast::Receiver<TransportEvent>> {
+        match &self.inner {
+            TransportInner::Http(_) => None,
+            TransportInner::Tcp(tcp) => Some(tcp.events()),
+        }
+    }
 }
 
 #[async_trait]
@@ -0,0 +1,194 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use nullspace_crypt::hash::Hash;
+use nullspace_crypt::signing::{Signature, SigningPublic, SigningSecret};
+use snow::{Builder, StatelessTransportState};
+use tokio::io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio::time;
+
+/// Both sides only use ephemeral keys. The server authenticates afterwards, by signing the
+/// handshake hash with its directory key, so that the directory key does not need an X25519 twin.
+const NOISE_PARAMS: &str = "Noise_NN_25519_ChaChaPoly_BLAKE2s";
+
+const MAX_NOISE_MESSAGE: usize = 65535;
+
+const NOISE_TAG_LEN: usize = 16;
+
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Runs the client side of the handshake, failing unless the server proves that it holds the
+/// secret key of `server_pk`. Returns a stream that carries plaintext over the session.
+pub(crate) async fn connect(
+    mut stream: TcpStream,
+    server_pk: SigningPublic,
+) -> anyhow::Result<DuplexStream> {
+    let handshake = async {
+        let mut noise = Builder::new(NOISE_PARAMS.parse()?).build_initiator()?;
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let len = noise.write_message(&[], &mut buf)?;
+        write_frame(&mut stream, &buf[..len]).await?;
+        let frame = read_frame(&mut stream).await?;
+        noise.read_message(&frame, &mut buf)?;
+        let binding = channel_binding(noise.get_handshake_hash());
+        let noise = noise.into_stateless_transport_mode()?;
+
+        let frame = read_frame(&mut stream).await?;
+        let len = noise.read_message(0, &frame, &mut buf)?;
+        let signature: [u8; 64] = buf[..len]
+            .try_into()
+            .map_err(|_| anyhow::anyhow!("malformed server signature"))?;
+        server_pk
+            .verify(&Signature::from_bytes(signature), &binding)
+            .map_err(|_| anyhow::anyhow!("server did not prove possession of its key"))?;
+        Ok::<_, anyhow::Error>(noise)
+    };
+    let noise = time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow::anyhow!("noise handshake timeout"))??;
+    Ok(spawn_session(stream, noise, 0, 1))
+}
+
+/// Runs the server side of the handshake, proving possession of `signing_sk`. Returns a stream
+/// that carries plaintext over the session.
+pub(crate) async fn accept(
+    mut stream: TcpStream,
+    signing_sk: &SigningSecret,
+) -> anyhow::Result<DuplexStream> {
+    let handshake = async {
+        let mut noise = Builder::new(NOISE_PARAMS.parse()?).build_responder()?;
+        let mut buf = vec![0u8; MAX_NOISE_MESSAGE];
+        let frame = read_frame(&mut stream).await?;
+        noise.read_message(&frame, &mut buf)?;
+        let len = noise.write_message(&[], &mut buf)?;
+        write_frame(&mut stream, &buf[..len]).await?;
+        let binding = channel_binding(noise.get_handshake_hash());
+        let noise = noise.into_stateless_transport_mode()?;
+
+        let signature = signing_sk.sign(&binding).to_bytes();
+        let len = noise.write_message(0, &signature, &mut buf)?;
+        write_frame(&mut stream, &buf[..len]).await?;
+        Ok::<_, anyhow::Error>(noise)
+    };
+    let noise = time::timeout(HANDSHAKE_TIMEOUT, handshake)
+        .await
+        .map_err(|_| anyhow::anyhow!("noise handshake timeout"))??;
+    Ok(spawn_session(stream, noise, 1, 0))
+}
+
+/// What the server signs: the handshake hash, under a key of its own so that the signature cannot
+/// be mistaken for one over anything else.
+fn channel_binding(handshake_hash: &[u8]) -> [u8; 32] {
+    Hash::keyed_digest(b"nullspace-noise-server", handshake_hash).to_bytes()
+}
+
+/// Moves plaintext between the returned stream and encrypted frames on the TCP stream, starting at
+/// the given nonces.
+fn spawn_session(
+    stream: TcpStream,
+    noise: StatelessTransportState,
+    send_nonce: u64,
+    recv_nonce: u64,
+) -> DuplexStream {
+    let noise = Arc::new(noise);
+    let (session, pipe) = tokio::io::duplex(MAX_NOISE_MESSAGE);
+    let (mut pipe_read, mut pipe_write) = tokio::io::split(pipe);
+    let (mut tcp_read, mut tcp_write) = stream.into_split();
+
+    let send_noise = noise.clone();
+    tokio::spawn(async move {
+        let mut plain = vec![0u8; MAX_NOISE_MESSAGE - NOISE_TAG_LEN];
+        let mut cipher = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut nonce = send_nonce;
+        loop {
+            let len = match pipe_read.read(&mut plain).await {
+                Ok(0) | Err(_) => break,
+                Ok(len) => len,
+            };
+            let Ok(len) = send_noise.write_message(nonce, &plain[..len], &mut cipher) else {
+                break;
+            };
+            nonce += 1;
+            if write_frame(&mut tcp_write, &cipher[..len]).await.is_err() {
+                break;
+            }
+        }
+        let _ = tcp_write.shutdown().await;
+    });
+
+    tokio::spawn(async move {
+        let mut plain = vec![0u8; MAX_NOISE_MESSAGE];
+        let mut nonce = recv_nonce;
+        loop {
+            let Ok(frame) = read_frame(&mut tcp_read).await else {
+                break;
+            };
+            let Ok(len) = noise.read_message(nonce, &frame, &mut plain) else {
+                tracing::debug!("noise frame failed to decrypt");
+                break;
+            };
+            nonce += 1;
+            if pipe_write.write_all(&plain[..len]).await.is_err() {
+                break;
+            }
+        }
+        // lets the other side of the session read to the end
+        let _ = pipe_write.shutdown().await;
+    });
+
+    session
+}
+
+async fn write_frame<W: AsyncWrite + Unpin>(writer: &mut W, frame: &[u8]) -> std::io::Result<()> {
+    writer.write_u16(frame.len() as u16).await?;
+    writer.write_all(frame).await?;
+    writer.flush().await
+}
+
+async fn read_frame<R: AsyncRead + Unpin>(reader: &mut R) -> std::io::Result<Vec<u8>> {
+    let len = reader.read_u16().await?;
+    let mut frame = vec![0u8; len as usize];
+    reader.read_exact(&mut frame).await?;
+    Ok(frame)
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn session_is_pinned_to_server_key() {
+        let signing_sk = SigningSecret::random();
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        let server_sk = signing_sk.clone();
+        tokio::spawn(async move {
+            loop {
+                let (stream, _) = listener.accept().await.expect("accept");
+                let Ok(mut session) = accept(stream, &server_sk).await else {
+                    continue;
+                };
+                let mut buf = [0u8; 5];
+                if session.read_exact(&mut buf).await.is_ok() {
+                    let _ = session.write_all(&buf).await;
+                }
+            }
+        });
+
+        let stream = TcpStream::connect(addr).await.expect("connect");
+        let mut session = connect(stream, signing_sk.public_key())
+            .await
+            .expect("handshake with the right key");
+        session.write_all(b"hello").await.expect("client write");
+        let mut buf = [0u8; 5];
+        session.read_exact(&mut buf).await.expect("client read");
+        assert_eq!(&buf, b"hello");
+
+        let stream = TcpStream::connect(addr).await.expect("connect");
+        let wrong_pk = SigningSecret::random().public_key();
+        assert!(connect(stream, wrong_pk).await.is_err());
+    }
+}
@@ -0,0 +1,88 @@
+use std::sync::Arc;
+use std::sync::atomic::{AtomicU64, Ordering};
+
+use futures_concurrency::future::Race;
+use serde_json::json;
+use tokio::sync::{mpsc, watch};
+
+static NEXT_CONNECTION_ID: AtomicU64 = AtomicU64::new(0);
+
+tokio::task_local! {
+    static NOTIFICATION_SINK: NotificationSink;
+}
+
+/// Returns the sink for the connection the current request came in on, if that connection can
+/// carry notifications. Only the connection-based transports can; over HTTP this returns `None`.
+pub fn notification_sink() -> Option<NotificationSink> {
+    NOTIFICATION_SINK.try_with(Clone::clone).ok()
+}
+
+/// Pushes JSON-RPC notifications to the client at the other end of a connection.
+#[derive(Clone)]
+pub struct NotificationSink {
+    connection_id: u64,
+    write_tx: mpsc::Sender<String>,
+    open: watch::Receiver<()>,
+    live: Arc<()>,
+}
+
+impl NotificationSink {
+    pub(crate) fn new(write_tx: mpsc::Sender<String>, open: watch::Receiver<()>) -> Self {
+        Self {
+            connection_id: NEXT_CONNECTION_ID.fetch_add(1, Ordering::Relaxed),
+            write_tx,
+            open,
+            live: Arc::new(()),
+        }
+    }
+
+    /// An identifier for the connection, unique within this process.
+    pub fn connection_id(&self) -> u64 {
+        self.connection_id
+    }
+
+    /// Sends a notification, failing once the connection is closed.
+    pub async fn notify(&self, method: &str, params: Vec<serde_json::Value>) -> anyhow::Result<()> {
+        let notification = json!({
+            "jsonrpc": "2.0",
+            "method": method,
+            "params": params,
+        });
+        self.write_tx
+            .send(notification.to_string())
+            .await
+            .map_err(|_| anyhow::anyhow!("connection closed"))
+    }
+
+    /// Resolves once the connection is closed.
+    pub async fn closed(&self) {
+        let mut open = self.open.clone();
+        let read_closed = async {
+            // nothing is ever sent, so this only returns once the connection task drops the sender
+            let _ = open.changed().await;
+        };
+        (read_closed, self.write_tx.closed()).race().await
+    }
+
+    /// Whether anything besides the connection itself still holds on to the sink, such as a
+    /// request being handled or a subscription.
+    pub(crate) fn in_use(&self) -> bool {
+        Arc::strong_count(&self.live) > 1
+    }
+
+    pub(crate) async fn scope<F: Future>(self, fut: F) -> F::Output {
+        NOTIFICATION_SINK.scope(self, fut).await
+    }
+}
+
+/// Something that happens on a connection outside of a request and its response.
+#[derive(Clone, Debug)]
+pub enum TransportEvent {
+    /// A JSON-RPC notification pushed by the server.
+    Notification {
+        method: String,
+        params: serde_json::Value,
+    },
+    /// The connection was lost, along with anything the server was going to push on it.
+    Reset,
+}
@@ -1,19 +1,23 @@
 use std::collections::HashMap;
+use std::path::Path;
 use std::time::Duration;
 
 use async_compression::tokio::bufread::Lz4Decoder;
 use async_compression::tokio::write::Lz4Encoder;
 use futures_concurrency::future::Race;
 use nanorpc::{JrpcId, JrpcRequest, JrpcResponse, RpcService};
+use nullspace_crypt::signing::{SigningPublic, SigningSecret};
 use serde_json::json;
 use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, BufReader};
 use tokio::net::{TcpListener, TcpStream, ToSocketAddrs};
-use tokio::sync::{mpsc, oneshot};
+use tokio::sync::{broadcast, mpsc, oneshot, watch};
 use tokio::time;
 use tokio_stream::StreamExt;
 use tokio_util::codec::{FramedRead, LinesCodec};
 use url::Url;
 
+use crate::{noise, ws};
+use crate::notify::{NotificationSink, TransportEvent};
 use crate::{MAX_MESSAGE_BYTES, REQUEST_TIMEOUT_SECS};
 
 pub async fn serve_tcp<S>(addr: impl ToSocketAddrs, service: S) -> anyhow::Result<()>
@@ -42,9 +46,85 @@ where
     }
 }
 
+/// Serves over a Unix domain socket at `path`, with the framing of `serve_tcp`, or of
+/// `serve_lz4tcp` if `lz4` is set. A stale socket left at `path` is replaced, and the new one gets
+/// `mode` as its permissions. Clients connect to these with `unix://` or `lz4+unix://` endpoints.
+pub async fn serve_unix<S>(
+    path: impl AsRef<Path>,
+    service: S,
+    lz4: bool,
+    mode: u32,
+) -> anyhow::Result<()>
+where
+    S: RpcService,
+{
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::{FileTypeExt, PermissionsExt};
+
+        let path = path.as_ref();
+        if let Ok(metadata) = std::fs::symlink_metadata(path)
+            && metadata.file_type().is_socket()
+        {
+            std::fs::remove_file(path)?;
+        }
+        let listener = tokio::net::UnixListener::bind(path)?;
+        std::fs::set_permissions(path, std::fs::Permissions::from_mode(mode))?;
+
+        let service = std::sync::Arc::new(service);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let service = service.clone();
+            tokio::spawn(async move { rpc_unix_connection(service, stream, lz4).await });
+        }
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = (path, service, lz4, mode);
+        anyhow::bail!("unix sockets are not supported on this platform")
+    }
+}
+
+/// Serves over WebSocket, one JSON line per text message. Clients connect to these with `ws://`
+/// endpoints, or `wss://` through a TLS-terminating proxy.
+pub async fn serve_ws<S>(addr: impl ToSocketAddrs, service: S) -> anyhow::Result<()>
+where
+    S: RpcService,
+{
+    let service = std::sync::Arc::new(service);
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = service.clone();
+        tokio::spawn(async move { rpc_ws_connection(service, stream).await });
+    }
+}
+
+/// Serves over Noise sessions, in which the server proves that it holds `signing_sk`. Clients
+/// connect to these with `noise+tcp://` endpoints.
+pub async fn serve_noise_tcp<S>(
+    addr: impl ToSocketAddrs,
+    service: S,
+    signing_sk: SigningSecret,
+) -> anyhow::Result<()>
+where
+    S: RpcService,
+{
+    let service = std::sync::Arc::new(service);
+    let signing_sk = std::sync::Arc::new(signing_sk);
+    let listener = TcpListener::bind(addr).await?;
+    loop {
+        let (stream, _) = listener.accept().await?;
+        let service = service.clone();
+        let signing_sk = signing_sk.clone();
+        tokio::spawn(async move { rpc_noise_connection(service, stream, &signing_sk).await });
+    }
+}
+
 #[derive(Clone)]
 pub(crate) struct RawTcpClient {
     cmd_tx: mpsc::Sender<ClientCommand>,
+    events: broadcast::Sender<TransportEvent>,
 }
 
 impl RawTcpClient {
@@ -56,10 +136,32 @@ impl RawTcpClient {
         Self::new_with_mode(endpoint, WireMode::Lz4)
     }
 
+    pub(crate) fn new_unix(endpoint: Url) -> Self {
+        Self::new_with_mode(endpoint, WireMode::Unix)
+    }
+
+    pub(crate) fn new_lz4unix(endpoint: Url) -> Self {
+        Self::new_with_mode(endpoint, WireMode::Lz4Unix)
+    }
+
+    pub(crate) fn new_ws(endpoint: Url) -> Self {
+        Self::new_with_mode(endpoint, WireMode::Ws)
+    }
+
+    pub(crate) fn new_noise(endpoint: Url, server_pk: Option<SigningPublic>) -> Self {
+        Self::new_with_mode(endpoint, WireMode::Noise(server_pk))
+    }
+
     fn new_with_mode(endpoint: Url, mode: WireMode) -> Self {
         let (cmd_tx, cmd_rx) = mpsc::channel(2000);
-        tokio::spawn(async move { run_tcp_client(endpoint, mode, cmd_rx).await });
-        Self { cmd_tx }
+        let (events, _) = broadcast::channel(256);
+        let events_tx = events.clone();
+        tokio::spawn(async move { run_tcp_client(endpoint, mode, cmd_rx, events_tx).await });
+        Self { cmd_tx, events }
+    }
+
+    pub(crate) fn events(&self) -> broadcast::Receiver<TransportEvent> {
+        self.events.subscribe()
     }
 
     pub(crate) async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, anyhow::Error> {
@@ -92,6 +194,10 @@ enum ClientCommand {
 
 enum ConnEvent {
     Response(JrpcResponse),
+    Notification {
+        method: String,
+        params: serde_json::Value,
+    },
     Closed(anyhow::Error),
 }
 
@@ -135,15 +241,75 @@ impl Connection {
         let (write_tx, event_rx) = connect_with_io(reader, writer, true).await;
         Ok(Self { write_tx, event_rx })
     }
+
+    #[cfg(unix)]
+    async fn connect_unix(endpoint: &Url, lz4: bool) -> Result<Self, anyhow::Error> {
+        if endpoint.path().is_empty() || endpoint.path() == "/" {
+            anyhow::bail!("unix endpoint missing path");
+        }
+        let stream = tokio::net::UnixStream::connect(endpoint.path()).await?;
+        let (reader, writer) = stream.into_split();
+
+        let (write_tx, event_rx) = if lz4 {
+            let reader = Lz4Decoder::new(BufReader::new(reader));
+            let writer = Lz4Encoder::new(writer);
+            connect_with_io(reader, writer, true).await
+        } else {
+            connect_with_io(reader, writer, false).await
+        };
+        Ok(Self { write_tx, event_rx })
+    }
+
+    #[cfg(not(unix))]
+    async fn connect_unix(_endpoint: &Url, _lz4: bool) -> Result<Self, anyhow::Error> {
+        anyhow::bail!("unix endpoints are not supported on this platform")
+    }
+
+    async fn connect_ws(endpoint: &Url) -> Result<Self, anyhow::Error> {
+        let session = ws::connect(endpoint).await?;
+        let (reader, writer) = tokio::io::split(session);
+
+        let (write_tx, event_rx) = connect_with_io(reader, writer, false).await;
+        Ok(Self { write_tx, event_rx })
+    }
+
+    async fn connect_noise(
+        endpoint: &Url,
+        server_pk: Option<SigningPublic>,
+    ) -> Result<Self, anyhow::Error> {
+        let server_pk = server_pk
+            .ok_or_else(|| anyhow::anyhow!("noise+tcp endpoint needs the server's public key"))?;
+        let host = endpoint
+            .host_str()
+            .ok_or_else(|| anyhow::anyhow!("noise+tcp endpoint missing host"))?;
+        let port = endpoint
+            .port()
+            .ok_or_else(|| anyhow::anyhow!("noise+tcp endpoint missing port"))?;
+        let stream = TcpStream::connect(format!("{host}:{port}")).await?;
+        let session = noise::connect(stream, server_pk).await?;
+        let (reader, writer) = tokio::io::split(session);
+
+        let (write_tx, event_rx) = connect_with_io(reader, writer, false).await;
+        Ok(Self { write_tx, event_rx })
+    }
 }
 
 #[derive(Clone, Copy)]
 enum WireMode {
     Plain,
     Lz4,
+    Unix,
+    Lz4Unix,
+    Ws,
+    Noise(Option<SigningPublic>),
 }
 
-async fn run_tcp_client(endpoint: Url, mode: WireMode, mut cmd_rx: mpsc::Receiver<ClientCommand>) {
+async fn run_tcp_client(
+    endpoint: Url,
+    mode: WireMode,
+    mut cmd_rx: mpsc::Receiver<ClientCommand>,
+    events: broadcast::Sender<TransportEvent>,
+) {
     let mut connection: Option<Connection> = None;
     let mut in_flight: HashMap<JrpcId, oneshot::Sender<Result<JrpcResponse, anyhow::Error>>> =
         HashMap::new();
@@ -163,6 +329,12 @@ async fn run_tcp_client(endpoint: Url, mode: WireMode, mut cmd_rx: mpsc::Receive
                     let result = match mode {
                         WireMode::Plain => Connection::connect(&endpoint).await,
                         WireMode::Lz4 => Connection::connect_lz4(&endpoint).await,
+                        WireMode::Unix => Connection::connect_unix(&endpoint, false).await,
+                        WireMode::Lz4Unix => Connection::connect_unix(&endpoint, true).await,
+                        WireMode::Ws => Connection::connect_ws(&endpoint).await,
+                        WireMode::Noise(server_pk) => {
+                            Connection::connect_noise(&endpoint, server_pk).await
+                        }
                     };
                     match result {
                         Ok(conn) => connection = Some(conn),
@@ -191,6 +363,7 @@ async fn run_tcp_client(endpoint: Url, mode: WireMode, mut cmd_rx: mpsc::Receive
                     let _ = resp_tx.send(Err(anyhow::anyhow!(err.to_string())));
                     fail_in_flight(&mut in_flight, err.to_string());
                     connection = None;
+                    let _ = events.send(TransportEvent::Reset);
                     continue;
                 }
 
@@ -208,13 +381,18 @@ async fn run_tcp_client(endpoint: Url, mode: WireMode, mut cmd_rx: mpsc::Receive
                     let _ = tx.send(Ok(resp));
                 }
             }
+            ClientEvent::Connection(Some(ConnEvent::Notification { method, params })) => {
+                let _ = events.send(TransportEvent::Notification { method, params });
+            }
             ClientEvent::Connection(Some(ConnEvent::Closed(err))) => {
                 fail_in_flight(&mut in_flight, err.to_string());
                 connection = None;
+                let _ = events.send(TransportEvent::Reset);
             }
             ClientEvent::Connection(None) => {
                 fail_in_flight(&mut in_flight, "tcp connection closed".to_string());
                 connection = None;
+                let _ = events.send(TransportEvent::Reset);
             }
         }
     }
@@ -247,6 +425,53 @@ where
     rpc_connection_io(service, reader, writer, true).await;
 }
 
+#[cfg(unix)]
+async fn rpc_unix_connection<S>(
+    service: std::sync::Arc<S>,
+    stream: tokio::net::UnixStream,
+    lz4: bool,
+) where
+    S: RpcService,
+{
+    let (reader, writer) = stream.into_split();
+    if lz4 {
+        let reader = Lz4Decoder::new(BufReader::new(reader));
+        let writer = Lz4Encoder::new(writer);
+        rpc_connection_io(service, reader, writer, true).await;
+    } else {
+        rpc_connection_io(service, reader, writer, false).await;
+    }
+}
+
+async fn rpc_ws_connection<S>(service: std::sync::Arc<S>, stream: TcpStream)
+where
+    S: RpcService,
+{
+    match ws::accept(stream).await {
+        Ok(session) => {
+            let (reader, writer) = tokio::io::split(session);
+            rpc_connection_io(service, reader, writer, false).await;
+        }
+        Err(err) => tracing::debug!(error = %err, "websocket handshake failed"),
+    }
+}
+
+async fn rpc_noise_connection<S>(
+    service: std::sync::Arc<S>,
+    stream: TcpStream,
+    signing_sk: &SigningSecret,
+) where
+    S: RpcService,
+{
+    match noise::accept(stream, signing_sk).await {
+        Ok(session) => {
+            let (reader, writer) = tokio::io::split(session);
+            rpc_connection_io(service, reader, writer, false).await;
+        }
+        Err(err) => tracing::debug!(error = %err, "noise handshake failed"),
+    }
+}
+
 async fn connect_with_io<R, W>(
     reader: R,
     mut writer: W,
@@ -268,9 +493,9 @@ where
                     if line.is_empty() {
                         continue;
                     }
-                    match serde_json::from_str::<JrpcResponse>(&line) {
-                        Ok(resp) => {
-                            if read_event_tx.send(ConnEvent::Response(resp)).await.is_err() {
+                    match parse_incoming(&line) {
+                        Ok(event) => {
+                            if read_event_tx.send(event).await.is_err() {
                                 break;
                             }
                         }
@@ -326,6 +551,20 @@ where
     (write_tx, event_rx)
 }
 
+/// Tells apart responses from notifications, which carry a method and no id.
+fn parse_incoming(line: &str) -> Result<ConnEvent, serde_json::Error> {
+    let mut value = serde_json::from_str::<serde_json::Value>(line)?;
+    if let Some(method) = value.get("method").and_then(|method| method.as_str()) {
+        let method = method.to_string();
+        let params = value
+            .get_mut("params")
+            .map(serde_json::Value::take)
+            .unwrap_or_default();
+        return Ok(ConnEvent::Notification { method, params });
+    }
+    Ok(ConnEvent::Response(serde_json::from_value(value)?))
+}
+
 async fn rpc_connection_io<S, R, W>(
     service: std::sync::Arc<S>,
     reader: R,
@@ -337,6 +576,9 @@ async fn rpc_connection_io<S, R, W>(
     W: AsyncWrite + Unpin + Send + 'static,
 {
     let (write_tx, mut write_rx) = mpsc::channel::<String>(256);
+    // dropped when this function returns, which tells subscriptions that the connection is gone
+    let (_open_tx, open_rx) = watch::channel(());
+    let sink = NotificationSink::new(write_tx.clone(), open_rx);
     tokio::spawn(async move {
         while let Some(line) = write_rx.recv().await {
             let write_line = async {
@@ -359,6 +601,10 @@ async fn rpc_connection_io<S, R, W>(
         let read_result =
             time::timeout(Duration::from_secs(REQUEST_TIMEOUT_SECS), reader.next()).await;
         let Ok(read_result) = read_result else {
+            // a client waiting on a subscription may have nothing to say for a long time
+            if sink.in_use() {
+                continue;
+            }
             return;
         };
         match read_result {
@@ -370,12 +616,12 @@ async fn rpc_connection_io<S, R, W>(
                     Ok(req) => {
                         let write_tx = write_tx.clone();
                         let service = service.clone();
-                        tokio::spawn(async move {
+                        tokio::spawn(sink.clone().scope(async move {
                             let resp = service.respond_raw(req).await;
                             if let Ok(payload) = serde_json::to_string(&resp) {
                                 let _ = write_tx.send(payload).await;
                             }
-                        });
+                        }));
                     }
                     Err(err) => {
                         let resp = json!({
@@ -395,3 +641,74 @@ async fn rpc_connection_io<S, R, W>(
         }
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn tells_notifications_from_responses() {
+        let line = r#"{"jsonrpc":"2.0","method":"v1_mailbox_notify","params":[{"subscription":1}]}"#;
+        match parse_incoming(line).expect("parse notification") {
+            ConnEvent::Notification { method, params } => {
+                assert_eq!(method, "v1_mailbox_notify");
+                assert_eq!(params, json!([{ "subscription": 1 }]));
+            }
+            _ => panic!("expected a notification"),
+        }
+
+        let line = r#"{"jsonrpc":"2.0","result":1,"id":1}"#;
+        assert!(matches!(parse_incoming(line), Ok(ConnEvent::Response(_))));
+    }
+
+    #[cfg(unix)]
+    #[tokio::test]
+    async fn serves_over_unix_sockets() {
+        use std::os::unix::fs::PermissionsExt;
+
+        struct Echo;
+
+        #[async_trait::async_trait]
+        impl RpcService for Echo {
+            async fn respond(
+                &self,
+                _method: &str,
+                params: Vec<serde_json::Value>,
+            ) -> Option<Result<serde_json::Value, nanorpc::ServerError>> {
+                Some(Ok(serde_json::Value::Array(params)))
+            }
+        }
+
+        let path = std::env::temp_dir().join(format!("nanorpc-{}.sock", std::process::id()));
+        tokio::spawn(serve_unix(path.clone(), Echo, true, 0o600));
+        let mode = async {
+            loop {
+                if let Ok(metadata) = std::fs::metadata(&path)
+                    && metadata.permissions().mode() & 0o777 == 0o600
+                {
+                    return;
+                }
+                time::sleep(Duration::from_millis(10)).await;
+            }
+        };
+        time::timeout(Duration::from_secs(5), mode)
+            .await
+            .expect("socket never got its permissions");
+
+        let endpoint = Url::parse(&format!("lz4+unix://{}", path.display())).expect("endpoint");
+        let client = RawTcpClient::new_lz4unix(endpoint);
+        let req = serde_json::from_value(json!({
+            "jsonrpc": "2.0",
+            "method": "echo",
+            "params": [1],
+            "id": 1,
+        }))
+        .expect("request");
+        let resp = client.call_raw(req).await.expect("call over unix socket");
+        assert_eq!(
+            serde_json::to_value(resp).expect("response")["result"],
+            json!([1])
+        );
+        let _ = std::fs::remove_file(&path);
+    }
+}
@@ -0,0 +1,98 @@
+use futures_util::{SinkExt, StreamExt};
+use tokio::io::{AsyncRead, AsyncWrite, AsyncWriteExt, DuplexStream};
+use tokio::net::TcpStream;
+use tokio_tungstenite::WebSocketStream;
+use tokio_tungstenite::tungstenite::Message;
+use tokio_util::codec::{FramedRead, LinesCodec};
+use url::Url;
+
+use crate::MAX_MESSAGE_BYTES;
+
+/// Connects to a `ws://` or `wss://` endpoint. Returns a stream of JSON lines, each of which
+/// travels as one text message.
+pub(crate) async fn connect(endpoint: &Url) -> anyhow::Result<DuplexStream> {
+    let (ws, _) = tokio_tungstenite::connect_async(endpoint.as_str()).await?;
+    Ok(spawn_bridge(ws))
+}
+
+/// Runs the server side of the WebSocket handshake. Returns a stream of JSON lines, each of which
+/// travels as one text message.
+pub(crate) async fn accept(stream: TcpStream) -> anyhow::Result<DuplexStream> {
+    let ws = tokio_tungstenite::accept_async(stream).await?;
+    Ok(spawn_bridge(ws))
+}
+
+/// Moves lines written to the returned stream out as text messages, and incoming text messages
+/// back in as lines.
+fn spawn_bridge<S>(ws: WebSocketStream<S>) -> DuplexStream
+where
+    S: AsyncRead + AsyncWrite + Unpin + Send + 'static,
+{
+    let (session, pipe) = tokio::io::duplex(64 * 1024);
+    let (pipe_read, mut pipe_write) = tokio::io::split(pipe);
+    let (mut ws_write, mut ws_read) = ws.split();
+
+    tokio::spawn(async move {
+        let mut lines =
+            FramedRead::new(pipe_read, LinesCodec::new_with_max_length(MAX_MESSAGE_BYTES));
+        while let Some(Ok(line)) = lines.next().await {
+            if ws_write.send(Message::text(line)).await.is_err() {
+                break;
+            }
+        }
+        let _ = ws_write.close().await;
+    });
+
+    tokio::spawn(async move {
+        while let Some(Ok(message)) = ws_read.next().await {
+            let text = match message {
+                Message::Text(text) => text,
+                Message::Close(_) => break,
+                // pings are answered by the library, and nothing is sent as binary
+                _ => continue,
+            };
+            let write_line = async {
+                pipe_write.write_all(text.as_bytes()).await?;
+                pipe_write.write_all(b"\n").await
+            };
+            if write_line.await.is_err() {
+                break;
+            }
+        }
+        // lets the other side of the session read to the end
+        let _ = pipe_write.shutdown().await;
+    });
+
+    session
+}
+
+#[cfg(test)]
+mod tests {
+    use tokio::io::{AsyncBufReadExt, BufReader};
+    use tokio::net::TcpListener;
+
+    use super::*;
+
+    #[tokio::test]
+    async fn lines_round_trip() {
+        let listener = TcpListener::bind("127.0.0.1:0").await.expect("bind");
+        let addr = listener.local_addr().expect("local addr");
+        tokio::spawn(async move {
+            let (stream, _) = listener.accept().await.expect("accept");
+            let session = accept(stream).await.expect("server handshake");
+            let (reader, mut writer) = tokio::io::split(session);
+            let mut lines = BufReader::new(reader).lines();
+            while let Ok(Some(line)) = lines.next_line().await {
+                let _ = writer.write_all(format!("{line}!\n").as_bytes()).await;
+            }
+        });
+
+        let endpoint = Url::parse(&format!("ws://{addr}")).expect("endpoint");
+        let session = connect(&endpoint).await.expect("client handshake");
+        let (reader, mut writer) = tokio::io::split(session);
+        writer.write_all(b"hello\nworld\n").await.expect("write");
+        let mut lines = BufReader::new(reader).lines();
+        assert_eq!(lines.next_line().await.expect("read").as_deref(), Some("hello!"));
+        assert_eq!(lines.next_line().await.expect("read").as_deref(), Some("world!"));
+    }
+}
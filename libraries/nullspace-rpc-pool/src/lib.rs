@@ -4,6 +4,7 @@ use std::sync::{Arc, Mutex};
 use async_trait::async_trait;
 use moka::future::Cache;
 use nanorpc::{JrpcRequest, JrpcResponse, RpcTransport};
+use nullspace_crypt::signing::SigningPublic;
 use nullspace_nanorpc::Transport;
 use url::Url;
 
@@ -18,11 +19,12 @@ pub struct RpcPoolBuilder {
 
 struct RpcPoolInner {
     max_concurrency: usize,
-    pools: Cache<Url, Arc<PerUrlPool>>,
+    pools: Cache<(Url, Option<[u8; 32]>), Arc<PerUrlPool>>,
 }
 
 struct PerUrlPool {
     url: Url,
+    server_pk: Option<SigningPublic>,
     max_concurrency: usize,
     next_index: AtomicUsize,
     transports: Mutex<Vec<Option<Arc<Transport>>>>,
@@ -32,6 +34,7 @@ struct PerUrlPool {
 pub struct PooledTransport {
     pool: RpcPool,
     url: Url,
+    server_pk: Option<SigningPublic>,
 }
 
 impl RpcPool {
@@ -47,16 +50,33 @@ impl RpcPool {
         PooledTransport {
             pool: self.clone(),
             url,
+            server_pk: None,
         }
     }
 
-    async fn call_raw(&self, url: Url, req: JrpcRequest) -> Result<JrpcResponse, anyhow::Error> {
+    /// Like `rpc`, but for a server that must prove it holds the secret key of `server_pk`, on
+    /// transports that can check it.
+    pub fn rpc_pinned(&self, url: Url, server_pk: SigningPublic) -> PooledTransport {
+        PooledTransport {
+            pool: self.clone(),
+            url,
+            server_pk: Some(server_pk),
+        }
+    }
+
+    async fn call_raw(
+        &self,
+        url: Url,
+        server_pk: Option<SigningPublic>,
+        req: JrpcRequest,
+    ) -> Result<JrpcResponse, anyhow::Error> {
         let max_concurrency = self.inner.max_concurrency;
+        let key = (url.clone(), server_pk.map(|pk| pk.to_bytes()));
         let pool = self
             .inner
             .pools
-            .get_with(url.clone(), async move {
-                Arc::new(PerUrlPool::new(url, max_concurrency))
+            .get_with(key, async move {
+                Arc::new(PerUrlPool::new(url, server_pk, max_concurrency))
             })
             .await;
         pool.call_raw(req).await
@@ -100,9 +120,10 @@ impl Default for RpcPoolBuilder {
 }
 
 impl PerUrlPool {
-    fn new(url: Url, max_concurrency: usize) -> Self {
+    fn new(url: Url, server_pk: Option<SigningPublic>, max_concurrency: usize) -> Self {
         Self {
             url,
+            server_pk,
             max_concurrency,
             next_index: AtomicUsize::new(0),
             transports: Mutex::new(Vec::new()),
@@ -133,7 +154,10 @@ impl PerUrlPool {
         if let Some(existing) = transports[index].as_ref() {
             return existing.clone();
         }
-        let transport = Arc::new(Transport::new(self.url.clone()));
+        let transport = Arc::new(match self.server_pk {
+            Some(server_pk) => Transport::new_pinned(self.url.clone(), server_pk),
+            None => Transport::new(self.url.clone()),
+        });
         transports[index] = Some(transport.clone());
         transport
     }
@@ -157,6 +181,8 @@ impl RpcTransport for PooledTransport {
     type Error = anyhow::Error;
 
     async fn call_raw(&self, req: JrpcRequest) -> Result<JrpcResponse, Self::Error> {
-        self.pool.call_raw(self.url.clone(), req).await
+        self.pool
+            .call_raw(self.url.clone(), self.server_pk, req)
+            .await
     }
 }
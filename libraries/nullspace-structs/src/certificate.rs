@@ -189,6 +189,65 @@ impl CertificateChain {
     }
 }
 
+#[derive(Clone, Debug, Serialize, Deserialize)]
+/// A revocation of a device, signed by a device that may issue certificates. Revoking a device
+/// also revokes every device whose chain passes through it.
+pub struct DeviceRevocation {
+    pub device_hash: Hash,
+    pub revoked_at: Timestamp,
+    pub issuer: CertificateChain,
+    pub signature: Signature,
+}
+
+impl Signable for DeviceRevocation {
+    fn signed_value(&self) -> Vec<u8> {
+        bcs::to_bytes(&(&self.device_hash, &self.revoked_at, &self.issuer))
+            .expect("bcs serialization failed")
+    }
+
+    fn signature_mut(&mut self) -> &mut Signature {
+        &mut self.signature
+    }
+
+    fn signature(&self) -> &Signature {
+        &self.signature
+    }
+}
+
+impl DeviceRevocation {
+    /// Create a revocation of `device_hash`, signed by the last device of `issuer`.
+    pub fn new(device_hash: Hash, issuer: CertificateChain, issuer_secret: &DeviceSecret) -> Self {
+        let mut revocation = Self {
+            device_hash,
+            revoked_at: unix_time(),
+            issuer,
+            signature: Signature::from_bytes([0u8; 64]),
+        };
+        revocation.sign(&issuer_secret.0);
+        revocation
+    }
+
+    /// Verify the revocation against a trusted root public key hash. The issuer must be allowed to
+    /// issue certificates, and may not revoke itself or any of its own ancestors.
+    pub fn verify_issuer(&self, trusted_pk_hash: Hash) -> anyhow::Result<()> {
+        self.issuer.verify(trusted_pk_hash)?;
+        let issuer = self.issuer.last_device();
+        if !issuer.can_issue {
+            bail!("revocation issuer cannot issue certificates");
+        }
+        if self
+            .issuer
+            .iter()
+            .any(|cert| cert.pk.bcs_hash() == self.device_hash)
+        {
+            bail!("revocation targets the issuer's own chain");
+        }
+        self.verify(issuer.pk.signing_public())
+            .map_err(|err| anyhow::anyhow!(err.to_string()))?;
+        Ok(())
+    }
+}
+
 fn unix_time() -> Timestamp {
     use std::time::{SystemTime, UNIX_EPOCH};
     let seconds = SystemTime::now()
@@ -271,4 +330,37 @@ mod tests {
         chain.verify(root_hash).expect("verify chain");
         assert_eq!(chain.last_device(), &leaf_cert);
     }
+
+    #[test]
+    fn revocation_requires_issuer_outside_target() {
+        let root_secret = DeviceSecret::random();
+        let root_cert = root_secret.self_signed(Timestamp(u64::MAX), true);
+        let root_hash = root_cert.pk.bcs_hash();
+        let root_chain = CertificateChain {
+            ancestors: Vec::new(),
+            this: root_cert.clone(),
+        };
+
+        let leaf_secret = DeviceSecret::random();
+        let leaf_cert =
+            root_secret.issue_certificate(&leaf_secret.public(), Timestamp(u64::MAX), false);
+        let leaf_hash = leaf_cert.pk.bcs_hash();
+        let leaf_chain = CertificateChain {
+            ancestors: vec![root_cert],
+            this: leaf_cert,
+        };
+
+        let revocation = DeviceRevocation::new(leaf_hash, root_chain.clone(), &root_secret);
+        revocation.verify_issuer(root_hash).expect("verify revocation");
+
+        let by_leaf = DeviceRevocation::new(root_hash, leaf_chain, &leaf_secret);
+        assert!(by_leaf.verify_issuer(root_hash).is_err());
+
+        let of_self = DeviceRevocation::new(root_hash, root_chain, &root_secret);
+        assert!(of_self.verify_issuer(root_hash).is_err());
+
+        let mut tampered = revocation;
+        tampered.device_hash = Hash::random();
+        assert!(tampered.verify_issuer(root_hash).is_err());
+    }
 }
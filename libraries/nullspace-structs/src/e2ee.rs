@@ -205,7 +205,8 @@ impl HeaderEncrypted {
     }
 }
 
-fn mpk_short(mpk: &DhPublic) -> [u8; 2] {
+/// The short index hint that headers carry for a medium-term public key.
+pub fn mpk_short(mpk: &DhPublic) -> [u8; 2] {
     let hash = mpk.bcs_hash().to_bytes();
     [hash[0], hash[1]]
 }
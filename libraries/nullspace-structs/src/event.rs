@@ -4,6 +4,7 @@ use smol_str::SmolStr;
 use thiserror::Error;
 
 use crate::group::GroupId;
+use crate::server::ServerName;
 use crate::timestamp::NanoTimestamp;
 use crate::username::UserName;
 
@@ -70,4 +71,194 @@ impl Event {
             body: Bytes::from(body),
         })
     }
+
+    /// Wraps the event into an [`ExpiringEvent`] that recipients delete at `expires_at`.
+    pub fn with_expiry(self, expires_at: NanoTimestamp) -> Self {
+        let inner = ExpiringEvent {
+            expires_at,
+            mime: self.mime,
+            body: self.body,
+        };
+        Self {
+            recipient: self.recipient,
+            sent_at: self.sent_at,
+            mime: SmolStr::new(ExpiringEvent::MIME),
+            body: Bytes::from(bcs::to_bytes(&inner).expect("expiring event always encodes")),
+        }
+    }
+
+    /// Unwraps an [`ExpiringEvent`] into the event it carries and its expiry. Other events are
+    /// returned unchanged, with no expiry.
+    pub fn split_expiry(self) -> Result<(Self, Option<NanoTimestamp>), bcs::Error> {
+        if self.mime != ExpiringEvent::MIME {
+            return Ok((self, None));
+        }
+        let inner: ExpiringEvent = bcs::from_bytes(&self.body)?;
+        Ok((
+            Self {
+                recipient: self.recipient,
+                sent_at: self.sent_at,
+                mime: inner.mime,
+                body: inner.body,
+            },
+            Some(inner.expires_at),
+        ))
+    }
+}
+
+/// An event that recipients should delete at `expires_at`, carried as the BCS-encoded body of
+/// an outer event. Keeping the expiry out of [`Event`] itself leaves the event layout unchanged.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct ExpiringEvent {
+    pub expires_at: NanoTimestamp,
+    pub mime: SmolStr,
+    pub body: Bytes,
+}
+
+impl ExpiringEvent {
+    pub const MIME: &str = "application/vnd.nullspace.v1.expiring";
+}
+
+/// A reference to a previously sent message, identified by its sender and send time.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq, Hash)]
+pub struct MessageRef {
+    pub sender: UserName,
+    pub sent_at: NanoTimestamp,
+}
+
+/// Replaces the text of a previously sent message. Only valid when signed by the original sender.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageEdit {
+    pub target: MessageRef,
+    pub text: String,
+}
+
+impl EventPayload for MessageEdit {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_edit"
+    }
+}
+
+/// Retracts a previously sent message. Only valid when signed by the original sender.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageDelete {
+    pub target: MessageRef,
+}
+
+impl EventPayload for MessageDelete {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_delete"
+    }
+}
+
+/// Adds or removes an emoji reaction on a previously sent message.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageReaction {
+    pub target: MessageRef,
+    pub emoji: SmolStr,
+    pub add: bool,
+}
+
+impl EventPayload for MessageReaction {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_reaction"
+    }
+}
+
+/// A text message sent in reply to a previous message of the same conversation.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageReply {
+    pub parent: MessageRef,
+    pub text: String,
+}
+
+impl EventPayload for MessageReply {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_reply"
+    }
+}
+
+/// How far a recipient has gotten through a direct conversation.
+#[derive(Clone, Copy, Debug, Serialize, Deserialize, PartialEq, Eq, PartialOrd, Ord)]
+#[serde(rename_all = "snake_case")]
+pub enum ReceiptKind {
+    Delivered,
+    Read,
+}
+
+/// Acknowledges every message from the recipient sent at or before `up_to`. A read receipt also
+/// implies delivery.
+#[derive(Clone, Debug, Serialize, Deserialize)]
+pub struct MessageReceipt {
+    pub kind: ReceiptKind,
+    pub up_to: NanoTimestamp,
+}
+
+impl EventPayload for MessageReceipt {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_receipt"
+    }
+}
+
+/// Signals that the sender is composing a message. Sent with a short mailbox TTL and never stored.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct TypingIndicator {}
+
+impl EventPayload for TypingIndicator {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.typing"
+    }
+}
+
+/// Sets how long new messages in a conversation live before every participant deletes them.
+/// `None` turns disappearing messages off.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct MessageTimer {
+    pub seconds: Option<u32>,
+}
+
+impl EventPayload for MessageTimer {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.message_timer"
+    }
+}
+
+/// Tells a contact that the sender moved their account to another server. Recipients do not trust
+/// the named server, but look the sender up in the directory again.
+#[derive(Clone, Debug, Serialize, Deserialize, PartialEq, Eq)]
+pub struct AccountMoved {
+    pub server_name: ServerName,
+}
+
+impl EventPayload for AccountMoved {
+    fn mime() -> &'static str {
+        "application/vnd.nullspace.v1.account_moved"
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use smol_str::SmolStr;
+
+    use super::{Event, ExpiringEvent, Recipient};
+    use crate::timestamp::NanoTimestamp;
+    use crate::username::UserName;
+
+    #[test]
+    fn expiring_event_roundtrip() {
+        let event = Event {
+            recipient: Recipient::User(UserName::parse("@user_01").expect("valid username")),
+            sent_at: NanoTimestamp(1),
+            mime: SmolStr::new("text/plain"),
+            body: Bytes::from_static(b"hello"),
+        };
+        let wrapped = event.with_expiry(NanoTimestamp(2));
+        assert_eq!(wrapped.mime, ExpiringEvent::MIME);
+        let decoded: Event = bcs::from_bytes(&bcs::to_bytes(&wrapped).unwrap()).unwrap();
+        let (inner, expires_at) = decoded.split_expiry().unwrap();
+        assert_eq!(expires_at, Some(NanoTimestamp(2)));
+        assert_eq!(inner.mime, "text/plain");
+        assert_eq!(inner.body, Bytes::from_static(b"hello"));
+    }
 }
@@ -13,6 +13,7 @@ use crate::{
     certificate::{CertificateChain, DeviceSecret},
     e2ee::DeviceSigned,
     event::{Event, EventPayload},
+    fragment::Attachment,
     server::{AuthToken, ServerName},
     timestamp::{NanoTimestamp, Timestamp},
     username::UserName,
@@ -44,10 +45,20 @@ pub struct GroupInviteMsg {
     pub group_key: AeadKey,
     pub token: AuthToken,
     pub created_at: NanoTimestamp,
+    /// The server the group currently lives on, when it has moved away from `descriptor.server`.
+    #[serde(default)]
+    pub server: Option<ServerName>,
 }
 
+/// Identifies the key a group message is encrypted with. It is derived from the key itself, so
+/// rekeys issued concurrently by different admins never share an epoch.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone, Copy, Hash)]
+#[serde(transparent)]
+pub struct GroupKeyEpoch(Hash);
+
 #[derive(Clone, Debug, Serialize, Deserialize)]
 pub struct GroupMessage {
+    pub epoch: GroupKeyEpoch,
     pub nonce: [u8; 24],
     pub ciphertext: Bytes,
 }
@@ -100,6 +111,26 @@ impl FromStr for GroupId {
     }
 }
 
+impl GroupKeyEpoch {
+    pub fn of_key(key: &AeadKey) -> Self {
+        Self(Hash::keyed_digest(b"nullspace-group-key-epoch", &key.to_bytes()))
+    }
+
+    pub fn as_bytes(&self) -> [u8; 32] {
+        self.0.to_bytes()
+    }
+
+    pub fn from_bytes(bytes: [u8; 32]) -> Self {
+        Self(Hash::from_bytes(bytes))
+    }
+}
+
+impl fmt::Display for GroupKeyEpoch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
 impl GroupDescriptor {
     pub fn id(&self) -> GroupId {
         GroupId(Hash::digest(
@@ -129,12 +160,14 @@ impl GroupMessage {
         )
         .map_err(|_| GroupMessageError::Encode)?;
         let plaintext_bytes = bcs::to_bytes(&signed).map_err(|_| GroupMessageError::Encode)?;
+        let epoch = GroupKeyEpoch::of_key(key);
         let mut nonce = [0u8; 24];
         rand::rng().fill_bytes(&mut nonce);
         let ciphertext = key
-            .encrypt(nonce, &plaintext_bytes, &[])
+            .encrypt(nonce, &plaintext_bytes, &epoch.as_bytes())
             .map_err(|_| GroupMessageError::Encrypt)?;
         Ok(Self {
+            epoch,
             nonce,
             ciphertext: Bytes::from(ciphertext),
         })
@@ -142,7 +175,7 @@ impl GroupMessage {
 
     pub fn decrypt_message(&self, key: &AeadKey) -> Result<DeviceSigned, GroupMessageError> {
         let plaintext = key
-            .decrypt(self.nonce, &self.ciphertext, &[])
+            .decrypt(self.nonce, &self.ciphertext, &self.epoch.as_bytes())
             .map_err(|_| GroupMessageError::Decrypt)?;
         bcs::from_bytes(&plaintext).map_err(|_| GroupMessageError::Decode)
     }
@@ -165,6 +198,21 @@ pub enum GroupManageMsg {
     Leave,
     AddAdmin(UserName),
     RemoveAdmin(UserName),
+    /// Sets the group name. An empty name clears it.
+    SetName(String),
+    /// Sets the group topic. An empty topic clears it.
+    SetTopic(String),
+    SetAvatar(Option<Attachment>),
+    /// Moves the group mailboxes to another server.
+    Moved(GroupMove),
+}
+
+/// Points the members of a group at its mailboxes on a new server. The token can only send and
+/// receive there, and members use it to register their own group tokens at the new server.
+#[derive(Serialize, Deserialize, Debug, PartialEq, Eq, PartialOrd, Ord, Clone)]
+pub struct GroupMove {
+    pub server: ServerName,
+    pub token: AuthToken,
 }
 
 impl EventPayload for GroupManageMsg {
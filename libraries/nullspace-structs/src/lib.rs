@@ -47,6 +47,8 @@ impl Blob {
     v1_kind!(direct_message);
     v1_kind!(group_message);
     v1_kind!(group_rekey);
+
+    v1_kind!(device_bundle);
 }
 
 fn debug_bytes_len<T: AsRef<[u8]>>(bytes: &T, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
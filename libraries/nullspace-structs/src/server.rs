@@ -15,7 +15,8 @@ use smol_str::{SmolStr, format_smolstr};
 use thiserror::Error;
 use url::Url;
 
-use crate::certificate::CertificateChain;
+use crate::certificate::{CertificateChain, DeviceRevocation};
+use crate::directory::{PowSeed, PowSolution};
 use crate::fragment::Fragment;
 use crate::group::GroupId;
 use crate::profile::UserProfile;
@@ -26,25 +27,73 @@ use crate::{Blob, timestamp::NanoTimestamp, username::UserName};
 #[nanorpc_derive]
 #[async_trait]
 pub trait ServerProtocol {
-    // /// Allocates a one-time provisioning channel, given a random channel ID. Blocks until somebody posts to this channel, or the timeout is hit.
-    // async fn v1_provision_allocate(&self) -> Result<u64, ServerRpcError>;
+    /// Allocates a one-time provisioning channel, returning a short random channel number. The channel expires if nothing is received from it within a few minutes.
+    async fn v1_provision_allocate(&self) -> Result<u64, ServerRpcError>;
 
-    // /// Posts to a provisioning channel. Must provide a valid auth token for rate limiting purposes.
-    // async fn v1_provision_send(&self, channel: u64, value: Blob) -> Result<(), ServerRpcError>;
+    /// Posts to a provisioning channel. Must provide a valid auth token for rate limiting purposes. Only the first value posted to a channel is accepted.
+    async fn v1_provision_send(
+        &self,
+        auth: AuthToken,
+        channel: u64,
+        value: Blob,
+    ) -> Result<(), ServerRpcError>;
 
-    /// Authenticates a device, returning the AuthToken proper to it. This is idempotent and should only return one AuthToken per unique device. If the device successfully authenticates, and this server is proper to the username, the device certificate chain served to others is updated for that device.
+    /// Receives the value posted to a provisioning channel, closing the channel. Blocks until somebody posts to the channel, or the timeout is hit, in which case None is returned and the channel stays open.
+    async fn v1_provision_recv(
+        &self,
+        channel: u64,
+        timeout_ms: u64,
+    ) -> Result<Option<Blob>, ServerRpcError>;
+
+    /// Authenticates a device, returning the AuthToken proper to it. This is idempotent and should only return one AuthToken per unique device. If the device successfully authenticates, the device certificate chain served to others is updated for that device. Usernames proper to another server are only accepted while a migration prepared with `v1_migration_prepare` is pending.
     async fn v1_device_auth(
         &self,
         username: UserName,
         cert: CertificateChain,
     ) -> Result<AuthToken, ServerRpcError>;
 
+    /// Like `v1_device_auth`, but redeems a single-use invite code, for servers that only admit new usernames by invite. The code is only used up if the username was not admitted already.
+    async fn v1_device_auth_invite(
+        &self,
+        username: UserName,
+        cert: CertificateChain,
+        invite_code: SmolStr,
+    ) -> Result<AuthToken, ServerRpcError>;
+
+    /// Announces that a username is moving its account to this server, so that `v1_device_auth` accepts its devices for the next day, before the directory names this server. The certificate chain must be valid for the username, and the registration policy applies as for a new username, redeeming the invite code if one is given.
+    async fn v1_migration_prepare(
+        &self,
+        username: UserName,
+        cert: CertificateChain,
+        invite_code: Option<SmolStr>,
+    ) -> Result<(), ServerRpcError>;
+
+    /// Whether the server's registration policy admits a username, with the invite code if one is given. Nothing is used up, so clients can check this before claiming a username in the directory.
+    async fn v1_registration_check(
+        &self,
+        username: UserName,
+        invite_code: Option<SmolStr>,
+    ) -> Result<bool, ServerRpcError>;
+
     /// Retrieve the devices for a given username.
     async fn v1_device_certs(
         &self,
         username: UserName,
     ) -> Result<Option<BTreeMap<Hash, CertificateChain>>, ServerRpcError>;
 
+    /// Revoke a device of a given username. The revocation must be signed by a device of that username that can issue certificates. The revoked device, and every device whose chain passes through it, can no longer authenticate, and loses its auth token and mailbox access.
+    async fn v1_device_revoke(
+        &self,
+        username: UserName,
+        revocation: DeviceRevocation,
+    ) -> Result<(), ServerRpcError>;
+
+    /// Retrieve the device revocations for a given username, keyed by the revoked device hash.
+    async fn v1_device_revocations(
+        &self,
+        username: UserName,
+    ) -> Result<BTreeMap<Hash, DeviceRevocation>, ServerRpcError>;
+
     /// Retrieve the medium-term keys for a given username.
     async fn v1_device_medium_pks(
         &self,
@@ -78,13 +127,46 @@ pub trait ServerProtocol {
         ttl: u32,
     ) -> Result<NanoTimestamp, ServerRpcError>;
 
-    /// Receive one or more messages, from one or many mailboxes. This is batched to make long-polling more efficient. The server may choose to limit the number of messages in the response, so clients should be prepared to repeat until getting an empty "page".
+    /// Send a message into a mailbox anonymously, with a proof-of-work stamp solved against a seed from `v1_mailbox_pow_seed`. Each seed can only be used once.
+    async fn v1_mailbox_send_pow(
+        &self,
+        mailbox: MailboxId,
+        message: Blob,
+        ttl: u32,
+        pow: PowSolution,
+    ) -> Result<NanoTimestamp, ServerRpcError>;
+
+    /// Returns a fresh proof-of-work seed if anonymous senders to the mailbox must attach a stamp, or None if they need not.
+    async fn v1_mailbox_pow_seed(
+        &self,
+        mailbox: MailboxId,
+    ) -> Result<Option<PowSeed>, ServerRpcError>;
+
+    /// Sets the proof-of-work effort anonymous senders to a mailbox must pay, or None to accept them without a stamp. Only tokens that can receive from the mailbox may do this.
+    async fn v1_mailbox_pow_set(
+        &self,
+        auth: AuthToken,
+        mailbox: MailboxId,
+        effort: Option<u64>,
+    ) -> Result<(), ServerRpcError>;
+
+    /// Receive one or more messages, from one or many mailboxes. This is batched to make long-polling more efficient. The server may choose to limit the number of messages in the response, so clients should be prepared to repeat until getting an empty "page". Every mailbox that has entries when the call returns is included, not just the first one found.
     async fn v1_mailbox_multirecv(
         &self,
         args: Vec<MailboxRecvArgs>,
         timeout_ms: u64,
     ) -> Result<BTreeMap<MailboxId, Vec<MailboxEntry>>, ServerRpcError>;
 
+    /// Subscribe to one or many mailboxes. Instead of waiting for a response, the server pushes a `MailboxNotification` on the same connection, as a JSON-RPC notification with method `MAILBOX_NOTIFY_METHOD`, whenever the mailboxes get new entries. The subscription ID is picked by the client, and replaces any subscription with the same ID on the connection. Returns false if the connection cannot carry notifications, as is the case over HTTP.
+    async fn v1_mailbox_subscribe(
+        &self,
+        subscription: u64,
+        args: Vec<MailboxRecvArgs>,
+    ) -> Result<bool, ServerRpcError>;
+
+    /// Ends a subscription made on the same connection.
+    async fn v1_mailbox_unsubscribe(&self, subscription: u64) -> Result<(), ServerRpcError>;
+
     /// Edit the mailbox ACL.
     async fn v1_mailbox_acl_edit(
         &self,
@@ -272,6 +354,23 @@ pub struct MailboxEntry {
     pub sender_auth_token_hash: Option<Hash>,
 }
 
+/// The JSON-RPC method of the notifications pushed on a mailbox subscription.
+pub const MAILBOX_NOTIFY_METHOD: &str = "v1_mailbox_notify";
+
+/// The longest a mailbox subscription goes without a notification. When nothing new comes in for
+/// that long, the server pushes one with no entries, so that clients can tell a dead connection
+/// from quiet mailboxes.
+pub const MAILBOX_HEARTBEAT_SECS: u64 = 60;
+
+/// Pushed on a mailbox subscription when some of its mailboxes get new entries, and as a heartbeat
+/// with no entries at least every `MAILBOX_HEARTBEAT_SECS`. An error, such as losing access to one
+/// of the mailboxes, ends the subscription.
+#[derive(Serialize, Deserialize, Debug, Clone)]
+pub struct MailboxNotification {
+    pub subscription: u64,
+    pub entries: Result<BTreeMap<MailboxId, Vec<MailboxEntry>>, ServerRpcError>,
+}
+
 /// An ACL for a mailbox.
 #[derive(Serialize, Deserialize, Debug, Clone)]
 pub struct MailboxAcl {
@@ -310,6 +409,8 @@ pub enum ServerRpcError {
     AccessDenied,
     #[error("rate limited, retry later")]
     RetryLater,
+    #[error("quota exceeded")]
+    QuotaExceeded,
 }
 
 /// An error proxying to another server.